@@ -0,0 +1,419 @@
+use std::{error::Error, fs::write, path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use axum::{
+    body::Body,
+    extract::Path,
+    http::{header::HOST, Request, StatusCode},
+    response::{IntoResponse, Redirect},
+    routing::get,
+    Router,
+};
+use fxhash::FxHashMap;
+
+use lers::Solver;
+use log::info;
+use openssl::{
+    asn1::{Asn1Object, Asn1OctetString, Asn1Time},
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    rsa::Rsa,
+    symm::Cipher,
+    x509::{extension::SubjectAlternativeName, X509Builder, X509Extension, X509NameBuilder},
+};
+use parking_lot::{Mutex, RwLock};
+use regex::RegexSet;
+use serde::Deserialize;
+use tokio_rustls::rustls::{
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey,
+};
+use tower::ServiceExt;
+
+use crate::{tls::CertResolver, ConfigError, HyperDomeConfig};
+
+mod dns;
+mod on_demand;
+pub(crate) use dns::DnsProvider;
+pub(crate) use on_demand::{start as start_on_demand, OnDemand, OnDemandTlsConfig};
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum AcmeKeyType {
+    #[default]
+    EcdsaP256,
+    EcdsaP384,
+    Rsa2048,
+    Rsa4096,
+}
+
+impl AcmeKeyType {
+    fn generate(self) -> Result<PKey<Private>, openssl::error::ErrorStack> {
+        let ec = |nid| EcGroup::from_curve_name(nid).and_then(|x| EcKey::generate(&x));
+        match self {
+            Self::EcdsaP256 => PKey::from_ec_key(ec(Nid::X9_62_PRIME256V1)?),
+            Self::EcdsaP384 => PKey::from_ec_key(ec(Nid::SECP384R1)?),
+            Self::Rsa2048 => PKey::from_rsa(Rsa::generate(2048)?),
+            Self::Rsa4096 => PKey::from_rsa(Rsa::generate(4096)?),
+        }
+    }
+}
+
+fn directory_url(config: &HyperDomeConfig) -> &str {
+    if !config.acme_directory_url.is_empty() {
+        return &config.acme_directory_url;
+    }
+
+    #[cfg(not(debug_assertions))]
+    {
+        lers::LETS_ENCRYPT_PRODUCTION_URL
+    }
+    #[cfg(debug_assertions)]
+    {
+        lers::LETS_ENCRYPT_STAGING_URL
+    }
+}
+
+/// Checks the settings of ACME, and those that acquiring the certificates that are
+/// missing at `cert_path` and `key_path` would need
+pub(crate) fn validate(config: &HyperDomeConfig) -> Result<(), ConfigError> {
+    if config.acme_eab_key_id.is_empty() != config.acme_eab_hmac_key.is_empty() {
+        return Err(ConfigError::AcmeEab);
+    }
+    if !config.acme_ca_path.is_empty() {
+        let pem = std::fs::read(&config.acme_ca_path).map_err(|error| ConfigError::Unreadable {
+            path: config.acme_ca_path.clone().into(),
+            error,
+        })?;
+        reqwest::Certificate::from_pem(&pem).map_err(ConfigError::AcmeCa)?;
+    }
+    if config.cert_path.is_empty() || config.key_path.is_empty() {
+        return Ok(());
+    }
+    let missing = certificate_slots(config)
+        .into_iter()
+        .filter(|x| !x.cert_path.exists() && !x.key_path.exists());
+    for slot in missing {
+        if config.email.is_empty() {
+            return Err(ConfigError::AcmeEmail);
+        }
+        if slot.domains.is_empty() {
+            return Err(ConfigError::AcmeDomains);
+        }
+        if config.acme_dns.is_none() {
+            if let Some(domain) = slot.domains.iter().find(|x| x.starts_with("*.")) {
+                return Err(ConfigError::AcmeWildcard(domain.clone()));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn http_client(config: &HyperDomeConfig) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder().user_agent(concat!(
+        env!("CARGO_PKG_NAME"),
+        "/",
+        env!("CARGO_PKG_VERSION")
+    ));
+
+    if !config.acme_ca_path.is_empty() {
+        let pem =
+            std::fs::read(&config.acme_ca_path).expect("acme_ca_path should have been validated");
+        builder = builder.add_root_certificate(
+            reqwest::Certificate::from_pem(&pem).expect("acme_ca_path should have been validated"),
+        );
+    }
+
+    builder
+        .build()
+        .expect("ACME HTTP client should be buildable")
+}
+
+/// Writes a file only readable by the current user
+pub(crate) fn write_private(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, contents)
+}
+
+/// A certificate to serve, along with the domains it covers and where it is stored
+#[derive(Clone)]
+pub(crate) struct CertificateSlot {
+    pub(crate) domains: Vec<String>,
+    pub(crate) cert_path: PathBuf,
+    pub(crate) key_path: PathBuf,
+}
+
+/// `cert.pem` becomes `cert.example.com.pem`, with wildcards written as `_`
+fn path_for_domain(path: &str, domain: &str) -> PathBuf {
+    let path = std::path::Path::new(path);
+    let domain = domain.replace('*', "_");
+    let file_name = match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(extension)) => format!(
+            "{}.{domain}.{}",
+            stem.to_string_lossy(),
+            extension.to_string_lossy()
+        ),
+        _ => format!("{}.{domain}", path.to_string_lossy()),
+    };
+    path.with_file_name(file_name)
+}
+
+/// Either one certificate covering every domain at `cert_path` and `key_path`, or one
+/// certificate per domain next to them if `acme_separate_certificates` is set
+pub(crate) fn certificate_slots(config: &HyperDomeConfig) -> Vec<CertificateSlot> {
+    let mut domains = vec![];
+    if !config.domain_name.is_empty() {
+        domains.push(config.domain_name.clone());
+    }
+    for domain in &config.domain_names {
+        if !domains.contains(domain) {
+            domains.push(domain.clone());
+        }
+    }
+
+    if !config.acme_separate_certificates || domains.len() <= 1 {
+        return vec![CertificateSlot {
+            domains,
+            cert_path: config.cert_path.clone().into(),
+            key_path: config.key_path.clone().into(),
+        }];
+    }
+
+    domains
+        .into_iter()
+        .map(|domain| CertificateSlot {
+            cert_path: path_for_domain(&config.cert_path, &domain),
+            key_path: path_for_domain(&config.key_path, &domain),
+            domains: vec![domain],
+        })
+        .collect()
+}
+
+/// Key authorizations of pending HTTP-01 challenges, served by the plain HTTP listener
+#[derive(Clone, Default)]
+pub(crate) struct Http01Challenges(Arc<RwLock<FxHashMap<String, String>>>);
+
+impl Http01Challenges {
+    /// Serves `/.well-known/acme-challenge/*` and the paths of `app` matching
+    /// `app_paths`, redirecting every other request to HTTPS
+    pub(crate) fn router(&self, https_port: u16, app: Router, app_paths: RegexSet) -> Router {
+        let challenges = self.clone();
+        Router::new()
+            .route(
+                "/.well-known/acme-challenge/:token",
+                get(move |Path(token): Path<String>| async move {
+                    match challenges.0.read().get(&token) {
+                        Some(key_authorization) => Ok(key_authorization.clone()),
+                        None => Err(StatusCode::NOT_FOUND),
+                    }
+                }),
+            )
+            .fallback(move |request: Request<Body>| async move {
+                if app_paths.is_match(request.uri().path()) {
+                    return app.oneshot(request).await.into_response();
+                }
+
+                let Some(host) = request.headers().get(HOST).and_then(|x| x.to_str().ok()) else {
+                    return StatusCode::BAD_REQUEST.into_response();
+                };
+                // IPv6 literals end with ] when they have no port
+                let host = if host.ends_with(']') {
+                    host
+                } else {
+                    host.rsplit_once(':').map_or(host, |x| x.0)
+                };
+                let path = request
+                    .uri()
+                    .path_and_query()
+                    .map(|x| x.as_str())
+                    .unwrap_or("/");
+                if https_port == 443 {
+                    Redirect::permanent(&format!("https://{host}{path}")).into_response()
+                } else {
+                    Redirect::permanent(&format!("https://{host}:{https_port}{path}"))
+                        .into_response()
+                }
+            })
+    }
+}
+
+#[async_trait]
+impl Solver for Http01Challenges {
+    async fn present(
+        &self,
+        _domain: String,
+        token: String,
+        key_authorization: String,
+    ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+        self.0.write().insert(token, key_authorization);
+        Ok(())
+    }
+
+    async fn cleanup(&self, token: &str) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+        self.0.write().remove(token);
+        Ok(())
+    }
+}
+
+/// Answers TLS-ALPN-01 challenges through the certificate resolver of the running
+/// TlsAcceptor
+struct TlsAlpn01Solver {
+    resolver: Arc<CertResolver>,
+    tokens: Mutex<FxHashMap<String, String>>,
+}
+
+fn challenge_certificate(
+    domain: &str,
+    key_authorization: &str,
+) -> Result<CertifiedKey, openssl::error::ErrorStack> {
+    let key = AcmeKeyType::EcdsaP256.generate()?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, domain)?;
+    let name = name.build();
+
+    // The acmeIdentifier extension holds the SHA-256 digest of the key authorization
+    // as a DER OCTET STRING
+    let mut identifier = vec![0x04, 0x20];
+    identifier.extend(openssl::sha::sha256(key_authorization.as_bytes()));
+
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+    builder.set_not_after(Asn1Time::days_from_now(1)?.as_ref())?;
+    builder.append_extension(
+        SubjectAlternativeName::new()
+            .dns(domain)
+            .build(&builder.x509v3_context(None, None))?,
+    )?;
+    builder.append_extension(X509Extension::new_from_der(
+        Asn1Object::from_str("1.3.6.1.5.5.7.1.31")?.as_ref(),
+        true,
+        Asn1OctetString::new_from_bytes(&identifier)?.as_ref(),
+    )?)?;
+    builder.sign(&key, MessageDigest::sha256())?;
+
+    let key = PrivateKey(key.private_key_to_pkcs8()?);
+    Ok(CertifiedKey::new(
+        vec![Certificate(builder.build().to_der()?)],
+        sign::any_supported_type(&key).expect("Generated key should be supported"),
+    ))
+}
+
+#[async_trait]
+impl Solver for TlsAlpn01Solver {
+    async fn present(
+        &self,
+        domain: String,
+        token: String,
+        key_authorization: String,
+    ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+        let certified = challenge_certificate(&domain, &key_authorization)?;
+        self.resolver
+            .set_challenge(&domain, Some(Arc::new(certified)));
+        self.tokens.lock().insert(token, domain);
+        Ok(())
+    }
+
+    async fn cleanup(&self, token: &str) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+        if let Some(domain) = self.tokens.lock().remove(token) {
+            self.resolver.set_challenge(&domain, None);
+        }
+        Ok(())
+    }
+}
+
+/// Obtains a certificate for every slot and writes them to the paths of the slots.
+/// Challenges are answered over DNS-01 if `acme_dns` is set, over TLS-ALPN-01 through
+/// `resolver` if `acme_tls_alpn` is set, and over HTTP-01 through `http_challenges`
+/// otherwise
+pub(crate) async fn acquire_certificates(
+    config: &HyperDomeConfig,
+    slots: &[CertificateSlot],
+    resolver: &Arc<CertResolver>,
+    http_challenges: &Http01Challenges,
+) -> Vec<(Vec<Certificate>, PrivateKey)> {
+    let certificates = obtain_certificates(config, slots, resolver, http_challenges)
+        .await
+        .unwrap_or_else(|e| panic!("Error running LERS: {e}"));
+    info!("Certificates successfully downloaded");
+    certificates
+}
+
+/// Like [`acquire_certificates`], except that failures are returned instead of
+/// panicking, and `config` is expected to have been checked
+async fn obtain_certificates(
+    config: &HyperDomeConfig,
+    slots: &[CertificateSlot],
+    resolver: &Arc<CertResolver>,
+    http_challenges: &Http01Challenges,
+) -> Result<Vec<(Vec<Certificate>, PrivateKey)>, String> {
+    let url = directory_url(config);
+    info!("Using ACME directory {url}");
+    let client = http_client(config);
+    let mut directory = lers::Directory::builder(url).client(client.clone());
+
+    directory = match config.acme_dns.clone() {
+        Some(provider) => directory.dns01_solver(provider.into_solver(client)),
+        None if config.acme_tls_alpn => directory.tls_alpn01_solver(Box::new(TlsAlpn01Solver {
+            resolver: resolver.clone(),
+            tokens: Default::default(),
+        })),
+        None => directory.http01_solver(Box::new(http_challenges.clone())),
+    };
+    let directory = directory.build().await.map_err(|e| e.to_string())?;
+
+    let mut account = directory
+        .account()
+        .terms_of_service_agreed(true)
+        .contacts(vec![format!("mailto:{}", config.email)]);
+    if !config.acme_eab_key_id.is_empty() {
+        account = account.external_account(&config.acme_eab_key_id, &config.acme_eab_hmac_key);
+    }
+    let account = account
+        .create_if_not_exists()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let passphrase = config.key_passphrase();
+    let mut certificates = vec![];
+    for slot in slots {
+        info!("Requesting a certificate for {}", slot.domains.join(", "));
+        let private_key = config.acme_key_type.generate().map_err(|e| e.to_string())?;
+        let mut builder = account.certificate().private_key(private_key.clone());
+        for domain in &slot.domains {
+            builder = builder.add_domain(domain);
+        }
+        let certificate = builder.obtain().await.map_err(|e| e.to_string())?;
+
+        let certs: Vec<_> = certificate
+            .x509_chain()
+            .iter()
+            .map(|x| Certificate(x.to_der().unwrap()))
+            .collect();
+        let key = PrivateKey(certificate.private_key_to_der().unwrap());
+
+        write(&slot.cert_path, certificate.fullchain_to_pem().unwrap())
+            .map_err(|e| format!("Cert file should be writable: {e}"))?;
+        let key_pem = match &passphrase {
+            Some(passphrase) => private_key
+                .private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), passphrase)
+                .map_err(|e| e.to_string())?,
+            None => certificate.private_key_to_pem().unwrap(),
+        };
+        write_private(&slot.key_path, &key_pem)
+            .map_err(|e| format!("Key file should be writable: {e}"))?;
+
+        certificates.push((certs, key));
+    }
+
+    Ok(certificates)
+}
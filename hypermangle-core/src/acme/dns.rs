@@ -0,0 +1,406 @@
+use std::{error::Error, fmt::Write, process::Stdio, time::Duration};
+
+use async_trait::async_trait;
+use fxhash::FxHashMap;
+use lers::{solver::dns::CloudflareDns01Solver, Solver};
+use log::{debug, info};
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use tokio::{io::AsyncWriteExt, process::Command};
+
+type SolverResult = Result<(), Box<dyn Error + Send + Sync + 'static>>;
+
+/// Where the TXT records for DNS-01 challenges get published
+#[derive(Deserialize, Clone)]
+#[serde(tag = "provider", rename_all = "kebab-case")]
+pub(crate) enum DnsProvider {
+    /// Reads `CLOUDFLARE_API_TOKEN` from the environment when `api_token` is empty
+    Cloudflare {
+        #[serde(default)]
+        api_token: String,
+    },
+    /// Reads the `AWS_*` credential variables from the environment when the keys are empty
+    Route53 {
+        hosted_zone_id: String,
+        #[serde(default)]
+        access_key_id: String,
+        #[serde(default)]
+        secret_access_key: String,
+        #[serde(default)]
+        session_token: String,
+    },
+    /// Dynamic DNS updates sent with `nsupdate`
+    Rfc2136 {
+        server: String,
+        #[serde(default = "default_dns_port")]
+        port: u16,
+        /// TSIG key file, passed to `nsupdate -k`
+        #[serde(default)]
+        key_file: String,
+        #[serde(default = "default_ttl")]
+        ttl: u32,
+    },
+}
+
+fn default_dns_port() -> u16 {
+    53
+}
+
+fn default_ttl() -> u32 {
+    60
+}
+
+fn non_empty_or_env(value: &str, var: &str) -> String {
+    if value.is_empty() {
+        std::env::var(var).unwrap_or_default()
+    } else {
+        value.to_owned()
+    }
+}
+
+impl DnsProvider {
+    pub(crate) fn into_solver(self, client: reqwest::Client) -> Box<dyn Solver> {
+        match self {
+            Self::Cloudflare { api_token } => {
+                let builder = if api_token.is_empty() {
+                    CloudflareDns01Solver::from_env()
+                        .expect("Cloudflare credentials should be in the environment")
+                } else {
+                    CloudflareDns01Solver::new_with_token(api_token)
+                };
+                Box::new(
+                    builder
+                        .build()
+                        .expect("Cloudflare DNS-01 solver should be buildable"),
+                )
+            }
+            Self::Route53 {
+                hosted_zone_id,
+                access_key_id,
+                secret_access_key,
+                session_token,
+            } => {
+                let access_key_id = non_empty_or_env(&access_key_id, "AWS_ACCESS_KEY_ID");
+                let secret_access_key =
+                    non_empty_or_env(&secret_access_key, "AWS_SECRET_ACCESS_KEY");
+                if access_key_id.is_empty() || secret_access_key.is_empty() {
+                    panic!("Route53 credentials not provided!");
+                }
+                Box::new(Route53Solver {
+                    client,
+                    hosted_zone_id: hosted_zone_id.trim_start_matches("/hostedzone/").to_owned(),
+                    access_key_id,
+                    secret_access_key,
+                    session_token: non_empty_or_env(&session_token, "AWS_SESSION_TOKEN"),
+                    records: Default::default(),
+                })
+            }
+            Self::Rfc2136 {
+                server,
+                port,
+                key_file,
+                ttl,
+            } => Box::new(Rfc2136Solver {
+                server,
+                port,
+                key_file,
+                ttl,
+                tokens: Default::default(),
+            }),
+        }
+    }
+}
+
+fn boxed_err(e: impl Into<String>) -> Box<dyn Error + Send + Sync + 'static> {
+    e.into().into()
+}
+
+fn record_name(domain: &str) -> String {
+    format!("_acme-challenge.{}.", domain.trim_start_matches("*."))
+}
+
+/// The TXT values currently published under each record name, which can be several
+/// when a wildcard and its base domain are validated together
+#[derive(Default)]
+struct PublishedRecords {
+    values: FxHashMap<String, Vec<String>>,
+    tokens: FxHashMap<String, (String, String)>,
+}
+
+const ROUTE53_HOST: &str = "route53.amazonaws.com";
+/// Route53 is a global service, signed against us-east-1
+const ROUTE53_REGION: &str = "us-east-1";
+
+struct Route53Solver {
+    client: reqwest::Client,
+    hosted_zone_id: String,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: String,
+    records: Mutex<PublishedRecords>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, x| {
+        let _ = write!(out, "{x:02x}");
+        out
+    })
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let key = PKey::hmac(key).expect("HMAC key should be valid");
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).expect("HMAC should be available");
+    signer
+        .sign_oneshot_to_vec(data.as_bytes())
+        .expect("HMAC should be computable")
+}
+
+impl Route53Solver {
+    /// Sends a request signed with AWS Signature Version 4
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: String,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        // 2023-01-02T03:04:05Z -> 20230102T030405Z
+        let amz_date: String = humantime::format_rfc3339_seconds(std::time::SystemTime::now())
+            .to_string()
+            .chars()
+            .filter(|x| *x != '-' && *x != ':')
+            .collect();
+        let date = &amz_date[..8];
+        let scope = format!("{date}/{ROUTE53_REGION}/route53/aws4_request");
+
+        let mut headers = vec![
+            ("host", ROUTE53_HOST.to_owned()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if !self.session_token.is_empty() {
+            headers.push(("x-amz-security-token", self.session_token.clone()));
+        }
+        let signed_headers = headers.iter().map(|x| x.0).collect::<Vec<_>>().join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}:{value}\n"))
+            .collect();
+
+        let canonical_request = format!(
+            "{method}\n{path}\n\n{canonical_headers}\n{signed_headers}\n{}",
+            hex(&openssl::sha::sha256(body.as_bytes()))
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex(&openssl::sha::sha256(canonical_request.as_bytes()))
+        );
+
+        let key = hmac(format!("AWS4{}", self.secret_access_key).as_bytes(), date);
+        let key = hmac(&key, ROUTE53_REGION);
+        let key = hmac(&key, "route53");
+        let key = hmac(&key, "aws4_request");
+        let signature = hex(&hmac(&key, &string_to_sign));
+
+        let mut request = self
+            .client
+            .request(method, format!("https://{ROUTE53_HOST}{path}"))
+            .header(
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+                    self.access_key_id
+                ),
+            )
+            .body(body);
+        for (name, value) in headers.into_iter().skip(1) {
+            request = request.header(name, value);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        let text = response.text().await?;
+        if status.is_success() {
+            Ok(text)
+        } else {
+            Err(boxed_err(format!(
+                "Route53 responded with {status}: {text}"
+            )))
+        }
+    }
+
+    /// Replaces the TXT values of `name`, deleting the record if `values` is empty
+    async fn change(&self, name: &str, values: &[String], previous: &[String]) -> SolverResult {
+        let (action, values) = if values.is_empty() {
+            ("DELETE", previous)
+        } else {
+            ("UPSERT", values)
+        };
+        let records: String = values
+            .iter()
+            .map(|x| format!("<ResourceRecord><Value>\"{x}\"</Value></ResourceRecord>"))
+            .collect();
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+<ChangeResourceRecordSetsRequest xmlns=\"https://route53.amazonaws.com/doc/2013-04-01/\">\
+<ChangeBatch><Changes><Change><Action>{action}</Action><ResourceRecordSet>\
+<Name>{name}</Name><Type>TXT</Type><TTL>{}</TTL><ResourceRecords>{records}</ResourceRecords>\
+</ResourceRecordSet></Change></Changes></ChangeBatch></ChangeResourceRecordSetsRequest>",
+            default_ttl()
+        );
+
+        let response = self
+            .send(
+                reqwest::Method::POST,
+                &format!("/2013-04-01/hostedzone/{}/rrset", self.hosted_zone_id),
+                body,
+            )
+            .await?;
+        let Some(change_id) = xml_value(&response, "Id") else {
+            return Err(boxed_err(format!(
+                "Unexpected Route53 response: {response}"
+            )));
+        };
+        let change_id = change_id.to_owned();
+
+        // The CA only checks once, so wait until every Route53 server has the record
+        for _ in 0..60 {
+            let response = self
+                .send(
+                    reqwest::Method::GET,
+                    &format!("/2013-04-01{change_id}"),
+                    String::new(),
+                )
+                .await?;
+            if xml_value(&response, "Status") == Some("INSYNC") {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
+        Err(boxed_err(format!(
+            "Route53 change {change_id} did not sync"
+        )))
+    }
+}
+
+fn xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{tag}>"))?;
+    Some(&xml[start..end])
+}
+
+#[async_trait]
+impl Solver for Route53Solver {
+    async fn present(
+        &self,
+        domain: String,
+        token: String,
+        key_authorization: String,
+    ) -> SolverResult {
+        let name = record_name(&domain);
+        let (values, previous) = {
+            let mut records = self.records.lock();
+            records
+                .tokens
+                .insert(token, (name.clone(), key_authorization.clone()));
+            let values = records.values.entry(name.clone()).or_default();
+            let previous = values.clone();
+            values.push(key_authorization);
+            (values.clone(), previous)
+        };
+        info!("Publishing {name} through Route53");
+        self.change(&name, &values, &previous).await
+    }
+
+    async fn cleanup(&self, token: &str) -> SolverResult {
+        let (name, values, previous) = {
+            let mut records = self.records.lock();
+            let Some((name, value)) = records.tokens.remove(token) else {
+                return Ok(());
+            };
+            let values = records.values.entry(name.clone()).or_default();
+            let previous = values.clone();
+            values.retain(|x| *x != value);
+            let values = values.clone();
+            if values.is_empty() {
+                records.values.remove(&name);
+            }
+            (name, values, previous)
+        };
+        self.change(&name, &values, &previous).await
+    }
+
+    fn attempts(&self) -> usize {
+        60
+    }
+}
+
+struct Rfc2136Solver {
+    server: String,
+    port: u16,
+    key_file: String,
+    ttl: u32,
+    tokens: Mutex<FxHashMap<String, (String, String)>>,
+}
+
+impl Rfc2136Solver {
+    async fn nsupdate(&self, update: String) -> SolverResult {
+        let mut command = Command::new("nsupdate");
+        if !self.key_file.is_empty() {
+            command.arg("-k").arg(&self.key_file);
+        }
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| boxed_err(format!("nsupdate should be runnable: {e}")))?;
+
+        let script = format!("server {} {}\n{update}\nsend\n", self.server, self.port);
+        debug!("Running nsupdate with:\n{script}");
+        let mut stdin = child
+            .stdin
+            .take()
+            .expect("stdin of nsupdate should be piped");
+        stdin.write_all(script.as_bytes()).await?;
+        drop(stdin);
+
+        let output = child.wait_with_output().await?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(boxed_err(format!(
+                "nsupdate failed with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            )))
+        }
+    }
+}
+
+#[async_trait]
+impl Solver for Rfc2136Solver {
+    async fn present(
+        &self,
+        domain: String,
+        token: String,
+        key_authorization: String,
+    ) -> SolverResult {
+        let name = record_name(&domain);
+        info!("Publishing {name} to {}", self.server);
+        self.nsupdate(format!(
+            "update add {name} {} TXT \"{key_authorization}\"",
+            self.ttl
+        ))
+        .await?;
+        self.tokens.lock().insert(token, (name, key_authorization));
+        Ok(())
+    }
+
+    async fn cleanup(&self, token: &str) -> SolverResult {
+        let Some((name, value)) = self.tokens.lock().remove(token) else {
+            return Ok(());
+        };
+        self.nsupdate(format!("update delete {name} TXT \"{value}\""))
+            .await
+    }
+}
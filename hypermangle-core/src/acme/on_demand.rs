@@ -0,0 +1,321 @@
+//! Obtains certificates the first time clients ask for names that have none, for
+//! hosting the custom domains of customers
+
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use fxhash::FxHashMap;
+use log::{info, warn};
+use openssl::{asn1::Asn1Time, x509::X509};
+use parking_lot::{Mutex, RwLock};
+use regex::RegexSet;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::{
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey,
+};
+
+use super::{obtain_certificates, CertificateSlot, Http01Challenges};
+use crate::{metrics, tls::CertResolver, ConfigError, HyperDomeConfig};
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct OnDemandTlsConfig {
+    /// Regexes that SNI names must match whole to have certificates obtained for them
+    allowed_names: Vec<String>,
+    /// Asked with `GET {ask_url}?domain={name}` before obtaining a certificate, which
+    /// is only obtained if it responds with a 2xx status
+    #[serde(default)]
+    ask_url: String,
+    /// Where obtained certificates are kept, as `{name}.cert.pem` and `{name}.key.pem`
+    #[serde(default = "default_dir")]
+    dir: String,
+    /// At most this many certificates are obtained in an hour, as the CA limits how
+    /// many it issues
+    #[serde(default = "default_max_per_hour")]
+    max_per_hour: usize,
+}
+
+impl OnDemandTlsConfig {
+    /// Names must match a pattern whole, not just contain a match
+    fn allowed_names(&self) -> Result<RegexSet, regex::Error> {
+        RegexSet::new(self.allowed_names.iter().map(|x| format!("^(?:{x})$")))
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        self.allowed_names()
+            .map(drop)
+            .map_err(ConfigError::OnDemandNames)
+    }
+}
+
+fn default_dir() -> String {
+    "certs/on-demand".into()
+}
+
+fn default_max_per_hour() -> usize {
+    10
+}
+
+/// Certificates closer than this to expiring are obtained again
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How long a name that could not get a certificate is left alone for
+const RETRY_AFTER: Duration = Duration::from_secs(60 * 60);
+
+const ASK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most names that are held at once. Clients can ask for any name, so names are
+/// not requested while this many are held, rather than remembering all of them
+const MAX_HELD: usize = 10_000;
+
+struct Obtained {
+    certified: Arc<CertifiedKey>,
+    renew_at: SystemTime,
+}
+
+/// The certificates obtained on demand, which [`CertResolver`] serves for the names
+/// its own certificates do not cover
+pub(crate) struct OnDemand {
+    allowed: RegexSet,
+    certificates: RwLock<FxHashMap<String, Obtained>>,
+    /// Names that are being obtained, or failed to be, and are not requested again
+    /// until then
+    held_until: Mutex<FxHashMap<String, Instant>>,
+    requests: mpsc::UnboundedSender<String>,
+}
+
+/// Only plain DNS names get certificates, which also keeps them safe as file names
+fn is_dns_name(name: &str) -> bool {
+    name.len() <= 253
+        && name.split('.').count() >= 2
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|x| x.is_ascii_lowercase() || x.is_ascii_digit() || x == b'-')
+        })
+        // Not an IPv4 address
+        && !name.split('.').all(|x| x.bytes().all(|x| x.is_ascii_digit()))
+}
+
+fn paths_for(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+    (
+        dir.join(format!("{name}.cert.pem")),
+        dir.join(format!("{name}.key.pem")),
+    )
+}
+
+fn count(outcome: &str) {
+    metrics::increment(
+        "hypermangle_on_demand_certificates_total",
+        "Certificates requested on demand, by whether they were obtained",
+        &[("outcome", outcome)],
+    );
+}
+
+fn to_obtained(certs: Vec<Certificate>, key: &PrivateKey) -> Option<Obtained> {
+    let leaf = X509::from_der(&certs.first()?.0).ok()?;
+    let days_left = Asn1Time::days_from_now(0)
+        .and_then(|now| now.diff(leaf.not_after()))
+        .ok()?
+        .days;
+    let renew_at = (SystemTime::now() + Duration::from_secs(days_left.max(0) as u64 * 86400))
+        .checked_sub(RENEW_BEFORE)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    Some(Obtained {
+        certified: Arc::new(CertifiedKey::new(
+            certs,
+            sign::any_supported_type(key).ok()?,
+        )),
+        renew_at,
+    })
+}
+
+impl OnDemand {
+    /// The certificate obtained for `name`, which is renewed once it is close to
+    /// expiring
+    pub(crate) fn get(&self, name: &str) -> Option<Arc<CertifiedKey>> {
+        let name = name.to_ascii_lowercase();
+        let certificates = self.certificates.read();
+        let obtained = certificates.get(&name)?;
+        if obtained.renew_at <= SystemTime::now() {
+            self.request(&name);
+        }
+        Some(obtained.certified.clone())
+    }
+
+    /// Asks for a certificate for `name` to be obtained, returning whether it is
+    /// allowed to have one
+    pub(crate) fn request(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        if !is_dns_name(&name) || !self.allowed.is_match(&name) {
+            return false;
+        }
+        let mut held_until = self.held_until.lock();
+        let now = Instant::now();
+        if held_until.get(&name).is_some_and(|x| *x > now) {
+            return true;
+        }
+        if held_until.len() >= MAX_HELD {
+            held_until.retain(|_, x| *x > now);
+            if held_until.len() >= MAX_HELD {
+                return true;
+            }
+        }
+        held_until.insert(name.clone(), now + RETRY_AFTER);
+        let _ = self.requests.send(name);
+        true
+    }
+
+    fn release(&self, name: &str) {
+        self.held_until.lock().remove(name);
+    }
+}
+
+/// Whether the `ask_url` allows `name` to have a certificate
+async fn is_allowed(client: &reqwest::Client, ask_url: &str, name: &str) -> bool {
+    if ask_url.is_empty() {
+        return true;
+    }
+    match client
+        .get(ask_url)
+        .query(&[("domain", name)])
+        .timeout(ASK_TIMEOUT)
+        .send()
+        .await
+    {
+        Ok(response) => response.status().is_success(),
+        Err(e) => {
+            warn!("Failed to ask {ask_url} about {name}: {e}");
+            false
+        }
+    }
+}
+
+/// Serves the certificates in the folder of `on_demand` through `resolver`, and
+/// obtains new ones when its handshakes ask for them
+pub(crate) fn start(
+    config: &HyperDomeConfig,
+    on_demand: &OnDemandTlsConfig,
+    resolver: &Arc<CertResolver>,
+    http_challenges: &Http01Challenges,
+) {
+    assert!(
+        !config.email.is_empty(),
+        "email should be set to obtain certificates on demand"
+    );
+    let allowed = on_demand
+        .allowed_names()
+        .expect("on_demand_tls.allowed_names should have been validated");
+    let dir = PathBuf::from(&on_demand.dir);
+    std::fs::create_dir_all(&dir).expect("on_demand_tls.dir should be creatable");
+
+    let passphrase = config.key_passphrase();
+    let mut certificates = FxHashMap::default();
+    for entry in dir
+        .read_dir()
+        .expect("on_demand_tls.dir should be readable")
+    {
+        let path = entry
+            .expect("On-demand certificate should be readable")
+            .path();
+        let Some(name) = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.strip_suffix(".cert.pem"))
+        else {
+            continue;
+        };
+        let (cert_path, key_path) = paths_for(&dir, name);
+        if !key_path.exists() {
+            warn!("On-demand certificate {cert_path:?} has no key at {key_path:?}");
+            continue;
+        }
+        let (certs, key) =
+            crate::tls::load_certificate(&cert_path, &key_path, passphrase.as_deref());
+        if let Some(obtained) = to_obtained(certs, &key) {
+            certificates.insert(name.to_owned(), obtained);
+        }
+    }
+    info!("Loaded {} on-demand certificates", certificates.len());
+
+    let (requests, mut receiver) = mpsc::unbounded_channel::<String>();
+    let on_demand_state = Arc::new(OnDemand {
+        allowed,
+        certificates: RwLock::new(certificates),
+        held_until: Default::default(),
+        requests,
+    });
+    resolver.set_on_demand(on_demand_state.clone());
+
+    let config = config.clone();
+    let ask_url = on_demand.ask_url.clone();
+    let max_per_hour = on_demand.max_per_hour;
+    let resolver = resolver.clone();
+    let http_challenges = http_challenges.clone();
+    let client = super::http_client(&config);
+    let on_demand = on_demand_state;
+    let handle = tokio::runtime::Handle::current();
+    // lers futures are not Send, so certificates are obtained one at a time on a
+    // thread of their own
+    std::thread::Builder::new()
+        .name("on-demand-tls".into())
+        .spawn(move || {
+            handle.block_on(async move {
+                let mut obtained_at: VecDeque<Instant> = VecDeque::new();
+                while let Some(name) = receiver.recv().await {
+                    if !is_allowed(&client, &ask_url, &name).await {
+                        info!("Refused to obtain a certificate for {name}");
+                        count("refused");
+                        continue;
+                    }
+                    while obtained_at
+                        .front()
+                        .is_some_and(|x| x.elapsed() > Duration::from_secs(3600))
+                    {
+                        obtained_at.pop_front();
+                    }
+                    if obtained_at.len() >= max_per_hour {
+                        warn!("Not obtaining a certificate for {name}, as on_demand_tls.max_per_hour was reached");
+                        count("limited");
+                        continue;
+                    }
+                    obtained_at.push_back(Instant::now());
+
+                    let (cert_path, key_path) = paths_for(&dir, &name);
+                    let slot = CertificateSlot {
+                        domains: vec![name.clone()],
+                        cert_path,
+                        key_path,
+                    };
+                    info!("Obtaining a certificate for {name} on demand");
+                    let result =
+                        obtain_certificates(&config, &[slot], &resolver, &http_challenges).await;
+                    match result.map(|mut x| x.pop()) {
+                        Ok(Some((certs, key))) => {
+                            if let Some(obtained) = to_obtained(certs, &key) {
+                                on_demand.certificates.write().insert(name.clone(), obtained);
+                            }
+                            on_demand.release(&name);
+                            info!("Obtained a certificate for {name}");
+                            count("obtained");
+                        }
+                        Ok(None) => unreachable!("A certificate should be obtained for the slot"),
+                        Err(e) => {
+                            warn!("Failed to obtain a certificate for {name}: {e}");
+                            count("failed");
+                        }
+                    }
+                }
+            })
+        })
+        .expect("On-demand TLS thread should be spawnable");
+}
@@ -0,0 +1,283 @@
+//! A dashboard for the operators of a server, showing what it serves and how it is
+//! doing, with the actions of the console that are needed most in an incident
+
+use std::time::Instant;
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use constant_time_eq::constant_time_eq;
+use hypermangle_py::{connections, runtime};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::{self, AuditEvent},
+    metrics, panics,
+    routes::{self, RouteRegistry},
+    spans::client_info,
+    ConfigError,
+};
+
+/// Served at `path`, to those who log in with `username` and `password`
+#[derive(Deserialize, Clone)]
+pub(crate) struct AdminConfig {
+    #[serde(default = "default_path")]
+    path: String,
+    #[serde(default = "default_username")]
+    username: String,
+    password: String,
+}
+
+fn default_path() -> String {
+    "/admin".into()
+}
+
+fn default_username() -> String {
+    "admin".into()
+}
+
+const INDEX_HTML: &str = include_str!("admin/index.html");
+const APP_JS: &str = include_str!("admin/app.js");
+const APP_CSS: &str = include_str!("admin/app.css");
+
+/// Browsers only send headers like it after a CORS preflight, which other sites
+/// cannot pass, so that they cannot make a logged in operator press the buttons
+const ACTION_HEADER: &str = "x-hypermangle-admin";
+
+#[derive(Serialize)]
+struct Sample {
+    name: &'static str,
+    labels: String,
+    value: u64,
+}
+
+#[derive(Serialize)]
+struct Route {
+    path: String,
+    methods: Vec<&'static str>,
+    access: &'static str,
+    source: String,
+    is_multi_pathed: bool,
+    lazy: bool,
+    /// The status the route responds with while disabled
+    disabled: Option<u16>,
+}
+
+#[derive(Serialize)]
+struct Connection {
+    id: u64,
+    path: String,
+    peer: String,
+    open_secs: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+#[derive(Serialize)]
+struct State {
+    version: &'static str,
+    uptime_secs: u64,
+    maintenance: bool,
+    in_flight: u64,
+    queue_depth: u64,
+    event_loop_lag_ms: f64,
+    metrics: Vec<Sample>,
+    routes: Vec<Route>,
+    errors: Vec<panics::RecentPanic>,
+    connections: Vec<Connection>,
+}
+
+fn state(started: Instant) -> State {
+    use std::sync::atomic::Ordering;
+
+    State {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_secs: started.elapsed().as_secs(),
+        maintenance: routes::in_maintenance(),
+        in_flight: runtime::IN_FLIGHT.load(Ordering::Relaxed),
+        queue_depth: runtime::QUEUE_DEPTH.load(Ordering::Relaxed),
+        event_loop_lag_ms: runtime::EVENT_LOOP_LAG_MICROS.load(Ordering::Relaxed) as f64 / 1e3,
+        metrics: metrics::samples()
+            .into_iter()
+            .map(|(name, labels, value)| Sample {
+                name,
+                labels,
+                value,
+            })
+            .collect(),
+        routes: routes::route_table()
+            .into_iter()
+            .map(|x| Route {
+                access: routes::access(&x.http_path),
+                disabled: routes::disabled_status(&x.http_path).map(|x| x.as_u16()),
+                source: x.source.display().to_string(),
+                path: x.http_path,
+                methods: x.methods,
+                is_multi_pathed: x.is_multi_pathed,
+                lazy: x.lazy,
+            })
+            .collect(),
+        errors: panics::recent(),
+        connections: connections::list()
+            .into_iter()
+            .map(|x| Connection {
+                id: x.id,
+                path: x.path,
+                peer: x.peer,
+                open_secs: x.duration.as_secs(),
+                bytes_in: x.bytes_in,
+                bytes_out: x.bytes_out,
+            })
+            .collect(),
+    }
+}
+
+/// Whether the `Authorization` header holds the credentials of the dashboard
+fn is_authorized(headers: &HeaderMap, username: &str, password: &str) -> bool {
+    let Some(encoded) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Basic "))
+    else {
+        return false;
+    };
+    let Ok(decoded) = openssl::base64::decode_block(encoded.trim()) else {
+        return false;
+    };
+    let Some(colon) = decoded.iter().position(|x| *x == b':') else {
+        return false;
+    };
+    let (given_username, given_password) = (&decoded[..colon], &decoded[colon + 1..]);
+    // Both are compared, so that the time taken does not tell which was wrong
+    let username_matches = constant_time_eq(given_username, username.as_bytes());
+    constant_time_eq(given_password, password.as_bytes()) && username_matches
+}
+
+fn no_store(response: impl IntoResponse) -> Response {
+    let mut response = response.into_response();
+    response
+        .headers_mut()
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response
+}
+
+fn asset(content_type: &'static str, body: &'static str) -> Response {
+    no_store(([(header::CONTENT_TYPE, content_type)], body))
+}
+
+#[derive(Deserialize)]
+struct Maintenance {
+    enabled: bool,
+}
+
+/// Routes the dashboard, returning the regex of its paths, which need no API token
+/// as the dashboard has credentials of its own
+impl AdminConfig {
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        let path = self.path.trim_end_matches('/');
+        if !path.starts_with('/') {
+            return Err(ConfigError::AdminPath(self.path.clone()));
+        }
+        if self.password.is_empty() {
+            return Err(ConfigError::AdminPassword);
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn register_routes(routes: &mut RouteRegistry, config: &AdminConfig) {
+    routes.add_prefix(config.path.trim_end_matches('/'), "the admin dashboard");
+}
+
+pub(crate) fn route_admin(router: Router, config: &AdminConfig) -> (Router, String) {
+    let path = config.path.trim_end_matches('/').to_owned();
+    let started = Instant::now();
+    // Relative, so that it still works behind a proxy that serves the server under a
+    // prefix of its own
+    let index = format!("{}/", path.rsplit('/').next().unwrap_or_default());
+    let username = config.username.clone();
+    let password = config.password.clone();
+
+    let admin = Router::new()
+        .route(&path, get(move || async move { Redirect::to(&index) }))
+        .route(
+            &format!("{path}/"),
+            get(|| async { asset("text/html; charset=utf-8", INDEX_HTML) }),
+        )
+        .route(
+            &format!("{path}/app.js"),
+            get(|| async { asset("text/javascript; charset=utf-8", APP_JS) }),
+        )
+        .route(
+            &format!("{path}/app.css"),
+            get(|| async { asset("text/css; charset=utf-8", APP_CSS) }),
+        )
+        .route(
+            &format!("{path}/state"),
+            get(move || async move { no_store(Json(state(started))) }),
+        )
+        .route(
+            &format!("{path}/reload"),
+            post(|| async { no_store(crate::console::reload().await) }),
+        )
+        .route(
+            &format!("{path}/maintenance"),
+            post(|Json(maintenance): Json<Maintenance>| async move {
+                routes::set_maintenance(maintenance.enabled);
+                no_store(StatusCode::NO_CONTENT)
+            }),
+        )
+        .route_layer(axum::middleware::from_fn(
+            move |request: Request<Body>, next: Next<Body>| {
+                let authorized = is_authorized(request.headers(), &username, &password);
+                let username = username.clone();
+                async move {
+                    if !authorized {
+                        audit::record(AuditEvent {
+                            action: "auth_failure",
+                            principal: "anonymous",
+                            detail: &format!(
+                                "{} {} from {}: admin credentials were not given",
+                                request.method(),
+                                request.uri().path(),
+                                client_info(&request)
+                            ),
+                        });
+                        return (
+                            StatusCode::UNAUTHORIZED,
+                            [(
+                                header::WWW_AUTHENTICATE,
+                                r#"Basic realm="hypermangle admin", charset="UTF-8""#,
+                            )],
+                        )
+                            .into_response();
+                    }
+                    if request.method() == axum::http::Method::POST {
+                        if !request.headers().contains_key(ACTION_HEADER) {
+                            return StatusCode::FORBIDDEN.into_response();
+                        }
+                        audit::record(AuditEvent {
+                            action: "admin_action",
+                            principal: &username,
+                            detail: &format!(
+                                "{} from {}",
+                                request.uri().path(),
+                                client_info(&request)
+                            ),
+                        });
+                    }
+                    next.run(request).await
+                }
+            },
+        ));
+
+    (
+        router.merge(admin),
+        format!("^{}(/.*)?$", regex::escape(&path)),
+    )
+}
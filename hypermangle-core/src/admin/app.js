@@ -0,0 +1,171 @@
+"use strict";
+
+// Refreshed this often, which is cheap as the state is only read from memory
+const REFRESH_MS = 2000;
+
+let state = null;
+
+function $(id) {
+  return document.getElementById(id);
+}
+
+function cell(row, text, className) {
+  const td = row.insertCell();
+  td.textContent = text;
+  if (className) td.className = className;
+  return td;
+}
+
+function duration(secs) {
+  const units = [["d", 86400], ["h", 3600], ["m", 60], ["s", 1]];
+  const parts = [];
+  for (const [unit, size] of units) {
+    if (secs >= size || (unit === "s" && parts.length === 0)) {
+      parts.push(Math.floor(secs / size) + unit);
+      secs %= size;
+    }
+    if (parts.length === 2) break;
+  }
+  return parts.join(" ");
+}
+
+function bytes(count) {
+  const units = ["B", "KiB", "MiB", "GiB"];
+  let unit = 0;
+  while (count >= 1024 && unit < units.length - 1) {
+    count /= 1024;
+    unit++;
+  }
+  return (unit ? count.toFixed(1) : count) + " " + units[unit];
+}
+
+function show(message, isError) {
+  const p = $("message");
+  p.textContent = message;
+  p.className = isError ? "error" : "";
+  p.hidden = false;
+}
+
+function renderErrors() {
+  const errors = $("errors");
+  errors.replaceChildren();
+  $("no-errors").hidden = state.errors.length > 0;
+  for (const error of state.errors) {
+    const details = document.createElement("details");
+    const summary = document.createElement("summary");
+    summary.textContent = `${error.at}  ${error.route}  ${error.message.split("\n")[0]}`;
+    const pre = document.createElement("pre");
+    pre.textContent = `request ${error.request_id}\n\n${error.message}`;
+    details.append(summary, pre);
+    errors.append(details);
+  }
+}
+
+function renderRoutes() {
+  const body = $("routes");
+  body.replaceChildren();
+  for (const route of state.routes) {
+    const row = body.insertRow();
+    cell(row, route.methods.join(", "));
+    cell(row, route.path + (route.is_multi_pathed ? "*" : ""), "mono");
+    cell(row, route.access);
+    cell(row, route.source, "mono");
+    if (state.maintenance) {
+      cell(row, "maintenance, responds 503", "warn");
+    } else if (route.disabled) {
+      cell(row, `disabled, responds ${route.disabled}`, "warn");
+    } else {
+      cell(row, route.lazy ? "lazy" : "serving");
+    }
+  }
+}
+
+function renderConnections() {
+  const body = $("connections");
+  body.replaceChildren();
+  for (const connection of state.connections) {
+    const row = body.insertRow();
+    cell(row, connection.id);
+    cell(row, connection.path, "mono");
+    cell(row, connection.peer, "mono");
+    cell(row, duration(connection.open_secs));
+    cell(row, bytes(connection.bytes_in), "number");
+    cell(row, bytes(connection.bytes_out), "number");
+  }
+}
+
+function renderMetrics() {
+  const filter = $("metric-filter").value.toLowerCase();
+  const body = $("metrics");
+  body.replaceChildren();
+  for (const metric of state.metrics) {
+    if (filter && !(metric.name + metric.labels).toLowerCase().includes(filter)) continue;
+    const row = body.insertRow();
+    cell(row, metric.name, "mono");
+    cell(row, metric.labels, "mono");
+    cell(row, metric.value.toLocaleString(), "number");
+  }
+}
+
+function render() {
+  $("version").textContent = state.version;
+  $("uptime").textContent = "up " + duration(state.uptime_secs);
+  $("status").textContent = state.maintenance ? "maintenance" : "serving";
+  $("status").className = "badge " + (state.maintenance ? "warn" : "ok");
+  $("maintenance").textContent = state.maintenance ? "End maintenance" : "Start maintenance";
+  $("in-flight").textContent = state.in_flight;
+  $("queue-depth").textContent = state.queue_depth;
+  $("event-loop-lag").textContent = state.event_loop_lag_ms.toFixed(1) + " ms";
+  $("connection-count").textContent = state.connections.length;
+  renderErrors();
+  renderRoutes();
+  renderConnections();
+  renderMetrics();
+}
+
+async function refresh() {
+  try {
+    const response = await fetch("state", { cache: "no-store" });
+    if (!response.ok) throw new Error(`the server responded ${response.status}`);
+    state = await response.json();
+    render();
+  } catch (e) {
+    show(`Could not refresh: ${e.message}`, true);
+  }
+}
+
+async function act(path, body) {
+  const options = { method: "POST", headers: { "x-hypermangle-admin": "1" } };
+  if (body !== undefined) {
+    options.headers["content-type"] = "application/json";
+    options.body = JSON.stringify(body);
+  }
+  const response = await fetch(path, options);
+  if (!response.ok) throw new Error(`the server responded ${response.status}`);
+  return response.text();
+}
+
+document.addEventListener("DOMContentLoaded", () => {
+  $("reload").addEventListener("click", async () => {
+    try {
+      show(await act("reload"), false);
+    } catch (e) {
+      show(`Could not reload: ${e.message}`, true);
+    }
+    refresh();
+  });
+  $("maintenance").addEventListener("click", async () => {
+    const enabled = !state.maintenance;
+    if (enabled && !confirm("Every route will respond with 503 until maintenance ends")) return;
+    try {
+      await act("maintenance", { enabled });
+    } catch (e) {
+      show(`Could not change maintenance mode: ${e.message}`, true);
+    }
+    refresh();
+  });
+  $("metric-filter").addEventListener("input", () => state && renderMetrics());
+
+  refresh();
+  setInterval(refresh, REFRESH_MS);
+});
@@ -0,0 +1,169 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{mpsc, OnceLock},
+    time::SystemTime,
+};
+
+use log::error;
+use serde::Serialize;
+
+/// An action worth keeping a record of, such as a console command or a failed login
+#[derive(Serialize)]
+pub struct AuditEvent<'a> {
+    pub action: &'a str,
+    /// Who performed the action, such as the name of an API key
+    pub principal: &'a str,
+    pub detail: &'a str,
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    time: String,
+    #[serde(flatten)]
+    event: AuditEvent<'a>,
+}
+
+struct AuditLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    keep: usize,
+}
+
+enum Message {
+    Line(Vec<u8>),
+    /// Answered with whether the file could be reopened
+    Reopen(mpsc::Sender<std::io::Result<()>>),
+}
+
+/// How many records can wait for the writer, past which they are dropped rather than
+/// holding up the actions they record
+const QUEUE_CAPACITY: usize = 4096;
+
+/// Records are written by a thread of their own, so that recording one never waits
+/// on the disk
+static AUDIT_LOG: OnceLock<mpsc::SyncSender<Message>> = OnceLock::new();
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.append(true).create(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+/// Starts recording audit events as JSON lines in `path`. Once the file would grow
+/// past `max_bytes`, it is moved to `path.1`, and older files are shifted along up to
+/// `path.{keep}`
+pub(crate) fn init(path: &Path, max_bytes: u64, keep: usize) {
+    let file =
+        open_append(path).unwrap_or_else(|e| panic!("Audit log {path:?} should be writable: {e}"));
+    let size = file.metadata().map(|x| x.len()).unwrap_or_default();
+    let mut log = AuditLog {
+        path: path.to_owned(),
+        file,
+        size,
+        max_bytes,
+        keep,
+    };
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+    if AUDIT_LOG.set(sender).is_err() {
+        return;
+    }
+    std::thread::Builder::new()
+        .name("audit-log".into())
+        .spawn(move || {
+            for message in receiver {
+                match message {
+                    Message::Line(line) => {
+                        if let Err(e) = log.append(&line) {
+                            error!("Faced the following error while writing to the audit log: {e}");
+                        }
+                    }
+                    Message::Reopen(reply) => {
+                        let _ = reply.send(log.reopen());
+                    }
+                }
+            }
+        })
+        .expect("Audit log thread should be spawnable");
+}
+
+impl AuditLog {
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.keep == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            for index in (1..self.keep).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    std::fs::rename(from, self.rotated_path(index + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = open_append(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn append(&mut self, line: &[u8]) -> std::io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn reopen(&mut self) -> std::io::Result<()> {
+        self.file = open_append(&self.path)?;
+        self.size = self.file.metadata()?.len();
+        Ok(())
+    }
+}
+
+/// Reopens the audit log at its path, such as after logrotate has moved it, returning
+/// whether one is configured
+pub(crate) fn reopen() -> std::io::Result<bool> {
+    let Some(log) = AUDIT_LOG.get() else {
+        return Ok(false);
+    };
+    let (reply, answer) = mpsc::channel();
+    let gone = || std::io::Error::other("The audit log thread has stopped");
+    log.send(Message::Reopen(reply)).map_err(|_| gone())?;
+    answer.recv().map_err(|_| gone())??;
+    Ok(true)
+}
+
+/// Queues `event` to be appended to the audit log, if one is configured
+pub fn record(event: AuditEvent) {
+    let Some(log) = AUDIT_LOG.get() else {
+        return;
+    };
+    let mut line = serde_json::to_vec(&AuditRecord {
+        time: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        event,
+    })
+    .expect("Audit events should be serializable");
+    line.push(b'\n');
+
+    match log.try_send(Message::Line(line)) {
+        Ok(()) => {}
+        Err(mpsc::TrySendError::Full(_)) => {
+            error!("Dropped an audit record, as the audit log is not keeping up")
+        }
+        Err(mpsc::TrySendError::Disconnected(_)) => {
+            error!("Dropped an audit record, as the audit log thread has stopped")
+        }
+    }
+}
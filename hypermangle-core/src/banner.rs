@@ -0,0 +1,89 @@
+//! The summary of what a server is serving, shown once its routes are loaded
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use fxhash::FxHashSet;
+use log::{debug, info};
+
+use crate::{routes, HyperDomeConfig};
+
+/// Set by `run --quiet`, which only logs the summary at the debug level
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_quiet(value: bool) {
+    QUIET.store(value, Ordering::Relaxed);
+}
+
+/// The features hypermangle was built with
+fn features() -> Vec<&'static str> {
+    [
+        ("python", cfg!(feature = "python")),
+        ("hot-reload", cfg!(feature = "hot-reload")),
+        ("nats", cfg!(feature = "nats")),
+        ("amqp", cfg!(feature = "amqp")),
+        ("kafka", cfg!(feature = "kafka")),
+        ("msgpack", cfg!(feature = "msgpack")),
+        ("cbor", cfg!(feature = "cbor")),
+        ("protobuf", cfg!(feature = "protobuf")),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect()
+}
+
+fn format_banner(config: &HyperDomeConfig) -> String {
+    let tls = !config.cert_path.is_empty() && !config.key_path.is_empty();
+    let mut out = format!(
+        "hypermangle {} serving {} on {}\n",
+        env!("CARGO_PKG_VERSION"),
+        if tls { "HTTPS" } else { "HTTP" },
+        config.bind_address
+    );
+    if tls {
+        if let Some(address) = config.plain_http_address() {
+            let _ = writeln!(out, "  Plain HTTP on {address}");
+        }
+    }
+    for listener in &config.listeners {
+        let _ = writeln!(
+            out,
+            "  {} on {}",
+            if listener.is_tls() { "HTTPS" } else { "HTTP" },
+            listener.address
+        );
+    }
+    if let Some(remote_console) = &config.remote_console {
+        let _ = writeln!(out, "  Remote console on {}", remote_console.bind_address());
+    }
+
+    let routes = routes::route_table();
+    let scripts: FxHashSet<_> = routes.iter().map(|x| &x.source).collect();
+    let features = features();
+    let _ = writeln!(
+        out,
+        "  {} scripts loaded, with features: {}",
+        scripts.len(),
+        if features.is_empty() {
+            "none".to_owned()
+        } else {
+            features.join(", ")
+        }
+    );
+    out + &routes::format_route_table()
+}
+
+/// Prints the summary when running under `dev`, and logs it otherwise, as operators
+/// would otherwise have no confirmation of what was loaded
+pub(crate) fn announce(config: &HyperDomeConfig) {
+    let banner = format_banner(config);
+    if QUIET.load(Ordering::Relaxed) {
+        debug!("{}", banner.trim_end());
+    } else if routes::should_print_routes() {
+        print!("{banner}");
+    } else {
+        info!("{}", banner.trim_end());
+    }
+}
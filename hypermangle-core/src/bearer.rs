@@ -1,15 +1,32 @@
 use axum::{
     body::HttpBody,
-    http::{HeaderValue, Request, Response, StatusCode},
+    http::{
+        header::ACCESS_CONTROL_REQUEST_METHOD, HeaderValue, Method, Request, Response, StatusCode,
+    },
 };
 use constant_time_eq::constant_time_eq;
 use regex::RegexSet;
-use std::marker::PhantomData;
+use std::{marker::PhantomData, sync::Arc};
 use tower_http::auth::AsyncAuthorizeRequest;
 
+use crate::{
+    audit::{self, AuditEvent},
+    keys::KeyStore,
+    signed_urls::SignedUrl,
+    spans::client_info,
+    tenants,
+};
+
+/// The bearer token a request was authenticated with, which may only be trusted once
+/// it has been compared with the configured ones
+#[derive(Clone)]
+pub(crate) struct VerifiedToken(pub(crate) String);
+
 pub struct BearerAuth<ResBody> {
-    api_token: HeaderValue,
+    api_token: Option<HeaderValue>,
+    keys: Option<Arc<KeyStore>>,
     public_paths: RegexSet,
+    cors_passthrough: Arc<[String]>,
     _phantom: PhantomData<ResBody>,
 }
 
@@ -20,17 +37,26 @@ impl<ResBody> Clone for BearerAuth<ResBody> {
     fn clone(&self) -> Self {
         Self {
             api_token: self.api_token.clone(),
+            keys: self.keys.clone(),
             public_paths: self.public_paths.clone(),
+            cors_passthrough: self.cors_passthrough.clone(),
             _phantom: self._phantom,
         }
     }
 }
 
 impl<ResBody> BearerAuth<ResBody> {
-    pub fn new(api_token: HeaderValue, public_paths: RegexSet) -> Self {
+    pub fn new(
+        api_token: Option<HeaderValue>,
+        keys: Option<Arc<KeyStore>>,
+        public_paths: RegexSet,
+        cors_passthrough: Vec<String>,
+    ) -> Self {
         Self {
             api_token,
+            keys,
             public_paths,
+            cors_passthrough: cors_passthrough.into(),
             _phantom: Default::default(),
         }
     }
@@ -45,48 +71,87 @@ where
 
     fn authorize(&mut self, request: Request<ReqBody>) -> Self::Future {
         macro_rules! unauthorized {
-            () => {
+            ($reason: literal) => {{
+                audit::record(AuditEvent {
+                    action: "auth_failure",
+                    principal: "anonymous",
+                    detail: &format!(
+                        "{} {} from {}: {}",
+                        request.method(),
+                        request.uri().path(),
+                        client_info(&request),
+                        $reason
+                    ),
+                });
                 return std::future::ready(Err(Response::builder()
                     .status(StatusCode::UNAUTHORIZED)
                     .body(Default::default())
-                    .unwrap()))
-            };
+                    .unwrap()));
+            }};
         }
-        if self.public_paths.is_match(request.uri().path()) {
+        // Browsers never send credentials with CORS preflights, which the CORS layer
+        // answers before they get here, except for proxies that pass them upstream
+        let is_preflight = request.method() == Method::OPTIONS
+            && request
+                .headers()
+                .contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+            && crate::proxy::is_cors_passthrough(&self.cors_passthrough, request.uri().path());
+        if is_preflight
+            || self.public_paths.is_match(request.uri().path())
+            || request.extensions().get::<SignedUrl>().is_some()
+        {
+            return std::future::ready(Ok(request));
+        }
+        let api_token = tenants::for_request_path(request.uri().path())
+            .and_then(|x| x.api_token.as_ref())
+            .or(self.api_token.as_ref());
+        // Only tenants may require tokens, in which case other routes are public
+        if api_token.is_none() && self.keys.is_none() {
             return std::future::ready(Ok(request));
         }
 
-        match request.headers().get("Authorization") {
+        let token = match request.headers().get("Authorization") {
             Some(header) => {
                 let header = match header.to_str() {
                     Ok(x) => x,
-                    Err(_) => unauthorized!(),
+                    Err(_) => unauthorized!("Authorization header is not valid UTF-8"),
                 };
 
                 if !header.starts_with("Bearer ") {
-                    unauthorized!()
+                    unauthorized!("Authorization header is not a bearer token")
                 }
 
-                let token = header.split_at(7).1;
-
-                if constant_time_eq(token.as_bytes(), self.api_token.as_bytes()) {
-                    std::future::ready(Ok(request))
-                } else {
-                    unauthorized!()
-                }
-            }
-            None => {
-                if let Some(query) = request.uri().query() {
-                    if query.contains(&format!(
-                        "api_token={}",
-                        self.api_token.to_str().expect("API Token to be utf-8")
-                    )) {
-                        return std::future::ready(Ok(request));
-                    }
-                }
-                unauthorized!()
+                header.split_at(7).1
             }
+            None => match request
+                .uri()
+                .query()
+                .and_then(|query| query.split('&').find_map(|x| x.strip_prefix("api_token=")))
+            {
+                Some(token) => token,
+                None => unauthorized!("no token was given"),
+            },
+        };
+
+        if api_token.is_some_and(|x| constant_time_eq(token.as_bytes(), x.as_bytes())) {
+            let token = VerifiedToken(token.to_owned());
+            let mut request = request;
+            request.extensions_mut().insert(token);
+            return std::future::ready(Ok(request));
         }
+        let Some(key) = self.keys.as_ref().and_then(|x| x.authenticate(token)) else {
+            unauthorized!("token is not valid")
+        };
+        audit::record(AuditEvent {
+            action: "key_used",
+            principal: &key.name,
+            detail: &format!("{} {}", request.method(), request.uri().path()),
+        });
+        let token = VerifiedToken(token.to_owned());
+        let mut request = request;
+        request.extensions_mut().insert(key);
+        request.extensions_mut().insert(token);
+        std::future::ready(Ok(request))
     }
 
     type RequestBody = ReqBody;
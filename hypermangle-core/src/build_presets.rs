@@ -0,0 +1,148 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub(crate) enum BuildPreset {
+    /// The host's own target, built with cargo
+    Native,
+    /// x86_64 Linux against glibc, built with cross using Cross.toml
+    LinuxGnu,
+    /// x86_64 Linux against musl. The Python interpreter must be linkable statically
+    LinuxMusl,
+    /// 64-bit ARM Linux against glibc
+    Aarch64,
+    /// x86_64 Windows through the GNU toolchain
+    Windows,
+}
+
+impl BuildPreset {
+    fn target_triple(self) -> Option<&'static str> {
+        match self {
+            Self::Native => None,
+            Self::LinuxGnu => Some("x86_64-unknown-linux-gnu"),
+            Self::LinuxMusl => Some("x86_64-unknown-linux-musl"),
+            Self::Aarch64 => Some("aarch64-unknown-linux-gnu"),
+            Self::Windows => Some("x86_64-pc-windows-gnu"),
+        }
+    }
+
+    fn exe_suffix(self) -> &'static str {
+        match self {
+            Self::Windows => ".exe",
+            _ => "",
+        }
+    }
+}
+
+/// A line of `--message-format=json`, of which only those for artifacts are read
+#[derive(Deserialize)]
+struct CargoMessage {
+    reason: String,
+    target: Option<CargoTarget>,
+    executable: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct CargoTarget {
+    name: String,
+}
+
+/// Cross reports the paths of artifacts in its container, which has the target
+/// directory of the host mounted elsewhere, so those that do not exist are found
+/// below the target triple in the target directory of the host instead
+fn host_path(reported: PathBuf, target: Option<&str>) -> PathBuf {
+    let Some(target) = target.filter(|_| !reported.exists()) else {
+        return reported;
+    };
+    let mut path =
+        PathBuf::from(std::env::var_os("CARGO_TARGET_DIR").unwrap_or_else(|| "target".into()));
+    path.extend(
+        reported
+            .components()
+            .skip_while(|x| x.as_os_str() != target),
+    );
+    path
+}
+
+pub(crate) fn build(preset: BuildPreset, debug: bool, output_dir: &Path) {
+    if !Path::new("Cargo.toml").exists() {
+        panic!("build must be ran from the root of the crate embedding hypermangle");
+    }
+
+    let exe = std::env::current_exe().expect("Current EXE name should be accessible");
+    let exe_name = exe
+        .file_stem()
+        .expect("Current EXE should have a file name")
+        .to_str()
+        .expect("Current EXE name should be valid unicode");
+
+    let mut command = match preset.target_triple() {
+        Some(target) => {
+            let mut command = Command::new("cross");
+            command.args(["build", "--target", target]);
+            command
+        }
+        None => {
+            let mut command =
+                Command::new(std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into()));
+            command.arg("build");
+            command
+        }
+    };
+    if !debug {
+        command.arg("--release");
+    }
+    // Diagnostics are still rendered to stderr, while the paths of artifacts are read
+    // from stdout, as they depend on the target directory and profile settings
+    command.args([
+        "--bin",
+        exe_name,
+        "--message-format=json-render-diagnostics",
+    ]);
+    command.stdout(Stdio::piped());
+
+    let mut child = command.spawn().expect("cargo or cross should be runnable");
+    let stdout = child.stdout.take().expect("Stdout should have been piped");
+    let mut artifact = None;
+    for line in BufReader::new(stdout).lines() {
+        let line = line.expect("Output of cargo should be readable");
+        let Ok(message) = serde_json::from_str::<CargoMessage>(&line) else {
+            println!("{line}");
+            continue;
+        };
+        if message.reason == "compiler-artifact"
+            && message.target.is_some_and(|x| x.name == exe_name)
+        {
+            artifact = message.executable.or(artifact);
+        }
+    }
+    let status = child.wait().expect("cargo or cross should be runnable");
+    if !status.success() {
+        println!("Build failed with {status}");
+        return;
+    }
+    let Some(artifact) = artifact else {
+        println!("Build did not report an executable for {exe_name}");
+        return;
+    };
+    let artifact = host_path(artifact, preset.target_triple());
+
+    let output_dir = match preset.target_triple() {
+        Some(target) => output_dir.join(target),
+        None => output_dir.join("native"),
+    };
+    let file_name = format!("{exe_name}{}", preset.exe_suffix());
+
+    fs::create_dir_all(&output_dir).expect("Output directory should be writable");
+    let destination = output_dir.join(file_name);
+    fs::copy(&artifact, &destination)
+        .unwrap_or_else(|e| panic!("{artifact:?} should be copyable: {e}"));
+    println!("Built {}", destination.display());
+}
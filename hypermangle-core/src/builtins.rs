@@ -0,0 +1,322 @@
+use std::{
+    fs::read_to_string,
+    path::Path,
+    sync::OnceLock,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    http::{header, HeaderValue},
+    routing::get,
+    Router,
+};
+use regex::{Regex, RegexSet};
+use serde::Deserialize;
+
+use crate::{
+    routes::{self, RouteRegistry},
+    ConfigError, HyperDomeConfig,
+};
+
+/// Served as `/robots.txt`
+#[derive(Deserialize, Clone, Default)]
+pub(crate) struct RobotsConfig {
+    #[serde(default)]
+    groups: Vec<RobotsGroup>,
+    /// URLs of sitemaps
+    #[serde(default)]
+    sitemaps: Vec<String>,
+}
+
+#[derive(Deserialize, Clone)]
+struct RobotsGroup {
+    /// Crawlers the group applies to, where `*` is every crawler
+    #[serde(default = "default_user_agents")]
+    user_agents: Vec<String>,
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    disallow: Vec<String>,
+    #[serde(default)]
+    crawl_delay: Option<u32>,
+}
+
+fn default_user_agents() -> Vec<String> {
+    vec!["*".into()]
+}
+
+impl RobotsConfig {
+    fn render(&self) -> String {
+        let mut txt = String::new();
+        for group in &self.groups {
+            for user_agent in &group.user_agents {
+                txt += &format!("User-agent: {user_agent}\n");
+            }
+            for path in &group.allow {
+                txt += &format!("Allow: {path}\n");
+            }
+            for path in &group.disallow {
+                txt += &format!("Disallow: {path}\n");
+            }
+            // An empty group would apply to the next one
+            if group.allow.is_empty() && group.disallow.is_empty() {
+                txt += "Disallow:\n";
+            }
+            if let Some(delay) = group.crawl_delay {
+                txt += &format!("Crawl-delay: {delay}\n");
+            }
+            txt += "\n";
+        }
+        for sitemap in &self.sitemaps {
+            txt += &format!("Sitemap: {sitemap}\n");
+        }
+        txt
+    }
+}
+
+/// Served as `/sitemap.xml`, listing the scripts with a GET handler. Scripts can
+/// leave themselves out by setting `SITEMAP = False`
+#[derive(Deserialize, Clone)]
+pub(crate) struct SitemapConfig {
+    /// Such as `https://example.com`, which the paths of the scripts are added to
+    base_url: String,
+    /// Regexes of the paths that are left out
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+impl SitemapConfig {
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        RegexSet::new(&self.exclude).map_err(ConfigError::SitemapExclude)?;
+        Ok(())
+    }
+
+    fn url(&self) -> String {
+        format!("{}{SITEMAP_PATH}", self.base_url.trim_end_matches('/'))
+    }
+}
+
+fn opts_out_of_sitemap(script: &Path) -> bool {
+    static OPT_OUT_REGEX: OnceLock<Regex> = OnceLock::new();
+    let regex =
+        OPT_OUT_REGEX.get_or_init(|| Regex::new(r"(?m)^SITEMAP\s*(?::[^=]*)?=\s*False\b").unwrap());
+    read_to_string(script).is_ok_and(|x| regex.is_match(&x))
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Rendered for every request, so that scripts added or changed since are included
+fn render_sitemap(base_url: &str, exclude: &RegexSet) -> String {
+    let mut xml = String::from(concat!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+        "\n",
+        r#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">"#,
+        "\n"
+    ));
+    let mut routes = routes::route_table();
+    routes.sort_by(|a, b| a.http_path.cmp(&b.http_path));
+    routes.dedup_by(|a, b| a.http_path == b.http_path);
+
+    for route in routes {
+        if !route.methods.contains(&"GET")
+            || exclude.is_match(&route.http_path)
+            || routes::disabled_status(&route.http_path).is_some()
+            || opts_out_of_sitemap(&route.source)
+        {
+            continue;
+        }
+        xml += "  <url>\n";
+        xml += &format!(
+            "    <loc>{}</loc>\n",
+            escape_xml(&format!("{base_url}{}", route.http_path))
+        );
+        if let Ok(modified) = std::fs::metadata(&route.source).and_then(|x| x.modified()) {
+            xml += &format!(
+                "    <lastmod>{}</lastmod>\n",
+                humantime::format_rfc3339_seconds(modified)
+            );
+        }
+        xml += "  </url>\n";
+    }
+    xml + "</urlset>\n"
+}
+
+/// Served as `/.well-known/security.txt`, as described in RFC 9116
+#[derive(Deserialize, Clone)]
+pub(crate) struct SecurityTxtConfig {
+    /// Such as `mailto:security@example.com`
+    contact: Vec<String>,
+    /// When the file should no longer be trusted, in RFC 3339. Defaults to a year
+    /// after the server started
+    #[serde(default)]
+    expires: String,
+    #[serde(default)]
+    encryption: Vec<String>,
+    #[serde(default)]
+    acknowledgments: Vec<String>,
+    #[serde(default)]
+    preferred_languages: Vec<String>,
+    #[serde(default)]
+    canonical: Vec<String>,
+    #[serde(default)]
+    policy: Vec<String>,
+    #[serde(default)]
+    hiring: Vec<String>,
+}
+
+impl SecurityTxtConfig {
+    fn render(&self) -> String {
+        assert!(
+            !self.contact.is_empty(),
+            "security_txt.contact should not be empty"
+        );
+        let expires = if self.expires.is_empty() {
+            let next_year = SystemTime::now() + Duration::from_secs(365 * 24 * 60 * 60);
+            humantime::format_rfc3339_seconds(next_year).to_string()
+        } else {
+            self.expires.clone()
+        };
+
+        let mut txt = String::new();
+        let mut field = |name: &str, values: &[String]| {
+            for value in values {
+                txt += &format!("{name}: {value}\n");
+            }
+        };
+        field("Contact", &self.contact);
+        field("Expires", &[expires]);
+        field("Encryption", &self.encryption);
+        field("Acknowledgments", &self.acknowledgments);
+        if !self.preferred_languages.is_empty() {
+            field(
+                "Preferred-Languages",
+                &[self.preferred_languages.join(", ")],
+            );
+        }
+        field("Canonical", &self.canonical);
+        field("Policy", &self.policy);
+        field("Hiring", &self.hiring);
+        txt
+    }
+}
+
+const ROBOTS_PATH: &str = "/robots.txt";
+const SECURITY_TXT_PATH: &str = "/.well-known/security.txt";
+const FAVICON_PATH: &str = "/favicon.ico";
+const SITEMAP_PATH: &str = "/sitemap.xml";
+
+fn favicon_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|x| x.to_str()) {
+        Some("png") => "image/png",
+        Some("svg") => "image/svg+xml",
+        Some("gif") => "image/gif",
+        _ => "image/x-icon",
+    }
+}
+
+pub(crate) fn register_routes(routes: &mut RouteRegistry, config: &HyperDomeConfig) {
+    let paths = [
+        (config.robots.is_some(), ROBOTS_PATH, "robots"),
+        (
+            config.security_txt.is_some(),
+            SECURITY_TXT_PATH,
+            "security_txt",
+        ),
+        (
+            !config.favicon_path.is_empty(),
+            FAVICON_PATH,
+            "favicon_path",
+        ),
+        (config.sitemap.is_some(), SITEMAP_PATH, "sitemap"),
+    ];
+    for (_, path, source) in paths.into_iter().filter(|(x, _, _)| *x) {
+        routes.add(path, "GET", source);
+    }
+}
+
+/// Routes the files that every site is expected to have, which are served to anyone,
+/// even if other routes need a token
+pub(crate) fn route_builtins(
+    mut router: Router,
+    config: &HyperDomeConfig,
+) -> (Router, Vec<String>) {
+    let mut paths = vec![];
+    let text = HeaderValue::from_static("text/plain; charset=utf-8");
+
+    if let Some(robots) = &config.robots {
+        let mut robots = robots.clone();
+        if let Some(sitemap) = &config.sitemap {
+            let url = sitemap.url();
+            if !robots.sitemaps.contains(&url) {
+                robots.sitemaps.push(url);
+            }
+        }
+        let txt = robots.render();
+        let text = text.clone();
+        router = router.route(
+            ROBOTS_PATH,
+            get(|| async move { ([(header::CONTENT_TYPE, text)], txt) }),
+        );
+        paths.push(ROBOTS_PATH);
+    }
+    if let Some(security_txt) = &config.security_txt {
+        let txt = security_txt.render();
+        router = router.route(
+            SECURITY_TXT_PATH,
+            get(|| async move { ([(header::CONTENT_TYPE, text)], txt) }),
+        );
+        paths.push(SECURITY_TXT_PATH);
+    }
+    if !config.favicon_path.is_empty() {
+        let path = Path::new(&config.favicon_path);
+        let icon = std::fs::read(path)
+            .unwrap_or_else(|e| panic!("Favicon {path:?} should be readable: {e}"));
+        let content_type = HeaderValue::from_static(favicon_type(path));
+        router = router.route(
+            FAVICON_PATH,
+            get(|| async move {
+                (
+                    [
+                        (header::CONTENT_TYPE, content_type),
+                        (
+                            header::CACHE_CONTROL,
+                            HeaderValue::from_static("public, max-age=86400"),
+                        ),
+                    ],
+                    icon,
+                )
+            }),
+        );
+        paths.push(FAVICON_PATH);
+    }
+    if let Some(sitemap) = &config.sitemap {
+        let base_url = sitemap.base_url.trim_end_matches('/').to_owned();
+        let exclude =
+            RegexSet::new(&sitemap.exclude).expect("sitemap.exclude should have been validated");
+        router = router.route(
+            SITEMAP_PATH,
+            get(|| async move {
+                (
+                    [(
+                        header::CONTENT_TYPE,
+                        HeaderValue::from_static("application/xml"),
+                    )],
+                    render_sitemap(&base_url, &exclude),
+                )
+            }),
+        );
+        paths.push(SITEMAP_PATH);
+    }
+
+    let public_paths = paths
+        .into_iter()
+        .map(|x| format!("^{}$", regex::escape(x)))
+        .collect();
+    (router, public_paths)
+}
@@ -0,0 +1,225 @@
+//! Sends part of the traffic of a route to another version of its script, so that a
+//! rewrite can be rolled out gradually
+
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    http::{header, HeaderValue, Request},
+    middleware::Next,
+    Router,
+};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use tower::ServiceExt;
+
+use crate::{error_tracking::is_sampled, metrics, ScriptOptions};
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct CanaryConfig {
+    /// The route of the script being replaced. Paths below it are split too, for
+    /// multi-pathed scripts
+    path: String,
+    /// The new version of the script, such as `canary/users_v2.py`, which is routed
+    /// at `path`. It should be outside of the scripts folders, as it would share
+    /// their routes otherwise
+    script: String,
+    /// The share of requests that go to `script`, from 0 to 100
+    #[serde(default)]
+    percent: f64,
+    /// A request header that picks the version with `canary` or `stable`, whatever
+    /// the percentage
+    #[serde(default)]
+    header: String,
+    /// A cookie that picks the version like `header`. Clients without it are given
+    /// one, so that they keep getting the version they got first
+    #[serde(default)]
+    cookie: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Variant {
+    Stable,
+    Canary,
+}
+
+impl Variant {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "stable" => Some(Self::Stable),
+            "canary" => Some(Self::Canary),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Stable => "stable",
+            Self::Canary => "canary",
+        }
+    }
+}
+
+struct Canary {
+    path: String,
+    /// Locked, as routers are not `Sync`
+    router: Mutex<Router>,
+    fraction: f64,
+    header: String,
+    cookie: String,
+}
+
+fn cookie<'a>(request: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    request
+        .headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(';'))
+        .find_map(|x| {
+            let (key, value) = x.trim().split_once('=')?;
+            (key == name).then_some(value)
+        })
+}
+
+impl Canary {
+    fn new(config: &CanaryConfig, options: ScriptOptions) -> Self {
+        let path = config.path.trim_end_matches('/').to_owned();
+        assert!(
+            path.starts_with('/'),
+            "Canary path should start with / and not be the root, not {:?}",
+            config.path
+        );
+        assert!(
+            (0.0..=100.0).contains(&config.percent),
+            "Canary percent of {path} should be from 0 to 100, not {}",
+            config.percent
+        );
+
+        let script = std::path::Path::new(&config.script);
+        assert!(
+            script.is_file(),
+            "Canary script {script:?} should be a file"
+        );
+        #[cfg(feature = "python")]
+        let router = {
+            crate::py::register_embedded_module();
+            // Routed at `path` itself, as it is at the root of its own folder
+            crate::py::load_py_into_router(
+                Router::new(),
+                script.parent().unwrap_or(script),
+                &path,
+                script,
+                options,
+            )
+        };
+        #[cfg(not(feature = "python"))]
+        let router = {
+            let _options = options;
+            Router::new()
+        };
+
+        Self {
+            path,
+            router: Mutex::new(router),
+            fraction: config.percent / 100.0,
+            header: config.header.clone(),
+            cookie: config.cookie.clone(),
+        }
+    }
+
+    fn covers(&self, path: &str) -> bool {
+        path.strip_prefix(self.path.as_str())
+            .is_some_and(|x| x.is_empty() || x.starts_with('/'))
+    }
+
+    /// The version picked for the request, and whether the client should be given
+    /// a cookie to keep it
+    fn pick(&self, request: &Request<Body>) -> (Variant, bool) {
+        if !self.header.is_empty() {
+            if let Some(variant) = request
+                .headers()
+                .get(&self.header)
+                .and_then(|x| x.to_str().ok())
+                .and_then(Variant::parse)
+            {
+                return (variant, false);
+            }
+        }
+        if !self.cookie.is_empty() {
+            if let Some(variant) = cookie(request, &self.cookie).and_then(Variant::parse) {
+                return (variant, false);
+            }
+        }
+        let variant = if is_sampled(self.fraction) {
+            Variant::Canary
+        } else {
+            Variant::Stable
+        };
+        (variant, !self.cookie.is_empty())
+    }
+}
+
+/// Splits the requests to the routes in `router` that are covered by `canaries`
+/// between their scripts and the new versions of them
+pub(crate) fn layer_canaries(
+    router: Router,
+    canaries: &[CanaryConfig],
+    options: ScriptOptions,
+) -> Router {
+    if canaries.is_empty() {
+        return router;
+    }
+    let canaries: Arc<[Arc<Canary>]> = canaries
+        .iter()
+        .map(|x| Arc::new(Canary::new(x, options)))
+        .collect();
+
+    router.layer(axum::middleware::from_fn(
+        move |request: Request<Body>, next: Next<Body>| {
+            let canary = canaries
+                .iter()
+                .find(|x| x.covers(request.uri().path()))
+                .cloned();
+            async move {
+                let Some(canary) = canary else {
+                    return next.run(request).await;
+                };
+                let (variant, set_cookie) = canary.pick(&request);
+                let mut response = match variant {
+                    Variant::Stable => next.run(request).await,
+                    Variant::Canary => {
+                        let router = canary.router.lock().clone();
+                        router
+                            .oneshot(request)
+                            .await
+                            .expect("Routers should be infallible")
+                    }
+                };
+
+                if set_cookie {
+                    let cookie = format!(
+                        "{}={}; Path={}; Max-Age=86400; SameSite=Lax; HttpOnly",
+                        canary.cookie,
+                        variant.as_str(),
+                        canary.path
+                    );
+                    if let Ok(cookie) = HeaderValue::try_from(cookie) {
+                        response.headers_mut().append(header::SET_COOKIE, cookie);
+                    }
+                }
+                let status = format!("{}xx", response.status().as_u16() / 100);
+                metrics::increment(
+                    "hypermangle_canary_responses_total",
+                    "Responses of routes split between versions, by version and status class",
+                    &[
+                        ("path", &canary.path),
+                        ("variant", variant.as_str()),
+                        ("status", &status),
+                    ],
+                );
+                response
+            }
+        },
+    ))
+}
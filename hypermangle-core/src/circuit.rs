@@ -0,0 +1,275 @@
+use std::time::{Duration, Instant};
+
+use axum::{
+    http::{header::RETRY_AFTER, StatusCode},
+    response::{IntoResponse, Response},
+};
+use log::warn;
+use parking_lot::Mutex;
+use serde::Deserialize;
+
+use crate::metrics;
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct CircuitBreakerConfig {
+    /// Consecutive failures that open the circuit. 0 disables the breaker
+    #[serde(default = "default_failure_threshold")]
+    failure_threshold: u32,
+    /// How long the circuit stays open before a single request is let through to
+    /// check whether the upstream has recovered
+    #[serde(default = "default_open_secs")]
+    open_secs: u64,
+    /// What requests are answered with while the circuit is open
+    #[serde(default = "default_fallback_status")]
+    fallback_status: u16,
+    #[serde(default)]
+    fallback_body: String,
+}
+
+fn default_failure_threshold() -> u32 {
+    5
+}
+
+fn default_open_secs() -> u64 {
+    30
+}
+
+fn default_fallback_status() -> u16 {
+    503
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: default_failure_threshold(),
+            open_secs: default_open_secs(),
+            fallback_status: default_fallback_status(),
+            fallback_body: String::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A request has been let through. Another one is if it has not reported back
+    /// by `until`, such as when its client went away
+    HalfOpen {
+        until: Instant,
+    },
+}
+
+impl State {
+    fn gauge(self) -> u64 {
+        match self {
+            Self::Closed { .. } => 0,
+            Self::Open { .. } => 1,
+            Self::HalfOpen { .. } => 2,
+        }
+    }
+}
+
+/// Stops sending requests to an upstream that keeps failing, so that it has time to
+/// recover and clients are answered straight away in the meantime
+pub(crate) struct CircuitBreaker {
+    name: String,
+    config: CircuitBreakerConfig,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(name: String, config: CircuitBreakerConfig) -> Self {
+        let breaker = Self {
+            name,
+            config,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        };
+        breaker.publish(State::Closed { failures: 0 });
+        breaker
+    }
+
+    fn open_duration(&self) -> Duration {
+        Duration::from_secs(self.config.open_secs)
+    }
+
+    fn publish(&self, state: State) {
+        metrics::set_gauge(
+            "hypermangle_circuit_state",
+            "Whether circuits are closed (0), open (1) or half-open (2)",
+            &[("circuit", &self.name)],
+            state.gauge(),
+        );
+    }
+
+    fn set(&self, state: &mut State, new: State) {
+        if state.gauge() != new.gauge() {
+            self.publish(new);
+        }
+        *state = new;
+    }
+
+    /// Whether a request may be sent now. Every request that is allowed should be
+    /// followed by `record`
+    pub(crate) fn allow(&self) -> bool {
+        if self.config.failure_threshold == 0 {
+            return true;
+        }
+        let mut state = self.state.lock();
+        let now = Instant::now();
+        let allowed = match *state {
+            State::Closed { .. } => true,
+            State::Open { until } | State::HalfOpen { until } if now < until => false,
+            State::Open { .. } | State::HalfOpen { .. } => {
+                self.set(
+                    &mut state,
+                    State::HalfOpen {
+                        until: now + self.open_duration(),
+                    },
+                );
+                true
+            }
+        };
+        if !allowed {
+            metrics::increment(
+                "hypermangle_circuit_rejections_total",
+                "Requests answered with the fallback because their circuit was open",
+                &[("circuit", &self.name)],
+            );
+        }
+        allowed
+    }
+
+    pub(crate) fn record(&self, success: bool) {
+        if self.config.failure_threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock();
+        let new = match (*state, success) {
+            (_, true) => State::Closed { failures: 0 },
+            (State::Closed { failures }, false) if failures + 1 < self.config.failure_threshold => {
+                State::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (State::Open { until }, false) => State::Open { until },
+            (_, false) => {
+                warn!(
+                    "Opening circuit {} for {}s after repeated failures",
+                    self.name, self.config.open_secs
+                );
+                State::Open {
+                    until: Instant::now() + self.open_duration(),
+                }
+            }
+        };
+        self.set(&mut state, new);
+    }
+
+    /// How long until a request will be let through again
+    pub(crate) fn retry_after(&self) -> Duration {
+        match *self.state.lock() {
+            State::Open { until } | State::HalfOpen { until } => {
+                until.saturating_duration_since(Instant::now())
+            }
+            State::Closed { .. } => Duration::ZERO,
+        }
+    }
+
+    /// What requests are answered with while the circuit is open
+    pub(crate) fn fallback(&self) -> Response {
+        let status = StatusCode::from_u16(self.config.fallback_status)
+            .expect("Circuit breaker fallback_status should be a valid status code");
+        let retry_after = self.retry_after().as_secs_f64().ceil() as u64;
+        (
+            status,
+            [(RETRY_AFTER, retry_after.to_string())],
+            self.config.fallback_body.clone(),
+        )
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(failure_threshold: u32) -> CircuitBreaker {
+        CircuitBreaker::new(
+            "test".into(),
+            CircuitBreakerConfig {
+                failure_threshold,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Ends the open period, as if `open_secs` had passed
+    fn expire(breaker: &CircuitBreaker) {
+        let mut state = breaker.state.lock();
+        if let State::Open { until } | State::HalfOpen { until } = &mut *state {
+            *until = Instant::now();
+        }
+    }
+
+    #[test]
+    fn circuits_open_after_consecutive_failures() {
+        let breaker = breaker(3);
+        breaker.record(false);
+        breaker.record(false);
+        breaker.record(true);
+        breaker.record(false);
+        breaker.record(false);
+        assert!(breaker.allow());
+        breaker.record(false);
+        assert!(!breaker.allow());
+        assert!(breaker.retry_after() > Duration::from_secs(29));
+        assert_eq!(breaker.fallback().status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn half_open_circuits_let_one_request_through() {
+        let breaker = breaker(1);
+        breaker.record(false);
+        expire(&breaker);
+        assert!(breaker.allow());
+        assert!(matches!(*breaker.state.lock(), State::HalfOpen { .. }));
+        assert!(!breaker.allow());
+        // Another is let through if the first never reports back
+        expire(&breaker);
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn half_open_circuits_close_on_success_and_reopen_on_failure() {
+        let breaker = breaker(2);
+        breaker.record(false);
+        breaker.record(false);
+        expire(&breaker);
+        assert!(breaker.allow());
+        breaker.record(false);
+        assert!(!breaker.allow());
+
+        expire(&breaker);
+        assert!(breaker.allow());
+        breaker.record(true);
+        assert!(breaker.allow());
+        assert_eq!(breaker.retry_after(), Duration::ZERO);
+        // Failures count from 0 again once closed
+        breaker.record(false);
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn a_threshold_of_zero_disables_the_breaker() {
+        let breaker = breaker(0);
+        for _ in 0..100 {
+            breaker.record(false);
+        }
+        assert!(breaker.allow());
+    }
+}
@@ -0,0 +1,162 @@
+//! Which address requests come from, which is the address of the connection unless
+//! it is one of the trusted proxies
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::OnceLock,
+};
+
+use axum::http::{HeaderMap, Request};
+use ipnet::IpNet;
+
+/// The address a connection came from, in the extensions of its requests
+#[derive(Clone, Copy)]
+pub(crate) struct PeerAddress(pub(crate) SocketAddr);
+
+static TRUSTED_PROXIES: OnceLock<Vec<IpNet>> = OnceLock::new();
+
+/// Proxies are given as addresses or ranges, such as `10.0.0.1` or `10.0.0.0/8`
+pub(crate) fn parse_proxy(proxy: &str) -> Option<IpNet> {
+    proxy
+        .parse()
+        .ok()
+        .or_else(|| proxy.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+pub(crate) fn init(trusted_proxies: &[String]) {
+    let trusted_proxies = trusted_proxies
+        .iter()
+        .map(|x| parse_proxy(x).expect("trusted_proxies should have been validated"))
+        .collect();
+    let _ = TRUSTED_PROXIES.set(trusted_proxies);
+}
+
+fn parse_forwarded(address: &str) -> Option<IpAddr> {
+    let address = address.trim();
+    address
+        .parse()
+        .ok()
+        .or_else(|| address.parse::<SocketAddr>().ok().map(|x| x.ip()))
+}
+
+/// The client behind `peer`. Proxies append the address they were connected from to
+/// `X-Forwarded-For`, so it is read from the right, and the first address that is not
+/// a trusted proxy is the client. Anything to the left of it could have been made up
+/// by the client
+pub(crate) fn resolve(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    let is_trusted = |address: &IpAddr| trusted_proxies.iter().any(|x| x.contains(address));
+    let peer = peer.to_canonical();
+    if !is_trusted(&peer) {
+        return peer;
+    }
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .collect();
+    if forwarded.is_empty() {
+        return headers
+            .get("x-real-ip")
+            .and_then(|x| x.to_str().ok())
+            .and_then(parse_forwarded)
+            .unwrap_or(peer);
+    }
+    let mut client = peer;
+    for address in forwarded.into_iter().rev() {
+        let Some(address) = parse_forwarded(address).map(|x| x.to_canonical()) else {
+            break;
+        };
+        client = address;
+        if !is_trusted(&address) {
+            break;
+        }
+    }
+    client
+}
+
+/// The address of the client a request came from, which is unknown for transports
+/// without addresses
+pub(crate) fn for_peer(peer: Option<&PeerAddress>, headers: &HeaderMap) -> Option<IpAddr> {
+    let trusted_proxies = TRUSTED_PROXIES.get().map(Vec::as_slice).unwrap_or_default();
+    Some(resolve(peer?.0.ip(), headers, trusted_proxies))
+}
+
+pub(crate) fn of<B>(request: &Request<B>) -> Option<IpAddr> {
+    for_peer(request.extensions().get(), request.headers())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(forwarded: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for x in forwarded {
+            headers.append("x-forwarded-for", x.parse().unwrap());
+        }
+        headers
+    }
+
+    fn proxies() -> Vec<IpNet> {
+        ["10.0.0.0/8", "192.168.1.1"]
+            .iter()
+            .map(|x| parse_proxy(x).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn untrusted_peers_are_the_client() {
+        let peer = "203.0.113.5".parse().unwrap();
+        assert_eq!(resolve(peer, &headers(&["1.1.1.1"]), &proxies()), peer);
+        assert_eq!(resolve(peer, &headers(&["1.1.1.1"]), &[]), peer);
+    }
+
+    #[test]
+    fn forwarded_addresses_are_read_from_the_right() {
+        let peer = "10.0.0.2".parse().unwrap();
+        let headers = headers(&["6.6.6.6, 203.0.113.5", "192.168.1.1"]);
+        assert_eq!(
+            resolve(peer, &headers, &proxies()),
+            "203.0.113.5".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn the_leftmost_proxy_is_the_client_if_all_are_trusted() {
+        let peer = "10.0.0.2".parse().unwrap();
+        assert_eq!(
+            resolve(peer, &headers(&["10.1.1.1"]), &proxies()),
+            "10.1.1.1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn unparsable_addresses_stop_the_search() {
+        let peer = "10.0.0.2".parse().unwrap();
+        assert_eq!(
+            resolve(peer, &headers(&["1.1.1.1, garbage"]), &proxies()),
+            peer
+        );
+    }
+
+    #[test]
+    fn real_ip_is_used_without_forwarded_for() {
+        let peer = "10.0.0.2".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-real-ip", "203.0.113.5".parse().unwrap());
+        assert_eq!(
+            resolve(peer, &headers, &proxies()),
+            "203.0.113.5".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn mapped_addresses_are_trusted_like_their_ipv4() {
+        let peer = "::ffff:10.0.0.2".parse().unwrap();
+        assert_eq!(
+            resolve(peer, &headers(&["203.0.113.5:4000"]), &proxies()),
+            "203.0.113.5".parse::<IpAddr>().unwrap()
+        );
+    }
+}
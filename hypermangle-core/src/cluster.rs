@@ -0,0 +1,378 @@
+use std::{
+    sync::{atomic::Ordering, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{http::StatusCode, response::IntoResponse, routing::get, Json, Router};
+use futures::StreamExt;
+use hypermangle_py::{
+    connections,
+    hub::{self, HubMessage},
+    runtime::{IN_FLIGHT, QUEUE_DEPTH},
+};
+use log::{error, info, warn};
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, OnceCell};
+
+use crate::{routes::RouteRegistry, ConfigError};
+
+/// Instances with the same `redis_url` and `channel_prefix` share published messages,
+/// rate limit counters and reloads through Redis
+#[derive(Deserialize, Clone)]
+pub(crate) struct ClusterConfig {
+    redis_url: String,
+    #[serde(default = "default_channel_prefix")]
+    channel_prefix: String,
+    /// Names this instance in load reports. Defaults to the host name and process id
+    #[serde(default)]
+    instance: String,
+    /// Where load balancers and peers reach this instance, such as
+    /// `http://10.0.0.2:8080`, which is passed along in load reports
+    #[serde(default)]
+    advertise_url: String,
+    /// Serves the load of this instance as JSON, and that of every instance in the
+    /// cluster, least loaded first, below `{load_path}/peers`. Not served if empty
+    #[serde(default)]
+    load_path: String,
+}
+
+fn default_channel_prefix() -> String {
+    "hypermangle".into()
+}
+
+fn default_instance() -> String {
+    let host = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_else(|| "hypermangle".into());
+    format!("{}-{}", host.trim(), std::process::id())
+}
+
+/// Messages that are sent to every instance over the control channel
+const RELOAD: &[u8] = b"reload";
+
+/// How long to wait before connecting to Redis again
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How often every instance reports its load
+const LOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Reports older than this many intervals are from instances that have stopped
+const LOAD_STALE_INTERVALS: u32 = 3;
+
+struct Cluster {
+    config: ClusterConfig,
+    outbox: mpsc::UnboundedSender<(String, Vec<u8>)>,
+    client: redis::Client,
+    connection: OnceCell<ConnectionManager>,
+}
+
+impl Cluster {
+    async fn connection(&self) -> redis::RedisResult<ConnectionManager> {
+        self.connection
+            .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+            .await
+            .cloned()
+    }
+}
+
+/// How busy an instance is, for routing new sessions to the least loaded one. Load
+/// balancers with sticky sessions can only move new clients, so open websockets
+/// count as much as requests
+#[derive(Serialize, Deserialize)]
+struct Load {
+    instance: String,
+    url: String,
+    /// Open websocket connections
+    connections: u64,
+    in_flight: u64,
+    queue_depth: u64,
+    /// Cores worth of CPU time used over the last second
+    cpu: f64,
+    /// Milliseconds since the UNIX epoch
+    reported_at: u64,
+}
+
+impl Load {
+    fn current(config: &ClusterConfig) -> Self {
+        Self {
+            instance: config.instance.clone(),
+            url: config.advertise_url.clone(),
+            connections: connections::count() as u64,
+            in_flight: IN_FLIGHT.load(Ordering::Relaxed),
+            queue_depth: QUEUE_DEPTH.load(Ordering::Relaxed),
+            cpu: crate::runtime::cpu_usage(),
+            reported_at: unix_millis(),
+        }
+    }
+
+    /// Instances with lower scores are less loaded, and ties go to the one using
+    /// less CPU
+    fn score(&self) -> (u64, u64) {
+        (
+            self.connections + self.in_flight + self.queue_depth,
+            (self.cpu * 1000.0) as u64,
+        )
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+static CLUSTER: OnceLock<Cluster> = OnceLock::new();
+
+impl ClusterConfig {
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        redis::Client::open(self.redis_url.as_str()).map_err(|error| ConfigError::RedisUrl {
+            url: self.redis_url.clone(),
+            error,
+        })?;
+        Ok(())
+    }
+
+    fn control_channel(&self) -> String {
+        format!("{}:control", self.channel_prefix)
+    }
+
+    fn hub_channel(&self, topic: &str) -> String {
+        format!("{}:hub:{topic}", self.channel_prefix)
+    }
+
+    /// A hash of the latest load report of every instance
+    fn load_key(&self) -> String {
+        format!("{}:load", self.channel_prefix)
+    }
+}
+
+/// The Redis server shared by the cluster, which rate limits without their own use
+pub(crate) fn redis_url() -> Option<&'static str> {
+    CLUSTER.get().map(|x| x.config.redis_url.as_str())
+}
+
+pub(crate) fn is_enabled() -> bool {
+    CLUSTER.get().is_some()
+}
+
+/// Asks every instance, including this one, to reload its scripts
+pub(crate) fn reload_all() {
+    let cluster = CLUSTER.get().expect("Cluster should be initialized");
+    publish(cluster.config.control_channel(), RELOAD.to_vec());
+}
+
+fn publish(channel: String, payload: Vec<u8>) {
+    let cluster = CLUSTER.get().expect("Cluster should be initialized");
+    // The publisher only stops along with the runtime
+    let _ = cluster.outbox.send((channel, payload));
+}
+
+fn relay(topic: &str, message: HubMessage) {
+    let cluster = CLUSTER.get().expect("Cluster should be initialized");
+    publish(cluster.config.hub_channel(topic), message.encode());
+}
+
+/// Joins the cluster, so that published messages and reloads go through Redis
+pub(crate) fn init(config: &ClusterConfig) {
+    let client = redis::Client::open(config.redis_url.as_str())
+        .expect("cluster.redis_url should have been validated");
+    let mut config = config.clone();
+    if config.instance.is_empty() {
+        config.instance = default_instance();
+    }
+    let (outbox, receiver) = mpsc::unbounded_channel();
+    if CLUSTER
+        .set(Cluster {
+            config: config.clone(),
+            outbox,
+            client: client.clone(),
+            connection: OnceCell::new(),
+        })
+        .is_err()
+    {
+        panic!("Cluster should only be initialized once");
+    }
+    let _ = hub::RELAY.set(relay);
+
+    tokio::spawn(run_publisher(client.clone(), receiver));
+    tokio::spawn(run_subscriber(client, config));
+    tokio::spawn(crate::runtime::monitor_cpu());
+    tokio::spawn(report_load());
+}
+
+async fn report_load() {
+    let cluster = CLUSTER.get().expect("Cluster should be initialized");
+    let key = cluster.config.load_key();
+    let mut interval = tokio::time::interval(LOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        let load = serde_json::to_string(&Load::current(&cluster.config))
+            .expect("Load should be serializable");
+        let result: redis::RedisResult<()> = async {
+            redis::cmd("HSET")
+                .arg(&key)
+                .arg(&cluster.config.instance)
+                .arg(load)
+                .query_async(&mut cluster.connection().await?)
+                .await
+        }
+        .await;
+        if let Err(e) = result {
+            warn!("Failed to report the load of this instance: {e}");
+        }
+    }
+}
+
+/// The latest load of every instance that is still reporting, least loaded first.
+/// Reports of stopped instances are removed along the way
+async fn peer_loads() -> redis::RedisResult<Vec<Load>> {
+    let cluster = CLUSTER.get().expect("Cluster should be initialized");
+    let key = cluster.config.load_key();
+    let mut connection = cluster.connection().await?;
+    let reports: Vec<(String, String)> = redis::cmd("HGETALL")
+        .arg(&key)
+        .query_async(&mut connection)
+        .await?;
+
+    let oldest =
+        unix_millis().saturating_sub((LOAD_INTERVAL * LOAD_STALE_INTERVALS).as_millis() as u64);
+    let mut loads = vec![];
+    let mut stale = vec![];
+    for (instance, report) in reports {
+        match serde_json::from_str::<Load>(&report) {
+            Ok(load) if load.reported_at >= oldest => loads.push(load),
+            _ => stale.push(instance),
+        }
+    }
+    if !stale.is_empty() {
+        redis::cmd("HDEL")
+            .arg(&key)
+            .arg(stale)
+            .query_async::<_, ()>(&mut connection)
+            .await?;
+    }
+    loads.sort_by_key(Load::score);
+    Ok(loads)
+}
+
+/// Serves the load reports at `load_path`, if it was configured
+pub(crate) fn register_routes(routes: &mut RouteRegistry, config: &ClusterConfig) {
+    if config.load_path.is_empty() {
+        return;
+    }
+    let load_path = config.load_path.trim_end_matches('/');
+    routes.add(load_path, "GET", "cluster.load_path");
+    routes.add(&format!("{load_path}/peers"), "GET", "cluster.load_path");
+}
+
+pub(crate) fn route_load(router: Router, config: &ClusterConfig) -> Router {
+    if config.load_path.is_empty() {
+        return router;
+    }
+    let load_path = config.load_path.trim_end_matches('/');
+    router
+        .route(
+            load_path,
+            get(|| async {
+                let cluster = CLUSTER.get().expect("Cluster should be initialized");
+                Json(Load::current(&cluster.config))
+            }),
+        )
+        .route(
+            &format!("{load_path}/peers"),
+            get(|| async {
+                match peer_loads().await {
+                    Ok(loads) => Json(loads).into_response(),
+                    Err(e) => {
+                        error!("Failed to read the load of the cluster: {e}");
+                        StatusCode::SERVICE_UNAVAILABLE.into_response()
+                    }
+                }
+            }),
+        )
+}
+
+async fn run_publisher(
+    client: redis::Client,
+    mut receiver: mpsc::UnboundedReceiver<(String, Vec<u8>)>,
+) {
+    let mut connection = loop {
+        match ConnectionManager::new(client.clone()).await {
+            Ok(x) => break x,
+            Err(e) => {
+                error!("Failed to connect to the cluster to publish: {e}");
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    };
+    while let Some((channel, payload)) = receiver.recv().await {
+        let result: redis::RedisResult<()> = redis::cmd("PUBLISH")
+            .arg(&channel)
+            .arg(payload)
+            .query_async(&mut connection)
+            .await;
+        if let Err(e) = result {
+            // The connection manager reconnects by itself, but this message is lost
+            error!("Failed to publish to {channel}: {e}");
+        }
+    }
+}
+
+async fn run_subscriber(client: redis::Client, config: ClusterConfig) {
+    let control_channel = config.control_channel();
+    let hub_prefix = config.hub_channel("");
+
+    loop {
+        let result: redis::RedisResult<()> = async {
+            let mut pubsub = client.get_async_connection().await?.into_pubsub();
+            pubsub.subscribe(&control_channel).await?;
+            pubsub.psubscribe(format!("{hub_prefix}*")).await?;
+            info!("Joined the cluster at {}", config.channel_prefix);
+
+            let mut messages = pubsub.on_message();
+            while let Some(msg) = messages.next().await {
+                let channel = msg.get_channel_name();
+                let payload = msg.get_payload_bytes();
+                if channel == control_channel {
+                    handle_control(payload);
+                } else if let Some(topic) = channel.strip_prefix(&hub_prefix) {
+                    match HubMessage::decode(payload) {
+                        Some(message) => {
+                            hub::deliver(topic, message);
+                        }
+                        None => warn!("Ignoring a malformed message published to {channel}"),
+                    }
+                }
+            }
+            Ok(())
+        }
+        .await;
+        match result {
+            Ok(()) => warn!("Lost the connection to the cluster, reconnecting"),
+            Err(e) => error!("Failed to subscribe to the cluster, reconnecting: {e}"),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+fn handle_control(payload: &[u8]) {
+    if payload != RELOAD {
+        warn!(
+            "Ignoring an unknown cluster command {:?}",
+            String::from_utf8_lossy(payload)
+        );
+        return;
+    }
+    #[cfg(feature = "hot-reload")]
+    tokio::task::spawn_blocking(|| {
+        let count = crate::py::reload_all();
+        info!("Reloaded {count} scripts as the cluster asked");
+    });
+    #[cfg(not(feature = "hot-reload"))]
+    warn!(
+        "The cluster asked to reload, but scripts can only be reloaded with the hot-reload feature"
+    );
+}
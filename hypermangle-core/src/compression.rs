@@ -0,0 +1,173 @@
+use std::io::Write;
+
+use axum::{
+    body::{Body, Bytes, HttpBody, StreamBody},
+    http::{
+        header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY},
+        Extensions, HeaderMap, HeaderValue, Request, StatusCode, Version,
+    },
+    middleware::Next,
+    response::Response,
+    Router,
+};
+use tower_http::compression::{
+    predicate::{DefaultPredicate, Predicate},
+    CompressionLayer,
+};
+
+/// Fast enough to compress chunks as they are streamed
+const BROTLI_QUALITY: u32 = 4;
+
+/// Marks responses whose body a handler streams, where every chunk should reach the
+/// client as soon as it is produced, such as server-sent events. `CompressionLayer`
+/// would hold chunks back until its encoder has enough of them, so these are
+/// compressed here instead, flushing after every chunk
+#[cfg_attr(not(feature = "python"), allow(dead_code))]
+#[derive(Clone, Copy)]
+pub(crate) struct StreamedBody;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// The encoding the client prefers in `Accept-Encoding`, with brotli winning ties
+    fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        let mut best: Option<(f32, Self)> = None;
+        for item in headers
+            .get_all(ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|x| x.to_str().ok())
+            .flat_map(|x| x.split(','))
+        {
+            let mut params = item.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let encoding = if name.eq_ignore_ascii_case("br") {
+                Self::Brotli
+            } else if name.eq_ignore_ascii_case("gzip") {
+                Self::Gzip
+            } else {
+                continue;
+            };
+            let quality = match params.find_map(|x| x.trim().strip_prefix("q=")) {
+                Some(x) => x.trim().parse().unwrap_or_default(),
+                None => 1.0,
+            };
+            if quality > 0.0
+                && best.is_none_or(|(best, _)| {
+                    quality > best || (quality == best && encoding == Self::Brotli)
+                })
+            {
+                best = Some((quality, encoding));
+            }
+        }
+        best.map(|x| x.1)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+        }
+    }
+}
+
+enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Brotli => Self::Brotli(Box::new(brotli::CompressorWriter::new(
+                vec![],
+                4096,
+                BROTLI_QUALITY,
+                22,
+            ))),
+            Encoding::Gzip => Self::Gzip(flate2::write::GzEncoder::new(
+                vec![],
+                flate2::Compression::default(),
+            )),
+        }
+    }
+
+    /// Compresses `chunk`, flushing so that the client can decode all of it right away
+    fn compress(&mut self, chunk: &[u8]) -> std::io::Result<Bytes> {
+        let output = match self {
+            Self::Brotli(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+            Self::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+        };
+        Ok(std::mem::take(output).into())
+    }
+
+    fn finish(self) -> std::io::Result<Bytes> {
+        Ok(match self {
+            Self::Brotli(encoder) => encoder.into_inner(),
+            Self::Gzip(encoder) => encoder.finish()?,
+        }
+        .into())
+    }
+}
+
+fn not_streamed(_: StatusCode, _: Version, _: &HeaderMap, extensions: &Extensions) -> bool {
+    extensions.get::<StreamedBody>().is_none()
+}
+
+/// Compresses streamed bodies chunk by chunk, unless the handler set its own
+/// `Content-Encoding`, such as `identity` to leave them uncompressed
+async fn compress_streams(request: Request<Body>, next: Next<Body>) -> Response {
+    let encoding = Encoding::negotiate(request.headers());
+    let response = next.run(request).await;
+    let Some(encoding) = encoding else {
+        return response;
+    };
+    if response.extensions().get::<StreamedBody>().is_none()
+        || response.headers().contains_key(CONTENT_ENCODING)
+    {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+
+    let chunks =
+        futures::stream::unfold(Some((body, Encoder::new(encoding))), |state| async move {
+            let (mut body, mut encoder) = state?;
+            match body.data().await {
+                Some(Ok(chunk)) => Some((
+                    encoder.compress(&chunk).map_err(axum::Error::new),
+                    Some((body, encoder)),
+                )),
+                // Cut short without finishing, so that the client notices
+                Some(Err(e)) => Some((Err(e), None)),
+                None => Some((encoder.finish().map_err(axum::Error::new), None)),
+            }
+        });
+    Response::from_parts(parts, axum::body::boxed(StreamBody::new(chunks)))
+}
+
+/// The built-in compression layer, which compresses whole bodies with
+/// `CompressionLayer`, and streamed ones as they are produced
+pub(crate) fn layer_compression(router: Router) -> Router {
+    router
+        .layer(axum::middleware::from_fn(compress_streams))
+        .layer(CompressionLayer::new().compress_when(DefaultPredicate::new().and(not_streamed)))
+}
@@ -0,0 +1,445 @@
+use std::{error::Error, fmt, path::PathBuf};
+
+/// Why a [`HyperDomeConfig`](crate::HyperDomeConfig) could not be loaded
+#[derive(Debug)]
+pub enum ConfigError {
+    Unreadable {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    /// Not valid TOML, or a value of the wrong type, such as a `bind_address` that
+    /// is not an IP address and port
+    InvalidToml {
+        path: PathBuf,
+        error: toml::de::Error,
+    },
+    /// The `HYPERMANGLE_` environment variables in `vars` gave values of the wrong
+    /// type, though the file was valid
+    InvalidEnv {
+        vars: Vec<String>,
+        error: toml::de::Error,
+    },
+    CorsOrigin {
+        origin: String,
+        reason: &'static str,
+    },
+    CorsMethod(String),
+    PublicPaths(regex::Error),
+    /// The listener on this address sets only one of `cert_path` and `key_path`
+    ListenerTls(std::net::SocketAddr),
+    /// `ids.node_id` does not fit in the bits Snowflake ids have for it
+    NodeId(u16),
+    /// `api_token` has characters that cannot be sent in a header
+    ApiToken,
+    /// An entry of `trusted_proxies` that is neither an address nor a range
+    TrustedProxy(String),
+    /// The tenant with this name has no folder in any of the scripts folders
+    TenantFolder(String),
+    /// The `api_token` of the tenant with this name cannot be sent in a header
+    TenantApiToken(String),
+    /// `admin.path` is the root, or does not start with /
+    AdminPath(String),
+    AdminPassword,
+    /// `signed_url_prefix` is set without `url_signing_key`
+    UrlSigningKey,
+    OnDemandNames(regex::Error),
+    GeoIpDatabase {
+        path: String,
+        error: maxminddb::MaxMindDBError,
+    },
+    /// `geoip` has country rules without a `country_database`
+    GeoIpRules,
+    /// The stream proxy on this address cannot terminate TLS, for `reason`
+    StreamProxyTls {
+        listen: std::net::SocketAddr,
+        reason: &'static str,
+    },
+    /// `log_level` is not one of the levels of the `log` crate
+    LogLevel(String),
+    HttpPaths(regex::Error),
+    /// The variable named by `key_passphrase_env` is not set
+    KeyPassphraseEnv(String),
+    KeyPassphraseCommand(std::io::Error),
+    /// `key_passphrase_command` ran, but exited with this status
+    KeyPassphraseStatus(std::process::ExitStatus),
+    /// The `paths` of a `response_headers` rule, or of one in a `[[vhost]]`
+    HeaderRulePaths(regex::Error),
+    EarlyHintPaths(regex::Error),
+    /// The regexes in `field` of the filter with this name
+    FilterRegexes {
+        name: String,
+        field: &'static str,
+        error: regex::Error,
+    },
+    /// The `prefix` of a `[[static_files]]` mount is the root, or does not start with /
+    StaticPrefix(String),
+    /// The `hide` regexes of the static mount of this folder
+    StaticHide {
+        dir: String,
+        error: regex::Error,
+    },
+    SitemapExclude(regex::Error),
+    RemoteConsoleToken,
+    /// `remote_console` has no certificate of its own, and the server has none either
+    RemoteConsoleCertificate,
+    /// The `redis_url` of `rate_limit` or `cluster`
+    RedisUrl {
+        url: String,
+        error: redis::RedisError,
+    },
+    /// Certificates are missing, so they would be acquired over ACME without an `email`
+    AcmeEmail,
+    /// Only one of `acme_eab_key_id` and `acme_eab_hmac_key` is set
+    AcmeEab,
+    /// Certificates are missing, so they would be acquired without a `domain_name`
+    AcmeDomains,
+    /// This wildcard domain would be acquired without `acme_dns`
+    AcmeWildcard(String),
+    AcmeCa(reqwest::Error),
+    /// The `prefix` of a `[[proxy]]` is the root, or does not start with /
+    ProxyPrefix(String),
+    /// The `retry.budget_percent` of the proxy at this prefix is out of range
+    RetryBudget {
+        prefix: String,
+        percent: f32,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unreadable { path, error } => write!(f, "{path:?} should be readable: {error}"),
+            Self::InvalidToml { path, error } => {
+                write!(f, "{path:?} should be valid toml: {error}")
+            }
+            Self::InvalidEnv { vars, error } => write!(
+                f,
+                "Environment variables {} should have valid values, with strings that would read as other types quoted, like '\"12345\"': {error}",
+                vars.join(", ")
+            ),
+            Self::CorsOrigin { origin, reason } => {
+                write!(f, "CORS origin {origin:?} should be valid: {reason}")
+            }
+            Self::CorsMethod(method) => {
+                write!(f, "CORS method {method:?} should be a valid HTTP method")
+            }
+            Self::PublicPaths(e) => write!(f, "public_paths should be valid regexes: {e}"),
+            Self::ListenerTls(address) => write!(
+                f,
+                "Listener on {address} should set both cert_path and key_path, or neither"
+            ),
+            Self::NodeId(node_id) => write!(
+                f,
+                "ids.node_id should be at most {}, not {node_id}",
+                crate::ids::MAX_NODE_ID
+            ),
+            Self::ApiToken => write!(
+                f,
+                "api_token should only have visible ASCII characters, as it is sent in a header"
+            ),
+            Self::TrustedProxy(proxy) => write!(
+                f,
+                "Trusted proxy {proxy:?} should be an IP address or range, such as 10.0.0.0/8"
+            ),
+            Self::TenantFolder(name) => write!(
+                f,
+                "Tenant {name} should have a folder in one of the scripts folders"
+            ),
+            Self::TenantApiToken(name) => write!(
+                f,
+                "The api_token of tenant {name} should only have visible ASCII characters"
+            ),
+            Self::AdminPath(path) => write!(
+                f,
+                "admin.path should start with / and not be the root, not {path:?}"
+            ),
+            Self::AdminPassword => write!(f, "admin.password should not be empty"),
+            Self::UrlSigningKey => write!(
+                f,
+                "url_signing_key should be set along with signed_url_prefix"
+            ),
+            Self::OnDemandNames(e) => write!(
+                f,
+                "on_demand_tls.allowed_names should be valid regexes: {e}"
+            ),
+            Self::GeoIpDatabase { path, error } => {
+                write!(f, "{path:?} should be a MaxMind database: {error}")
+            }
+            Self::GeoIpRules => write!(
+                f,
+                "geoip.country_database should be set along with country rules"
+            ),
+            Self::StreamProxyTls { listen, reason } => {
+                write!(f, "Stream proxy on {listen} should {reason}")
+            }
+            Self::LogLevel(level) => write!(
+                f,
+                "log_level should be one of off, error, warn, info, debug or trace, not {level:?}"
+            ),
+            Self::HttpPaths(e) => write!(f, "http_paths should be valid regexes: {e}"),
+            Self::KeyPassphraseEnv(var) => write!(f, "{var} should be set to the key passphrase"),
+            Self::KeyPassphraseCommand(e) => {
+                write!(f, "key_passphrase_command should be runnable: {e}")
+            }
+            Self::KeyPassphraseStatus(status) => {
+                write!(f, "key_passphrase_command should succeed, not fail with {status}")
+            }
+            Self::HeaderRulePaths(e) => {
+                write!(f, "Header rule paths should be valid regexes: {e}")
+            }
+            Self::EarlyHintPaths(e) => write!(f, "Early hint paths should be valid regexes: {e}"),
+            Self::FilterRegexes { name, field, error } => write!(
+                f,
+                "The {field} of filter {name} should be valid regexes: {error}"
+            ),
+            Self::StaticPrefix(prefix) => write!(
+                f,
+                "Static mount prefix should start with / and not be the root, not {prefix:?}"
+            ),
+            Self::StaticHide { dir, error } => write!(
+                f,
+                "Hidden files of {dir:?} should be valid regexes: {error}"
+            ),
+            Self::SitemapExclude(e) => write!(f, "sitemap.exclude should be valid regexes: {e}"),
+            Self::RemoteConsoleToken => write!(f, "remote_console.token should not be empty"),
+            Self::RemoteConsoleCertificate => write!(
+                f,
+                "remote_console should set cert_path and key_path, or the server should"
+            ),
+            Self::RedisUrl { url, error } => {
+                write!(f, "Redis URL {url:?} should be valid: {error}")
+            }
+            Self::AcmeEmail => write!(
+                f,
+                "email should be set, as certificates are missing and would be acquired over ACME"
+            ),
+            Self::AcmeEab => write!(
+                f,
+                "acme_eab_key_id and acme_eab_hmac_key should be set together"
+            ),
+            Self::AcmeDomains => write!(
+                f,
+                "domain_name should be set, as certificates are missing and would be acquired over ACME"
+            ),
+            Self::AcmeWildcard(domain) => write!(
+                f,
+                "acme_dns should be set to acquire {domain}, as wildcards can only be validated over DNS"
+            ),
+            Self::AcmeCa(e) => write!(f, "acme_ca_path should be a PEM certificate: {e}"),
+            Self::ProxyPrefix(prefix) => write!(
+                f,
+                "Proxy prefix should start with / and not be the root, not {prefix:?}"
+            ),
+            Self::RetryBudget { prefix, percent } => write!(
+                f,
+                "retry.budget_percent of the proxy at {prefix} should be from 0 to 100000, not {percent}"
+            ),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Unreadable { error, .. } => Some(error),
+            Self::InvalidToml { error, .. } | Self::InvalidEnv { error, .. } => Some(error),
+            Self::PublicPaths(e)
+            | Self::OnDemandNames(e)
+            | Self::HttpPaths(e)
+            | Self::HeaderRulePaths(e)
+            | Self::EarlyHintPaths(e)
+            | Self::SitemapExclude(e) => Some(e),
+            Self::FilterRegexes { error, .. } | Self::StaticHide { error, .. } => Some(error),
+            Self::GeoIpDatabase { error, .. } => Some(error),
+            Self::KeyPassphraseCommand(e) => Some(e),
+            Self::RedisUrl { error, .. } => Some(error),
+            Self::AcmeCa(e) => Some(e),
+            Self::CorsOrigin { .. }
+            | Self::CorsMethod(_)
+            | Self::ListenerTls(_)
+            | Self::NodeId(_)
+            | Self::ApiToken
+            | Self::TrustedProxy(_)
+            | Self::TenantFolder(_)
+            | Self::TenantApiToken(_)
+            | Self::AdminPath(_)
+            | Self::AdminPassword
+            | Self::UrlSigningKey
+            | Self::GeoIpRules
+            | Self::StreamProxyTls { .. }
+            | Self::LogLevel(_)
+            | Self::KeyPassphraseEnv(_)
+            | Self::KeyPassphraseStatus(_)
+            | Self::StaticPrefix(_)
+            | Self::RemoteConsoleToken
+            | Self::RemoteConsoleCertificate
+            | Self::AcmeEmail
+            | Self::AcmeEab
+            | Self::AcmeDomains
+            | Self::AcmeWildcard(_)
+            | Self::ProxyPrefix(_)
+            | Self::RetryBudget { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::HyperDomeConfig;
+
+    fn config(toml: &str) -> HyperDomeConfig {
+        toml::from_str(&format!("bind_address = \"127.0.0.1:8080\"\n{toml}")).unwrap()
+    }
+
+    fn error_of(toml: &str) -> String {
+        config(toml).validate().unwrap_err().to_string()
+    }
+
+    const MISSING_CERTIFICATE: &str =
+        "cert_path = \"/nonexistent/cert.pem\"\nkey_path = \"/nonexistent/key.pem\"\n";
+
+    #[test]
+    fn log_levels_are_checked() {
+        assert!(error_of("log_level = \"loud\"").starts_with("log_level should be one of"));
+        config("log_level = \"debug\"").validate().unwrap();
+    }
+
+    #[test]
+    fn http_paths_are_checked() {
+        assert!(error_of("http_paths = [\"(\"]").starts_with("http_paths should be valid regexes"));
+    }
+
+    #[test]
+    fn key_passphrase_env_is_checked() {
+        let error = error_of("key_passphrase_env = \"HYPERMANGLE_TEST_UNSET_PASSPHRASE\"");
+        assert_eq!(
+            error,
+            "HYPERMANGLE_TEST_UNSET_PASSPHRASE should be set to the key passphrase"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn key_passphrase_command_is_checked_and_read_once() {
+        let error = error_of("key_passphrase_command = \"exit 3\"");
+        assert!(error.starts_with("key_passphrase_command should succeed, not fail with"));
+
+        let config = config("key_passphrase_command = \"echo secret\"");
+        config.validate().unwrap();
+        assert_eq!(config.key_passphrase().as_deref(), Some(&b"secret"[..]));
+    }
+
+    #[test]
+    fn header_rule_paths_are_checked() {
+        let expected = "Header rule paths should be valid regexes";
+        assert!(error_of("[[response_headers]]\npaths = [\"(\"]").starts_with(expected));
+        let vhost = "[[vhost]]\nhosts = [\"a.com\"]\nscripts_dir = \"a\"\n[[vhost.headers]]\npaths = [\"(\"]";
+        assert!(error_of(vhost).starts_with(expected));
+    }
+
+    #[test]
+    fn early_hint_paths_are_checked() {
+        let error = error_of("[[early_hints]]\npaths = [\"(\"]\nlinks = []");
+        assert!(error.starts_with("Early hint paths should be valid regexes"));
+    }
+
+    #[test]
+    fn filter_regexes_are_checked() {
+        let error = error_of(
+            "[[filters.rules]]\nname = \"bots\"\naction = \"deny\"\nheaders = { user-agent = \"(\" }",
+        );
+        assert!(error.starts_with("The headers of filter bots should be valid regexes"));
+    }
+
+    #[test]
+    fn static_mounts_are_checked() {
+        let error = error_of("[[static_files]]\nprefix = \"/\"\ndir = \"public\"");
+        assert_eq!(
+            error,
+            "Static mount prefix should start with / and not be the root, not \"/\""
+        );
+        let error = error_of("[[static_files]]\nprefix = \"/a\"\ndir = \"public\"\nhide = [\"(\"]");
+        assert!(error.starts_with("Hidden files of \"public\" should be valid regexes"));
+    }
+
+    #[test]
+    fn sitemap_exclude_is_checked() {
+        let error = error_of("[sitemap]\nbase_url = \"https://a.com\"\nexclude = [\"(\"]");
+        assert!(error.starts_with("sitemap.exclude should be valid regexes"));
+    }
+
+    #[test]
+    fn remote_console_is_checked() {
+        let console = "[remote_console]\nbind_address = \"127.0.0.1:8081\"\n";
+        assert_eq!(
+            error_of(&format!("{console}token = \"\"")),
+            "remote_console.token should not be empty"
+        );
+        assert_eq!(
+            error_of(&format!("{console}token = \"a\"")),
+            "remote_console should set cert_path and key_path, or the server should"
+        );
+        config(&format!("{MISSING_CERTIFICATE}email = \"a@a.com\"\ndomain_name = \"a.com\"\n{console}token = \"a\""))
+            .validate()
+            .unwrap();
+    }
+
+    #[test]
+    fn redis_urls_are_checked() {
+        let error = error_of("[rate_limit]\nrequests = 1\nredis_url = \"nope\"");
+        assert!(error.starts_with("Redis URL \"nope\" should be valid"));
+        let error = error_of("[cluster]\nredis_url = \"nope\"");
+        assert!(error.starts_with("Redis URL \"nope\" should be valid"));
+    }
+
+    #[test]
+    fn acme_email_is_checked() {
+        let error = error_of(&format!("{MISSING_CERTIFICATE}domain_name = \"a.com\""));
+        assert!(error.starts_with("email should be set"));
+    }
+
+    #[test]
+    fn acme_eab_is_checked() {
+        assert_eq!(
+            error_of("acme_eab_key_id = \"a\""),
+            "acme_eab_key_id and acme_eab_hmac_key should be set together"
+        );
+    }
+
+    #[test]
+    fn acme_domains_are_checked() {
+        let error = error_of(&format!("{MISSING_CERTIFICATE}email = \"a@a.com\""));
+        assert!(error.starts_with("domain_name should be set"));
+    }
+
+    #[test]
+    fn acme_wildcards_are_checked() {
+        let error = error_of(&format!(
+            "{MISSING_CERTIFICATE}email = \"a@a.com\"\ndomain_name = \"*.a.com\""
+        ));
+        assert!(error.starts_with("acme_dns should be set to acquire *.a.com"));
+    }
+
+    #[test]
+    fn acme_ca_is_checked() {
+        let path = std::env::temp_dir().join("hypermangle-test-invalid-ca.pem");
+        std::fs::write(&path, "not a certificate").unwrap();
+        let error = error_of(&format!("acme_ca_path = {:?}", path.to_str().unwrap()));
+        assert!(error.starts_with("acme_ca_path should be a PEM certificate"));
+    }
+
+    #[test]
+    fn proxies_are_checked() {
+        let proxy = "[[proxy]]\nupstream = \"http://127.0.0.1:9000\"\n";
+        assert_eq!(
+            error_of(&format!("{proxy}prefix = \"/\"")),
+            "Proxy prefix should start with / and not be the root, not \"/\""
+        );
+        assert_eq!(
+            error_of(&format!(
+                "{proxy}prefix = \"/api/\"\nretry.budget_percent = -1.0"
+            )),
+            "retry.budget_percent of the proxy at /api should be from 0 to 100000, not -1"
+        );
+    }
+}
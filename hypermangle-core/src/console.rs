@@ -1,6 +1,6 @@
-use std::{ffi::OsString, mem::take};
+use std::{ffi::OsString, future::Future, mem::take, time::Duration};
 
-use clap::{crate_name, Parser};
+use clap::{crate_name, CommandFactory, Parser};
 use futures::AsyncReadExt;
 use interprocess::local_socket::tokio::{LocalSocketListener, LocalSocketStream};
 use log::error;
@@ -9,13 +9,29 @@ use serde::{Deserialize, Serialize};
 use futures::AsyncWriteExt;
 use tokio::sync::mpsc;
 
+use builtin::BuiltinCommand;
+
+mod builtin;
+pub(crate) mod remote;
+
+pub(crate) use builtin::reload;
+
+/// Either end of a console connection, over the local socket or TLS
+trait ConsoleStream: futures::AsyncRead + futures::AsyncWrite + Unpin + Send {}
+
+impl<T: futures::AsyncRead + futures::AsyncWrite + Unpin + Send> ConsoleStream for T {}
+
+type BoxedStream = Box<dyn ConsoleStream>;
+
 pub struct RemoteClient {
-    stream: Option<LocalSocketStream>,
+    stream: Option<BoxedStream>,
+    framing: Framing,
 }
 
 impl RemoteClient {
     pub async fn send(&mut self, msg: String) {
-        if let Err(e) = send_msg(BaseCommand::Packet(msg), self.stream.as_mut().unwrap()).await {
+        let stream = self.stream.as_mut().unwrap();
+        if let Err(e) = send_msg(BaseCommand::Packet(msg), stream, self.framing).await {
             error!("Faced the following error while responding to remote client: {e}");
         }
     }
@@ -24,14 +40,17 @@ impl RemoteClient {
 impl Drop for RemoteClient {
     fn drop(&mut self) {
         let mut stream = take(&mut self.stream).unwrap();
+        let framing = self.framing;
         tokio::spawn(async move {
-            if let Err(e) = send_msg(BaseCommand::CloseSocket, &mut stream).await {
+            if let Err(e) = send_msg(BaseCommand::CloseSocket, &mut stream, framing).await {
                 error!("Faced the following error while ending connection to remote client: {e}");
             }
         });
     }
 }
 
+/// Variants are only ever appended, so that peers on other versions can still decode
+/// the ones they have in common
 #[derive(Serialize, Deserialize)]
 enum BaseCommand {
     IdRequest,
@@ -39,10 +58,70 @@ enum BaseCommand {
     Args(Vec<OsString>),
     Packet(String),
     CloseSocket,
+    /// Sent by clients before `Args`, and answered with the version and capabilities
+    /// of the server, which are the console commands it understands
+    Hello {
+        version: u32,
+        capabilities: Vec<String>,
+    },
+    /// Sent by clients of the remote console before anything else
+    Auth(String),
+}
+
+/// Bumped whenever the messages change in a way older peers cannot handle
+const PROTOCOL_VERSION: u32 = 3;
+/// The oldest version this side still talks to
+const MIN_PROTOCOL_VERSION: u32 = 1;
+/// The version of clients that send `Args` without a `Hello`
+const LEGACY_PROTOCOL_VERSION: u32 = 1;
+/// The first version whose frames are `Framing::Compact`
+const COMPACT_FRAMING_VERSION: u32 = 3;
+
+/// How frames give the length of the message after them. Connections start out
+/// `Legacy`, which every version reads, and are `Compact` after the hellos once both
+/// sides have at least `COMPACT_FRAMING_VERSION`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Framing {
+    /// The `usize` of the first versions, which were built for 64-bit little-endian
+    /// hosts
+    Legacy,
+    /// A little-endian `u32`, which consoles and servers on other machines agree on
+    Compact,
+}
+
+impl Framing {
+    fn of_version(version: u32) -> Self {
+        if version >= COMPACT_FRAMING_VERSION {
+            Self::Compact
+        } else {
+            Self::Legacy
+        }
+    }
+
+    fn header_len(self) -> usize {
+        match self {
+            Self::Legacy => std::mem::size_of::<u64>(),
+            Self::Compact => std::mem::size_of::<u32>(),
+        }
+    }
+}
+
+fn unsupported_version(version: u32) -> String {
+    format!(
+        "Console protocol version {version} is not supported, as versions {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION} are. Use a client and server built from the same hypermangle\n"
+    )
+}
+
+fn builtin_capabilities() -> Vec<String> {
+    BuiltinCommand::command()
+        .get_subcommands()
+        .map(|x| x.get_name().to_owned())
+        .collect()
 }
 
 fn get_socket_name() -> String {
-    format!("/run/{}.sock", crate_name!())
+    std::env::var("HYPERMANGLE_SOCKET_PATH")
+        .unwrap_or_else(|_| format!("/run/{}.sock", crate_name!()))
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -50,66 +129,218 @@ pub async fn does_remote_exist() -> Option<u32> {
     let Ok(mut stream) = LocalSocketStream::connect(get_socket_name()).await else {
         return None;
     };
-    send_msg(BaseCommand::IdRequest, &mut stream).await.ok()?;
-    let Ok(BaseCommand::IdResponse(id)) = recv_msg(&mut stream).await else {
+    send_msg(BaseCommand::IdRequest, &mut stream, Framing::Legacy)
+        .await
+        .ok()?;
+    let Ok(BaseCommand::IdResponse(id)) = recv_msg(&mut stream, Framing::Legacy).await else {
         panic!("Remote service should have responded with is Process ID")
     };
     Some(id)
 }
 
-async fn send_msg(msg: BaseCommand, stream: &mut LocalSocketStream) -> std::io::Result<()> {
-    let mut msg = bincode::serialize(&msg).unwrap();
-
-    let mut tmp = msg.len().to_ne_bytes().to_vec();
-    tmp.append(&mut msg);
-    msg = tmp;
+async fn send_msg(
+    msg: BaseCommand,
+    stream: &mut (impl futures::AsyncWrite + Unpin),
+    framing: Framing,
+) -> std::io::Result<()> {
+    // Serialized after room for its length, so that the frame is built in place
+    let header_len = framing.header_len();
+    let mut frame = vec![0u8; header_len];
+    bincode::serialize_into(&mut frame, &msg).unwrap();
+    let length = frame.len() - header_len;
+    if length > MAX_MSG_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Message is too large",
+        ));
+    }
+    match framing {
+        Framing::Legacy => frame[..header_len].copy_from_slice(&(length as u64).to_le_bytes()),
+        Framing::Compact => frame[..header_len].copy_from_slice(&(length as u32).to_le_bytes()),
+    }
 
-    stream.write_all(&msg).await
+    stream.write_all(&frame).await
 }
 
+/// Larger messages are refused, rather than allocated for whatever size a corrupt
+/// frame claims
+const MAX_MSG_SIZE: usize = 16 * 1024 * 1024;
+
 async fn recv_msg(
-    stream: &mut LocalSocketStream,
+    stream: &mut (impl futures::AsyncRead + Unpin),
+    framing: Framing,
 ) -> Result<BaseCommand, Box<dyn std::error::Error>> {
-    let mut msg_size = [0u8; (usize::BITS / 8) as usize];
-    stream.read_exact(&mut msg_size).await.map_err(Box::new)?;
-    let msg_size = usize::from_ne_bytes(msg_size);
+    let mut header = [0u8; std::mem::size_of::<u64>()];
+    let header = &mut header[..framing.header_len()];
+    stream.read_exact(header).await?;
+    let msg_size = match framing {
+        Framing::Legacy => u64::from_le_bytes(header.try_into().unwrap()),
+        Framing::Compact => u32::from_le_bytes(header.try_into().unwrap()).into(),
+    };
+    let msg_size = usize::try_from(msg_size).unwrap_or(usize::MAX);
+    if msg_size > MAX_MSG_SIZE {
+        return Err(format!("Message of {msg_size} bytes is too large").into());
+    }
     let mut msg = vec![0u8; msg_size];
-    stream.read_exact(&mut msg).await.map_err(Box::new)?;
+    stream.read_exact(&mut msg).await?;
 
     bincode::deserialize(&msg).map_err(Into::into)
 }
 
+/// How long the client waits for the server to connect or send anything, from
+/// `HYPERMANGLE_CONSOLE_TIMEOUT_SECS`, where 0 waits forever
+fn client_timeout() -> Option<Duration> {
+    let secs = match std::env::var("HYPERMANGLE_CONSOLE_TIMEOUT_SECS") {
+        Ok(x) => x
+            .parse()
+            .expect("HYPERMANGLE_CONSOLE_TIMEOUT_SECS should be a whole number of seconds"),
+        Err(_) => 30,
+    };
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+async fn within<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = T>,
+) -> Result<T, String> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| format!("The server did not respond within {timeout:?}")),
+        None => Ok(future.await),
+    }
+}
+
+/// Sends the arguments of this process to the running server as a console command,
+/// and prints what it answers. Exits with 1 if the server cannot be reached or stops
+/// responding
 #[tokio::main(flavor = "current_thread")]
 pub async fn send_args_to_remote() {
-    let mut stream = LocalSocketStream::connect(get_socket_name())
+    if let Err(e) = run_remote_command(client_timeout()).await {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+async fn run_remote_command(timeout: Option<Duration>) -> Result<(), String> {
+    let mut stream = match within(timeout, remote::connect()).await?? {
+        Some(x) => x,
+        None => Box::new(
+            within(timeout, LocalSocketStream::connect(get_socket_name()))
+                .await?
+                .map_err(|e| {
+                    format!(
+                        "Failed to connect to the server at {}: {e}",
+                        get_socket_name()
+                    )
+                })?,
+        ),
+    };
+    let (capabilities, framing) = match client_hello(&mut stream, timeout).await? {
+        ServerHello::Supported {
+            capabilities,
+            framing,
+        } => (capabilities, framing),
+        ServerHello::Refused(msg) => {
+            print!("{msg}");
+            return Ok(());
+        }
+    };
+    let args: Vec<OsString> = std::env::args_os().collect();
+    // Builtins this client knows of, but an older server may not
+    if let Some(command) = args.get(1).and_then(|x| x.to_str()) {
+        if BuiltinCommand::is_builtin(&args) && !capabilities.iter().any(|x| x == command) {
+            println!("The server does not support `{command}`, as it is older than this client");
+            return Ok(());
+        }
+    }
+    send_msg(BaseCommand::Args(args), &mut stream, framing)
         .await
-        .expect("Connection to remote service should have succeeded");
+        .map_err(lost)?;
+
+    loop {
+        match within(timeout, recv_msg(&mut stream, framing)).await? {
+            Ok(BaseCommand::Packet(msg)) => print!("{msg}"),
+            Ok(BaseCommand::CloseSocket) => return Ok(()),
+            Ok(_) => {}
+            // Servers hang up without `CloseSocket` after explaining what went wrong
+            Err(e) if is_hang_up(e.as_ref()) => return Ok(()),
+            Err(e) => return Err(lost(e)),
+        }
+    }
+}
 
+/// What a server answered the hello of a client with
+enum ServerHello {
+    /// The commands of the server, and the framing of the rest of the connection
+    Supported {
+        capabilities: Vec<String>,
+        framing: Framing,
+    },
+    /// Why the server cannot be used, which is printed
+    Refused(String),
+}
+
+/// Sends the hello of this client, which is framed like those of older versions so
+/// that older servers can read it
+async fn client_hello(
+    stream: &mut (impl futures::AsyncRead + futures::AsyncWrite + Unpin),
+    timeout: Option<Duration>,
+) -> Result<ServerHello, String> {
     send_msg(
-        BaseCommand::Args(std::env::args_os().collect()),
-        &mut stream,
+        BaseCommand::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: vec![],
+        },
+        stream,
+        Framing::Legacy,
     )
     .await
-    .expect("Remote service should have accepted the given arguments");
+    .map_err(lost)?;
+    match within(timeout, recv_msg(stream, Framing::Legacy)).await? {
+        Ok(BaseCommand::Hello {
+            version,
+            capabilities,
+        }) => {
+            if version < MIN_PROTOCOL_VERSION {
+                return Ok(ServerHello::Refused(unsupported_version(version)));
+            }
+            Ok(ServerHello::Supported {
+                capabilities,
+                framing: Framing::of_version(version),
+            })
+        }
+        // Servers refusing the version explain why before closing
+        Ok(BaseCommand::Packet(msg)) => Ok(ServerHello::Refused(msg)),
+        Err(e) if !is_hang_up(e.as_ref()) => Err(lost(e)),
+        // Servers from before versioning cannot decode the hello, and hang up
+        _ => Ok(ServerHello::Refused(format!("The server does not understand console protocol version {PROTOCOL_VERSION}, as it is older than this client\n"))),
+    }
+}
 
-    loop {
-        let msg = recv_msg(&mut stream)
-            .await
-            .expect("Remote service should have sent a valid message");
+fn lost(error: impl std::fmt::Display) -> String {
+    format!("Lost the connection to the server: {error}")
+}
 
-        match msg {
-            BaseCommand::Packet(msg) => print!("{msg}"),
-            BaseCommand::CloseSocket => break,
-            _ => {}
-        }
+fn is_hang_up(error: &(dyn std::error::Error + 'static)) -> bool {
+    error
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|x| x.kind() == std::io::ErrorKind::UnexpectedEof)
+}
+
+/// Decodes a console frame from `data`, for fuzz targets
+#[cfg(fuzzing)]
+pub(crate) fn fuzz_frame(data: &[u8]) {
+    for framing in [Framing::Legacy, Framing::Compact] {
+        let _ = futures::executor::block_on(recv_msg(&mut futures::io::Cursor::new(data), framing));
     }
 }
 
 pub trait ExecutableArgs: Parser + Send + 'static {
-    fn execute(self, writer: RemoteClient) -> impl std::future::Future<Output=bool> + Send;
+    fn execute(self, writer: RemoteClient) -> impl std::future::Future<Output = bool> + Send;
 }
 
-pub fn listen_for_commands<P: ExecutableArgs>() -> impl std::future::Future<Output=()> {
+pub fn listen_for_commands<P: ExecutableArgs>() -> impl std::future::Future<Output = ()> {
     let (sender, receiver) = mpsc::channel(1);
     tokio::spawn(listen_for_commands_inner::<P>(receiver));
     async move {
@@ -118,16 +349,17 @@ pub fn listen_for_commands<P: ExecutableArgs>() -> impl std::future::Future<Outp
     }
 }
 
-
 async fn listen_for_commands_inner<P: ExecutableArgs + Send>(mut receiver: mpsc::Receiver<()>) {
     #[cfg(unix)]
     let _ = std::fs::remove_file(get_socket_name());
 
     let listener = LocalSocketListener::bind(get_socket_name())
         .expect("Command listener should have started successfully");
+    let mut remote_clients = remote::listen();
 
     loop {
-        let mut stream;
+        let mut stream: BoxedStream;
+        let principal;
 
         macro_rules! unwrap {
             ($result: expr) => {
@@ -147,21 +379,112 @@ async fn listen_for_commands_inner<P: ExecutableArgs + Send>(mut receiver: mpsc:
                 break
             }
             result = listener.accept() => {
-                stream = unwrap!(result);
+                stream = Box::new(unwrap!(result));
+                principal = "console".to_owned();
+            }
+            Some((remote, peer)) = async {
+                match &mut remote_clients {
+                    Some(x) => x.recv().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                stream = remote;
+                principal = format!("console@{peer}");
             }
         }
 
-        let msg: BaseCommand = unwrap!(recv_msg(&mut stream).await);
+        // Hellos and the commands of clients from before them are framed the old way
+        let mut framing = Framing::Legacy;
+        let mut msg: BaseCommand = unwrap!(recv_msg(&mut stream, framing).await);
+
+        let version = match msg {
+            BaseCommand::Hello { version, .. } => version,
+            BaseCommand::Args(_) => LEGACY_PROTOCOL_VERSION,
+            _ => PROTOCOL_VERSION,
+        };
+        if version < MIN_PROTOCOL_VERSION {
+            unwrap!(
+                send_msg(
+                    BaseCommand::Packet(unsupported_version(version)),
+                    &mut stream,
+                    framing
+                )
+                .await
+            );
+            let _ = send_msg(BaseCommand::CloseSocket, &mut stream, framing).await;
+            continue;
+        }
+        if let BaseCommand::Hello { .. } = msg {
+            let mut capabilities = builtin_capabilities();
+            capabilities.extend(
+                P::command()
+                    .get_subcommands()
+                    .map(|x| x.get_name().to_owned()),
+            );
+            unwrap!(
+                send_msg(
+                    BaseCommand::Hello {
+                        version: PROTOCOL_VERSION,
+                        capabilities,
+                    },
+                    &mut stream,
+                    framing
+                )
+                .await
+            );
+            framing = Framing::of_version(version);
+            msg = unwrap!(recv_msg(&mut stream, framing).await);
+        }
 
         match msg {
             BaseCommand::IdRequest => {
-                unwrap!(send_msg(BaseCommand::IdResponse(std::process::id()), &mut stream).await);
+                unwrap!(
+                    send_msg(
+                        BaseCommand::IdResponse(std::process::id()),
+                        &mut stream,
+                        framing
+                    )
+                    .await
+                );
             }
             BaseCommand::Args(args) => {
+                crate::audit::record(crate::audit::AuditEvent {
+                    action: "console_command",
+                    principal: &principal,
+                    detail: &args
+                        .iter()
+                        .skip(1)
+                        .map(|x| x.to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                });
+                match BuiltinCommand::try_parse_from(&args) {
+                    Ok(command) => {
+                        command
+                            .execute(RemoteClient {
+                                stream: Some(stream),
+                                framing,
+                            })
+                            .await;
+                        continue;
+                    }
+                    Err(e) if BuiltinCommand::is_builtin(&args) => {
+                        unwrap!(
+                            send_msg(BaseCommand::Packet(e.to_string()), &mut stream, framing)
+                                .await
+                        );
+                        let _ = stream.close().await;
+                        continue;
+                    }
+                    Err(_) => {}
+                }
                 let args = match P::try_parse_from(args) {
                     Ok(x) => x,
                     Err(e) => {
-                        unwrap!(send_msg(BaseCommand::Packet(e.to_string()), &mut stream).await);
+                        unwrap!(
+                            send_msg(BaseCommand::Packet(e.to_string()), &mut stream, framing)
+                                .await
+                        );
                         let _ = stream.close().await;
                         continue;
                     }
@@ -169,6 +492,7 @@ async fn listen_for_commands_inner<P: ExecutableArgs + Send>(mut receiver: mpsc:
                 if args
                     .execute(RemoteClient {
                         stream: Some(stream),
+                        framing,
                     })
                     .await
                 {
@@ -179,6 +503,138 @@ async fn listen_for_commands_inner<P: ExecutableArgs + Send>(mut receiver: mpsc:
             _ => {}
         }
 
-        unwrap!(send_msg(BaseCommand::CloseSocket, &mut stream).await);
+        unwrap!(send_msg(BaseCommand::CloseSocket, &mut stream, framing).await);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+    use super::*;
+
+    /// The messages of servers from before versioning
+    #[derive(Serialize, Deserialize)]
+    enum BaselineCommand {
+        IdRequest,
+        IdResponse(u32),
+        Args(Vec<OsString>),
+        Packet(String),
+        CloseSocket,
+    }
+
+    fn pipe() -> (
+        Compat<tokio::io::DuplexStream>,
+        Compat<tokio::io::DuplexStream>,
+    ) {
+        let (a, b) = tokio::io::duplex(1024);
+        (a.compat(), b.compat())
+    }
+
+    /// Reads a frame as the first versions did, with a native `usize` length
+    async fn read_v1_frame(stream: &mut (impl futures::AsyncRead + Unpin)) -> Vec<u8> {
+        let mut length = [0u8; (usize::BITS / 8) as usize];
+        stream.read_exact(&mut length).await.unwrap();
+        let length = usize::from_ne_bytes(length);
+        assert!(length <= MAX_MSG_SIZE, "{length} bytes should not be read");
+        let mut msg = vec![0u8; length];
+        stream.read_exact(&mut msg).await.unwrap();
+        msg
+    }
+
+    async fn write_v1_frame(stream: &mut (impl futures::AsyncWrite + Unpin), msg: &[u8]) {
+        stream.write_all(&msg.len().to_ne_bytes()).await.unwrap();
+        stream.write_all(msg).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn commands_of_clients_from_before_versioning_are_read() {
+        let (mut client, mut server) = pipe();
+        let args = BaselineCommand::Args(vec!["hypermangle".into(), "status".into()]);
+        write_v1_frame(&mut client, &bincode::serialize(&args).unwrap()).await;
+        let Ok(BaseCommand::Args(args)) = recv_msg(&mut server, Framing::Legacy).await else {
+            panic!("Args should have been read");
+        };
+        assert_eq!(args, ["hypermangle", "status"]);
+
+        send_msg(
+            BaseCommand::Packet("ok".into()),
+            &mut server,
+            Framing::Legacy,
+        )
+        .await
+        .unwrap();
+        let msg = read_v1_frame(&mut client).await;
+        assert!(matches!(
+            bincode::deserialize(&msg),
+            Ok(BaselineCommand::Packet(x)) if x == "ok"
+        ));
+    }
+
+    #[tokio::test]
+    async fn servers_from_before_versioning_refuse_hellos_without_hanging() {
+        let (mut client, mut server) = pipe();
+        let baseline_server = tokio::spawn(async move {
+            let msg = read_v1_frame(&mut server).await;
+            // Which it cannot decode, so it hangs up
+            assert!(bincode::deserialize::<BaselineCommand>(&msg).is_err());
+        });
+        let hello = client_hello(&mut client, Some(Duration::from_secs(5))).await;
+        baseline_server.await.unwrap();
+        let Ok(ServerHello::Refused(msg)) = hello else {
+            panic!("The hello should have been refused");
+        };
+        assert!(msg.contains("older than this client"), "{msg}");
+    }
+
+    /// The framing a client settles on with a server of `version`
+    async fn framing_with(version: u32) -> Framing {
+        let (mut client, mut server) = pipe();
+        let server = tokio::spawn(async move {
+            let Ok(BaseCommand::Hello { .. }) = recv_msg(&mut server, Framing::Legacy).await else {
+                panic!("The client should have sent a hello");
+            };
+            let hello = BaseCommand::Hello {
+                version,
+                capabilities: vec![],
+            };
+            send_msg(hello, &mut server, Framing::Legacy).await.unwrap();
+        });
+        let hello = client_hello(&mut client, Some(Duration::from_secs(5))).await;
+        server.await.unwrap();
+        match hello {
+            Ok(ServerHello::Supported { framing, .. }) => framing,
+            _ => panic!("The server should have been supported"),
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_are_compact_only_once_both_sides_support_it() {
+        assert_eq!(framing_with(2).await, Framing::Legacy);
+        assert_eq!(framing_with(PROTOCOL_VERSION).await, Framing::Compact);
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_refused_before_they_are_read() {
+        for (framing, header) in [
+            (Framing::Legacy, (5u64 << 32).to_le_bytes().to_vec()),
+            (Framing::Compact, u32::MAX.to_le_bytes().to_vec()),
+        ] {
+            let mut stream = futures::io::Cursor::new(header);
+            let e = recv_msg(&mut stream, framing).await.err().unwrap();
+            assert!(e.to_string().contains("too large"), "{e}");
+        }
+    }
+
+    #[tokio::test]
+    async fn compact_frames_round_trip() {
+        let (mut a, mut b) = pipe();
+        send_msg(BaseCommand::IdResponse(7), &mut a, Framing::Compact)
+            .await
+            .unwrap();
+        assert!(matches!(
+            recv_msg(&mut b, Framing::Compact).await,
+            Ok(BaseCommand::IdResponse(7))
+        ));
     }
 }
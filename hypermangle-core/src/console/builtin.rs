@@ -0,0 +1,306 @@
+use std::ffi::OsString;
+
+use axum::http::StatusCode;
+use clap::{CommandFactory, Parser, Subcommand};
+use hypermangle_py::connections;
+
+use super::RemoteClient;
+use crate::{jobs::JobQueue, keys::KeyStore, routes};
+
+/// Console commands every hypermangle server understands, which are tried before
+/// the commands of the application
+#[derive(Parser)]
+pub(super) enum BuiltinCommand {
+    /// Manage the API keys in the key store
+    #[command(subcommand)]
+    Keys(KeysCommand),
+    /// Inspect and terminate open websocket connections
+    #[command(subcommand)]
+    Ws(WsCommand),
+    /// Take routes out of service without unloading their scripts
+    #[command(subcommand)]
+    Route(RouteCommand),
+    /// Inspect and manage the jobs queued by scripts
+    #[command(subcommand)]
+    Jobs(JobsCommand),
+    /// Make every route of this instance respond with 503 instead of running its
+    /// handlers, or stop
+    #[command(subcommand)]
+    Maintenance(MaintenanceCommand),
+    /// Reload every script that has been imported, on every instance in cluster mode
+    Reload,
+    /// Reopen the log file and the audit log, after logrotate has moved them away.
+    /// SIGHUP does the same
+    Logrotate,
+}
+
+#[derive(Subcommand)]
+pub(super) enum KeysCommand {
+    /// Create a key and print its token, which is not stored
+    Create {
+        name: String,
+        /// Comma separated scopes that handlers can check for
+        #[arg(long, value_delimiter = ',')]
+        scopes: Vec<String>,
+        /// How long until the key expires, such as `30days`
+        #[arg(long)]
+        expires_in: Option<humantime::Duration>,
+    },
+    Revoke {
+        name: String,
+    },
+    List,
+}
+
+#[derive(Subcommand)]
+pub(super) enum WsCommand {
+    List,
+    /// Close a connection, using the id shown by `ws list`
+    Kill {
+        id: u64,
+    },
+}
+
+#[derive(Subcommand)]
+pub(super) enum RouteCommand {
+    /// Respond with 503 at `path` instead of running its handlers
+    Disable {
+        path: String,
+        /// Respond with 404 instead, as if the route did not exist
+        #[arg(long)]
+        not_found: bool,
+    },
+    Enable {
+        path: String,
+    },
+    /// Show every route and whether it is disabled
+    List,
+}
+
+#[derive(Subcommand)]
+pub(super) enum MaintenanceCommand {
+    On,
+    Off,
+}
+
+#[derive(Subcommand)]
+pub(super) enum JobsCommand {
+    /// Show every job that has not completed, including failed ones
+    List,
+    /// Run a failed job again, with all of its attempts
+    Retry { id: i64 },
+    /// Remove a job that is not running
+    Cancel { id: i64 },
+}
+
+impl BuiltinCommand {
+    /// Whether `args` names a builtin command, so that its errors should be shown
+    /// instead of those of the application
+    pub(super) fn is_builtin(args: &[OsString]) -> bool {
+        args.get(1)
+            .and_then(|x| x.to_str())
+            .is_some_and(|x| Self::command().find_subcommand(x).is_some())
+    }
+
+    pub(super) async fn execute(self, mut writer: RemoteClient) {
+        match self {
+            Self::Keys(command) => {
+                let Some(keys) = KeyStore::get() else {
+                    writer
+                        .send("No key store is configured, so set keys_path first\n".into())
+                        .await;
+                    return;
+                };
+                let msg = match command {
+                    KeysCommand::Create {
+                        name,
+                        scopes,
+                        expires_in,
+                    } => match keys.create(name, scopes, expires_in.map(Into::into)) {
+                        Ok(token) => format!("{token}\n"),
+                        Err(e) => format!("Failed to create key: {e}\n"),
+                    },
+                    KeysCommand::Revoke { name } => match keys.revoke(&name) {
+                        Ok(true) => format!("Revoked {name}\n"),
+                        Ok(false) => format!("There is no key named {name}\n"),
+                        Err(e) => format!("Failed to revoke key: {e}\n"),
+                    },
+                    KeysCommand::List => {
+                        let keys = keys.list();
+                        if keys.is_empty() {
+                            "There are no keys\n".into()
+                        } else {
+                            let mut out = String::new();
+                            for key in keys {
+                                let created = humantime::format_rfc3339_seconds(
+                                    std::time::UNIX_EPOCH
+                                        + std::time::Duration::from_secs(key.created_at),
+                                );
+                                let expiry = match key.expires_at {
+                                    _ if key.is_expired() => "expired".to_owned(),
+                                    Some(x) => format!(
+                                        "expires {}",
+                                        humantime::format_rfc3339_seconds(
+                                            std::time::UNIX_EPOCH
+                                                + std::time::Duration::from_secs(x)
+                                        )
+                                    ),
+                                    None => "never expires".to_owned(),
+                                };
+                                out += &format!(
+                                    "{}  scopes: [{}]  created {created}, {expiry}\n",
+                                    key.name,
+                                    key.scopes.join(", ")
+                                );
+                            }
+                            out
+                        }
+                    }
+                };
+                writer.send(msg).await;
+            }
+            Self::Ws(command) => {
+                let msg = match command {
+                    WsCommand::List => {
+                        let connections = connections::list();
+                        if connections.is_empty() {
+                            "There are no open websocket connections\n".into()
+                        } else {
+                            let mut out = String::new();
+                            for connection in connections {
+                                let duration =
+                                    std::time::Duration::from_secs(connection.duration.as_secs());
+                                out += &format!(
+                                    "{}  {}  peer {}  open for {}  in {}B  out {}B\n",
+                                    connection.id,
+                                    connection.path,
+                                    connection.peer,
+                                    humantime::format_duration(duration),
+                                    connection.bytes_in,
+                                    connection.bytes_out
+                                );
+                            }
+                            out
+                        }
+                    }
+                    WsCommand::Kill { id } => {
+                        if connections::kill(id) {
+                            format!("Closing connection {id}\n")
+                        } else {
+                            format!("There is no connection with id {id}\n")
+                        }
+                    }
+                };
+                writer.send(msg).await;
+            }
+            Self::Route(command) => {
+                let msg = match command {
+                    RouteCommand::Disable { path, not_found } => {
+                        let status = if not_found {
+                            StatusCode::NOT_FOUND
+                        } else {
+                            StatusCode::SERVICE_UNAVAILABLE
+                        };
+                        if routes::disable_route(&path, status) {
+                            format!("Disabled {path}\n")
+                        } else {
+                            format!("There is no route at {path}\n")
+                        }
+                    }
+                    RouteCommand::Enable { path } => {
+                        if routes::enable_route(&path) {
+                            format!("Enabled {path}\n")
+                        } else {
+                            format!("{path} was not disabled\n")
+                        }
+                    }
+                    RouteCommand::List => routes::format_route_table(),
+                };
+                writer.send(msg).await;
+            }
+            Self::Jobs(command) => {
+                let Some(jobs) = JobQueue::get() else {
+                    writer
+                        .send("No job queue is configured, so set jobs first\n".into())
+                        .await;
+                    return;
+                };
+                let msg = match command {
+                    JobsCommand::List => match jobs.list() {
+                        Ok(jobs) if jobs.is_empty() => "There are no jobs\n".into(),
+                        Ok(jobs) => {
+                            let mut out = String::new();
+                            for job in jobs {
+                                let run_at = humantime::format_rfc3339_seconds(
+                                    std::time::UNIX_EPOCH
+                                        + std::time::Duration::from_millis(job.run_at),
+                                );
+                                out += &format!(
+                                    "{}  {}  {}  attempts {}  runs at {run_at}\n",
+                                    job.id, job.function, job.state, job.attempts
+                                );
+                                if let Some(error) = job.last_error {
+                                    out += &format!("    last error: {error}\n");
+                                }
+                            }
+                            out
+                        }
+                        Err(e) => format!("Failed to list jobs: {e}\n"),
+                    },
+                    JobsCommand::Retry { id } => match jobs.retry(id) {
+                        Ok(true) => format!("Retrying job {id}\n"),
+                        Ok(false) => format!("There is no failed job with id {id}\n"),
+                        Err(e) => format!("Failed to retry job: {e}\n"),
+                    },
+                    JobsCommand::Cancel { id } => match jobs.cancel(id) {
+                        Ok(true) => format!("Cancelled job {id}\n"),
+                        Ok(false) => format!("There is no job with id {id} that is not running\n"),
+                        Err(e) => format!("Failed to cancel job: {e}\n"),
+                    },
+                };
+                writer.send(msg).await;
+            }
+            Self::Maintenance(command) => {
+                let enabled = matches!(command, MaintenanceCommand::On);
+                routes::set_maintenance(enabled);
+                let msg = if enabled {
+                    "Every route responds with 503 until `maintenance off`\n"
+                } else {
+                    "Routes run their handlers again\n"
+                };
+                writer.send(msg.into()).await;
+            }
+            Self::Reload => writer.send(reload().await).await,
+            Self::Logrotate => {
+                let msg = match crate::log_files::reopen_all() {
+                    Ok(count) => format!("Reopened {count} log files\n"),
+                    Err(e) => format!("Failed to reopen log files: {e}\n"),
+                };
+                writer.send(msg).await;
+            }
+        }
+    }
+}
+
+/// Reloads the scripts of this instance, or of every instance in cluster mode
+pub(crate) async fn reload() -> String {
+    if crate::cluster::is_enabled() {
+        crate::cluster::reload_all();
+        "Asked every instance in the cluster to reload\n".to_owned()
+    } else {
+        reload_here().await
+    }
+}
+
+#[cfg(feature = "hot-reload")]
+async fn reload_here() -> String {
+    let count = tokio::task::spawn_blocking(crate::py::reload_all)
+        .await
+        .expect("Reloading should not have panicked");
+    format!("Reloaded {count} scripts\n")
+}
+
+#[cfg(not(feature = "hot-reload"))]
+async fn reload_here() -> String {
+    "Scripts can only be reloaded with the hot-reload feature\n".into()
+}
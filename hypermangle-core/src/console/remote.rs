@@ -0,0 +1,213 @@
+use std::{net::SocketAddr, path::Path, sync::Arc, time::Duration};
+
+use constant_time_eq::constant_time_eq;
+use log::{error, info, warn};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+use super::{recv_msg, send_msg, BaseCommand, BoxedStream, Framing};
+use crate::{
+    audit::{self, AuditEvent},
+    ConfigError,
+};
+
+/// Serves the console over TLS, for administering the server from other machines.
+/// Clients connect to it by setting `HYPERMANGLE_REMOTE` to its `host:port` and
+/// `HYPERMANGLE_REMOTE_TOKEN` to `token`
+#[derive(Deserialize, Clone)]
+pub(crate) struct RemoteConsoleConfig {
+    bind_address: SocketAddr,
+    token: String,
+    /// Both default to the certificate of the server
+    #[serde(default)]
+    cert_path: String,
+    #[serde(default)]
+    key_path: String,
+}
+
+/// How long clients have to finish the handshake and send their token
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Listener {
+    bind_address: SocketAddr,
+    token: Arc<str>,
+    acceptor: tokio_rustls::TlsAcceptor,
+}
+
+static LISTENER: Mutex<Option<Listener>> = Mutex::new(None);
+
+/// Loads the certificate of the remote console, which starts along with the local one.
+/// `cert_path` and `key_path` are those of the server
+pub(crate) fn init(
+    config: &RemoteConsoleConfig,
+    cert_path: &str,
+    key_path: &str,
+    passphrase: Option<&[u8]>,
+) {
+    let (cert_path, key_path) = if config.cert_path.is_empty() {
+        (cert_path, key_path)
+    } else {
+        (config.cert_path.as_str(), config.key_path.as_str())
+    };
+    let (certs, key) =
+        crate::tls::load_certificate(Path::new(cert_path), Path::new(key_path), passphrase);
+    let tls_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .expect("Remote console certificate should match its key");
+    *LISTENER.lock() = Some(Listener {
+        bind_address: config.bind_address,
+        token: config.token.as_str().into(),
+        acceptor: tokio_rustls::TlsAcceptor::from(Arc::new(tls_config)),
+    });
+}
+
+async fn authenticate(
+    tcp: TcpStream,
+    acceptor: tokio_rustls::TlsAcceptor,
+    token: &str,
+) -> Result<BoxedStream, String> {
+    let tls = acceptor.accept(tcp).await.map_err(|e| e.to_string())?;
+    let mut stream: BoxedStream = Box::new(tls.compat());
+    let msg = recv_msg(&mut stream, Framing::Legacy)
+        .await
+        .map_err(|e| e.to_string())?;
+    match msg {
+        BaseCommand::Auth(given) if constant_time_eq(given.as_bytes(), token.as_bytes()) => {
+            Ok(stream)
+        }
+        _ => {
+            let _ = send_msg(
+                BaseCommand::Packet("The console token is not valid\n".into()),
+                &mut stream,
+                Framing::Legacy,
+            )
+            .await;
+            Err("token is not valid".into())
+        }
+    }
+}
+
+/// Accepts remote clients that authenticate, along with their address, if the remote
+/// console was configured
+pub(super) fn listen() -> Option<mpsc::Receiver<(BoxedStream, SocketAddr)>> {
+    let Listener {
+        bind_address,
+        token,
+        acceptor,
+    } = LISTENER.lock().take()?;
+    let (sender, receiver) = mpsc::channel(8);
+
+    tokio::spawn(async move {
+        let listener = match TcpListener::bind(bind_address).await {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Failed to bind the remote console to {bind_address}: {e}");
+                return;
+            }
+        };
+        info!("Serving the remote console on {bind_address}");
+        loop {
+            let (tcp, peer) = match listener.accept().await {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("Failed to accept a remote console client: {e}");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let acceptor = acceptor.clone();
+            let token = token.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(AUTH_TIMEOUT, authenticate(tcp, acceptor, &token)).await
+                {
+                    Ok(Ok(stream)) => {
+                        let _ = sender.send((stream, peer)).await;
+                    }
+                    Ok(Err(e)) => audit::record(AuditEvent {
+                        action: "auth_failure",
+                        principal: "anonymous",
+                        detail: &format!("remote console from {peer}: {e}"),
+                    }),
+                    Err(_) => warn!("Remote console client {peer} did not authenticate in time"),
+                }
+            });
+        }
+    });
+    Some(receiver)
+}
+
+impl RemoteConsoleConfig {
+    /// `has_certificate` is whether the server has a certificate to fall back on
+    pub(crate) fn validate(&self, has_certificate: bool) -> Result<(), ConfigError> {
+        if self.token.is_empty() {
+            return Err(ConfigError::RemoteConsoleToken);
+        }
+        let has_certificate = if self.cert_path.is_empty() {
+            has_certificate
+        } else {
+            !self.key_path.is_empty()
+        };
+        if !has_certificate {
+            return Err(ConfigError::RemoteConsoleCertificate);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn bind_address(&self) -> SocketAddr {
+        self.bind_address
+    }
+}
+
+/// Connects to the server named by `HYPERMANGLE_REMOTE`, if it is set. Servers with
+/// certificates that are not trusted by the system can be trusted with the PEM file
+/// at `HYPERMANGLE_REMOTE_CA`
+pub(super) async fn connect() -> Result<Option<BoxedStream>, String> {
+    let Ok(address) = std::env::var("HYPERMANGLE_REMOTE") else {
+        return Ok(None);
+    };
+    let token = std::env::var("HYPERMANGLE_REMOTE_TOKEN")
+        .expect("HYPERMANGLE_REMOTE_TOKEN should be set along with HYPERMANGLE_REMOTE");
+    let host = address
+        .rsplit_once(':')
+        .map_or(address.as_str(), |(host, _)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+
+    let mut connector = tokio_native_tls::native_tls::TlsConnector::builder();
+    if let Ok(ca_path) = std::env::var("HYPERMANGLE_REMOTE_CA") {
+        let pem = std::fs::read(&ca_path).unwrap_or_else(|e| {
+            panic!("HYPERMANGLE_REMOTE_CA {ca_path:?} should be readable: {e}")
+        });
+        connector.add_root_certificate(
+            tokio_native_tls::native_tls::Certificate::from_pem(&pem)
+                .expect("HYPERMANGLE_REMOTE_CA should be a PEM certificate"),
+        );
+    }
+    let connector = tokio_native_tls::TlsConnector::from(
+        connector
+            .build()
+            .expect("TLS connector should be buildable"),
+    );
+    let tcp = TcpStream::connect(&address)
+        .await
+        .map_err(|e| format!("Failed to connect to {address}: {e}"))?;
+    let tls = connector
+        .connect(host, tcp)
+        .await
+        .map_err(|e| format!("TLS handshake with {address} failed: {e}"))?;
+
+    let mut stream: BoxedStream = Box::new(tls.compat());
+    send_msg(BaseCommand::Auth(token), &mut stream, Framing::Legacy)
+        .await
+        .map_err(|e| format!("Failed to send the token to {address}: {e}"))?;
+    Ok(Some(stream))
+}
@@ -0,0 +1,207 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+#[cfg(feature = "amqp")]
+mod amqp;
+#[cfg(feature = "kafka")]
+mod kafka;
+#[cfg(feature = "nats")]
+mod nats;
+
+/// Calls a function of a script for every message from a queue, in the order they
+/// arrive
+#[derive(Deserialize, Clone)]
+#[cfg_attr(not(feature = "python"), allow(dead_code))]
+pub(crate) struct ConsumerConfig {
+    #[serde(flatten)]
+    source: Source,
+    script: PathBuf,
+    #[serde(default = "default_function")]
+    function: String,
+    /// How long to wait after rejecting a message, so that a message that keeps
+    /// failing is not retried in a tight loop
+    #[serde(default = "default_nack_delay_ms")]
+    nack_delay_ms: u64,
+}
+
+fn default_function() -> String {
+    "message_handler".into()
+}
+
+fn default_nack_delay_ms() -> u64 {
+    1000
+}
+
+fn default_prefetch() -> u16 {
+    16
+}
+
+// The fields of sources whose feature is disabled are never read
+#[allow(dead_code)]
+#[derive(Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum Source {
+    /// A durable pull consumer called `group` on a JetStream stream, which only
+    /// receives `subject` if it is not empty
+    Nats {
+        url: String,
+        stream: String,
+        #[serde(default)]
+        subject: String,
+        group: String,
+    },
+    Amqp {
+        url: String,
+        queue: String,
+        /// How many unacknowledged messages the broker may send ahead
+        #[serde(default = "default_prefetch")]
+        prefetch: u16,
+    },
+    /// Offsets are committed once a message is acknowledged, and rejected messages are
+    /// seeked back to
+    Kafka {
+        brokers: String,
+        topic: String,
+        group: String,
+    },
+}
+
+#[cfg(feature = "python")]
+impl Source {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Nats { .. } => "nats",
+            Self::Amqp { .. } => "amqp",
+            Self::Kafka { .. } => "kafka",
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        match self {
+            Self::Nats { .. } => cfg!(feature = "nats"),
+            Self::Amqp { .. } => cfg!(feature = "amqp"),
+            Self::Kafka { .. } => cfg!(feature = "kafka"),
+        }
+    }
+}
+
+#[cfg(feature = "python")]
+struct Handler {
+    function: pyo3::PyObject,
+    /// `script:function`, for logs
+    name: String,
+    nack_delay: std::time::Duration,
+}
+
+#[cfg(feature = "python")]
+impl Handler {
+    /// Runs the handler on a message, returning whether it should be acknowledged
+    #[cfg_attr(
+        not(any(feature = "nats", feature = "amqp", feature = "kafka")),
+        allow(dead_code)
+    )]
+    async fn handle(&self, subject: &str, body: &[u8], redelivered: bool) -> bool {
+        use pyo3::{types::PyBytes, Python};
+
+        let future = Python::with_gil(|py| {
+            let message = hypermangle_py::QueueMessage {
+                subject: subject.to_owned(),
+                body: PyBytes::new(py, body).into(),
+                redelivered,
+            };
+            let coroutine = self.function.call1(py, (message,))?;
+            pyo3_asyncio::into_future_with_locals(&crate::task_locals(), coroutine.as_ref(py))
+        });
+        let result = match future {
+            Ok(future) => future.await,
+            Err(e) => Err(e),
+        };
+        let acked = match result {
+            Ok(x) => Python::with_gil(|py| !matches!(x.extract::<bool>(py), Ok(false))),
+            Err(e) => {
+                log::error!("{} failed on a message from {subject}: {e}", self.name);
+                false
+            }
+        };
+        if !acked {
+            tokio::time::sleep(self.nack_delay).await;
+        }
+        acked
+    }
+}
+
+#[cfg(feature = "python")]
+#[cfg_attr(
+    not(any(feature = "nats", feature = "amqp", feature = "kafka")),
+    allow(unused_variables)
+)]
+async fn consume(source: &Source, handler: &Handler) -> Result<(), String> {
+    match source {
+        #[cfg(feature = "nats")]
+        Source::Nats {
+            url,
+            stream,
+            subject,
+            group,
+        } => nats::consume(url, stream, subject, group, handler).await,
+        #[cfg(feature = "amqp")]
+        Source::Amqp {
+            url,
+            queue,
+            prefetch,
+        } => amqp::consume(url, queue, *prefetch, handler).await,
+        #[cfg(feature = "kafka")]
+        Source::Kafka {
+            brokers,
+            topic,
+            group,
+        } => kafka::consume(brokers, topic, group, handler).await,
+        #[allow(unreachable_patterns)]
+        _ => unreachable!("Consumers of disabled features should not be started"),
+    }
+}
+
+/// Reconnects whenever the connection to the queue is lost
+#[cfg(feature = "python")]
+async fn run(source: Source, handler: Handler) {
+    use std::time::Duration;
+
+    crate::py::wait_for_event_loop().await;
+    loop {
+        let e = match consume(&source, &handler).await {
+            Ok(()) => "the connection was closed".to_owned(),
+            Err(e) => e,
+        };
+        log::error!(
+            "{} consumer for {} stopped, reconnecting in 5s: {e}",
+            source.kind(),
+            handler.name
+        );
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// Loads the message handler of every consumer and starts consuming. Must be called
+/// from within the tokio runtime
+#[cfg(feature = "python")]
+pub(crate) fn start(configs: &[ConsumerConfig]) {
+    crate::py::register_embedded_module();
+
+    for config in configs {
+        assert!(
+            config.source.is_enabled(),
+            "{0} consumers need hypermangle to be built with the `{0}` feature",
+            config.source.kind()
+        );
+        let name = format!("{}:{}", config.script.display(), config.function);
+        let function = crate::py::load_py_function(&config.script, &config.function)
+            .unwrap_or_else(|e| panic!("Message handler {name} should be loadable: {e}"));
+        let handler = Handler {
+            function,
+            name,
+            nack_delay: std::time::Duration::from_millis(config.nack_delay_ms),
+        };
+        tokio::spawn(run(config.source.clone(), handler));
+    }
+}
@@ -0,0 +1,59 @@
+use futures::StreamExt;
+use lapin::{
+    options::{BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicQosOptions},
+    types::FieldTable,
+    Connection, ConnectionProperties,
+};
+
+use super::Handler;
+
+pub(super) async fn consume(
+    url: &str,
+    queue: &str,
+    prefetch: u16,
+    handler: &Handler,
+) -> Result<(), String> {
+    let connection = Connection::connect(url, ConnectionProperties::default())
+        .await
+        .map_err(|e| e.to_string())?;
+    let channel = connection
+        .create_channel()
+        .await
+        .map_err(|e| e.to_string())?;
+    channel
+        .basic_qos(prefetch, BasicQosOptions::default())
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut consumer = channel
+        .basic_consume(
+            queue,
+            "hypermangle",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery.map_err(|e| e.to_string())?;
+        let acked = handler
+            .handle(
+                delivery.routing_key.as_str(),
+                &delivery.data,
+                delivery.redelivered,
+            )
+            .await;
+        if acked {
+            delivery.ack(BasicAckOptions::default()).await
+        } else {
+            delivery
+                .nack(BasicNackOptions {
+                    requeue: true,
+                    ..Default::default()
+                })
+                .await
+        }
+        .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
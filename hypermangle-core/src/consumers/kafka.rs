@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use rdkafka::{
+    consumer::{CommitMode, Consumer, StreamConsumer},
+    ClientConfig, Message, Offset,
+};
+
+use super::Handler;
+
+pub(super) async fn consume(
+    brokers: &str,
+    topic: &str,
+    group: &str,
+    handler: &Handler,
+) -> Result<(), String> {
+    let consumer: StreamConsumer = ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .set("group.id", group)
+        .set("enable.auto.commit", "false")
+        .create()
+        .map_err(|e| e.to_string())?;
+    consumer.subscribe(&[topic]).map_err(|e| e.to_string())?;
+    // Kafka does not track redeliveries, so the last rejected message is remembered
+    let mut rejected = None;
+
+    loop {
+        let message = consumer.recv().await.map_err(|e| e.to_string())?;
+        let position = (message.partition(), message.offset());
+        let acked = handler
+            .handle(
+                message.topic(),
+                message.payload().unwrap_or_default(),
+                rejected == Some(position),
+            )
+            .await;
+        if acked {
+            consumer
+                .commit_message(&message, CommitMode::Async)
+                .map_err(|e| e.to_string())?;
+        } else {
+            rejected = Some(position);
+            consumer
+                .seek(
+                    message.topic(),
+                    message.partition(),
+                    Offset::Offset(message.offset()),
+                    Duration::from_secs(10),
+                )
+                .map_err(|e| e.to_string())?;
+        }
+    }
+}
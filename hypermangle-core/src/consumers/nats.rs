@@ -0,0 +1,50 @@
+use async_nats::jetstream::{
+    self,
+    consumer::{pull, AckPolicy, PullConsumer},
+    AckKind,
+};
+use futures::StreamExt;
+
+use super::Handler;
+
+pub(super) async fn consume(
+    url: &str,
+    stream: &str,
+    subject: &str,
+    group: &str,
+    handler: &Handler,
+) -> Result<(), String> {
+    let client = async_nats::connect(url).await.map_err(|e| e.to_string())?;
+    let stream = jetstream::new(client)
+        .get_stream(stream)
+        .await
+        .map_err(|e| e.to_string())?;
+    let consumer: PullConsumer = stream
+        .get_or_create_consumer(
+            group,
+            pull::Config {
+                durable_name: Some(group.to_owned()),
+                filter_subject: subject.to_owned(),
+                ack_policy: AckPolicy::Explicit,
+                ..Default::default()
+            },
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut messages = consumer.messages().await.map_err(|e| e.to_string())?;
+
+    while let Some(message) = messages.next().await {
+        let message = message.map_err(|e| e.to_string())?;
+        let redelivered = message.info().is_ok_and(|x| x.delivered > 1);
+        let kind = if handler
+            .handle(&message.subject, &message.payload, redelivered)
+            .await
+        {
+            AckKind::Ack
+        } else {
+            AckKind::Nak(None)
+        };
+        message.ack_with(kind).await.map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,148 @@
+use std::{
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    sync::mpsc,
+};
+
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use crate::SYNC_CHANGES_DELAY;
+
+enum Change {
+    Config,
+    Rust,
+}
+
+fn spawn_server() -> Option<Child> {
+    match Command::new(std::env::current_exe().expect("Current EXE name should be accessible"))
+        .args(["run", "--dev"])
+        .spawn()
+    {
+        Ok(child) => Some(child),
+        Err(e) => {
+            println!("[dev] Failed to start the server: {e}");
+            None
+        }
+    }
+}
+
+fn stop_server(child: Option<Child>) {
+    let Some(mut child) = child else {
+        return;
+    };
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+fn rebuild() -> bool {
+    let mut command = Command::new(std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into()));
+    command.arg("build");
+
+    let is_release = std::env::current_exe()
+        .ok()
+        .and_then(|x| x.parent().map(Path::to_path_buf))
+        .is_some_and(|x| x.ends_with("release"));
+    if is_release {
+        command.arg("--release");
+    }
+
+    match command.status() {
+        Ok(status) => status.success(),
+        Err(e) => {
+            println!("[dev] Failed to run cargo: {e}");
+            false
+        }
+    }
+}
+
+fn classify(path: &Path, config_path: &Path) -> Option<Change> {
+    if path.file_name() == config_path.file_name() {
+        Some(Change::Config)
+    } else if path.extension().is_some_and(|x| x == "rs") || path.ends_with("Cargo.toml") {
+        Some(Change::Rust)
+    } else {
+        None
+    }
+}
+
+/// Runs the server as a child process, restarting it whenever the config or the
+/// Rust code embedding hypermangle changes. Scripts are hot-reloaded by the child itself
+pub(crate) fn run_dev() {
+    let config_path = PathBuf::from("hypermangle.toml");
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |res| {
+        let _ = sender.send(res);
+    })
+    .expect("Filesystem notification should be available");
+
+    watcher
+        .watch(&config_path, RecursiveMode::NonRecursive)
+        .expect("hypermangle.toml should be watchable");
+
+    let is_cargo_project = Path::new("Cargo.toml").exists();
+    if is_cargo_project {
+        watcher
+            .watch("Cargo.toml".as_ref(), RecursiveMode::NonRecursive)
+            .expect("Cargo.toml should be watchable");
+        if Path::new("src").is_dir() {
+            watcher
+                .watch("src".as_ref(), RecursiveMode::Recursive)
+                .expect("src directory should be watchable");
+        }
+        println!("[dev] Watching hypermangle.toml, Cargo.toml and src/");
+    } else {
+        println!("[dev] Watching hypermangle.toml");
+    }
+
+    let mut child = spawn_server();
+
+    while let Ok(result) = receiver.recv() {
+        let mut changed_paths = vec![];
+        let mut rust_changed = false;
+        let mut handle = |result: notify::Result<notify::Event>| match result {
+            Ok(event) => {
+                if !matches!(
+                    event.kind,
+                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                ) {
+                    return;
+                }
+                for path in event.paths {
+                    match classify(&path, &config_path) {
+                        Some(Change::Config) => {}
+                        Some(Change::Rust) => rust_changed = true,
+                        None => continue,
+                    }
+                    if !changed_paths.contains(&path) {
+                        changed_paths.push(path);
+                    }
+                }
+            }
+            Err(e) => println!("[dev] File Watcher Error: {e:?}"),
+        };
+
+        handle(result);
+        // Collect the burst of events an editor save usually produces
+        while let Ok(result) = receiver.recv_timeout(SYNC_CHANGES_DELAY) {
+            handle(result);
+        }
+
+        if changed_paths.is_empty() {
+            continue;
+        }
+
+        for path in &changed_paths {
+            println!("[dev] Change detected in {}", path.display());
+        }
+        println!("[dev] Restarting server...");
+        stop_server(child);
+
+        if rust_changed && is_cargo_project && !rebuild() {
+            println!("[dev] Build failed, waiting for further changes");
+            child = None;
+            continue;
+        }
+
+        child = spawn_server();
+    }
+}
@@ -0,0 +1,210 @@
+//! Overrides the values of hypermangle.toml with `HYPERMANGLE_` environment variables,
+//! for deployments like containers where the file is baked into the image
+
+use toml::{Table, Value};
+
+const PREFIX: &str = "HYPERMANGLE_";
+
+/// Read by the console instead of the server, so they never override the config
+const RESERVED: &[&str] = &[
+    "SOCKET_PATH",
+    "CONSOLE_TIMEOUT_SECS",
+    "REMOTE",
+    "REMOTE_TOKEN",
+    "REMOTE_CA",
+];
+
+/// Values are read as TOML, such as `8080`, `true` or `["a", "b"]`, and as strings
+/// otherwise. Fields that are strings in the file stay strings, so that a token of
+/// digits is not read as a number
+fn parse_value(raw: &str, existing: Option<&Value>) -> Value {
+    if existing.is_some_and(Value::is_str) {
+        return Value::String(raw.to_owned());
+    }
+    toml::from_str::<Table>(&format!("value = {raw}"))
+        .ok()
+        .and_then(|mut x| x.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_owned()))
+}
+
+/// Sets the fields of `table` named by `vars`. `HYPERMANGLE_BIND_ADDRESS` sets
+/// `bind_address`, and a double underscore reaches into a table, so that
+/// `HYPERMANGLE_IDS__NODE_ID` sets `node_id` in `[ids]`. Returns the names of the
+/// variables that were applied
+///
+/// `fits` is whether a table deserializes into the config. Values that are not read
+/// as strings are set as strings instead if only that fits, so that a string field
+/// missing from the file is not set to a number by a value like `1e3`
+pub(crate) fn apply(
+    table: &mut Table,
+    vars: impl IntoIterator<Item = (String, String)>,
+    fits: impl Fn(&Table) -> bool,
+) -> Vec<String> {
+    let mut applied = vec![];
+    for (name, raw) in vars {
+        let Some(key) = name.strip_prefix(PREFIX) else {
+            continue;
+        };
+        if key.is_empty() || RESERVED.contains(&key) {
+            continue;
+        }
+        let key = key.to_ascii_lowercase();
+        let mut path: Vec<&str> = key.split("__").collect();
+        let field = path.pop().expect("Split should yield at least one part");
+
+        let mut current = &mut *table;
+        for part in &path {
+            let entry = current
+                .entry(*part)
+                .or_insert_with(|| Value::Table(Table::new()));
+            if !entry.is_table() {
+                *entry = Value::Table(Table::new());
+            }
+            current = entry
+                .as_table_mut()
+                .expect("Entry should have just been made a table");
+        }
+        let value = parse_value(&raw, current.get(field));
+        let typed = !value.is_str();
+        current.insert(field.to_owned(), value);
+        if typed && !fits(table) {
+            let previous = set(table, &path, field, Value::String(raw.clone()));
+            if !fits(table) {
+                set(table, &path, field, previous);
+            }
+        }
+        applied.push(name);
+    }
+    applied
+}
+
+/// Replaces the value of `field` in the table at `path`, which `apply` has made,
+/// returning the value it had
+fn set(table: &mut Table, path: &[&str], field: &str, value: Value) -> Value {
+    let mut current = table;
+    for part in path {
+        current = current[*part]
+            .as_table_mut()
+            .expect("Path should have been made of tables");
+    }
+    current
+        .insert(field.to_owned(), value)
+        .expect("Field should have been set")
+}
+
+/// The `HYPERMANGLE_` variables of the process, skipping those that are not unicode
+pub(crate) fn vars() -> impl Iterator<Item = (String, String)> {
+    std::env::vars_os().filter_map(|(name, value)| {
+        let name = name.into_string().ok()?;
+        if !name.starts_with(PREFIX) {
+            return None;
+        }
+        Some((name, value.into_string().ok()?))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn applied(file: &str, vars: &[(&str, &str)]) -> (Table, Vec<String>) {
+        let mut table: Table = toml::from_str(file).unwrap();
+        let vars = vars.iter().map(|(x, y)| (x.to_string(), y.to_string()));
+        let applied = apply(&mut table, vars, |_| true);
+        (table, applied)
+    }
+
+    #[test]
+    fn values_are_read_as_toml_or_strings() {
+        let (table, applied) = applied(
+            "",
+            &[
+                ("HYPERMANGLE_WORKERS", "8"),
+                ("HYPERMANGLE_DEV", "true"),
+                ("HYPERMANGLE_PUBLIC_PATHS", r#"["^/a", "^/b"]"#),
+                ("HYPERMANGLE_BIND_ADDRESS", "0.0.0.0:80"),
+            ],
+        );
+        assert_eq!(applied.len(), 4);
+        assert_eq!(table["workers"], Value::Integer(8));
+        assert_eq!(table["dev"], Value::Boolean(true));
+        assert_eq!(
+            table["public_paths"],
+            Value::Array(vec!["^/a".into(), "^/b".into()])
+        );
+        assert_eq!(table["bind_address"], Value::from("0.0.0.0:80"));
+    }
+
+    #[test]
+    fn fields_that_are_strings_in_the_file_stay_strings() {
+        let (table, _) = applied(
+            "api_token = \"abc\"\nworkers = 2",
+            &[
+                ("HYPERMANGLE_API_TOKEN", "12345"),
+                ("HYPERMANGLE_WORKERS", "12345"),
+            ],
+        );
+        assert_eq!(table["api_token"], Value::from("12345"));
+        assert_eq!(table["workers"], Value::Integer(12345));
+    }
+
+    #[test]
+    fn string_fields_missing_from_the_file_stay_strings() {
+        let mut table: Table = toml::from_str("bind_address = \"127.0.0.1:80\"").unwrap();
+        let vars = [
+            ("HYPERMANGLE_API_TOKEN", "1e3"),
+            ("HYPERMANGLE_URL_SIGNING_KEY", "0123"),
+            ("HYPERMANGLE_TLS_SESSION_CACHE_SIZE", "64"),
+        ];
+        let fits = |table: &Table| {
+            Value::Table(table.clone())
+                .try_into::<crate::HyperDomeConfig>()
+                .is_ok()
+        };
+        apply(
+            &mut table,
+            vars.iter().map(|(x, y)| (x.to_string(), y.to_string())),
+            fits,
+        );
+        assert_eq!(table["api_token"], Value::from("1e3"));
+        assert_eq!(table["url_signing_key"], Value::from("0123"));
+        assert_eq!(table["tls_session_cache_size"], Value::Integer(64));
+    }
+
+    #[test]
+    fn double_underscores_reach_into_tables() {
+        let (table, _) = applied(
+            "[ids]\nformat = \"uuid7\"\n[admin]\nenabled = false",
+            &[
+                ("HYPERMANGLE_IDS__NODE_ID", "7"),
+                ("HYPERMANGLE_GEOIP__COUNTRY_DATABASE", "/geo.mmdb"),
+                ("HYPERMANGLE_ADMIN__ENABLED", "true"),
+            ],
+        );
+        assert_eq!(table["ids"]["node_id"], Value::Integer(7));
+        assert_eq!(table["ids"]["format"], Value::from("uuid7"));
+        assert_eq!(table["geoip"]["country_database"], Value::from("/geo.mmdb"));
+        assert_eq!(table["admin"]["enabled"], Value::Boolean(true));
+    }
+
+    #[test]
+    fn values_that_are_not_tables_are_replaced_by_them() {
+        let (table, _) = applied("ids = 3", &[("HYPERMANGLE_IDS__NODE_ID", "7")]);
+        assert_eq!(table["ids"]["node_id"], Value::Integer(7));
+    }
+
+    #[test]
+    fn reserved_and_unprefixed_variables_are_skipped() {
+        let (table, applied) = applied(
+            "",
+            &[
+                ("HYPERMANGLE_SOCKET_PATH", "/tmp/x"),
+                ("HYPERMANGLE_REMOTE_TOKEN", "secret"),
+                ("HYPERMANGLE_", "1"),
+                ("PATH", "/bin"),
+            ],
+        );
+        assert!(applied.is_empty());
+        assert!(table.is_empty());
+    }
+}
@@ -0,0 +1,657 @@
+//! Sends panics and the exceptions raised by handlers to a Sentry-compatible server
+
+use std::{
+    cell::{Cell, RefCell},
+    future::Future,
+    panic::PanicHookInfo,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, Request},
+};
+use futures::future::BoxFuture;
+use fxhash::FxHashMap;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tower::{Layer, Service};
+
+use crate::metrics;
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct ErrorTrackingConfig {
+    /// Such as `https://{public_key}@o0.ingest.sentry.io/{project_id}`
+    dsn: String,
+    #[serde(default = "default_environment")]
+    environment: String,
+    /// The version of hypermangle if empty
+    #[serde(default)]
+    release: String,
+    /// The fraction of errors that are sent, from 0 to 1
+    #[serde(default = "default_sample_rate")]
+    sample_rate: f64,
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+}
+
+fn default_environment() -> String {
+    "production".into()
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+fn default_timeout_ms() -> u64 {
+    5_000
+}
+
+/// Events beyond this many that are still being sent are dropped, so that a server
+/// that cannot be reached does not hold on to every error
+const MAX_PENDING: usize = 100;
+
+/// Request headers that are not sent, as they hold credentials
+const REDACTED_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-api-key",
+    "x-hypermangle-signature",
+];
+
+/// Query parameters holding tokens, which are sent with their values masked
+const REDACTED_QUERY_PARAMS: &[&str] = &["api_token", "signature"];
+
+/// Where events are sent to, as found in a DSN
+struct Dsn {
+    envelope_url: String,
+    auth: String,
+}
+
+impl Dsn {
+    fn parse(dsn: &str) -> Result<Self, String> {
+        let url = reqwest::Url::parse(dsn).map_err(|e| e.to_string())?;
+        if url.username().is_empty() {
+            return Err("it has no public key".into());
+        }
+        let host = url.host_str().ok_or("it has no host")?;
+        let path = url.path().trim_end_matches('/');
+        let (prefix, project_id) = path.rsplit_once('/').ok_or("it has no project id")?;
+        if project_id.is_empty() {
+            return Err("it has no project id".into());
+        }
+        let port = url.port().map(|x| format!(":{x}")).unwrap_or_default();
+
+        let mut auth = format!(
+            "Sentry sentry_version=7, sentry_client={}/{}, sentry_key={}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            url.username()
+        );
+        if let Some(secret) = url.password() {
+            auth += &format!(", sentry_secret={secret}");
+        }
+        Ok(Self {
+            envelope_url: format!(
+                "{}://{host}{port}{prefix}/api/{project_id}/envelope/",
+                url.scheme()
+            ),
+            auth,
+        })
+    }
+}
+
+struct Tracker {
+    config: ErrorTrackingConfig,
+    dsn: Dsn,
+    release: String,
+    server_name: String,
+    client: reqwest::Client,
+    runtime: tokio::runtime::Handle,
+    pending: AtomicUsize,
+}
+
+static TRACKER: OnceLock<Tracker> = OnceLock::new();
+
+thread_local! {
+    /// How many handlers are being polled on this thread, whose panics are sent by
+    /// the panic capture layer along with their request
+    static HANDLER_DEPTH: Cell<u32> = const { Cell::new(0) };
+    /// The exception a handler raised right before panicking over it
+    static EXCEPTION: RefCell<Option<Exception>> = const { RefCell::new(None) };
+    /// The panic of a handler, until the panic capture layer takes it
+    static CAPTURED: RefCell<Option<Captured>> = const { RefCell::new(None) };
+}
+
+#[derive(Serialize, Clone)]
+pub(crate) struct Frame {
+    pub(crate) filename: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) function: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) lineno: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) colno: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) context_line: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Stacktrace {
+    /// Oldest first
+    frames: Vec<Frame>,
+}
+
+#[derive(Serialize)]
+struct Mechanism {
+    #[serde(rename = "type")]
+    ty: &'static str,
+    handled: bool,
+}
+
+/// An exception raised in a script
+#[derive(Clone)]
+pub(crate) struct Exception {
+    pub(crate) ty: String,
+    pub(crate) module: Option<String>,
+    pub(crate) value: String,
+    pub(crate) frames: Vec<Frame>,
+}
+
+#[derive(Serialize)]
+struct ExceptionValue {
+    #[serde(rename = "type")]
+    ty: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    module: Option<String>,
+    value: String,
+    stacktrace: Stacktrace,
+    mechanism: Mechanism,
+}
+
+/// A panic, along with the exception that caused it if any
+#[derive(Clone)]
+pub(crate) struct Captured {
+    message: String,
+    location: Option<Frame>,
+    thread: Option<String>,
+    exception: Option<Exception>,
+}
+
+/// What is known about the request a handler panicked during
+pub(crate) struct RequestContext {
+    method: String,
+    url: String,
+    query_string: String,
+    headers: FxHashMap<String, String>,
+    client: Option<String>,
+}
+
+impl RequestContext {
+    pub(crate) fn new(request: &Request<Body>) -> Self {
+        let headers = request.headers();
+        let host = headers
+            .get(header::HOST)
+            .and_then(|x| x.to_str().ok())
+            .or_else(|| request.uri().authority().map(|x| x.as_str()))
+            .unwrap_or_default();
+        // TLS is ended before requests get here, so only a proxy in front can tell
+        let scheme = headers
+            .get("x-forwarded-proto")
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or("http");
+        Self {
+            method: request.method().to_string(),
+            url: format!("{scheme}://{host}{}", request.uri().path()),
+            query_string: redacted_query(request.uri().query().unwrap_or_default()),
+            headers: redacted(headers),
+            client: crate::client_address::of(request).map(|x| x.to_string()),
+        }
+    }
+}
+
+fn redacted(headers: &HeaderMap) -> FxHashMap<String, String> {
+    headers
+        .iter()
+        .filter(|(name, _)| !REDACTED_HEADERS.contains(&name.as_str()))
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+        .collect()
+}
+
+fn redacted_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|param| match param.split_once('=') {
+            Some((name, _)) if REDACTED_QUERY_PARAMS.contains(&name) => {
+                format!("{name}=[Filtered]")
+            }
+            _ => param.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+#[derive(Serialize)]
+struct SentryRequest {
+    method: String,
+    url: String,
+    query_string: String,
+    headers: FxHashMap<String, String>,
+    #[serde(skip_serializing_if = "FxHashMap::is_empty")]
+    env: FxHashMap<&'static str, String>,
+}
+
+#[derive(Serialize)]
+struct Values<T> {
+    values: Vec<T>,
+}
+
+#[derive(Serialize)]
+struct Event<'a> {
+    event_id: &'a str,
+    timestamp: f64,
+    platform: &'static str,
+    level: &'static str,
+    logger: &'static str,
+    server_name: &'a str,
+    release: &'a str,
+    environment: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transaction: Option<&'a str>,
+    exception: Values<ExceptionValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request: Option<SentryRequest>,
+    tags: FxHashMap<&'static str, String>,
+    extra: FxHashMap<&'static str, String>,
+}
+
+#[derive(Serialize)]
+struct EnvelopeHeader<'a> {
+    event_id: &'a str,
+    sent_at: String,
+}
+
+#[derive(Serialize)]
+struct ItemHeader {
+    #[serde(rename = "type")]
+    ty: &'static str,
+    content_type: &'static str,
+    length: usize,
+}
+
+fn event_id() -> String {
+    let mut bytes = [0u8; 16];
+    openssl::rand::rand_bytes(&mut bytes).expect("Random bytes should be available");
+    bytes.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+/// Whether something that happens at `rate`, from 0 to 1, happens this time
+pub(crate) fn is_sampled(rate: f64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    }
+    let mut bytes = [0u8; 4];
+    openssl::rand::rand_bytes(&mut bytes).expect("Random bytes should be available");
+    (u32::from_le_bytes(bytes) as f64 / u32::MAX as f64) < rate
+}
+
+impl Tracker {
+    fn envelope(
+        &self,
+        captured: Captured,
+        route: Option<&str>,
+        request_id: Option<&str>,
+        context: Option<RequestContext>,
+    ) -> (String, Vec<u8>) {
+        let event_id = event_id();
+        let mut tags = FxHashMap::default();
+        let mut extra = FxHashMap::default();
+        if let Some(route) = route {
+            tags.insert("route", route.to_owned());
+        }
+        if let Some(request_id) = request_id.filter(|x| !x.is_empty()) {
+            tags.insert("request_id", request_id.to_owned());
+        }
+        if let Some(thread) = captured.thread {
+            tags.insert("thread", thread);
+        }
+        let mechanism = Mechanism {
+            ty: "panic",
+            handled: false,
+        };
+        let (platform, exception) = match captured.exception {
+            Some(exception) => {
+                extra.insert("panic", captured.message);
+                (
+                    "python",
+                    ExceptionValue {
+                        ty: exception.ty,
+                        module: exception.module,
+                        value: exception.value,
+                        stacktrace: Stacktrace {
+                            frames: exception.frames,
+                        },
+                        mechanism,
+                    },
+                )
+            }
+            None => (
+                "native",
+                ExceptionValue {
+                    ty: "panic".into(),
+                    module: None,
+                    value: captured.message,
+                    stacktrace: Stacktrace {
+                        frames: captured.location.into_iter().collect(),
+                    },
+                    mechanism,
+                },
+            ),
+        };
+        let request = context.map(|x| SentryRequest {
+            method: x.method,
+            url: x.url,
+            query_string: x.query_string,
+            headers: x.headers,
+            env: x
+                .client
+                .map(|x| FxHashMap::from_iter([("REMOTE_ADDR", x)]))
+                .unwrap_or_default(),
+        });
+        let event = Event {
+            event_id: &event_id,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            platform,
+            level: "error",
+            logger: "hypermangle",
+            server_name: &self.server_name,
+            release: &self.release,
+            environment: &self.config.environment,
+            transaction: route,
+            exception: Values {
+                values: vec![exception],
+            },
+            request,
+            tags,
+            extra,
+        };
+
+        let event = serde_json::to_vec(&event).expect("Events should be serializable");
+        let mut envelope = serde_json::to_vec(&EnvelopeHeader {
+            event_id: &event_id,
+            sent_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        })
+        .expect("Envelope headers should be serializable");
+        envelope.push(b'\n');
+        serde_json::to_writer(
+            &mut envelope,
+            &ItemHeader {
+                ty: "event",
+                content_type: "application/json",
+                length: event.len(),
+            },
+        )
+        .expect("Item headers should be serializable");
+        envelope.push(b'\n');
+        envelope.extend(event);
+        envelope.push(b'\n');
+        (event_id, envelope)
+    }
+
+    fn outcome(outcome: &str) {
+        metrics::increment(
+            "hypermangle_error_tracking_events_total",
+            "Events sent to error tracking by whether they were sent, failed or dropped",
+            &[("outcome", outcome)],
+        );
+    }
+
+    /// Sends the envelope in the background, returning a receiver that is notified
+    /// once it has been sent or failed to be
+    fn send(&'static self, event_id: String, envelope: Vec<u8>) -> std::sync::mpsc::Receiver<()> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        if self.pending.fetch_add(1, Ordering::Relaxed) >= MAX_PENDING {
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+            Self::outcome("dropped");
+            return receiver;
+        }
+        self.runtime.spawn(async move {
+            let result = self
+                .client
+                .post(&self.dsn.envelope_url)
+                .header("content-type", "application/x-sentry-envelope")
+                .header("x-sentry-auth", &self.dsn.auth)
+                .body(envelope)
+                .send()
+                .await;
+            match result {
+                Ok(response) if response.status().is_success() => Self::outcome("sent"),
+                Ok(response) => {
+                    warn!(
+                        "Error tracking rejected event {event_id} with {}",
+                        response.status()
+                    );
+                    Self::outcome("failed");
+                }
+                Err(e) => {
+                    warn!("Failed to send event {event_id} to error tracking: {e}");
+                    Self::outcome("failed");
+                }
+            }
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+            let _ = sender.send(());
+        });
+        receiver
+    }
+
+    fn capture(
+        &'static self,
+        captured: Captured,
+        route: Option<&str>,
+        request_id: Option<&str>,
+        context: Option<RequestContext>,
+    ) -> Option<std::sync::mpsc::Receiver<()>> {
+        if !is_sampled(self.config.sample_rate) {
+            return None;
+        }
+        let (event_id, envelope) = self.envelope(captured, route, request_id, context);
+        Some(self.send(event_id, envelope))
+    }
+}
+
+pub(crate) fn is_enabled() -> bool {
+    TRACKER.get().is_some()
+}
+
+/// Called right before panicking over `exception`, so that the exception is sent
+/// instead of the panic
+#[cfg_attr(not(feature = "python"), allow(dead_code))]
+pub(crate) fn stash_exception(exception: Exception) {
+    EXCEPTION.set(Some(exception));
+}
+
+/// Takes the panic that a handler polled on this thread just caught
+pub(crate) fn take_captured() -> Option<Captured> {
+    CAPTURED.take()
+}
+
+/// Sends the panic of the handler for `route`, caught by the panic capture layer
+pub(crate) fn capture_handler_panic(
+    captured: Captured,
+    route: &str,
+    request_id: &str,
+    context: Option<RequestContext>,
+) {
+    if let Some(tracker) = TRACKER.get() {
+        tracker.capture(captured, Some(route), Some(request_id), context);
+    }
+}
+
+fn panic_hook(info: &PanicHookInfo) {
+    let Some(tracker) = TRACKER.get() else {
+        return;
+    };
+    let payload = info.payload();
+    let message = if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else {
+        "Unknown panic message".to_owned()
+    };
+    let thread = std::thread::current();
+    let captured = Captured {
+        message,
+        location: info.location().map(|x| Frame {
+            filename: x.file().to_owned(),
+            function: None,
+            lineno: Some(x.line()),
+            colno: Some(x.column()),
+            context_line: None,
+        }),
+        thread: thread.name().map(str::to_owned),
+        exception: EXCEPTION.take(),
+    };
+
+    if HANDLER_DEPTH.get() > 0 {
+        CAPTURED.set(Some(captured));
+        return;
+    }
+    let Some(sent) = tracker.capture(captured, None, None, None) else {
+        return;
+    };
+    // The process is most likely about to exit, so it should outlive the event
+    if thread.name() == Some("main") {
+        let _ = sent.recv_timeout(Duration::from_millis(tracker.config.timeout_ms));
+    }
+}
+
+/// Starts sending panics, which are still printed as before. Must be called from
+/// within the tokio runtime
+pub(crate) fn init(config: &ErrorTrackingConfig) {
+    let dsn = Dsn::parse(&config.dsn)
+        .unwrap_or_else(|e| panic!("error_tracking.dsn should be a valid DSN, but {e}"));
+    let client = reqwest::Client::builder()
+        .user_agent(concat!(
+            env!("CARGO_PKG_NAME"),
+            "/",
+            env!("CARGO_PKG_VERSION")
+        ))
+        .timeout(Duration::from_millis(config.timeout_ms))
+        .build()
+        .expect("Error tracking HTTP client should be buildable");
+    let server_name = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_else(|| "hypermangle".into());
+    let release = if config.release.is_empty() {
+        concat!("hypermangle@", env!("CARGO_PKG_VERSION")).to_owned()
+    } else {
+        config.release.clone()
+    };
+    let tracker = Tracker {
+        config: config.clone(),
+        dsn,
+        release,
+        server_name: server_name.trim().to_owned(),
+        client,
+        runtime: tokio::runtime::Handle::current(),
+        pending: AtomicUsize::new(0),
+    };
+    if TRACKER.set(tracker).is_err() {
+        return;
+    }
+
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        previous(info);
+        panic_hook(info);
+    }));
+}
+
+/// Marks the thread as polling a handler while its service is called or polled, so
+/// that the panics of handlers are left to the panic capture layer
+#[derive(Clone, Copy)]
+pub(crate) struct HandlerScopeLayer;
+
+impl<S> Layer<S> for HandlerScopeLayer {
+    type Service = HandlerScope<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        HandlerScope { inner }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct HandlerScope<S> {
+    inner: S,
+}
+
+/// Resets the depth even if the handler unwinds
+struct DepthGuard;
+
+impl DepthGuard {
+    fn enter() -> Self {
+        HANDLER_DEPTH.set(HANDLER_DEPTH.get() + 1);
+        Self
+    }
+}
+
+impl Drop for DepthGuard {
+    fn drop(&mut self) {
+        HANDLER_DEPTH.set(HANDLER_DEPTH.get() - 1);
+    }
+}
+
+impl<S, B> Service<Request<B>> for HandlerScope<S>
+where
+    S: Service<Request<B>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<S::Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        let future = {
+            let _guard = DepthGuard::enter();
+            self.inner.call(request)
+        };
+        Box::pin(async move {
+            let mut future = std::pin::pin!(future);
+            std::future::poll_fn(move |cx| {
+                let _guard = DepthGuard::enter();
+                future.as_mut().poll(cx)
+            })
+            .await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_in_queries_are_masked() {
+        assert_eq!(
+            redacted_query("page=2&api_token=secret&expires=10&signature=abc"),
+            "page=2&api_token=[Filtered]&expires=10&signature=[Filtered]"
+        );
+        assert_eq!(redacted_query("api_token"), "api_token");
+        assert_eq!(redacted_query(""), "");
+    }
+}
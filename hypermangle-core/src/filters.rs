@@ -0,0 +1,213 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    body::Body,
+    http::{header::USER_AGENT, HeaderMap, HeaderName, Request, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+    Router,
+};
+use fxhash::FxHashMap;
+use log::debug;
+use regex::{Regex, RegexSet};
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+
+use crate::{metrics, spans::client_info, ConfigError};
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct FiltersConfig {
+    /// Checked in order. The first matching `deny` or `tarpit` rule decides what
+    /// happens to a request, while every matching `tag` rule tags it
+    #[serde(default)]
+    rules: Vec<FilterRule>,
+    /// Requests tarpitted at once, beyond which they are denied straight away
+    #[serde(default = "default_max_tarpitted")]
+    max_tarpitted: usize,
+}
+
+fn default_max_tarpitted() -> usize {
+    256
+}
+
+impl FiltersConfig {
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        for rule in &self.rules {
+            let invalid = |field| {
+                move |error| ConfigError::FilterRegexes {
+                    name: rule.name.clone(),
+                    field,
+                    error,
+                }
+            };
+            RegexSet::new(&rule.user_agents).map_err(invalid("user_agents"))?;
+            for regex in rule.headers.values() {
+                Regex::new(regex).map_err(invalid("headers"))?;
+            }
+            RegexSet::new(&rule.paths).map_err(invalid("paths"))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Clone)]
+struct FilterRule {
+    /// Used in metrics and logs
+    name: String,
+    /// Regexes matched against the user agent, which is empty if missing. Matches
+    /// every user agent if empty
+    #[serde(default)]
+    user_agents: Vec<String>,
+    /// Regexes that each header must match, where missing headers are empty
+    #[serde(default)]
+    headers: FxHashMap<String, String>,
+    /// Regexes matched against the request path. Matches every path if empty
+    #[serde(default)]
+    paths: Vec<String>,
+    action: FilterAction,
+    /// What denied and tarpitted requests are answered with
+    #[serde(default = "default_status")]
+    status: u16,
+    /// How long tarpitted requests are held before being answered
+    #[serde(default = "default_tarpit_ms")]
+    tarpit_ms: u64,
+}
+
+fn default_status() -> u16 {
+    403
+}
+
+fn default_tarpit_ms() -> u64 {
+    10_000
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum FilterAction {
+    Deny,
+    Tarpit,
+    /// Lets the request through, with the name of the rule in `RequestContext.tags`
+    Tag,
+}
+
+impl FilterAction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Deny => "deny",
+            Self::Tarpit => "tarpit",
+            Self::Tag => "tag",
+        }
+    }
+}
+
+/// The names of the `tag` rules a request matched
+#[derive(Clone, Default)]
+pub(crate) struct RequestTags(
+    #[cfg_attr(not(feature = "python"), allow(dead_code))] pub(crate) Vec<String>,
+);
+
+struct CompiledRule {
+    name: String,
+    user_agents: RegexSet,
+    headers: Vec<(HeaderName, Regex)>,
+    paths: RegexSet,
+    action: FilterAction,
+    status: StatusCode,
+    tarpit: Duration,
+}
+
+impl CompiledRule {
+    fn new(rule: &FilterRule) -> Self {
+        let name = &rule.name;
+        Self {
+            name: name.clone(),
+            user_agents: RegexSet::new(&rule.user_agents)
+                .expect("Filter user agents should have been validated"),
+            headers: rule
+                .headers
+                .iter()
+                .map(|(header, regex)| {
+                    let header = header.parse().unwrap_or_else(|_| {
+                        panic!("{header:?} in filter {name} should be a valid header name")
+                    });
+                    let regex = Regex::new(regex)
+                        .expect("Filter header regexes should have been validated");
+                    (header, regex)
+                })
+                .collect(),
+            paths: RegexSet::new(&rule.paths).expect("Filter paths should have been validated"),
+            action: rule.action,
+            status: StatusCode::from_u16(rule.status)
+                .unwrap_or_else(|_| panic!("Status of filter {name} should be a valid status")),
+            tarpit: Duration::from_millis(rule.tarpit_ms),
+        }
+    }
+
+    fn matches(&self, path: &str, headers: &HeaderMap) -> bool {
+        let header = |name: &HeaderName| {
+            headers
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .unwrap_or_default()
+        };
+        (self.paths.is_empty() || self.paths.is_match(path))
+            && (self.user_agents.is_empty() || self.user_agents.is_match(header(&USER_AGENT)))
+            && self
+                .headers
+                .iter()
+                .all(|(name, regex)| regex.is_match(header(name)))
+    }
+}
+
+/// Denies, holds or tags requests by their user agent and headers, before they reach
+/// the handlers
+pub(crate) fn layer_filters(router: Router, config: &FiltersConfig) -> Router {
+    let rules: Arc<[CompiledRule]> = config.rules.iter().map(CompiledRule::new).collect();
+    let tarpit_slots = Arc::new(Semaphore::new(config.max_tarpitted));
+
+    router.layer(axum::middleware::from_fn(
+        move |mut request: Request<Body>, next: Next<Body>| {
+            let rules = rules.clone();
+            let tarpit_slots = tarpit_slots.clone();
+            async move {
+                let path = request.uri().path();
+                let mut tags = vec![];
+                let mut verdict = None;
+                for rule in rules.iter() {
+                    if !rule.matches(path, request.headers()) {
+                        continue;
+                    }
+                    metrics::increment(
+                        "hypermangle_filtered_requests_total",
+                        "Requests matched by filter rules",
+                        &[("rule", &rule.name), ("action", rule.action.as_str())],
+                    );
+                    if rule.action == FilterAction::Tag {
+                        tags.push(rule.name.clone());
+                    } else {
+                        verdict = Some(rule);
+                        break;
+                    }
+                }
+
+                if let Some(rule) = verdict {
+                    debug!(
+                        "Filter {} stopped a request to {path} from {}",
+                        rule.name,
+                        client_info(&request)
+                    );
+                    if rule.action == FilterAction::Tarpit {
+                        if let Ok(_slot) = tarpit_slots.try_acquire() {
+                            tokio::time::sleep(rule.tarpit).await;
+                        }
+                    }
+                    return rule.status.into_response();
+                }
+                if !tags.is_empty() {
+                    request.extensions_mut().insert(RequestTags(tags));
+                }
+                next.run(request).await
+            }
+        },
+    ))
+}
@@ -0,0 +1,70 @@
+//! Entry points for cargo-fuzz targets, which build with `--cfg fuzzing`:
+//!
+//! ```ignore
+//! fuzz_target!(|data: &[u8]| hypermangle_core::fuzz::auth(data));
+//! ```
+use axum::{http::HeaderValue, routing::get, Router};
+use regex::RegexSet;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tower_http::auth::AsyncRequireAuthorizationLayer;
+
+use crate::bearer::BearerAuth;
+
+/// The token that `auth` accepts
+pub const API_TOKEN: &str = "fuzz-token";
+
+thread_local! {
+    static RUNTIME: tokio::runtime::Runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Fuzzing runtime should be buildable");
+}
+
+/// Serves `data` to `router` as the raw bytes of an HTTP/1.1 connection, without a
+/// socket, returning the raw bytes of the responses
+pub fn serve_bytes(router: Router, data: &[u8]) -> Vec<u8> {
+    RUNTIME.with(|runtime| {
+        runtime.block_on(async {
+            let (mut client, server) = tokio::io::duplex(64 * 1024);
+            let connection = hyper::server::conn::Http::new()
+                .http1_only(true)
+                .http1_keep_alive(false)
+                .http1_half_close(true)
+                .serve_connection(server, router);
+            let exchange = async {
+                let _ = client.write_all(data).await;
+                let _ = client.shutdown().await;
+                let mut response = vec![];
+                let _ = client.read_to_end(&mut response).await;
+                response
+            };
+            let (_, response) = futures::future::join(connection, exchange).await;
+            response
+        })
+    })
+}
+
+/// Serves `data` to a router behind the bearer authentication layer, which accepts
+/// `API_TOKEN` and lets anything below `/public` through
+pub fn auth(data: &[u8]) -> Vec<u8> {
+    let router = Router::new()
+        .route("/*path", get(|| async { "authorized" }))
+        .layer(AsyncRequireAuthorizationLayer::new(BearerAuth::new(
+            Some(HeaderValue::from_static(API_TOKEN)),
+            None,
+            RegexSet::new(["^/public"]).expect("Public path regex should be valid"),
+        )));
+    serve_bytes(router, data)
+}
+
+/// Decodes `data` as a frame of the console protocol
+pub fn console_frame(data: &[u8]) {
+    crate::console::fuzz_frame(data);
+}
+
+/// Converts what `data` evaluates to as a Python literal, such as
+/// `(200, {"x-a": "b"}, [1, 2])`, into a response like those of handlers
+#[cfg(feature = "python")]
+pub fn handler_response(data: &[u8]) {
+    let _ = crate::py::fuzz_response(data);
+}
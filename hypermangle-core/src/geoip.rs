@@ -0,0 +1,133 @@
+use std::{net::IpAddr, path::Path, sync::Arc};
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+    Router,
+};
+use maxminddb::{geoip2, Reader};
+use serde::Deserialize;
+
+use crate::ConfigError;
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct GeoIpConfig {
+    /// A GeoLite2 or GeoIP2 Country or City database
+    #[serde(default)]
+    country_database: String,
+    /// A GeoLite2 or GeoIP2 ASN database
+    #[serde(default)]
+    asn_database: String,
+    /// ISO codes of the only countries whose clients are served, if not empty.
+    /// Clients whose country is unknown are refused too
+    #[serde(default)]
+    allow_countries: Vec<String>,
+    /// ISO codes of the countries whose clients are refused with 403
+    #[serde(default)]
+    deny_countries: Vec<String>,
+}
+
+/// The country and network of the client a request came from, attached to requests
+/// and to their responses so that it can be logged
+#[derive(Clone, Default)]
+pub(crate) struct GeoInfo {
+    pub(crate) country: Option<String>,
+    pub(crate) asn: Option<u32>,
+    #[cfg_attr(not(feature = "python"), allow(dead_code))]
+    pub(crate) as_organization: Option<String>,
+}
+
+struct GeoIp {
+    country: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+    allow_countries: Vec<String>,
+    deny_countries: Vec<String>,
+}
+
+fn open_database(path: &str) -> Result<Option<Reader<Vec<u8>>>, ConfigError> {
+    if path.is_empty() {
+        return Ok(None);
+    }
+    Reader::open_readfile(Path::new(path))
+        .map(Some)
+        .map_err(|error| ConfigError::GeoIpDatabase {
+            path: path.to_owned(),
+            error,
+        })
+}
+
+impl GeoIpConfig {
+    /// Opens the databases, which are read whole, to check them
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        let country = open_database(&self.country_database)?;
+        open_database(&self.asn_database)?;
+        if country.is_none() && !(self.allow_countries.is_empty() && self.deny_countries.is_empty())
+        {
+            return Err(ConfigError::GeoIpRules);
+        }
+        Ok(())
+    }
+}
+
+impl GeoIp {
+    fn lookup(&self, address: IpAddr) -> GeoInfo {
+        let country = self.country.as_ref().and_then(|reader| {
+            let record: geoip2::Country = reader.lookup(address).ok()?;
+            Some(record.country?.iso_code?.to_owned())
+        });
+        let asn: Option<geoip2::Asn> = self
+            .asn
+            .as_ref()
+            .and_then(|reader| reader.lookup(address).ok());
+        GeoInfo {
+            country,
+            asn: asn.as_ref().and_then(|x| x.autonomous_system_number),
+            as_organization: asn
+                .and_then(|x| x.autonomous_system_organization)
+                .map(str::to_owned),
+        }
+    }
+
+    fn allows(&self, info: &GeoInfo) -> bool {
+        let listed = |countries: &[String]| {
+            info.country
+                .as_ref()
+                .is_some_and(|x| countries.iter().any(|y| y.eq_ignore_ascii_case(x)))
+        };
+        (self.allow_countries.is_empty() || listed(&self.allow_countries))
+            && !listed(&self.deny_countries)
+    }
+}
+
+/// Looks up the country and network of every client, refusing those that the
+/// country rules do not allow
+pub(crate) fn layer_geoip(router: Router, config: &GeoIpConfig) -> Router {
+    let open = |path| open_database(path).expect("geoip databases should have been validated");
+    let geoip = Arc::new(GeoIp {
+        country: open(&config.country_database),
+        asn: open(&config.asn_database),
+        allow_countries: config.allow_countries.clone(),
+        deny_countries: config.deny_countries.clone(),
+    });
+
+    router.layer(axum::middleware::from_fn(
+        move |mut request: Request<Body>, next: Next<Body>| {
+            let info = crate::client_address::of(&request)
+                .map(|address| geoip.lookup(address))
+                .unwrap_or_default();
+            let allowed = geoip.allows(&info);
+            request.extensions_mut().insert(info.clone());
+            async move {
+                let mut response = if allowed {
+                    next.run(request).await
+                } else {
+                    StatusCode::FORBIDDEN.into_response()
+                };
+                response.extensions_mut().insert(info);
+                response
+            }
+        },
+    ))
+}
@@ -0,0 +1,337 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    http::{header::CONTENT_SECURITY_POLICY, HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+    Router,
+};
+use fxhash::FxHashMap;
+use regex::RegexSet;
+use serde::Deserialize;
+
+use crate::{vhost::request_host, ConfigError};
+
+/// Headers added to or removed from the responses of matching requests
+#[derive(Deserialize, Clone, Default)]
+pub(crate) struct HeaderRule {
+    /// Regexes matched against the request path. Matches every path if empty
+    #[serde(default)]
+    pub(crate) paths: Vec<String>,
+    /// Matches every host if empty. Rules inside a `[[vhost]]` default to its hosts
+    #[serde(default)]
+    pub(crate) hosts: Vec<String>,
+    /// Replaces headers set by handlers
+    #[serde(default)]
+    pub(crate) set: FxHashMap<String, String>,
+    /// Only added if the handler did not set the header
+    #[serde(default)]
+    pub(crate) if_missing: FxHashMap<String, String>,
+    #[serde(default)]
+    pub(crate) remove: Vec<String>,
+}
+
+impl HeaderRule {
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        RegexSet::new(&self.paths).map_err(ConfigError::HeaderRulePaths)?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SecurityHeaders {
+    Strict,
+    Relaxed,
+    #[default]
+    Off,
+}
+
+impl SecurityHeaders {
+    /// The preset as a rule that never replaces headers set by handlers. An `overrides`
+    /// entry replaces the preset value of that header, or drops it if empty
+    pub(crate) fn rule(
+        self,
+        tls: bool,
+        overrides: &FxHashMap<String, String>,
+    ) -> Option<HeaderRule> {
+        let (hsts, frame_options, referrer_policy) = match self {
+            Self::Strict => (
+                "max-age=63072000; includeSubDomains; preload",
+                "DENY",
+                "no-referrer",
+            ),
+            Self::Relaxed => (
+                "max-age=31536000",
+                "SAMEORIGIN",
+                "strict-origin-when-cross-origin",
+            ),
+            Self::Off => return None,
+        };
+        let mut headers: FxHashMap<String, String> = [
+            ("x-content-type-options", "nosniff"),
+            ("x-frame-options", frame_options),
+            ("referrer-policy", referrer_policy),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .collect();
+        // Browsers ignore HSTS over plain HTTP
+        if tls {
+            headers.insert("strict-transport-security".into(), hsts.into());
+        }
+        for (name, value) in overrides {
+            let name = name.to_ascii_lowercase();
+            if value.is_empty() {
+                headers.remove(&name);
+            } else {
+                headers.insert(name, value.clone());
+            }
+        }
+
+        Some(HeaderRule {
+            if_missing: headers,
+            ..Default::default()
+        })
+    }
+}
+
+struct CompiledRule {
+    paths: Option<RegexSet>,
+    hosts: Vec<String>,
+    set: Vec<(HeaderName, HeaderValue)>,
+    if_missing: Vec<(HeaderName, HeaderValue)>,
+    remove: Vec<HeaderName>,
+}
+
+fn parse_headers(headers: &FxHashMap<String, String>) -> Vec<(HeaderName, HeaderValue)> {
+    let mut headers: Vec<_> = headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.parse()
+                    .unwrap_or_else(|_| panic!("{name:?} should be a valid header name")),
+                value
+                    .parse()
+                    .unwrap_or_else(|_| panic!("{value:?} should be a valid header value")),
+            )
+        })
+        .collect();
+    // Keeps the order of headers in responses stable
+    headers.sort_by(|a: &(HeaderName, _), b| a.0.as_str().cmp(b.0.as_str()));
+    headers
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(base) => host.split_once('.').is_some_and(|x| x.1 == base),
+        None => pattern == host,
+    }
+}
+
+impl CompiledRule {
+    fn new(rule: &HeaderRule) -> Self {
+        Self {
+            paths: (!rule.paths.is_empty()).then(|| {
+                RegexSet::new(&rule.paths).expect("Header rule paths should have been validated")
+            }),
+            hosts: rule.hosts.iter().map(|x| x.to_ascii_lowercase()).collect(),
+            set: parse_headers(&rule.set),
+            if_missing: parse_headers(&rule.if_missing),
+            remove: rule
+                .remove
+                .iter()
+                .map(|name| {
+                    name.parse()
+                        .unwrap_or_else(|_| panic!("{name:?} should be a valid header name"))
+                })
+                .collect(),
+        }
+    }
+
+    fn matches(&self, path: &str, host: Option<&str>) -> bool {
+        if self.paths.as_ref().is_some_and(|x| !x.is_match(path)) {
+            return false;
+        }
+        self.hosts.is_empty()
+            || host.is_some_and(|host| self.hosts.iter().any(|x| host_matches(x, host)))
+    }
+}
+
+/// Applies `rules` in order to the response of every request they match
+pub(crate) fn layer_header_rules(router: Router, rules: &[HeaderRule]) -> Router {
+    if rules.is_empty() {
+        return router;
+    }
+    let rules: Arc<Vec<_>> = Arc::new(rules.iter().map(CompiledRule::new).collect());
+
+    router.layer(axum::middleware::from_fn(
+        move |request: Request<Body>, next: Next<Body>| {
+            let rules = rules.clone();
+            async move {
+                let path = request.uri().path().to_owned();
+                let host = request_host(&request);
+                let mut response: Response = next.run(request).await;
+                let headers = response.headers_mut();
+
+                for rule in rules.iter() {
+                    if !rule.matches(&path, host.as_deref()) {
+                        continue;
+                    }
+                    for name in &rule.remove {
+                        headers.remove(name);
+                    }
+                    for (name, value) in &rule.set {
+                        headers.insert(name, value.clone());
+                    }
+                    for (name, value) in &rule.if_missing {
+                        if !headers.contains_key(name) {
+                            headers.insert(name, value.clone());
+                        }
+                    }
+                }
+
+                response
+            }
+        },
+    ))
+}
+
+/// Stands for the nonce of the request in `content_security_policy`
+const NONCE_PLACEHOLDER: &str = "{nonce}";
+
+/// The nonce generated for a request, which inline scripts and styles on the page
+/// carry as `nonce="..."` to be allowed by the policy
+#[derive(Clone)]
+pub(crate) struct CspNonce(pub(crate) Arc<str>);
+
+fn new_nonce() -> String {
+    let mut bytes = [0u8; 16];
+    openssl::rand::rand_bytes(&mut bytes).expect("Random bytes should be available");
+    openssl::base64::encode_block(&bytes)
+}
+
+/// Sends `policy` with responses that do not have a `Content-Security-Policy` of
+/// their own. If it mentions `{nonce}`, every request gets a nonce, which replaces it
+/// as `'nonce-...'`
+pub(crate) fn layer_content_security_policy(router: Router, policy: &str) -> Router {
+    if policy.is_empty() {
+        return router;
+    }
+    let policy: Arc<str> = policy.into();
+    if !policy.contains(NONCE_PLACEHOLDER) {
+        let value: HeaderValue = policy
+            .parse()
+            .expect("content_security_policy should be a valid header value");
+        return router.layer(axum::middleware::map_response(
+            move |mut response: Response| {
+                let value = value.clone();
+                async move {
+                    response
+                        .headers_mut()
+                        .entry(CONTENT_SECURITY_POLICY)
+                        .or_insert(value);
+                    response
+                }
+            },
+        ));
+    }
+
+    router.layer(axum::middleware::from_fn(
+        move |mut request: Request<Body>, next: Next<Body>| {
+            let policy = policy.clone();
+            async move {
+                let nonce: Arc<str> = new_nonce().into();
+                request.extensions_mut().insert(CspNonce(nonce.clone()));
+                let mut response = next.run(request).await;
+                let value = policy.replace(NONCE_PLACEHOLDER, &format!("'nonce-{nonce}'"));
+                response
+                    .headers_mut()
+                    .entry(CONTENT_SECURITY_POLICY)
+                    .or_insert(
+                        value
+                            .parse()
+                            .expect("content_security_policy should be a valid header value"),
+                    );
+                response
+            }
+        },
+    ))
+}
+
+/// Appends the headers that CORS responses vary on. `CorsLayer` would replace the
+/// `Vary` header of a response with them, losing what handlers vary on
+pub(crate) async fn append_cors_vary(mut response: Response) -> Response {
+    for name in tower_http::cors::preflight_request_headers() {
+        response
+            .headers_mut()
+            .append(axum::http::header::VARY, HeaderValue::from(name));
+    }
+    response
+}
+
+/// `Link` headers for the resources that pages on matching paths need, such as
+/// `</app.css>; rel=preload; as=style`. hyper cannot write `103 Early Hints`, so they
+/// are sent with the response head instead, which streamed bodies send right away
+#[derive(Deserialize, Clone)]
+pub(crate) struct EarlyHints {
+    /// Regexes matched against the request path. Matches every path if empty
+    #[serde(default)]
+    paths: Vec<String>,
+    links: Vec<String>,
+}
+
+impl EarlyHints {
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        RegexSet::new(&self.paths).map_err(ConfigError::EarlyHintPaths)?;
+        Ok(())
+    }
+}
+
+pub(crate) fn layer_early_hints(router: Router, hints: &[EarlyHints]) -> Router {
+    if hints.is_empty() {
+        return router;
+    }
+    let hints: Arc<Vec<_>> = Arc::new(
+        hints
+            .iter()
+            .map(|hints| {
+                let paths = (!hints.paths.is_empty()).then(|| {
+                    RegexSet::new(&hints.paths)
+                        .expect("Early hint paths should have been validated")
+                });
+                let links: Vec<HeaderValue> = hints
+                    .links
+                    .iter()
+                    .map(|link| {
+                        link.parse()
+                            .unwrap_or_else(|_| panic!("{link:?} should be a valid Link header"))
+                    })
+                    .collect();
+                (paths, links)
+            })
+            .collect(),
+    );
+
+    router.layer(axum::middleware::from_fn(
+        move |request: Request<Body>, next: Next<Body>| {
+            let hints = hints.clone();
+            async move {
+                let path = request.uri().path().to_owned();
+                let mut response: Response = next.run(request).await;
+                for (paths, links) in hints.iter() {
+                    if paths.as_ref().is_some_and(|x| !x.is_match(&path)) {
+                        continue;
+                    }
+                    for link in links {
+                        response
+                            .headers_mut()
+                            .append(axum::http::header::LINK, link.clone());
+                    }
+                }
+                response
+            }
+        },
+    ))
+}
@@ -0,0 +1,122 @@
+//! Pings a monitoring service while the server runs, in the style of healthchecks.io,
+//! so that it alerts once the pings stop
+
+use std::time::Duration;
+
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::metrics;
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct HeartbeatConfig {
+    url: String,
+    #[serde(default = "default_interval_secs")]
+    interval_secs: u64,
+    /// Pinged when the server is stopped with SIGTERM or SIGINT, so that stops are
+    /// alerted at once instead of after a missed ping. `{url}/fail` if empty, and
+    /// nothing is pinged if `none`
+    #[serde(default)]
+    shutdown_url: String,
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+}
+
+fn default_interval_secs() -> u64 {
+    60
+}
+
+fn default_timeout_ms() -> u64 {
+    10_000
+}
+
+impl HeartbeatConfig {
+    fn shutdown_url(&self) -> Option<String> {
+        match self.shutdown_url.as_str() {
+            "" => Some(format!("{}/fail", self.url.trim_end_matches('/'))),
+            "none" => None,
+            url => Some(url.to_owned()),
+        }
+    }
+}
+
+async fn ping(client: &reqwest::Client, url: &str, kind: &str) {
+    let outcome = match client.get(url).send().await {
+        Ok(response) if response.status().is_success() => "sent",
+        Ok(response) => {
+            warn!(
+                "Heartbeat {kind} ping was rejected with {}",
+                response.status()
+            );
+            "failed"
+        }
+        Err(e) => {
+            warn!("Failed to send heartbeat {kind} ping: {e}");
+            "failed"
+        }
+    };
+    metrics::increment(
+        "hypermangle_heartbeats_total",
+        "Heartbeat pings by kind and whether they were sent",
+        &[("kind", kind), ("outcome", outcome)],
+    );
+}
+
+/// Resolves with the exit code of the signal that stops the server
+async fn stop_signal() -> i32 {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM should be listenable");
+        tokio::select! {
+            _ = terminate.recv() => 128 + 15,
+            _ = tokio::signal::ctrl_c() => 128 + 2,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        128 + 2
+    }
+}
+
+/// Pings `url` right away and on every interval. Stopping the server with a signal
+/// pings `shutdown_url` before it exits
+pub(crate) fn start(config: &HeartbeatConfig) {
+    assert!(
+        config.interval_secs > 0,
+        "heartbeat.interval_secs should be more than 0"
+    );
+    let client = reqwest::Client::builder()
+        .user_agent(concat!(
+            env!("CARGO_PKG_NAME"),
+            "/",
+            env!("CARGO_PKG_VERSION")
+        ))
+        .timeout(Duration::from_millis(config.timeout_ms))
+        .build()
+        .expect("Heartbeat HTTP client should be buildable");
+
+    let url = config.url.clone();
+    let period = Duration::from_secs(config.interval_secs);
+    let interval_client = client.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            ping(&interval_client, &url, "interval").await;
+        }
+    });
+
+    let shutdown_url = config.shutdown_url();
+    tokio::spawn(async move {
+        let code = stop_signal().await;
+        if let Some(url) = shutdown_url {
+            info!("Sending the heartbeat shutdown ping before stopping");
+            ping(&client, &url, "shutdown").await;
+        }
+        std::process::exit(code);
+    });
+}
@@ -0,0 +1,155 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    http::{
+        header::{HOST, ORIGIN, UPGRADE},
+        HeaderMap, Request, StatusCode,
+    },
+    middleware::Next,
+    response::IntoResponse,
+    Router,
+};
+use fxhash::FxHashSet;
+use log::debug;
+
+use crate::{metrics, spans::client_info, vhost::request_host};
+
+/// Host names, where `*.example.com` matches any single subdomain of example.com
+struct HostNames {
+    names: FxHashSet<String>,
+    /// Keyed by the part after `*.`
+    wildcards: FxHashSet<String>,
+}
+
+impl HostNames {
+    fn new(hosts: &[String]) -> Self {
+        let mut names = FxHashSet::default();
+        let mut wildcards = FxHashSet::default();
+        for host in hosts {
+            let host = host.trim_end_matches('.').to_ascii_lowercase();
+            match host.strip_prefix("*.") {
+                Some(base) => wildcards.insert(base.to_owned()),
+                None => names.insert(host),
+            };
+        }
+        Self { names, wildcards }
+    }
+
+    fn contains(&self, host: &str) -> bool {
+        self.names.contains(host)
+            || host
+                .split_once('.')
+                .is_some_and(|(_, base)| self.wildcards.contains(base))
+    }
+}
+
+/// Origins allowed to open websockets, such as `https://example.com`. `self` allows
+/// pages served from the host the websocket is opened on
+struct Origins {
+    origins: FxHashSet<String>,
+    same_host: bool,
+}
+
+impl Origins {
+    fn new(origins: &[String]) -> Self {
+        Self {
+            origins: origins
+                .iter()
+                .filter(|x| *x != "self")
+                .map(|x| x.trim_end_matches('/').to_ascii_lowercase())
+                .collect(),
+            same_host: origins.iter().any(|x| x == "self"),
+        }
+    }
+
+    /// Whether `origin` may open a websocket to `host`
+    fn allows(&self, origin: &str, host: Option<&str>) -> bool {
+        let origin = origin.to_ascii_lowercase();
+        if self.origins.contains(&origin) {
+            return true;
+        }
+        // The host of `scheme://host[:port]`, where IPv6 literals end with ]
+        let origin_host = origin.split_once("://").map(|(_, x)| {
+            if x.ends_with(']') {
+                x
+            } else {
+                x.rsplit_once(':').map_or(x, |x| x.0)
+            }
+        });
+        self.same_host && origin_host.is_some() && origin_host == host
+    }
+}
+
+fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    headers
+        .get(UPGRADE)
+        .and_then(|x| x.to_str().ok())
+        .is_some_and(|x| x.eq_ignore_ascii_case("websocket"))
+}
+
+fn reject(reason: &str, status: StatusCode, request: &Request<Body>) -> axum::response::Response {
+    debug!(
+        "Rejected a request to {} from {} for its {reason}",
+        request.uri().path(),
+        client_info(request)
+    );
+    metrics::increment(
+        "hypermangle_host_check_rejections_total",
+        "Requests rejected for their Host header, or websockets for their Origin",
+        &[("reason", reason)],
+    );
+    status.into_response()
+}
+
+/// Rejects requests for hosts other than `hosts` with 421, so that pages on other
+/// sites cannot reach the server by pointing their own domain at it, and websocket
+/// upgrades from origins other than `websocket_origins` with 403. Either check is
+/// skipped when its list is empty
+pub(crate) fn layer_host_checks(
+    router: Router,
+    hosts: &[String],
+    websocket_origins: &[String],
+) -> Router {
+    if hosts.is_empty() && websocket_origins.is_empty() {
+        return router;
+    }
+    let hosts = (!hosts.is_empty()).then(|| HostNames::new(hosts));
+    let origins = (!websocket_origins.is_empty()).then(|| Origins::new(websocket_origins));
+    let checks = Arc::new((hosts, origins));
+
+    router.layer(axum::middleware::from_fn(
+        move |request: Request<Body>, next: Next<Body>| {
+            let checks = checks.clone();
+            async move {
+                let (hosts, origins) = &*checks;
+                let host = request_host(&request);
+                if let Some(hosts) = hosts {
+                    if request.headers().get_all(HOST).iter().count() > 1 {
+                        return reject("host", StatusCode::BAD_REQUEST, &request);
+                    }
+                    match &host {
+                        Some(host) if hosts.contains(host) => {}
+                        Some(_) => {
+                            return reject("host", StatusCode::MISDIRECTED_REQUEST, &request)
+                        }
+                        None => return reject("host", StatusCode::BAD_REQUEST, &request),
+                    }
+                }
+                // Only browsers send Origin, and only they can be made to open
+                // websockets for another site, so other clients are let through
+                if let Some(origins) = origins {
+                    let allowed = match request.headers().get(ORIGIN).map(|x| x.to_str()) {
+                        Some(Ok(origin)) => origins.allows(origin, host.as_deref()),
+                        Some(Err(_)) => false,
+                        None => true,
+                    };
+                    if !allowed && is_websocket_upgrade(request.headers()) {
+                        return reject("origin", StatusCode::FORBIDDEN, &request);
+                    }
+                }
+                next.run(request).await
+            }
+        },
+    ))
+}
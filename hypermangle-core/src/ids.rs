@@ -0,0 +1,216 @@
+//! Unique ids for the records scripts and handlers create, which instances sharing a
+//! database can make without asking each other
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum IdFormat {
+    /// Random UUIDs
+    #[default]
+    Uuid4,
+    /// UUIDs that start with the time they were made, so that they sort by it and
+    /// keep database indexes compact
+    Uuid7,
+    /// 64-bit integers of the time, `node_id` and a sequence, as decimal strings
+    Snowflake,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub(crate) struct IdConfig {
+    #[serde(default)]
+    pub(crate) format: IdFormat,
+    /// Sets Snowflake ids of each instance apart, from 0 to 1023. Instances sharing a
+    /// database should each have their own
+    #[serde(default)]
+    pub(crate) node_id: u16,
+}
+
+pub(crate) const MAX_NODE_ID: u16 = (1 << NODE_BITS) - 1;
+
+const NODE_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+
+/// 2020-01-01T00:00:00Z, which Snowflake ids count milliseconds from
+const SNOWFLAKE_EPOCH_MS: u64 = 1_577_836_800_000;
+
+/// Makes ids in the configured format. They are unique across threads, and Snowflake
+/// and UUIDv7 ids made by one generator always increase
+pub struct IdGenerator {
+    format: IdFormat,
+    node_id: u64,
+    /// The milliseconds of the last UUIDv7, shifted above the sequence within them
+    last_uuid: AtomicU64,
+    /// Like `last_uuid`, for Snowflake ids, which count from their own epoch
+    last_snowflake: AtomicU64,
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time should be after the UNIX epoch")
+        .as_millis() as u64
+}
+
+fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    openssl::rand::rand_bytes(&mut bytes).expect("Random bytes should be available");
+    bytes
+}
+
+fn format_uuid(bytes: [u8; 16]) -> String {
+    let hex: String = bytes.iter().map(|x| format!("{x:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+impl IdGenerator {
+    pub fn new(format: IdFormat, node_id: u16) -> Self {
+        assert!(
+            node_id <= MAX_NODE_ID,
+            "node_id should be at most {MAX_NODE_ID}, not {node_id}"
+        );
+        Self {
+            format,
+            node_id: node_id.into(),
+            last_uuid: AtomicU64::new(0),
+            last_snowflake: AtomicU64::new(0),
+        }
+    }
+
+    /// The milliseconds and sequence of the next id. When the sequence of a
+    /// millisecond runs out, or the clock goes back, ids borrow from the next one
+    fn next_tick(last: &AtomicU64, millis: u64) -> (u64, u64) {
+        let now = millis << SEQUENCE_BITS;
+        let last = last
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                Some(now.max(last + 1))
+            })
+            .unwrap_or_else(|last| last);
+        let next = now.max(last + 1);
+        (next >> SEQUENCE_BITS, next & ((1 << SEQUENCE_BITS) - 1))
+    }
+
+    pub fn new_id(&self) -> String {
+        match self.format {
+            IdFormat::Uuid4 => {
+                let mut bytes = random::<16>();
+                bytes[6] = (bytes[6] & 0x0f) | 0x40;
+                bytes[8] = (bytes[8] & 0x3f) | 0x80;
+                format_uuid(bytes)
+            }
+            IdFormat::Uuid7 => {
+                let (millis, sequence) = Self::next_tick(&self.last_uuid, unix_millis());
+                let mut bytes = random::<16>();
+                bytes[..6].copy_from_slice(&millis.to_be_bytes()[2..]);
+                // The sequence takes the place of rand_a, keeping ids in order
+                bytes[6] = 0x70 | (sequence >> 8) as u8;
+                bytes[7] = sequence as u8;
+                bytes[8] = (bytes[8] & 0x3f) | 0x80;
+                format_uuid(bytes)
+            }
+            IdFormat::Snowflake => self.new_snowflake().to_string(),
+        }
+    }
+
+    /// A Snowflake id, whatever the configured format
+    pub fn new_snowflake(&self) -> u64 {
+        let millis = unix_millis().saturating_sub(SNOWFLAKE_EPOCH_MS);
+        let (millis, sequence) = Self::next_tick(&self.last_snowflake, millis);
+        (millis << (NODE_BITS + SEQUENCE_BITS)) | (self.node_id << SEQUENCE_BITS) | sequence
+    }
+}
+
+static GENERATOR: OnceLock<IdGenerator> = OnceLock::new();
+
+/// The generator configured with `[ids]`, which makes random UUIDs if the server has
+/// not started
+pub fn generator() -> &'static IdGenerator {
+    GENERATOR.get_or_init(|| IdGenerator::new(IdFormat::default(), 0))
+}
+
+/// A new id from the configured generator, as `new_id` gives scripts
+pub fn new_id() -> String {
+    generator().new_id()
+}
+
+pub(crate) fn init(config: &IdConfig) {
+    let _ = GENERATOR.set(IdGenerator::new(config.format, config.node_id));
+    let _ = hypermangle_py::ids::GENERATOR.set(new_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEQUENCES: u64 = 1 << SEQUENCE_BITS;
+
+    #[test]
+    fn ticks_count_up_within_a_millisecond() {
+        let last = AtomicU64::new(0);
+        assert_eq!(IdGenerator::next_tick(&last, 1000), (1000, 0));
+        assert_eq!(IdGenerator::next_tick(&last, 1000), (1000, 1));
+        assert_eq!(IdGenerator::next_tick(&last, 1001), (1001, 0));
+    }
+
+    #[test]
+    fn ticks_borrow_from_the_next_millisecond_once_the_sequence_runs_out() {
+        let last = AtomicU64::new(0);
+        for sequence in 0..SEQUENCES {
+            assert_eq!(IdGenerator::next_tick(&last, 1000), (1000, sequence));
+        }
+        assert_eq!(IdGenerator::next_tick(&last, 1000), (1001, 0));
+        // The millisecond that was borrowed from carries on where it left off
+        assert_eq!(IdGenerator::next_tick(&last, 1001), (1001, 1));
+    }
+
+    #[test]
+    fn ticks_keep_increasing_when_the_clock_goes_back() {
+        let last = AtomicU64::new(0);
+        assert_eq!(IdGenerator::next_tick(&last, 5000), (5000, 0));
+        assert_eq!(IdGenerator::next_tick(&last, 4000), (5000, 1));
+        assert_eq!(IdGenerator::next_tick(&last, 4001), (5000, 2));
+        assert_eq!(IdGenerator::next_tick(&last, 5001), (5001, 0));
+    }
+
+    #[test]
+    fn snowflakes_increase_and_carry_the_node_id() {
+        let generator = IdGenerator::new(IdFormat::Snowflake, 513);
+        let ids: Vec<u64> = (0..10_000).map(|_| generator.new_snowflake()).collect();
+        assert!(ids.windows(2).all(|x| x[0] < x[1]));
+        assert!(ids
+            .iter()
+            .all(|x| (x >> SEQUENCE_BITS) & u64::from(MAX_NODE_ID) == 513));
+    }
+
+    #[test]
+    fn uuids_have_their_version_and_variant() {
+        for (format, version) in [(IdFormat::Uuid4, '4'), (IdFormat::Uuid7, '7')] {
+            let id = IdGenerator::new(format, 0).new_id();
+            assert_eq!(id.len(), 36);
+            assert_eq!(id.chars().nth(14), Some(version));
+            assert!("89ab".contains(id.chars().nth(19).unwrap()));
+        }
+    }
+
+    #[test]
+    fn uuid7s_sort_in_the_order_they_were_made() {
+        let generator = IdGenerator::new(IdFormat::Uuid7, 0);
+        let ids: Vec<String> = (0..10_000).map(|_| generator.new_id()).collect();
+        assert!(ids.windows(2).all(|x| x[0] < x[1]));
+    }
+}
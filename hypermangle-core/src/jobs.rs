@@ -0,0 +1,351 @@
+use std::{
+    path::Path,
+    sync::{Arc, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::Mutex;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Deserialize;
+use tokio::sync::Notify;
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct JobsConfig {
+    /// SQLite database the queue is kept in, which is created if missing
+    database_path: String,
+    /// How many jobs can run at the same time
+    #[serde(default = "default_workers")]
+    #[cfg_attr(not(feature = "python"), allow(dead_code))]
+    workers: usize,
+    /// Jobs that fail this many times are kept as failed until retried with
+    /// `jobs retry`
+    #[serde(default = "default_max_attempts")]
+    max_attempts: u32,
+    /// The delay between attempts doubles from 1 second up to this
+    #[serde(default = "default_max_backoff_secs")]
+    max_backoff_secs: u64,
+}
+
+fn default_workers() -> usize {
+    4
+}
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+fn default_max_backoff_secs() -> u64 {
+    3600
+}
+
+pub(crate) struct Job {
+    pub(crate) id: i64,
+    /// `path/to/script.py:function`
+    pub(crate) function: String,
+    /// JSON list of positional arguments
+    #[cfg_attr(not(feature = "python"), allow(dead_code))]
+    args: String,
+    /// UNIX timestamp in milliseconds
+    pub(crate) run_at: u64,
+    pub(crate) attempts: u32,
+    /// `pending`, `running` or `failed`
+    pub(crate) state: String,
+    pub(crate) last_error: Option<String>,
+}
+
+impl Job {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            function: row.get("function")?,
+            args: row.get("args")?,
+            run_at: row.get("run_at")?,
+            attempts: row.get("attempts")?,
+            state: row.get("state")?,
+            last_error: row.get("last_error")?,
+        })
+    }
+}
+
+/// Calls to script functions persisted in SQLite, which are run by a pool of workers
+/// and retried with a backoff when they raise
+pub(crate) struct JobQueue {
+    connection: Mutex<Connection>,
+    config: JobsConfig,
+    /// Wakes a worker when a job is enqueued
+    enqueued: Notify,
+}
+
+static JOB_QUEUE: OnceLock<Arc<JobQueue>> = OnceLock::new();
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time should be after the UNIX epoch")
+        .as_millis() as u64
+}
+
+fn enqueue(function: &str, args: String, delay: Duration) -> Result<i64, String> {
+    JOB_QUEUE
+        .get()
+        .expect("Job queue should be initialized")
+        .enqueue(function, args, delay)
+}
+
+impl JobQueue {
+    /// Opens the queue at the configured path and makes it the one `enqueue` and
+    /// console commands use. Jobs that were running when the server stopped are run
+    /// again
+    pub(crate) fn init(config: &JobsConfig) -> Arc<Self> {
+        JOB_QUEUE
+            .get_or_init(|| {
+                let path = &config.database_path;
+                let connection = Connection::open(path)
+                    .unwrap_or_else(|e| panic!("Job queue {path:?} should be openable: {e}"));
+                connection
+                    .execute_batch(
+                        "CREATE TABLE IF NOT EXISTS jobs (
+                            id INTEGER PRIMARY KEY,
+                            function TEXT NOT NULL,
+                            args TEXT NOT NULL,
+                            run_at INTEGER NOT NULL,
+                            attempts INTEGER NOT NULL DEFAULT 0,
+                            state TEXT NOT NULL DEFAULT 'pending',
+                            last_error TEXT
+                        );
+                        CREATE INDEX IF NOT EXISTS jobs_due ON jobs (state, run_at);
+                        UPDATE jobs SET state = 'pending' WHERE state = 'running';",
+                    )
+                    .unwrap_or_else(|e| panic!("Job queue {path:?} should be usable: {e}"));
+                let _ = hypermangle_py::jobs::ENQUEUER.set(enqueue);
+
+                Arc::new(Self {
+                    connection: Mutex::new(connection),
+                    config: config.clone(),
+                    enqueued: Notify::new(),
+                })
+            })
+            .clone()
+    }
+
+    pub(crate) fn get() -> Option<Arc<Self>> {
+        JOB_QUEUE.get().cloned()
+    }
+
+    fn enqueue(&self, function: &str, args: String, delay: Duration) -> Result<i64, String> {
+        let (script, _) = function
+            .split_once(':')
+            .ok_or("Jobs should name a function as `path/to/script.py:function`")?;
+        if !Path::new(script).is_file() {
+            return Err(format!("There is no script at {script}"));
+        }
+
+        let connection = self.connection.lock();
+        connection
+            .execute(
+                "INSERT INTO jobs (function, args, run_at) VALUES (?1, ?2, ?3)",
+                params![function, args, unix_millis() + delay.as_millis() as u64],
+            )
+            .map_err(|e| e.to_string())?;
+        let id = connection.last_insert_rowid();
+        drop(connection);
+
+        self.enqueued.notify_one();
+        Ok(id)
+    }
+
+    /// Marks the next job that is due as running
+    #[cfg_attr(not(feature = "python"), allow(dead_code))]
+    fn claim(&self) -> rusqlite::Result<Option<Job>> {
+        self.connection
+            .lock()
+            .query_row(
+                "UPDATE jobs SET state = 'running' WHERE id = (
+                    SELECT id FROM jobs WHERE state = 'pending' AND run_at <= ?1
+                    ORDER BY run_at LIMIT 1
+                ) RETURNING *",
+                params![unix_millis()],
+                Job::from_row,
+            )
+            .optional()
+    }
+
+    /// How long until the next pending job is due, if there are any
+    #[cfg_attr(not(feature = "python"), allow(dead_code))]
+    fn next_due(&self) -> rusqlite::Result<Option<Duration>> {
+        let run_at: Option<u64> = self.connection.lock().query_row(
+            "SELECT MIN(run_at) FROM jobs WHERE state = 'pending'",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(run_at.map(|x| Duration::from_millis(x.saturating_sub(unix_millis()))))
+    }
+
+    #[cfg_attr(not(feature = "python"), allow(dead_code))]
+    fn complete(&self, id: i64) -> rusqlite::Result<()> {
+        self.connection
+            .lock()
+            .execute("DELETE FROM jobs WHERE id = ?1", params![id])
+            .map(drop)
+    }
+
+    /// Schedules the job to be retried after a backoff, returning the backoff, or
+    /// marks it as failed if it has run out of attempts
+    #[cfg_attr(not(feature = "python"), allow(dead_code))]
+    fn fail(&self, job: &Job, error: &str) -> rusqlite::Result<Option<Duration>> {
+        let attempts = job.attempts + 1;
+        let backoff = (attempts < self.config.max_attempts).then(|| {
+            let secs = 1u64
+                .checked_shl(attempts - 1)
+                .unwrap_or(u64::MAX)
+                .min(self.config.max_backoff_secs);
+            Duration::from_secs(secs)
+        });
+        let (state, run_at) = match backoff {
+            Some(backoff) => ("pending", unix_millis() + backoff.as_millis() as u64),
+            None => ("failed", job.run_at),
+        };
+        self.connection.lock().execute(
+            "UPDATE jobs SET state = ?1, run_at = ?2, attempts = ?3, last_error = ?4 WHERE id = ?5",
+            params![state, run_at, attempts, error, job.id],
+        )?;
+        Ok(backoff)
+    }
+
+    pub(crate) fn list(&self) -> rusqlite::Result<Vec<Job>> {
+        let connection = self.connection.lock();
+        let mut statement = connection.prepare("SELECT * FROM jobs ORDER BY run_at")?;
+        let jobs = statement.query_map([], Job::from_row)?.collect();
+        jobs
+    }
+
+    /// Runs a failed job again as soon as possible, with all of its attempts
+    pub(crate) fn retry(&self, id: i64) -> rusqlite::Result<bool> {
+        let changed = self.connection.lock().execute(
+            "UPDATE jobs SET state = 'pending', run_at = ?1, attempts = 0
+            WHERE id = ?2 AND state = 'failed'",
+            params![unix_millis(), id],
+        )?;
+        if changed > 0 {
+            self.enqueued.notify_one();
+        }
+        Ok(changed > 0)
+    }
+
+    /// Removes a job that is not running
+    pub(crate) fn cancel(&self, id: i64) -> rusqlite::Result<bool> {
+        self.connection
+            .lock()
+            .execute(
+                "DELETE FROM jobs WHERE id = ?1 AND state != 'running'",
+                params![id],
+            )
+            .map(|x| x > 0)
+    }
+}
+
+#[cfg(feature = "python")]
+type LoadedFunctions = Mutex<fxhash::FxHashMap<String, pyo3::PyObject>>;
+
+/// Calls the function of `job`, which is only imported the first time a job needs it
+#[cfg(feature = "python")]
+async fn run_job(job: &Job, functions: &LoadedFunctions) -> Result<(), String> {
+    use pyo3::{types::PyTuple, Python};
+
+    let function = functions.lock().get(&job.function).cloned();
+    let function = match function {
+        Some(x) => x,
+        None => {
+            let (script, name) = job
+                .function
+                .split_once(':')
+                .ok_or("Jobs should name a function as `path/to/script.py:function`")?;
+            let function = crate::py::load_py_function(script.as_ref(), name)?;
+            functions
+                .lock()
+                .insert(job.function.clone(), function.clone());
+            function
+        }
+    };
+
+    let future = Python::with_gil(|py| {
+        let args: Vec<&pyo3::PyAny> = py
+            .import("json")?
+            .call_method1("loads", (&job.args,))?
+            .extract()?;
+        let coroutine = function.call1(py, PyTuple::new(py, args))?;
+        pyo3_asyncio::into_future_with_locals(&crate::task_locals(), coroutine.as_ref(py))
+    })
+    .map_err(|e| e.to_string())?;
+    future.await.map(drop).map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "python")]
+async fn work(queue: Arc<JobQueue>, functions: Arc<LoadedFunctions>) {
+    use log::{error, warn};
+
+    loop {
+        let job = match queue.claim() {
+            Ok(Some(job)) => job,
+            Ok(None) => {
+                let wait = match queue.next_due() {
+                    Ok(Some(x)) => x,
+                    Ok(None) => Duration::from_secs(60),
+                    Err(e) => {
+                        error!("Failed to read the job queue: {e}");
+                        Duration::from_secs(1)
+                    }
+                };
+                let _ = tokio::time::timeout(wait, queue.enqueued.notified()).await;
+                continue;
+            }
+            Err(e) => {
+                error!("Failed to claim a job: {e}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let result = match run_job(&job, &functions).await {
+            Ok(()) => queue.complete(job.id),
+            Err(e) => match queue.fail(&job, &e) {
+                Ok(Some(backoff)) => {
+                    warn!(
+                        "Job {} ({}) failed, retrying in {backoff:?}: {e}",
+                        job.id, job.function
+                    );
+                    Ok(())
+                }
+                Ok(None) => {
+                    error!(
+                        "Job {} ({}) failed {} times, so it is given up on: {e}",
+                        job.id,
+                        job.function,
+                        job.attempts + 1
+                    );
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+        };
+        if let Err(e) = result {
+            error!("Failed to update job {}: {e}", job.id);
+        }
+    }
+}
+
+/// Starts the workers of `queue`. Must be called from within the tokio runtime
+#[cfg(feature = "python")]
+pub(crate) fn start(queue: Arc<JobQueue>) {
+    crate::py::register_embedded_module();
+    let functions = Arc::new(LoadedFunctions::default());
+
+    for _ in 0..queue.config.workers {
+        let queue = queue.clone();
+        let functions = functions.clone();
+        tokio::spawn(async move {
+            crate::py::wait_for_event_loop().await;
+            work(queue, functions).await
+        });
+    }
+}
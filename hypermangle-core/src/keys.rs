@@ -0,0 +1,183 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use fxhash::FxHashMap;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::acme::write_private;
+
+/// The key a request was authenticated with, which is available to handlers as a
+/// request extension
+#[derive(Clone, Debug)]
+pub struct AuthenticatedKey {
+    pub name: String,
+    pub scopes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct StoredKey {
+    pub(crate) name: String,
+    /// SHA-256 of the token, as tokens are only shown once when created
+    hash: String,
+    #[serde(default)]
+    pub(crate) scopes: Vec<String>,
+    pub(crate) created_at: u64,
+    /// UNIX timestamp in seconds
+    #[serde(default)]
+    pub(crate) expires_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct KeyFile {
+    #[serde(default)]
+    keys: Vec<StoredKey>,
+}
+
+/// API keys persisted in a TOML file, keyed by the hashes of their tokens
+pub(crate) struct KeyStore {
+    path: PathBuf,
+    keys: RwLock<FxHashMap<String, StoredKey>>,
+}
+
+static KEY_STORE: OnceLock<Arc<KeyStore>> = OnceLock::new();
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time should be after the UNIX epoch")
+        .as_secs()
+}
+
+/// Hex encoded SHA-256 of `token`
+pub(crate) fn hash_token(token: &str) -> String {
+    openssl::sha::sha256(token.as_bytes())
+        .iter()
+        .map(|x| format!("{x:02x}"))
+        .collect()
+}
+
+impl KeyStore {
+    /// Opens the key store at `path` and makes it the one console commands manage.
+    /// The file is created when the first key is
+    pub(crate) fn init(path: &Path) -> Arc<Self> {
+        KEY_STORE
+            .get_or_init(|| {
+                let file: KeyFile = match std::fs::read_to_string(path) {
+                    Ok(x) => toml::from_str(&x)
+                        .unwrap_or_else(|e| panic!("Key store {path:?} should be valid: {e}")),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => KeyFile::default(),
+                    Err(e) => panic!("Key store {path:?} should be readable: {e}"),
+                };
+                Arc::new(Self {
+                    path: path.to_owned(),
+                    keys: RwLock::new(file.keys.into_iter().map(|x| (x.hash.clone(), x)).collect()),
+                })
+            })
+            .clone()
+    }
+
+    pub(crate) fn get() -> Option<Arc<Self>> {
+        KEY_STORE.get().cloned()
+    }
+
+    fn save(&self, keys: &FxHashMap<String, StoredKey>) -> std::io::Result<()> {
+        let mut file = KeyFile {
+            keys: keys.values().cloned().collect(),
+        };
+        file.keys.sort_by_key(|x| x.created_at);
+        let contents = toml::to_string(&file).expect("Key store should be serializable");
+        write_private(&self.path, contents.as_bytes())
+    }
+
+    /// Returns the token of the new key, which cannot be recovered afterwards
+    pub(crate) fn create(
+        &self,
+        name: String,
+        scopes: Vec<String>,
+        expires_in: Option<Duration>,
+    ) -> Result<String, String> {
+        let mut keys = self.keys.write();
+        if keys.values().any(|x| x.name == name) {
+            return Err(format!("A key named {name} already exists"));
+        }
+
+        let mut bytes = [0u8; 24];
+        openssl::rand::rand_bytes(&mut bytes).expect("Random bytes should be available");
+        let token =
+            String::from("hm_") + &bytes.iter().map(|x| format!("{x:02x}")).collect::<String>();
+
+        let now = unix_now();
+        let hash = hash_token(&token);
+        // Swapped in once saved, so that a key that failed to save does not authenticate
+        let mut updated = keys.clone();
+        updated.insert(
+            hash.clone(),
+            StoredKey {
+                name,
+                hash,
+                scopes,
+                created_at: now,
+                expires_at: expires_in.map(|x| now + x.as_secs()),
+            },
+        );
+        self.save(&updated).map_err(|e| e.to_string())?;
+        *keys = updated;
+        Ok(token)
+    }
+
+    /// Returns false if there was no key named `name`
+    pub(crate) fn revoke(&self, name: &str) -> std::io::Result<bool> {
+        let mut keys = self.keys.write();
+        let mut updated = keys.clone();
+        updated.retain(|_, x| x.name != name);
+        if updated.len() == keys.len() {
+            return Ok(false);
+        }
+        self.save(&updated)?;
+        *keys = updated;
+        Ok(true)
+    }
+
+    pub(crate) fn list(&self) -> Vec<StoredKey> {
+        let mut keys: Vec<_> = self.keys.read().values().cloned().collect();
+        keys.sort_by_key(|x| x.created_at);
+        keys
+    }
+
+    pub(crate) fn authenticate(&self, token: &str) -> Option<AuthenticatedKey> {
+        let keys = self.keys.read();
+        let key = keys.get(&hash_token(token))?;
+        if key.is_expired() {
+            return None;
+        }
+        Some(AuthenticatedKey {
+            name: key.name.clone(),
+            scopes: key.scopes.clone(),
+        })
+    }
+}
+
+impl StoredKey {
+    pub(crate) fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|x| x <= unix_now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_that_fail_to_save_are_not_kept() {
+        let store = KeyStore {
+            path: "/nonexistent/keys.toml".into(),
+            keys: RwLock::default(),
+        };
+        assert!(store.create("ci".into(), vec![], None).is_err());
+        assert!(store.list().is_empty());
+    }
+}
@@ -1,25 +1,21 @@
-#![feature(path_file_prefix)]
-#![feature(result_flattening)]
-#![feature(never_type)]
-#![feature(os_str_bytes)]
-#![feature(return_position_impl_trait_in_trait)]
-
 use std::{
-    error::Error,
-    fs::{read_to_string, write, File},
-    io::BufReader,
+    fs::read_to_string,
+    future::Future,
     net::SocketAddr,
-    path::Path,
+    path::{Path, PathBuf},
     process::Stdio,
-    time::SystemTime,
+    sync::{Arc, OnceLock},
+    time::{Duration, SystemTime},
 };
 
-use axum::Router;
+use axum::{
+    http::{HeaderValue, Method, Uri},
+    Router,
+};
 use bearer::BearerAuth;
 use clap::{Parser, Subcommand};
 use console::{listen_for_commands, send_args_to_remote, ExecutableArgs};
-use hyper::server::{accept::Accept, Builder};
-use lers::solver::Http01Solver;
+use hyper::server::Builder;
 use log::{info, warn};
 #[cfg(feature = "python")]
 use py::load_py_into_router;
@@ -27,37 +23,199 @@ use py::load_py_into_router;
 use pyo3_asyncio::TaskLocals;
 use regex::RegexSet;
 use serde::Deserialize;
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_rustls::rustls::{Certificate, PrivateKey};
+use toml::Value;
 use tower::ServiceBuilder;
 use tower_http::{
-    auth::AsyncRequireAuthorizationLayer, compression::CompressionLayer, cors::CorsLayer,
+    auth::AsyncRequireAuthorizationLayer,
+    cors::CorsLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
     trace::TraceLayer,
 };
 
-use crate::{console::does_remote_exist, tls::TlsAcceptor};
+use crate::{
+    console::does_remote_exist,
+    keys::KeyStore,
+    tls::{CertResolver, TlsAcceptor, TlsOptions},
+};
 
+mod acme;
+mod admin;
+pub mod audit;
+mod banner;
 mod bearer;
+mod build_presets;
+mod builtins;
+mod canary;
+mod circuit;
+mod client_address;
+mod cluster;
+mod compression;
+mod config_error;
 pub mod console;
+mod consumers;
+#[cfg(feature = "hot-reload")]
+mod dev;
+mod env_overrides;
+mod error_tracking;
+mod filters;
+#[cfg(fuzzing)]
+pub mod fuzz;
+mod geoip;
+mod headers;
+mod heartbeat;
+mod host_checks;
+pub mod ids;
+mod jobs;
+pub mod keys;
+pub mod listener;
+mod log_files;
+pub mod metrics;
+mod package;
+mod panics;
+mod proxy;
 #[cfg(feature = "python")]
 mod py;
+pub mod rate_limit;
+pub mod routes;
+mod runtime;
+mod sandbox;
+mod shadow;
+mod shared_router;
+mod signed_urls;
+mod spans;
+mod static_files;
+mod stream_proxy;
+mod tenants;
 mod tls;
+pub mod transform;
+#[cfg_attr(not(feature = "python"), allow(dead_code))]
+mod uploads;
+pub mod vhost;
+mod webhooks;
+
+const CONFIG_PATH: &str = "hypermangle.toml";
 
-#[cfg(all(feature = "hot-reload", feature = "python"))]
+#[cfg(feature = "hot-reload")]
 const SYNC_CHANGES_DELAY: std::time::Duration = std::time::Duration::from_millis(1000);
 
+/// Set while the Python runtime is running
 #[cfg(feature = "python")]
-static PY_TASK_LOCALS: std::sync::OnceLock<TaskLocals> = std::sync::OnceLock::new();
+static PY_TASK_LOCALS: parking_lot::RwLock<Option<TaskLocals>> = parking_lot::RwLock::new(None);
+
+pub use config_error::ConfigError;
+pub use listener::{AcceptListener, Connection};
+#[cfg(feature = "python")]
+pub use py::PythonRuntime;
+pub use tls::TlsInfo;
+
+/// The event loop of the Python runtime
+#[cfg(feature = "python")]
+fn task_locals() -> TaskLocals {
+    PY_TASK_LOCALS
+        .read()
+        .clone()
+        .expect("Python runtime should be running")
+}
+
+/// Options controlling how scripts are loaded into a router
+#[derive(Clone, Copy, Default)]
+pub struct ScriptOptions {
+    /// Register routes by scanning the scripts, and only import each script when
+    /// one of its routes is first requested
+    pub lazy: bool,
+    /// How long a handler may spend running on the event loop before it is aborted
+    /// with 503
+    pub cpu_budget: Option<Duration>,
+    /// How long a handler has to respond before it is cancelled with 504
+    pub timeout: Option<Duration>,
+}
+
+/// What becomes of one of the layers hypermangle wraps every router in
+#[derive(Clone, Default)]
+pub enum BuiltinLayer {
+    /// The layer as configured in hypermangle.toml
+    #[default]
+    Default,
+    /// Left out, such as when a proxy in front of the server already compresses
+    Disabled,
+    /// Applied where the default layer would have been
+    Custom(Arc<dyn Fn(Router) -> Router + Send + Sync>),
+}
+
+impl BuiltinLayer {
+    pub fn custom(layer: impl Fn(Router) -> Router + Send + Sync + 'static) -> Self {
+        Self::Custom(Arc::new(layer))
+    }
+
+    fn apply(&self, router: Router, default: impl FnOnce(Router) -> Router) -> Router {
+        match self {
+            Self::Default => default(router),
+            Self::Disabled => router,
+            Self::Custom(layer) => layer(router),
+        }
+    }
+}
+
+/// Options for embedding applications that replace the layers hypermangle would
+/// otherwise always apply
+#[derive(Clone, Default)]
+pub struct LayerOptions {
+    pub compression: BuiltinLayer,
+    /// Logs requests, and the spans that requests are logged in
+    pub trace: BuiltinLayer,
+    pub cors: BuiltinLayer,
+    /// Transform the bodies of the routes they cover before scripts see them
+    pub body_transforms: Vec<transform::BodyTransform>,
+}
 
 pub fn load_scripts_into_router(router: Router, path: &Path) -> Router {
+    load_scripts_into_router_with_options(router, path, ScriptOptions::default())
+}
+
+pub fn load_scripts_into_router_with_options(
+    router: Router,
+    path: &Path,
+    options: ScriptOptions,
+) -> Router {
+    load_scripts_into_router_at(router, path, "", options)
+}
+
+/// Loads the scripts in `path` as if the folder was routed at `prefix`, such as
+/// `/api/v1`, instead of the root
+pub fn load_scripts_into_router_at(
+    router: Router,
+    path: &Path,
+    prefix: &str,
+    options: ScriptOptions,
+) -> Router {
+    #[cfg(feature = "python")]
+    py::check_scripts(path, prefix.trim_end_matches('/'));
+    load_checked_scripts(router, path, prefix, options)
+}
+
+/// Like `load_scripts_into_router_at`, for scripts whose routes were already checked
+fn load_checked_scripts(
+    router: Router,
+    path: &Path,
+    prefix: &str,
+    options: ScriptOptions,
+) -> Router {
+    let prefix = prefix.trim_end_matches('/');
+    assert!(
+        prefix.is_empty() || prefix.starts_with('/'),
+        "Route prefix of {path:?} should start with /, not {prefix:?}"
+    );
+
     #[cfg(feature = "python")]
     {
-        let mut router = router;
         #[cfg(feature = "hot-reload")]
         {
             use notify::Watcher;
             let async_runtime = tokio::runtime::Handle::current();
-            let working_dir = path.canonicalize().unwrap().parent().unwrap().to_owned();
+            // Scripts are registered by their path relative to the working directory
+            let working_dir = std::env::current_dir()
+                .and_then(|x| x.canonicalize())
+                .expect("Working directory should be accessible");
             let mut watcher =
                 notify::recommended_watcher(move |res: Result<notify::Event, _>| match res {
                     Ok(event) => {
@@ -76,45 +234,67 @@ pub fn load_scripts_into_router(router: Router, path: &Path) -> Router {
             Box::leak(Box::new(watcher));
         }
 
-        for result in path
-            .read_dir()
-            .expect("Scripts directory should be readable")
-        {
-            let entry = result.expect("Script or sub-directory should be readable");
-            let path = entry.path();
-            let file_type = entry
-                .file_type()
-                .expect("File type of script or sub-directory should be accessible");
-
-            if file_type.is_dir() {
-                router = load_scripts_into_router(router, &path);
-            } else if file_type.is_file() {
-                match path.extension().map(std::ffi::OsStr::to_str).flatten() {
-                    #[cfg(feature = "python")]
-                    Some("py") => router = load_py_into_router(router, &path),
-                    _ => {}
-                }
-            } else {
-                panic!("Failed to get the file type of {entry:?}");
-            }
+        py::register_embedded_module();
+        if !options.lazy {
+            py::precompile_scripts(path);
         }
 
-        router
+        load_scripts_dir(router, path, prefix, path, options)
     }
 
     #[cfg(not(feature = "python"))]
     {
         let _path = path;
+        let _prefix = prefix;
+        let _options = options;
         router
     }
 }
 
-pub fn setup_logger(log_file_path: &str, log_level: &str) {
-    let log_level = if log_level.is_empty() {
+#[cfg(feature = "python")]
+fn load_scripts_dir(
+    mut router: Router,
+    root: &Path,
+    prefix: &str,
+    path: &Path,
+    options: ScriptOptions,
+) -> Router {
+    for result in path
+        .read_dir()
+        .expect("Scripts directory should be readable")
+    {
+        let entry = result.expect("Script or sub-directory should be readable");
+        let path = entry.path();
+        let file_type = entry
+            .file_type()
+            .expect("File type of script or sub-directory should be accessible");
+
+        if file_type.is_dir() {
+            router = load_scripts_dir(router, root, prefix, &path, options);
+        } else if file_type.is_file() {
+            match path.extension().and_then(std::ffi::OsStr::to_str) {
+                #[cfg(feature = "python")]
+                Some("py") => router = load_py_into_router(router, root, prefix, &path, options),
+                _ => {}
+            }
+        } else {
+            panic!("Failed to get the file type of {entry:?}");
+        }
+    }
+
+    router
+}
+
+fn parse_log_level(log_level: &str) -> log::LevelFilter {
+    if log_level.is_empty() {
         log::LevelFilter::Info
     } else {
         log_level.parse().expect("Log Level should be valid")
-    };
+    }
+}
+
+pub fn setup_logger(log_file_path: &str, log_level: &str) {
+    let log_level = parse_log_level(log_level);
 
     let mut dispatch = fern::Dispatch::new()
         .format(|out, message, record| {
@@ -130,8 +310,9 @@ pub fn setup_logger(log_file_path: &str, log_level: &str) {
         .chain(std::io::stdout());
 
     if !log_file_path.is_empty() {
-        dispatch =
-            dispatch.chain(fern::log_file(log_file_path).expect("Log File should be writable"))
+        let log_file =
+            log_files::LogFile::open(log_file_path.as_ref()).expect("Log File should be writable");
+        dispatch = dispatch.chain(Box::new(log_file) as Box<dyn std::io::Write + Send>)
     }
 
     dispatch
@@ -139,94 +320,1003 @@ pub fn setup_logger(log_file_path: &str, log_level: &str) {
         .expect("Logger should have initialized successfully");
 }
 
-#[cfg(feature = "python")]
-#[inline]
-fn u16_to_status(code: u16, f: impl Fn() -> String) -> axum::http::StatusCode {
-    axum::http::StatusCode::from_u16(code).expect(&f())
+/// Who installs the global logger that hypermangle logs to
+#[derive(Default)]
+pub enum Logging {
+    /// hypermangle logs to stdout and `log_file_path` with `setup_logger`
+    #[default]
+    Default,
+    /// The application installed its own logger, such as env_logger or a bridge to
+    /// tracing-subscriber
+    External,
+    /// hypermangle installs this logger, filtered to `log_level`
+    Custom(Box<dyn log::Log>),
 }
 
-#[derive(Deserialize)]
+impl Logging {
+    fn install(self, config: &HyperDomeConfig) {
+        match self {
+            Self::Default => setup_logger(&config.log_file_path, &config.log_level),
+            Self::External => {}
+            Self::Custom(logger) => {
+                log::set_boxed_logger(logger)
+                    .expect("Custom logger should be the only logger installed");
+                log::set_max_level(parse_log_level(&config.log_level));
+            }
+        }
+    }
+}
+
+/// Options for applications that embed hypermangle
+#[derive(Default)]
+pub struct EmbedOptions {
+    pub layers: LayerOptions,
+    pub logging: Logging,
+}
+
+/// A scripts folder whose routes start with `prefix`
+#[derive(Deserialize, Clone)]
+struct Mount {
+    dir: String,
+    #[serde(default)]
+    prefix: String,
+}
+
+/// Another address the routes are served on, alongside `bind_address`, such as for
+/// plain HTTP on an internal interface while the public one serves HTTPS
+#[derive(Deserialize, Clone)]
+struct Listener {
+    address: SocketAddr,
+    /// Serves HTTPS with this certificate, along with `key_path`, instead of plain
+    /// HTTP. The key is decrypted and the other TLS settings are applied as for the
+    /// `cert_path` of the server
+    #[serde(default)]
+    cert_path: String,
+    #[serde(default)]
+    key_path: String,
+}
+
+impl Listener {
+    fn is_tls(&self) -> bool {
+        !self.cert_path.is_empty()
+    }
+}
+
+#[derive(Deserialize, Clone)]
 pub struct HyperDomeConfig {
     #[serde(default)]
     cors_methods: Vec<String>,
     #[serde(default)]
     cors_origins: Vec<String>,
+    /// Host names requests may be for, where `*.example.com` matches any single
+    /// subdomain. Others are rejected with 421, or 400 without a host. Any host is
+    /// accepted if empty
+    #[serde(default)]
+    accepted_hosts: Vec<String>,
+    /// Origins that may open websockets, such as `https://example.com`, along with
+    /// `self` for pages on the host the websocket is opened on. Clients that send no
+    /// origin are let through. Any origin is accepted if empty
+    #[serde(default)]
+    websocket_origins: Vec<String>,
     #[serde(default)]
     api_token: String,
+    /// Where API keys managed with the `keys` console commands are stored
+    #[serde(default)]
+    keys_path: String,
     bind_address: SocketAddr,
     #[serde(default)]
+    listeners: Vec<Listener>,
+    #[serde(default)]
     public_paths: Vec<String>,
     #[serde(default)]
     cert_path: String,
     #[serde(default)]
     key_path: String,
     #[serde(default)]
+    key_passphrase_env: String,
+    #[serde(default)]
+    key_passphrase_command: String,
+    /// Read by `validate`, so that `key_passphrase_command` runs once
+    #[serde(skip)]
+    key_passphrase: OnceLock<Option<Vec<u8>>>,
+    #[serde(default)]
     email: String,
     #[serde(default)]
     domain_name: String,
     #[serde(default)]
+    domain_names: Vec<String>,
+    #[serde(default)]
+    acme_separate_certificates: bool,
+    #[serde(default)]
+    acme_directory_url: String,
+    #[serde(default)]
+    acme_ca_path: String,
+    #[serde(default)]
+    acme_eab_key_id: String,
+    #[serde(default)]
+    acme_eab_hmac_key: String,
+    #[serde(default)]
+    acme_key_type: acme::AcmeKeyType,
+    #[serde(default)]
+    acme_dns: Option<acme::DnsProvider>,
+    #[serde(default)]
+    acme_tls_alpn: bool,
+    /// PEM certificates of the CAs that client certificates are verified against,
+    /// which makes the server ask clients for certificates
+    #[serde(default)]
+    tls_client_ca_path: String,
+    /// Rejects clients without a certificate, instead of only verifying those that
+    /// send one
+    #[serde(default)]
+    tls_client_auth_required: bool,
+    /// Obtains certificates for the SNI names clients ask for that have none
+    #[serde(default)]
+    on_demand_tls: Option<acme::OnDemandTlsConfig>,
+    #[serde(default)]
+    http_bind_address: Option<SocketAddr>,
+    #[serde(default)]
+    http_paths: Vec<String>,
+    #[serde(default)]
+    mounts: Vec<Mount>,
+    /// Paths forwarded to other HTTP servers
+    #[serde(default)]
+    proxy: Vec<proxy::ProxyConfig>,
+    /// Routes whose traffic is split between their scripts and new versions of them
+    #[serde(default)]
+    canary: Vec<canary::CanaryConfig>,
+    /// Routes whose requests are also sent to a rewrite of them, logging where its
+    /// responses differ
+    #[serde(default)]
+    shadow: Vec<shadow::ShadowConfig>,
+    /// Folders whose files are served as they are
+    #[serde(default)]
+    static_files: Vec<static_files::StaticMount>,
+    /// Raw TCP and UDP ports forwarded to other servers
+    #[serde(default)]
+    stream_proxy: Vec<stream_proxy::StreamProxyConfig>,
+    #[serde(default)]
+    remote_console: Option<console::remote::RemoteConsoleConfig>,
+    /// Shares published messages, rate limits and reloads with other instances
+    #[serde(default)]
+    cluster: Option<cluster::ClusterConfig>,
+    /// An embedded dashboard for operators
+    #[serde(default)]
+    admin: Option<admin::AdminConfig>,
+    #[serde(default)]
+    robots: Option<builtins::RobotsConfig>,
+    #[serde(default)]
+    security_txt: Option<builtins::SecurityTxtConfig>,
+    /// Served as `/favicon.ico`
+    #[serde(default)]
+    favicon_path: String,
+    #[serde(default)]
+    sitemap: Option<builtins::SitemapConfig>,
+    #[serde(default)]
+    vhost: Vec<vhost::VirtualHost>,
+    #[serde(default)]
+    response_headers: Vec<headers::HeaderRule>,
+    #[serde(default)]
+    security_headers: headers::SecurityHeaders,
+    #[serde(default)]
+    security_header_overrides: fxhash::FxHashMap<String, String>,
+    /// Sent unless the handler sent its own. `{nonce}` is replaced by a nonce made for
+    /// each request, which templates get as `csp_nonce`
+    #[serde(default)]
+    content_security_policy: String,
+    #[serde(default)]
+    early_hints: Vec<headers::EarlyHints>,
+    #[serde(default)]
     log_file_path: String,
     #[serde(default)]
     log_level: String,
+    /// Console commands, authentication failures and API key usage are recorded here
+    #[serde(default)]
+    audit_log_path: String,
+    #[serde(default = "default_audit_log_max_bytes")]
+    audit_log_max_bytes: u64,
+    /// How many rotated audit logs to keep
+    #[serde(default = "default_audit_log_keep")]
+    audit_log_keep: usize,
+    #[serde(default)]
+    lazy_scripts: bool,
+    /// How many milliseconds a GET or POST handler may spend running on the event
+    /// loop before it is aborted with 503. 0 disables this
+    #[serde(default)]
+    handler_cpu_budget_ms: u64,
+    /// How many milliseconds a GET or POST handler has to respond before it is
+    /// cancelled with 504. Handlers see the deadline in `deadline_var`. 0 disables this
+    #[serde(default)]
+    handler_timeout_ms: u64,
+    #[serde(default = "default_max_pending_handshakes")]
+    max_pending_handshakes: usize,
+    #[serde(default = "default_tls_handshake_timeout_ms")]
+    tls_handshake_timeout_ms: u64,
+    #[serde(default = "default_tls_session_cache_size")]
+    tls_session_cache_size: usize,
+    #[serde(default = "default_true")]
+    tls_session_tickets: bool,
+    #[serde(default = "default_tls_ticket_rotation_secs")]
+    tls_ticket_rotation_secs: u64,
+    #[serde(default)]
+    metrics_path: String,
+    /// Requests taking longer than this are logged as warnings. 0 disables this
+    #[serde(default)]
+    slow_request_ms: u64,
+    /// Proxies in front of the server, as addresses or ranges such as `10.0.0.0/8`.
+    /// The `X-Forwarded-For` headers of their requests are trusted to say which
+    /// client they came from, which is otherwise the address of the connection
+    #[serde(default)]
+    trusted_proxies: Vec<String>,
+    #[serde(default)]
+    rate_limit: Option<rate_limit::RateLimitConfig>,
+    /// Where clients are, and which countries are served
+    #[serde(default)]
+    geoip: Option<geoip::GeoIpConfig>,
+    /// Rules that deny, hold or tag requests by their user agent and headers
+    #[serde(default)]
+    filters: Option<filters::FiltersConfig>,
+    /// Resident memory above which a warning is logged. 0 disables this
+    #[serde(default)]
+    memory_soft_limit_mb: u64,
+    /// Resident memory above which requests are rejected with 503 until usage
+    /// falls again. 0 disables this
+    #[serde(default)]
+    memory_hard_limit_mb: u64,
+    #[serde(default)]
+    #[cfg_attr(not(feature = "python"), allow(dead_code))]
+    sandbox: Option<sandbox::SandboxConfig>,
+    /// Where and what `upload_handler` scripts can be sent
+    #[serde(default)]
+    uploads: uploads::UploadConfig,
+    /// Key used by `sign_url` in scripts. Signing is disabled if empty
+    #[serde(default)]
+    url_signing_key: String,
+    /// Requests under this path need a URL signed by `sign_url`, instead of a token
+    #[serde(default)]
+    signed_url_prefix: String,
+    /// A URL pinged while the server runs, so that a monitoring service alerts once
+    /// the pings stop
+    #[serde(default)]
+    heartbeat: Option<heartbeat::HeartbeatConfig>,
+    /// Where panics and the exceptions raised by handlers are sent
+    #[serde(default)]
+    error_tracking: Option<error_tracking::ErrorTrackingConfig>,
+    /// Where events from `emit` in scripts are delivered
+    #[serde(default)]
+    webhooks: Option<webhooks::WebhookConfig>,
+    /// How `new_id` in scripts and `ids::new_id` make ids
+    #[serde(default)]
+    ids: ids::IdConfig,
+    /// Where jobs from `enqueue` in scripts are kept and how they are run
+    #[serde(default)]
+    jobs: Option<jobs::JobsConfig>,
+    /// Queues whose messages are handled by scripts
+    #[serde(default)]
+    #[cfg_attr(not(feature = "python"), allow(dead_code))]
+    consumers: Vec<consumers::ConsumerConfig>,
+    /// Threads of the runtimes that `auto_main` starts
+    #[serde(default)]
+    runtime: runtime::RuntimeConfig,
+    /// Settings for the scripts in top-level subdirectories of the scripts folders,
+    /// keyed by the name of the subdirectory
+    #[serde(default)]
+    tenants: fxhash::FxHashMap<String, tenants::TenantConfig>,
+}
+
+fn default_audit_log_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_audit_log_keep() -> usize {
+    5
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_tls_session_cache_size() -> usize {
+    256
+}
+
+fn default_tls_ticket_rotation_secs() -> u64 {
+    6 * 60 * 60
+}
+
+fn default_max_pending_handshakes() -> usize {
+    256
+}
+
+fn default_tls_handshake_timeout_ms() -> u64 {
+    10_000
 }
 
 impl HyperDomeConfig {
+    /// Where the plain HTTP listener runs alongside TLS, if it is needed at all. It is
+    /// needed for HTTP-01 challenges, or when it should serve some paths itself
+    fn plain_http_address(&self) -> Option<SocketAddr> {
+        let uses_http01 = self.acme_dns.is_none() && !self.acme_tls_alpn;
+        if !uses_http01 && self.http_bind_address.is_none() && self.http_paths.is_empty() {
+            return None;
+        }
+        Some(self.http_bind_address.unwrap_or_else(|| {
+            let mut address = self.bind_address;
+            address.set_port(80);
+            address
+        }))
+    }
+
+    /// The passphrase protecting private keys
+    fn key_passphrase(&self) -> Option<Vec<u8>> {
+        self.key_passphrase
+            .get_or_init(|| {
+                self.read_key_passphrase()
+                    .expect("Key passphrase should have been validated")
+            })
+            .clone()
+    }
+
+    /// Reads the passphrase from `key_passphrase_env` or the output of
+    /// `key_passphrase_command`
+    fn read_key_passphrase(&self) -> Result<Option<Vec<u8>>, ConfigError> {
+        if !self.key_passphrase_env.is_empty() {
+            let passphrase = std::env::var(&self.key_passphrase_env)
+                .map_err(|_| ConfigError::KeyPassphraseEnv(self.key_passphrase_env.clone()))?;
+            return Ok(Some(passphrase.into_bytes()));
+        }
+        if !self.key_passphrase_command.is_empty() {
+            #[cfg(unix)]
+            let mut command = std::process::Command::new("sh");
+            #[cfg(unix)]
+            command.arg("-c");
+            #[cfg(windows)]
+            let mut command = std::process::Command::new("cmd");
+            #[cfg(windows)]
+            command.arg("/C");
+
+            let output = command
+                .arg(&self.key_passphrase_command)
+                .stderr(Stdio::inherit())
+                .output()
+                .map_err(ConfigError::KeyPassphraseCommand)?;
+            if !output.status.success() {
+                return Err(ConfigError::KeyPassphraseStatus(output.status));
+            }
+            let mut passphrase = output.stdout;
+            while passphrase
+                .last()
+                .is_some_and(|x| *x == b'\n' || *x == b'\r')
+            {
+                passphrase.pop();
+            }
+            return Ok(Some(passphrase));
+        }
+        Ok(None)
+    }
+
+    /// The script folders served for every host, which is just `scripts` unless
+    /// `mounts` is set
+    fn mounts(&self) -> Vec<Mount> {
+        if self.mounts.is_empty() {
+            vec![Mount {
+                dir: "scripts".into(),
+                prefix: String::new(),
+            }]
+        } else {
+            self.mounts.clone()
+        }
+    }
+
+    /// Every folder scripts are loaded from, including those of virtual hosts
+    fn scripts_dirs(&self) -> Vec<String> {
+        let mut dirs: Vec<String> = self.mounts().into_iter().map(|x| x.dir).collect();
+        for vhost in &self.vhost {
+            if !dirs.contains(&vhost.scripts_dir) {
+                dirs.push(vhost.scripts_dir.clone());
+            }
+        }
+        dirs
+    }
+
+    fn tls_options(&self) -> TlsOptions {
+        TlsOptions {
+            max_pending_handshakes: self.max_pending_handshakes,
+            handshake_timeout: Duration::from_millis(self.tls_handshake_timeout_ms),
+            session_cache_size: self.tls_session_cache_size,
+            session_tickets: self.tls_session_tickets,
+            ticket_rotation: Duration::from_secs(self.tls_ticket_rotation_secs),
+            acme_tls_alpn: self.acme_tls_alpn,
+            client_ca_path: (!self.tls_client_ca_path.is_empty())
+                .then(|| self.tls_client_ca_path.clone().into()),
+            client_auth_required: self.tls_client_auth_required,
+        }
+    }
+
     pub fn from_toml_file(path: &Path) -> Self {
-        let txt = read_to_string(path).expect(&format!("{path:?} should be readable"));
-        toml::from_str(&txt).expect(&format!("{path:?} should be valid toml"))
+        Self::try_from_toml_file(path).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Reads the config at `path` and checks it with `validate`
+    pub fn try_from_toml_file(path: &Path) -> Result<Self, ConfigError> {
+        let (_, config) = Self::read_toml_file(path)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_env_and_file(path: &Path) -> Self {
+        Self::try_from_env_and_file(path).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Reads the config at `path` like `try_from_toml_file`, with its fields
+    /// overridden by `HYPERMANGLE_` environment variables. Variables take precedence
+    /// over the file, which takes precedence over the defaults.
+    ///
+    /// `HYPERMANGLE_BIND_ADDRESS` sets `bind_address`, and a double underscore reaches
+    /// into a table, as `HYPERMANGLE_IDS__NODE_ID` sets `node_id` in `[ids]`. Values
+    /// are read as TOML, such as `8080`, `true` or `["a", "b"]`, and as strings
+    /// otherwise, or when the field they set is a string
+    pub fn try_from_env_and_file(path: &Path) -> Result<Self, ConfigError> {
+        let (mut table, config) = Self::read_toml_file(path)?;
+        let fits = |table: &toml::Table| Value::Table(table.clone()).try_into::<Self>().is_ok();
+        let vars = env_overrides::apply(&mut table, env_overrides::vars(), fits);
+        if vars.is_empty() {
+            config.validate()?;
+            return Ok(config);
+        }
+        let config: Self = Value::Table(table)
+            .try_into()
+            .map_err(|error| ConfigError::InvalidEnv { vars, error })?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The table in the file at `path`, along with the config it holds, so that the
+    /// errors of the file are reported before any overrides are applied
+    fn read_toml_file(path: &Path) -> Result<(toml::Table, Self), ConfigError> {
+        let invalid = |error| ConfigError::InvalidToml {
+            path: path.to_owned(),
+            error,
+        };
+        let txt = read_to_string(path).map_err(|error| ConfigError::Unreadable {
+            path: path.to_owned(),
+            error,
+        })?;
+        // Parsed from the text, as errors only point at their lines from there
+        let config = toml::from_str(&txt).map_err(invalid)?;
+        let table = toml::from_str(&txt).map_err(invalid)?;
+        Ok((table, config))
+    }
+
+    /// Checks the values that serde cannot, which would otherwise panic once the
+    /// server starts
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !self.log_level.is_empty() && self.log_level.parse::<log::LevelFilter>().is_err() {
+            return Err(ConfigError::LogLevel(self.log_level.clone()));
+        }
+        for origin in &self.cors_origins {
+            let reason = if origin == "*" {
+                Some("wildcards are not supported, so every origin should be listed")
+            } else if HeaderValue::try_from(origin.as_str()).is_err() {
+                Some("it has characters that cannot be sent in a header")
+            } else {
+                match origin.parse::<Uri>() {
+                    Ok(uri) if uri.scheme().is_some() && uri.authority().is_some() => uri
+                        .path_and_query()
+                        .filter(|x| x.as_str() != "/" || origin.ends_with('/'))
+                        .map(|_| "it should not have a path, or a trailing slash"),
+                    _ => Some("it should be a scheme and host, such as https://example.com"),
+                }
+            };
+            if let Some(reason) = reason {
+                return Err(ConfigError::CorsOrigin {
+                    origin: origin.clone(),
+                    reason,
+                });
+            }
+        }
+        for method in &self.cors_methods {
+            if method.parse::<Method>().is_err() {
+                return Err(ConfigError::CorsMethod(method.clone()));
+            }
+        }
+        RegexSet::new(&self.public_paths).map_err(ConfigError::PublicPaths)?;
+        RegexSet::new(&self.http_paths).map_err(ConfigError::HttpPaths)?;
+        for listener in &self.listeners {
+            if listener.cert_path.is_empty() != listener.key_path.is_empty() {
+                return Err(ConfigError::ListenerTls(listener.address));
+            }
+        }
+        if self.ids.node_id > ids::MAX_NODE_ID {
+            return Err(ConfigError::NodeId(self.ids.node_id));
+        }
+        if HeaderValue::try_from(self.api_token.as_str()).is_err() {
+            return Err(ConfigError::ApiToken);
+        }
+        for proxy in &self.trusted_proxies {
+            if client_address::parse_proxy(proxy).is_none() {
+                return Err(ConfigError::TrustedProxy(proxy.clone()));
+            }
+        }
+        tenants::validate(&self.mounts(), &self.tenants)?;
+        if let Some(admin) = &self.admin {
+            admin.validate()?;
+        }
+        if !self.signed_url_prefix.is_empty() && self.url_signing_key.is_empty() {
+            return Err(ConfigError::UrlSigningKey);
+        }
+        if let Some(on_demand) = &self.on_demand_tls {
+            on_demand.validate()?;
+        }
+        if let Some(geoip) = &self.geoip {
+            geoip.validate()?;
+        }
+        let has_certificate = !self.cert_path.is_empty() && !self.key_path.is_empty();
+        for proxy in &self.stream_proxy {
+            proxy.validate(has_certificate)?;
+        }
+        if let Some(remote_console) = &self.remote_console {
+            remote_console.validate(has_certificate)?;
+        }
+        let rules = self.vhost.iter().flat_map(|x| &x.headers);
+        for rule in self.response_headers.iter().chain(rules) {
+            rule.validate()?;
+        }
+        for hints in &self.early_hints {
+            hints.validate()?;
+        }
+        if let Some(filters) = &self.filters {
+            filters.validate()?;
+        }
+        for mount in &self.static_files {
+            mount.validate()?;
+        }
+        if let Some(sitemap) = &self.sitemap {
+            sitemap.validate()?;
+        }
+        if let Some(rate_limit) = &self.rate_limit {
+            rate_limit.validate()?;
+        }
+        if let Some(cluster) = &self.cluster {
+            cluster.validate()?;
+        }
+        for proxy in &self.proxy {
+            proxy.validate()?;
+        }
+        acme::validate(self)?;
+        if self.key_passphrase.get().is_none() {
+            let _ = self.key_passphrase.set(self.read_key_passphrase()?);
+        }
+        Ok(())
     }
 }
 
-#[inline]
-pub async fn async_run_router<P, I>(server: Builder<I>, mut router: Router, config: HyperDomeConfig)
-where
-    P: ExecutableArgs,
-    I: Accept,
-    I::Error: Into<Box<dyn Error + Send + Sync>>,
-    I::Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
-{
-    router = load_scripts_into_router(router, "scripts".as_ref());
+/// Panics with every invalid script, and every route that is registered more than
+/// once, whether by scripts in different mounts or by settings, in the router of
+/// every host
+#[cfg_attr(not(feature = "python"), allow(unused_mut))]
+fn check_routes(config: &HyperDomeConfig) {
+    let mut invalid = vec![];
+    let mut routes = routes::RouteRegistry::default();
+    #[cfg(feature = "python")]
+    for mount in config.mounts() {
+        let prefix = mount.prefix.trim_end_matches('/');
+        invalid.extend(py::collect_script_routes(
+            mount.dir.as_ref(),
+            prefix,
+            &mut routes,
+        ));
+    }
+    proxy::register_routes(&mut routes, &config.proxy);
+    static_files::register_routes(&mut routes, &config.static_files);
+    builtins::register_routes(&mut routes, config);
+    if let Some(admin) = &config.admin {
+        admin::register_routes(&mut routes, admin);
+    }
+    if let Some(cluster) = &config.cluster {
+        cluster::register_routes(&mut routes, cluster);
+    }
+    if !config.metrics_path.is_empty() {
+        routes.add(&config.metrics_path, "GET", "metrics_path");
+    }
 
-    router = router.layer(
-        ServiceBuilder::new()
-            .layer(CompressionLayer::new())
-            .layer(TraceLayer::new_for_http())
-            .layer(
+    let mut routers = vec![("the default host".to_owned(), routes)];
+    #[cfg(feature = "python")]
+    for vhost in &config.vhost {
+        let mut routes = routes::RouteRegistry::default();
+        invalid.extend(py::collect_script_routes(
+            vhost.scripts_dir.as_ref(),
+            "",
+            &mut routes,
+        ));
+        routers.push((format!("the hosts {}", vhost.hosts.join(", ")), routes));
+    }
+    routes::check(invalid, &routers);
+}
+
+/// Loads the scripts into `router` and wraps it in the layers configured in `config`,
+/// with the built-in ones replaced as `layers` says
+fn build_router(mut router: Router, config: &HyperDomeConfig, layers: &LayerOptions) -> Router {
+    if !config.audit_log_path.is_empty() {
+        audit::init(
+            config.audit_log_path.as_ref(),
+            config.audit_log_max_bytes,
+            config.audit_log_keep,
+        );
+    }
+
+    #[cfg(feature = "python")]
+    if let Some(sandbox) = &config.sandbox {
+        sandbox::install(sandbox);
+    }
+
+    let options = ScriptOptions {
+        lazy: config.lazy_scripts,
+        cpu_budget: (config.handler_cpu_budget_ms > 0)
+            .then(|| Duration::from_millis(config.handler_cpu_budget_ms)),
+        timeout: (config.handler_timeout_ms > 0)
+            .then(|| Duration::from_millis(config.handler_timeout_ms)),
+    };
+    if !config.url_signing_key.is_empty() {
+        signed_urls::init(&config.url_signing_key);
+    }
+    ids::init(&config.ids);
+    client_address::init(&config.trusted_proxies);
+    if let Some(remote_console) = &config.remote_console {
+        console::remote::init(
+            remote_console,
+            &config.cert_path,
+            &config.key_path,
+            config.key_passphrase().as_deref(),
+        );
+    }
+    if let Some(cluster) = &config.cluster {
+        cluster::init(cluster);
+    }
+    uploads::init(&config.uploads);
+    if let Some(error_tracking) = &config.error_tracking {
+        error_tracking::init(error_tracking);
+    }
+    tenants::init(&config.mounts(), &config.tenants);
+    // Before consumers and jobs start, so that the scripts they load are registered
+    #[cfg(feature = "python")]
+    {
+        let allowed_hosts: fxhash::FxHashMap<_, _> = tenants::tenants()
+            .iter()
+            .filter_map(|x| Some((x.name.clone(), x.allowed_hosts.clone()?)))
+            .collect();
+        if !allowed_hosts.is_empty() {
+            sandbox::restrict_outbound_hosts(allowed_hosts);
+        }
+    }
+    if let Some(webhooks) = &config.webhooks {
+        webhooks::init(webhooks);
+    }
+    #[cfg(feature = "python")]
+    consumers::start(&config.consumers);
+    if let Some(jobs) = &config.jobs {
+        let _queue = jobs::JobQueue::init(jobs);
+        #[cfg(feature = "python")]
+        jobs::start(_queue);
+    }
+
+    check_routes(config);
+    for mount in config.mounts() {
+        router = load_checked_scripts(router, mount.dir.as_ref(), &mount.prefix, options);
+    }
+    router = canary::layer_canaries(router, &config.canary, options);
+    router = shadow::layer_shadows(router, &config.shadow, options);
+    router = proxy::route_proxies(router, &config.proxy);
+    router = static_files::route_static_mounts(router, &config.static_files);
+    let (mut router, mut builtin_paths) = builtins::route_builtins(router, config);
+    if let Some(admin) = &config.admin {
+        let (admin_router, admin_paths) = admin::route_admin(router, admin);
+        router = admin_router;
+        builtin_paths.push(admin_paths);
+    }
+
+    if !config.vhost.is_empty() {
+        let mut hosts = vec![];
+        for vhost in &config.vhost {
+            let vhost_router =
+                load_checked_scripts(Router::new(), vhost.scripts_dir.as_ref(), "", options);
+            for host in &vhost.hosts {
+                hosts.push((host.clone(), vhost_router.clone()));
+            }
+        }
+        router = vhost::route_by_host(router, hosts);
+    }
+
+    if let Some(cluster) = &config.cluster {
+        router = cluster::route_load(router, cluster);
+    }
+    router = transform::layer_body_transforms(router, &layers.body_transforms);
+
+    if !config.metrics_path.is_empty() {
+        router = router.route(
+            &config.metrics_path,
+            axum::routing::get(|| async {
+                runtime::publish_metrics();
+                metrics::render()
+            }),
+        );
+    }
+
+    // Inside the trace layer, so that the location of clients is logged
+    if let Some(geoip) = &config.geoip {
+        router = geoip::layer_geoip(router, geoip);
+    }
+    if let Some(filters) = &config.filters {
+        router = filters::layer_filters(router, filters);
+    }
+    router = panics::layer_panic_capture(router);
+    router = layers.trace.apply(router, |router| {
+        router.layer(
+            TraceLayer::new_for_http()
+                .make_span_with(spans::make_span)
+                .on_response(spans::on_response),
+        )
+    });
+    router = layers
+        .compression
+        .apply(router, compression::layer_compression);
+
+    router = runtime::layer_in_flight(router);
+
+    if config.memory_hard_limit_mb > 0 {
+        router = runtime::layer_memory_guard(router, config.metrics_path.clone());
+    }
+
+    if config.slow_request_ms > 0 {
+        router = spans::layer_slow_requests(router, Duration::from_millis(config.slow_request_ms));
+    }
+
+    let tenants = tenants::tenants();
+    // Inside the bearer auth, so that only verified tokens are limited by their quotas
+    let rate_limit = config
+        .rate_limit
+        .as_ref()
+        .map(|x| (x.backend(), Arc::new(x.options())));
+    if rate_limit.is_some() || tenants.iter().any(|x| x.rate_limit.is_some()) {
+        router = rate_limit::layer_rate_limits(router, rate_limit);
+    }
+
+    let keys = (!config.keys_path.is_empty()).then(|| KeyStore::init(config.keys_path.as_ref()));
+    let public_paths = (!config.api_token.is_empty()
+        || keys.is_some()
+        || tenants.iter().any(|x| x.api_token.is_some()))
+    .then(|| {
+        RegexSet::new(config.public_paths.iter().chain(&builtin_paths))
+            .expect("public_paths should have been validated")
+    });
+    if let Some(public_paths) = &public_paths {
+        router = router.layer(AsyncRequireAuthorizationLayer::new(BearerAuth::new(
+            (!config.api_token.is_empty()).then(|| {
+                config
+                    .api_token
+                    .parse()
+                    .expect("api_token should have been validated")
+            }),
+            keys,
+            public_paths.clone(),
+            proxy::cors_passthrough_prefixes(&config.proxy),
+        )));
+    }
+    // Outside the bearer auth, so that preflights are answered without a token
+    router = layers.cors.apply(router, |router| {
+        router
+            .layer(proxy::CorsExceptLayer::new(
                 CorsLayer::new()
+                    .vary(Vec::new())
                     .allow_methods(
                         config
                             .cors_methods
-                            .into_iter()
-                            .map(|x| {
-                                x.parse()
-                                    .expect("CORS Method should be a valid HTTP Method")
-                            })
+                            .iter()
+                            .map(|x| x.parse().expect("CORS method should have been validated"))
                             .collect::<Vec<_>>(),
                     )
                     .allow_origin(
                         config
                             .cors_origins
-                            .into_iter()
-                            .map(|x| x.parse().expect("CORS Origin should be a valid origin"))
+                            .iter()
+                            .map(|x| x.parse().expect("CORS origin should have been validated"))
                             .collect::<Vec<_>>(),
                     ),
-            ),
+                proxy::cors_passthrough_prefixes(&config.proxy),
+            ))
+            .layer(axum::middleware::map_response(headers::append_cors_vary))
+    });
+    routes::set_access(public_paths, &config.signed_url_prefix);
+    banner::announce(config);
+
+    if !config.signed_url_prefix.is_empty() {
+        assert!(
+            !config.url_signing_key.is_empty(),
+            "url_signing_key should have been validated"
+        );
+        router = signed_urls::layer_signed_urls(router, config.signed_url_prefix.clone());
+    }
+
+    // Outside the auth, signed URLs, tenants and rate limits, so that they judge the
+    // request as rewritten instead of the path it was sent to
+    #[cfg(feature = "python")]
+    {
+        let mounts = config.mounts();
+        router = py::route_through_script(router, mounts.iter().map(|x| Path::new(&x.dir)));
+    }
+    // Outside the rewrite, so that the panics of `route` are reported with their id
+    router = router.layer(
+        ServiceBuilder::new()
+            .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+            .layer(PropagateRequestIdLayer::x_request_id()),
     );
 
-    if !config.api_token.is_empty() {
-        router = router.layer(AsyncRequireAuthorizationLayer::new(BearerAuth::new(
-            config.api_token.parse().expect("msg"),
-            RegexSet::new(config.public_paths).expect("msg"),
-        )));
+    router = headers::layer_early_hints(router, &config.early_hints);
+    router =
+        host_checks::layer_host_checks(router, &config.accepted_hosts, &config.websocket_origins);
+
+    // Outermost, so that responses rejected by the layers above get the headers too
+    let tls = !config.cert_path.is_empty() && !config.key_path.is_empty();
+    let mut header_rules: Vec<_> = config
+        .security_headers
+        .rule(tls, &config.security_header_overrides)
+        .into_iter()
+        .collect();
+    header_rules.extend(config.response_headers.iter().cloned());
+    for vhost in &config.vhost {
+        header_rules.extend(vhost.headers.iter().cloned().map(|mut rule| {
+            if rule.hosts.is_empty() {
+                rule.hosts = vhost.hosts.clone();
+            }
+            rule
+        }));
     }
+    router = headers::layer_header_rules(router, &header_rules);
+    // Outside the header rules, so that they can replace the policy
+    router = headers::layer_content_security_policy(router, &config.content_security_policy);
+
+    router
+}
 
+/// Serves `router` with the connections of `server` until `shutdown`, giving requests
+/// the address and [`TlsInfo`] of their connection
+async fn serve<I: AcceptListener>(
+    server: Builder<I>,
+    router: Router,
+    shutdown: impl Future<Output = ()>,
+) {
+    let make_service = hyper::service::make_service_fn(move |connection: &I::Conn| {
+        let peer = connection.peer_address().map(client_address::PeerAddress);
+        let tls = connection.tls_info();
+        let service = tower::ServiceExt::map_request(
+            router.clone(),
+            move |mut request: axum::http::Request<axum::body::Body>| {
+                if let Some(peer) = peer {
+                    request.extensions_mut().insert(peer);
+                }
+                if let Some(tls) = &tls {
+                    request.extensions_mut().insert(tls.clone());
+                }
+                request
+            },
+        );
+        async move { Ok::<_, std::convert::Infallible>(service) }
+    });
     server
-        .serve(router.into_make_service())
-        .with_graceful_shutdown(listen_for_commands::<P>())
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
         .await
         .unwrap();
 }
 
+async fn serve_router<P, I>(server: Builder<I>, router: Router)
+where
+    P: ExecutableArgs,
+    I: AcceptListener,
+{
+    serve(server, router, listen_for_commands::<P>()).await;
+}
+
+/// Serves `router` with the connections of `listener`, which may be any transport
+/// carrying HTTP, wrapped in the layers configured in `config` like
+/// `async_run_router`
+pub async fn async_run_listener<P, I>(listener: I, router: Router, config: HyperDomeConfig)
+where
+    P: ExecutableArgs,
+    I: AcceptListener,
+{
+    async_run_router::<P, _>(axum::Server::builder(listener), router, config).await;
+}
+
+/// Tells every server of an instance to stop at once, when the main server does
+#[derive(Clone)]
+struct Shutdown(tokio::sync::watch::Receiver<bool>);
+
+impl Shutdown {
+    /// The signal, along with the future the main server is shut down by, which
+    /// sends it once `stop` resolves
+    fn after(stop: impl Future<Output = ()>) -> (Self, impl Future<Output = ()>) {
+        let (sender, receiver) = tokio::sync::watch::channel(false);
+        let stop = async move {
+            stop.await;
+            let _ = sender.send(true);
+        };
+        (Self(receiver), stop)
+    }
+
+    /// Also resolves if the main server is gone without sending it
+    async fn wait(mut self) {
+        let _ = self.0.wait_for(|x| *x).await;
+    }
+}
+
+/// Serves `router` on the `listeners` of `config`, each in a task of its own that
+/// ends once they have shut down
+async fn spawn_listeners(
+    router: &Router,
+    config: &HyperDomeConfig,
+    shutdown: &Shutdown,
+) -> Vec<tokio::task::JoinHandle<()>> {
+    let mut servers = vec![];
+    for listener in &config.listeners {
+        if !listener.is_tls() {
+            let server = axum::Server::try_bind(&listener.address)
+                .unwrap_or_else(|e| panic!("Listener should bind {}: {e}", listener.address));
+            info!("Serving HTTP on {}", listener.address);
+            servers.push(tokio::spawn(serve(
+                server,
+                router.clone(),
+                shutdown.clone().wait(),
+            )));
+            continue;
+        }
+        info!("Loading HTTP Certificates from {:?}", listener.cert_path);
+        let certificate = tls::load_certificate(
+            listener.cert_path.as_ref(),
+            listener.key_path.as_ref(),
+            config.key_passphrase().as_deref(),
+        );
+        let options = TlsOptions {
+            // Challenges are only answered by the certificates of `bind_address`
+            acme_tls_alpn: false,
+            ..config.tls_options()
+        };
+        let acceptor = TlsAcceptor::new(
+            Arc::new(CertResolver::new(vec![certificate])),
+            &listener.address,
+            &options,
+        )
+        .await;
+        info!("Serving HTTPS on {}", listener.address);
+        servers.push(tokio::spawn(serve(
+            axum::Server::builder(acceptor),
+            router.clone(),
+            shutdown.clone().wait(),
+        )));
+    }
+    servers
+}
+
+#[inline]
+pub async fn async_run_router<P, I>(server: Builder<I>, router: Router, config: HyperDomeConfig)
+where
+    P: ExecutableArgs,
+    I: AcceptListener,
+{
+    async_run_router_with_layers::<P, _>(server, router, config, LayerOptions::default()).await;
+}
+
+pub async fn async_run_router_with_layers<P, I>(
+    server: Builder<I>,
+    router: Router,
+    config: HyperDomeConfig,
+    layers: LayerOptions,
+) where
+    P: ExecutableArgs,
+    I: AcceptListener,
+{
+    serve_router::<P, _>(server, build_router(router, &config, &layers)).await;
+}
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -239,20 +1329,94 @@ enum Commands {
     Run {
         #[arg(short, long)]
         detached: bool,
+        /// Set by `dev` on the server processes it supervises
+        #[arg(long, hide = true)]
+        dev: bool,
+        /// Only log what was loaded at the debug level, instead of showing it
+        #[arg(short, long)]
+        quiet: bool,
+    },
+    /// Run the server, restarting it when hypermangle.toml or the Rust code changes
+    #[cfg(feature = "hot-reload")]
+    Dev,
+    /// Build the crate embedding hypermangle for a deployment target
+    Build {
+        #[arg(short, long, value_enum, default_value = "native")]
+        preset: build_presets::BuildPreset,
+        /// Build with the debug profile instead of release
+        #[arg(long)]
+        debug: bool,
+        #[arg(short, long, default_value = "dist")]
+        output_dir: PathBuf,
+    },
+    /// Copy the binary, config and scripts into a directory ready for deployment
+    Package {
+        #[arg(short, long, default_value = "package")]
+        output_dir: PathBuf,
+        /// Also write a Dockerfile for the packaged directory
+        #[arg(long)]
+        docker: bool,
+        /// Build a docker image with the given tag from the packaged directory
+        #[arg(long)]
+        image: Option<String>,
+    },
+    /// Run the `test_*` functions of the `test_*.py` scripts in a folder against the
+    /// server, without binding its address
+    #[cfg(feature = "python")]
+    Test {
+        #[arg(default_value = "tests")]
+        dir: PathBuf,
+        /// Overwrite the snapshots that responses are compared to
+        #[arg(long)]
+        update_snapshots: bool,
+    },
+    /// Write Python type stubs for the API exposed to scripts
+    #[cfg(feature = "python")]
+    Stubs {
+        #[arg(default_value = "scripts")]
+        output_dir: PathBuf,
     },
 }
 
 pub fn auto_main<P: ExecutableArgs>(router: impl Fn() -> Router) {
+    auto_main_with_options::<P>(router, EmbedOptions::default());
+}
+
+pub fn auto_main_with_options<P: ExecutableArgs>(
+    router: impl Fn() -> Router,
+    options: EmbedOptions,
+) {
+    try_auto_main_with_options::<P>(router, options).unwrap_or_else(|e| panic!("{e}"));
+}
+
+/// Like `auto_main`, but returns the errors in hypermangle.toml instead of panicking,
+/// so that they can be reported as the application sees fit
+pub fn try_auto_main<P: ExecutableArgs>(router: impl Fn() -> Router) -> Result<(), ConfigError> {
+    try_auto_main_with_options::<P>(router, EmbedOptions::default())
+}
+
+pub fn try_auto_main_with_options<P: ExecutableArgs>(
+    router: impl Fn() -> Router,
+    options: EmbedOptions,
+) -> Result<(), ConfigError> {
     let Ok(args) = Args::try_parse_from(std::env::args_os()) else {
         send_args_to_remote();
-        return;
+        return Ok(());
     };
 
     match args.command {
-        Commands::Run { detached } => {
+        Commands::Run {
+            detached,
+            dev,
+            quiet,
+        } => {
+            if dev {
+                routes::set_print_routes(true);
+            }
+            banner::set_quiet(quiet);
             if let Some(id) = does_remote_exist() {
                 println!("Remote already exists with process id: {id}");
-                return;
+                return Ok(());
             }
             if detached {
                 let id = std::process::Command::new(
@@ -266,158 +1430,232 @@ pub fn auto_main<P: ExecutableArgs>(router: impl Fn() -> Router) {
                 .expect("Child process should have spawned successfully")
                 .id();
                 println!("Process has spawned successfully with id: {id}");
-                return;
+                return Ok(());
             }
         }
+        #[cfg(feature = "hot-reload")]
+        Commands::Dev => {
+            if let Some(id) = does_remote_exist() {
+                println!("Remote already exists with process id: {id}");
+                return Ok(());
+            }
+            dev::run_dev();
+            return Ok(());
+        }
+        Commands::Build {
+            preset,
+            debug,
+            output_dir,
+        } => {
+            build_presets::build(preset, debug, &output_dir);
+            return Ok(());
+        }
+        Commands::Package {
+            output_dir,
+            docker,
+            image,
+        } => {
+            let config = HyperDomeConfig::try_from_env_and_file(CONFIG_PATH.as_ref())?;
+            package::package(&config, &output_dir, docker, image);
+            return Ok(());
+        }
+        #[cfg(feature = "python")]
+        Commands::Test {
+            dir,
+            update_snapshots,
+        } => {
+            let config = HyperDomeConfig::try_from_env_and_file(CONFIG_PATH.as_ref())?;
+            if !test_main(router(), options, config, &dir, update_snapshots) {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        #[cfg(feature = "python")]
+        Commands::Stubs { output_dir } => {
+            py::write_stubs(&output_dir);
+            return Ok(());
+        }
     }
 
-    auto_main_inner::<P>(router());
+    let config = HyperDomeConfig::try_from_env_and_file(CONFIG_PATH.as_ref())?;
+    auto_main_inner::<P>(router(), options, config);
+    Ok(())
 }
 
-#[tokio::main]
-async fn auto_main_inner<P: ExecutableArgs>(router: Router) {
-    let config = HyperDomeConfig::from_toml_file("hypermangle.toml".as_ref());
-    setup_logger(&config.log_file_path, &config.log_level);
+#[cfg(feature = "python")]
+fn test_main(
+    router: Router,
+    options: EmbedOptions,
+    config: HyperDomeConfig,
+    dir: &Path,
+    update_snapshots: bool,
+) -> bool {
+    config.runtime.init_python();
+    config.runtime.build().block_on(async {
+        options.logging.install(&config);
+        let _python_runtime =
+            PythonRuntime::start().unwrap_or_else(|e| panic!("Python runtime should start: {e}"));
+        py::run_tests(
+            build_router(router, &config, &options.layers),
+            dir,
+            update_snapshots,
+        )
+        .await
+    })
+}
 
+fn auto_main_inner<P: ExecutableArgs>(
+    router: Router,
+    options: EmbedOptions,
+    config: HyperDomeConfig,
+) {
     #[cfg(feature = "python")]
-    std::thread::spawn(|| {
-        pyo3::Python::with_gil(|py| {
-            // Disable Ctrl-C handling
-            let signal_module = py.import("signal").unwrap();
-            signal_module
-                .call_method1(
-                    "signal",
-                    (
-                        signal_module.getattr("SIGINT").unwrap(),
-                        signal_module.getattr("SIG_DFL").unwrap(),
-                    ),
-                )
-                .unwrap();
-
-            let event_loop = py
-                .import("asyncio")
-                .unwrap()
-                .call_method0("new_event_loop")
-                .unwrap();
-            PY_TASK_LOCALS
-                .set(pyo3_asyncio::TaskLocals::new(event_loop))
-                .unwrap();
-            event_loop.call_method0("run_forever").unwrap();
-        })
+    config.runtime.init_python();
+    config
+        .runtime
+        .build()
+        .block_on(run_with_config_and_options::<P>(router, config, options));
+}
+
+/// Runs the server like `auto_main`, but on the runtime of the caller, so that it
+/// can be one part of a larger application. Unlike `async_run_router`, this also
+/// serves TLS, acquires certificates and monitors the runtime as configured
+pub async fn run_with_config<P: ExecutableArgs>(router: Router, config: HyperDomeConfig) {
+    run_with_config_and_options::<P>(router, config, EmbedOptions::default()).await;
+}
+
+/// The future is not `Send`, as certificate acquisition is not, so it should be
+/// awaited rather than spawned
+pub async fn run_with_config_and_options<P: ExecutableArgs>(
+    router: Router,
+    config: HyperDomeConfig,
+    options: EmbedOptions,
+) {
+    options.logging.install(&config);
+    let layers = options.layers;
+
+    // Started unless the embedding application started its own
+    #[cfg(feature = "python")]
+    let _python_runtime = (!PythonRuntime::is_running()).then(|| {
+        PythonRuntime::start().unwrap_or_else(|e| panic!("Python runtime should start: {e}"))
     });
+    #[cfg(feature = "python")]
+    tokio::spawn(runtime::monitor_event_loop());
+    #[cfg(unix)]
+    tokio::spawn(log_files::reopen_on_sighup());
+    if config.memory_soft_limit_mb > 0 || config.memory_hard_limit_mb > 0 {
+        tokio::spawn(runtime::monitor_memory(
+            config.memory_soft_limit_mb * 1024 * 1024,
+            config.memory_hard_limit_mb * 1024 * 1024,
+        ));
+    }
 
-    if !config.cert_path.is_empty() && !config.key_path.is_empty() {
-        let cert_path: &Path = config.cert_path.as_ref();
-        let key_path: &Path = config.key_path.as_ref();
-
-        if cert_path.exists() && key_path.exists() {
-            info!("Loading HTTP Certificates");
-            let file = File::open(cert_path).expect("Cert path should be readable");
-            let mut reader = BufReader::new(file);
-            let certs = rustls_pemfile::certs(&mut reader).expect("Cert file should be valid");
-            let certs: Vec<_> = certs.into_iter().map(Certificate).collect();
-
-            let file = File::open(&key_path).expect("Key path should be readable");
-            let mut reader = BufReader::new(file);
-            let mut keys =
-                rustls_pemfile::pkcs8_private_keys(&mut reader).expect("Key file should be valid");
-
-            let key = match keys.len() {
-                0 => panic!("No PKCS8-encoded private key found in key file"),
-                1 => PrivateKey(keys.remove(0)),
-                _ => panic!("More than one PKCS8-encoded private key found in key file"),
-            };
+    if let Some(heartbeat) = &config.heartbeat {
+        heartbeat::start(heartbeat);
+    }
 
-            info!("HTTP Certificates successfully loaded");
-            async_run_router::<P, _>(
-                axum::Server::builder(TlsAcceptor::new(certs, key, &config.bind_address).await),
-                router,
-                config,
-            )
-            .await;
-            return;
-        } else if !cert_path.exists() && !key_path.exists() {
-            warn!("Acquiring HTTP Certificates");
-            macro_rules! unwrap {
-                ($result: expr) => {
-                    match $result {
-                        Ok(x) => x,
-                        Err(e) => {
-                            panic!("Error running LERS: {e}");
-                        }
-                    }
-                };
-            }
+    if !config.cert_path.is_empty() && !config.key_path.is_empty() {
+        let slots = acme::certificate_slots(&config);
+        let mut certificates = vec![];
+        let mut missing = vec![];
 
-            #[cfg(not(debug_assertions))]
-            const URL: &str = lers::LETS_ENCRYPT_PRODUCTION_URL;
-            #[cfg(debug_assertions)]
-            const URL: &str = lers::LETS_ENCRYPT_STAGING_URL;
+        let passphrase = config.key_passphrase();
 
-            if config.email.is_empty() {
-                panic!("Email not provided!");
+        for slot in slots {
+            if slot.cert_path.exists() && slot.key_path.exists() {
+                info!("Loading HTTP Certificates from {:?}", slot.cert_path);
+                certificates.push(Some(tls::load_certificate(
+                    &slot.cert_path,
+                    &slot.key_path,
+                    passphrase.as_deref(),
+                )));
+            } else if !slot.cert_path.exists() && !slot.key_path.exists() {
+                certificates.push(None);
+                missing.push(slot);
+            } else if !slot.cert_path.exists() {
+                panic!("Certificate does not exist at {:?}", slot.cert_path);
+            } else {
+                panic!("Private Key does not exist at {:?}", slot.key_path);
             }
+        }
 
-            let mut bind_address = config.bind_address;
-            bind_address.set_port(80);
-            let solver = Http01Solver::new();
-            let handle = unwrap!(solver.start(&bind_address));
-
-            let directory = unwrap!(
-                lers::Directory::builder(URL)
-                    .http01_solver(Box::new(solver))
-                    .build()
-                    .await
-            );
-
-            let account = unwrap!(
-                directory
-                    .account()
-                    .terms_of_service_agreed(true)
-                    .contacts(vec![format!("mailto:{}", config.email)])
-                    .create_if_not_exists()
-                    .await
-            );
-
-            let certificate = unwrap!(
-                account
-                    .certificate()
-                    .add_domain(&config.domain_name)
-                    .obtain()
-                    .await
-            );
-
-            tokio::spawn(handle.stop());
-
-            let certs: Vec<_> = certificate
-                .x509_chain()
-                .iter()
-                .map(|x| Certificate(x.to_der().unwrap()))
-                .collect();
-            let key = PrivateKey(certificate.private_key_to_der().unwrap());
+        let resolver = Arc::new(CertResolver::new(
+            certificates.iter().flatten().cloned().collect(),
+        ));
+        let http_challenges = acme::Http01Challenges::default();
+        if let Some(on_demand) = &config.on_demand_tls {
+            acme::start_on_demand(&config, on_demand, &resolver, &http_challenges);
+        }
+        stream_proxy::start(&config.stream_proxy, Some(&resolver));
 
-            write(cert_path, certificate.fullchain_to_pem().unwrap())
-                .expect("Cert file should be writable");
-            write(key_path, certificate.private_key_to_pem().unwrap())
-                .expect("Key file should be writable");
+        let router = build_router(router, &config, &layers);
+        let (shutdown, stop) = Shutdown::after(listen_for_commands::<P>());
+        let mut servers = spawn_listeners(&router, &config, &shutdown).await;
 
-            info!("Certificates successfully downloaded");
+        if let Some(http_address) = config.plain_http_address() {
+            let http_paths =
+                RegexSet::new(&config.http_paths).expect("http_paths should have been validated");
+            let http_router =
+                http_challenges.router(config.bind_address.port(), router.clone(), http_paths);
+            match axum::Server::try_bind(&http_address) {
+                Ok(server) => {
+                    info!("Serving plain HTTP on {http_address}");
+                    servers.push(tokio::spawn(serve(server, http_router, shutdown.wait())));
+                }
+                Err(e) => warn!(
+                    "Failed to bind {http_address}: {e}. HTTP-01 challenges must be forwarded to http_bind_address"
+                ),
+            }
+        }
 
-            let bind_address = config.bind_address.clone();
+        if config.acme_tls_alpn && config.bind_address.port() != 443 {
+            warn!("Warning! TLS-ALPN-01 challenges are only sent to port 443");
+        }
 
-            async_run_router::<P, _>(
-                axum::Server::builder(TlsAcceptor::new(certs, key, &bind_address).await),
-                router,
-                config,
+        // Certificates are acquired while the server is running, since it may have to
+        // answer the challenges. lers futures are not Send, so this runs alongside the
+        // server instead of being spawned
+        let acme_config = config.clone();
+        let acme_resolver = resolver.clone();
+        let acquisition = async move {
+            if missing.is_empty() {
+                info!("HTTP Certificates successfully loaded");
+                return;
+            }
+            warn!("Acquiring HTTP Certificates");
+            let mut acquired = acme::acquire_certificates(
+                &acme_config,
+                &missing,
+                &acme_resolver,
+                &http_challenges,
             )
-            .await;
-            return;
-        } else if !cert_path.exists() {
-            panic!("Certificate does not exist at the given path");
-        } else {
-            panic!("Private Key does not exist at the given path");
-        }
+            .await
+            .into_iter();
+            let certificates = certificates
+                .into_iter()
+                .map(|x| x.or_else(|| acquired.next()))
+                .map(|x| x.expect("Every certificate should be loaded or acquired"))
+                .collect();
+            acme_resolver.set_certificates(certificates);
+            info!("HTTP Certificates successfully loaded");
+        };
+
+        let server = serve(
+            axum::Server::builder(
+                TlsAcceptor::new(resolver, &config.bind_address, &config.tls_options()).await,
+            ),
+            router,
+            stop,
+        );
+        futures::future::join(server, acquisition).await;
+        futures::future::join_all(servers).await;
+        return;
     }
 
-    async_run_router::<P, _>(axum::Server::bind(&config.bind_address), router, config).await;
+    stream_proxy::start(&config.stream_proxy, None);
+    let router = build_router(router, &config, &layers);
+    let (shutdown, stop) = Shutdown::after(listen_for_commands::<P>());
+    let servers = spawn_listeners(&router, &config, &shutdown).await;
+    serve(axum::Server::bind(&config.bind_address), router, stop).await;
+    futures::future::join_all(servers).await;
 }
@@ -0,0 +1,150 @@
+//! Serving routers over transports other than TCP, such as QUIC bridges, Tor onion
+//! services or in-memory connections in tests
+
+use std::{
+    error::Error,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{self, Poll},
+};
+
+use futures::Stream;
+use hyper::server::{accept::Accept, conn::AddrStream};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream},
+    net::TcpStream,
+    sync::mpsc,
+};
+
+use crate::TlsInfo;
+
+/// What is known about a connection before any request is read from it
+pub trait Connection {
+    /// The address the connection came from, which requests are rate limited and
+    /// located by. Connections without one share a single rate limit
+    fn peer_address(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    fn tls_info(&self) -> Option<TlsInfo> {
+        None
+    }
+}
+
+impl Connection for AddrStream {
+    fn peer_address(&self) -> Option<SocketAddr> {
+        Some(self.remote_addr())
+    }
+}
+
+impl Connection for TcpStream {
+    fn peer_address(&self) -> Option<SocketAddr> {
+        self.peer_addr().ok()
+    }
+}
+
+impl Connection for tokio_rustls::server::TlsStream<TcpStream> {
+    fn peer_address(&self) -> Option<SocketAddr> {
+        self.get_ref().0.peer_addr().ok()
+    }
+
+    fn tls_info(&self) -> Option<TlsInfo> {
+        Some(TlsInfo::of(self))
+    }
+}
+
+impl Connection for DuplexStream {}
+
+/// Anything connections can be accepted from and served with [`async_run_listener`].
+/// Implemented for every [`Accept`] whose connections are byte streams that
+/// implement [`Connection`], so it only has to be named
+///
+/// [`async_run_listener`]: crate::async_run_listener
+pub trait AcceptListener:
+    Accept<
+    Error: Into<Box<dyn Error + Send + Sync>>,
+    Conn: AsyncRead + AsyncWrite + Connection + Unpin + Send + 'static,
+>
+{
+}
+
+impl<I> AcceptListener for I where
+    I: Accept<
+        Error: Into<Box<dyn Error + Send + Sync>>,
+        Conn: AsyncRead + AsyncWrite + Connection + Unpin + Send + 'static,
+    >
+{
+}
+
+/// Accepts the connections a stream yields, such as the incoming connections of a
+/// QUIC endpoint turned into byte streams
+pub struct StreamListener<S>(S);
+
+/// Serves the connections of `stream`, until it ends
+pub fn from_stream<S, C, E>(stream: S) -> StreamListener<S>
+where
+    S: Stream<Item = Result<C, E>> + Unpin,
+{
+    StreamListener(stream)
+}
+
+impl<S, C, E> Accept for StreamListener<S>
+where
+    S: Stream<Item = Result<C, E>> + Unpin,
+{
+    type Conn = C;
+
+    type Error = E;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        Pin::new(&mut self.0).poll_next(cx)
+    }
+}
+
+/// How many bytes each in-memory connection buffers in either direction
+const DUPLEX_BUFFER: usize = 64 * 1024;
+
+/// Accepts the connections opened with its [`DuplexConnector`], which never touch a
+/// socket
+pub struct DuplexListener(mpsc::UnboundedReceiver<DuplexStream>);
+
+impl Accept for DuplexListener {
+    type Conn = DuplexStream;
+
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.0.poll_recv(cx).map(|x| x.map(Ok))
+    }
+}
+
+/// Opens in-memory connections to a [`DuplexListener`], which HTTP clients like
+/// `hyper::client::conn` can send requests over
+#[derive(Clone)]
+pub struct DuplexConnector(mpsc::UnboundedSender<DuplexStream>);
+
+impl DuplexConnector {
+    /// Fails once the listener has been dropped, such as when the server stopped
+    pub fn connect(&self) -> io::Result<DuplexStream> {
+        let (client, server) = tokio::io::duplex(DUPLEX_BUFFER);
+        self.0
+            .send(server)
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "Server has stopped"))?;
+        Ok(client)
+    }
+}
+
+/// A listener for in-memory connections and what opens them, for testing a server
+/// without binding an address. The server stops accepting once every connector is
+/// dropped
+pub fn duplex() -> (DuplexConnector, DuplexListener) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    (DuplexConnector(sender), DuplexListener(receiver))
+}
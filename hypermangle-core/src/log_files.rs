@@ -0,0 +1,83 @@
+//! Log files that are reopened at their paths once logrotate has moved them away, by
+//! the `logrotate` console command or SIGHUP
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+};
+
+use parking_lot::Mutex;
+
+use crate::audit;
+
+/// The file `setup_logger` logs to, which keeps logging to the same file until it
+/// is reopened
+#[derive(Clone)]
+pub(crate) struct LogFile {
+    path: Arc<PathBuf>,
+    file: Arc<Mutex<File>>,
+}
+
+static LOG_FILE: OnceLock<LogFile> = OnceLock::new();
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().append(true).create(true).open(path)
+}
+
+impl LogFile {
+    /// Opens `path` for appending, as the file `reopen_all` reopens
+    pub(crate) fn open(path: &Path) -> std::io::Result<Self> {
+        let log_file = Self {
+            path: Arc::new(path.to_owned()),
+            file: Arc::new(Mutex::new(open_append(path)?)),
+        };
+        let _ = LOG_FILE.set(log_file.clone());
+        Ok(log_file)
+    }
+
+    fn reopen(&self) -> std::io::Result<()> {
+        // Opened before locking, so that logging is never held up by the filesystem
+        let file = open_append(&self.path)?;
+        *self.file.lock() = file;
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.lock().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.lock().flush()
+    }
+}
+
+/// Reopens the log file and the audit log, returning how many were open
+pub(crate) fn reopen_all() -> std::io::Result<usize> {
+    let mut count = 0;
+    if let Some(log_file) = LOG_FILE.get() {
+        log_file.reopen()?;
+        count += 1;
+    }
+    if audit::reopen()? {
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(unix)]
+pub(crate) async fn reopen_on_sighup() {
+    use log::{error, info};
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup()).expect("SIGHUP should be listenable");
+    while hangups.recv().await.is_some() {
+        match reopen_all() {
+            Ok(count) => info!("Reopened {count} log files after SIGHUP"),
+            Err(e) => error!("Faced the following error while reopening log files: {e}"),
+        }
+    }
+}
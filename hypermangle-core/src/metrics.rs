@@ -0,0 +1,148 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use fxhash::FxHashMap;
+use parking_lot::RwLock;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+struct Metric {
+    help: &'static str,
+    kind: MetricKind,
+    /// Keyed by the rendered label set, such as `route="/api"`
+    values: FxHashMap<String, AtomicU64>,
+}
+
+static METRICS: RwLock<Option<FxHashMap<&'static str, Metric>>> = RwLock::new(None);
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    let mut out = String::new();
+    for (i, (key, value)) in labels.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let value = value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        let _ = write!(out, "{key}=\"{value}\"");
+    }
+    out
+}
+
+fn with_value(
+    name: &'static str,
+    help: &'static str,
+    kind: MetricKind,
+    labels: &[(&str, &str)],
+    f: impl Fn(&AtomicU64),
+) {
+    let labels = render_labels(labels);
+    {
+        let lock = METRICS.read();
+        if let Some(value) = lock
+            .as_ref()
+            .and_then(|x| x.get(name))
+            .and_then(|x| x.values.get(&labels))
+        {
+            f(value);
+            return;
+        }
+    }
+
+    let mut lock = METRICS.write();
+    let metric = lock
+        .get_or_insert_with(Default::default)
+        .entry(name)
+        .or_insert_with(|| Metric {
+            help,
+            kind,
+            values: Default::default(),
+        });
+    f(metric.values.entry(labels).or_default());
+}
+
+/// Adds `amount` to the counter `name` with the given labels, creating it if needed
+pub fn increment_by(name: &'static str, help: &'static str, labels: &[(&str, &str)], amount: u64) {
+    with_value(name, help, MetricKind::Counter, labels, |x| {
+        x.fetch_add(amount, Ordering::Relaxed);
+    });
+}
+
+pub fn increment(name: &'static str, help: &'static str, labels: &[(&str, &str)]) {
+    increment_by(name, help, labels, 1);
+}
+
+pub fn set_gauge(name: &'static str, help: &'static str, labels: &[(&str, &str)], value: u64) {
+    with_value(name, help, MetricKind::Gauge, labels, |x| {
+        x.store(value, Ordering::Relaxed);
+    });
+}
+
+/// Reads the current value of a metric, if it has been recorded
+pub fn get(name: &str, labels: &[(&str, &str)]) -> Option<u64> {
+    METRICS
+        .read()
+        .as_ref()?
+        .get(name)?
+        .values
+        .get(&render_labels(labels))
+        .map(|x| x.load(Ordering::Relaxed))
+}
+
+/// The current value of every metric, keyed by name and rendered labels, for the
+/// admin dashboard
+pub(crate) fn samples() -> Vec<(&'static str, String, u64)> {
+    let lock = METRICS.read();
+    let mut samples: Vec<_> = lock
+        .iter()
+        .flatten()
+        .flat_map(|(name, metric)| {
+            metric
+                .values
+                .iter()
+                .map(|(labels, value)| (*name, labels.clone(), value.load(Ordering::Relaxed)))
+        })
+        .collect();
+    samples.sort();
+    samples
+}
+
+/// Renders every metric in the Prometheus text exposition format
+pub fn render() -> String {
+    let lock = METRICS.read();
+    let Some(metrics) = lock.as_ref() else {
+        return String::new();
+    };
+    let mut names: Vec<_> = metrics.keys().collect();
+    names.sort();
+
+    let mut out = String::new();
+    for name in names {
+        let metric = &metrics[name];
+        let kind = match metric.kind {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+        };
+        let _ = writeln!(out, "# HELP {name} {}", metric.help);
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+
+        let mut values: Vec<_> = metric.values.iter().collect();
+        values.sort_by(|a, b| a.0.cmp(b.0));
+        for (labels, value) in values {
+            let value = value.load(Ordering::Relaxed);
+            if labels.is_empty() {
+                let _ = writeln!(out, "{name} {value}");
+            } else {
+                let _ = writeln!(out, "{name}{{{labels}}} {value}");
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,150 @@
+use std::{fs, path::Path, process::Command};
+
+use crate::HyperDomeConfig;
+
+/// Where the console socket lives inside packaged containers. /run is usually not
+/// writable by unprivileged container users
+const CONTAINER_SOCKET_PATH: &str = "/tmp/hypermangle.sock";
+
+fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap_or_else(|e| panic!("{to:?} should be creatable: {e}"));
+    for result in from
+        .read_dir()
+        .unwrap_or_else(|e| panic!("{from:?} should be readable: {e}"))
+    {
+        let entry = result.expect("Directory entry should be readable");
+        let file_type = entry
+            .file_type()
+            .expect("File type of directory entry should be accessible");
+        let target = to.join(entry.file_name());
+
+        if file_type.is_dir() {
+            if entry.file_name() == "__pycache__" {
+                continue;
+            }
+            copy_dir(&entry.path(), &target);
+        } else {
+            fs::copy(entry.path(), &target)
+                .unwrap_or_else(|e| panic!("{target:?} should be writable: {e}"));
+        }
+    }
+}
+
+fn python_version() -> String {
+    #[cfg(feature = "python")]
+    {
+        pyo3::Python::with_gil(|py| {
+            let version = py.version_info();
+            format!("{}.{}", version.major, version.minor)
+        })
+    }
+    #[cfg(not(feature = "python"))]
+    {
+        "3".into()
+    }
+}
+
+fn dockerfile(exe_name: &str, config: &HyperDomeConfig) -> String {
+    let mut exposed = vec![config.bind_address.port()];
+    if !config.cert_path.is_empty() && !config.key_path.is_empty() {
+        if let Some(http_address) = config.plain_http_address() {
+            if !exposed.contains(&http_address.port()) {
+                exposed.push(http_address.port());
+            }
+        }
+    }
+    for port in config.listeners.iter().map(|x| x.address.port()) {
+        if !exposed.contains(&port) {
+            exposed.push(port);
+        }
+    }
+    let exposed = exposed
+        .into_iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    format!(
+        "# Generated by `{exe_name} package --docker`
+# The binary was built on the packaging host, so the glibc of this base image
+# must be at least as new as the host's
+FROM python:{python}-slim
+
+WORKDIR /app
+COPY {exe_name} hypermangle.toml ./
+{copy_scripts}
+
+ENV HYPERMANGLE_SOCKET_PATH={CONTAINER_SOCKET_PATH}
+EXPOSE {exposed}
+
+CMD [\"./{exe_name}\", \"run\"]
+",
+        python = python_version(),
+        copy_scripts = config
+            .scripts_dirs()
+            .iter()
+            .map(|x| format!("COPY {x} ./{x}"))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    )
+}
+
+pub(crate) fn package(
+    config: &HyperDomeConfig,
+    output_dir: &Path,
+    docker: bool,
+    image: Option<String>,
+) {
+    let config_path: &Path = crate::CONFIG_PATH.as_ref();
+    let exe = std::env::current_exe().expect("Current EXE name should be accessible");
+    let exe_name = exe
+        .file_name()
+        .expect("Current EXE should have a file name")
+        .to_str()
+        .expect("Current EXE name should be valid unicode");
+
+    if config.bind_address.ip().is_loopback() && (docker || image.is_some()) {
+        println!(
+            "Warning! bind_address is {}, which will not be reachable from outside the container",
+            config.bind_address
+        );
+    }
+    if !config.log_file_path.is_empty() && (docker || image.is_some()) {
+        println!("Warning! log_file_path is set, so logs will be written inside the container instead of only to stdout");
+    }
+
+    fs::create_dir_all(output_dir).expect("Output directory should be writable");
+    fs::copy(&exe, output_dir.join(exe_name)).expect("Binary should be copyable");
+    fs::copy(config_path, output_dir.join("hypermangle.toml"))
+        .expect("hypermangle.toml should be copyable");
+
+    for scripts_dir in config.scripts_dirs() {
+        let path: &Path = scripts_dir.as_ref();
+        if path.is_dir() {
+            copy_dir(path, &output_dir.join(path));
+        } else {
+            fs::create_dir_all(output_dir.join(path)).expect("Output directory should be writable");
+        }
+    }
+
+    if docker || image.is_some() {
+        fs::write(output_dir.join("Dockerfile"), dockerfile(exe_name, config))
+            .expect("Dockerfile should be writable");
+    }
+    println!("Packaged into {}", output_dir.display());
+
+    if let Some(image) = image {
+        let status = Command::new("docker")
+            .arg("build")
+            .arg("-t")
+            .arg(&image)
+            .arg(output_dir)
+            .status()
+            .expect("docker should be runnable");
+        if status.success() {
+            println!("Built image {image}");
+        } else {
+            println!("docker build failed with {status}");
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::{any::Any, collections::VecDeque, time::SystemTime};
+
+use axum::{
+    body::Body,
+    extract::MatchedPath,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json, Router,
+};
+use log::error;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tower_http::catch_panic::CatchPanicLayer;
+
+use crate::error_tracking;
+
+/// Marks responses made by [`CatchPanicLayer`] so that the outer middleware can
+/// fill in details about the request
+#[derive(Clone)]
+struct Panicked(String, Option<error_tracking::Captured>);
+
+#[derive(Serialize)]
+struct PanicBody<'a> {
+    error: &'static str,
+    request_id: &'a str,
+}
+
+/// How many of the latest panics are kept for the admin dashboard
+const RECENT_CAPACITY: usize = 50;
+
+static RECENT: Mutex<VecDeque<RecentPanic>> = Mutex::new(VecDeque::new());
+
+#[derive(Serialize, Clone)]
+pub(crate) struct RecentPanic {
+    /// In RFC 3339
+    at: String,
+    route: String,
+    request_id: String,
+    /// Along with the traceback, for exceptions raised by scripts
+    message: String,
+}
+
+/// The latest panics in handlers, newest first
+pub(crate) fn recent() -> Vec<RecentPanic> {
+    RECENT.lock().iter().cloned().collect()
+}
+
+fn record(route: &str, request_id: &str, message: &str) {
+    let mut recent = RECENT.lock();
+    if recent.len() == RECENT_CAPACITY {
+        recent.pop_back();
+    }
+    recent.push_front(RecentPanic {
+        at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        route: route.to_owned(),
+        request_id: request_id.to_owned(),
+        message: message.to_owned(),
+    });
+}
+
+fn panic_response(payload: Box<dyn Any + Send + 'static>) -> Response {
+    let message = if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else {
+        "Unknown panic message".to_owned()
+    };
+    let mut response = StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    response
+        .extensions_mut()
+        .insert(Panicked(message, error_tracking::take_captured()));
+    response
+}
+
+/// Turns panics in handlers into 500 responses with a JSON body, instead of dropping
+/// the connection
+pub(crate) fn layer_panic_capture(mut router: Router) -> Router {
+    if error_tracking::is_enabled() {
+        router = router.layer(error_tracking::HandlerScopeLayer);
+    }
+    router
+        .layer(CatchPanicLayer::custom(panic_response))
+        .layer(axum::middleware::from_fn(
+            |request: Request<Body>, next: Next<Body>| async move {
+                // Requests that matched no route share a label, rather than adding one
+                // for every path clients make up
+                let route = request
+                    .extensions()
+                    .get::<MatchedPath>()
+                    .map_or("unmatched", |x| x.as_str())
+                    .to_owned();
+                let path = request.uri().path().to_owned();
+                let request_id = request
+                    .headers()
+                    .get("x-request-id")
+                    .and_then(|x| x.to_str().ok())
+                    .unwrap_or_default()
+                    .to_owned();
+                let context = error_tracking::is_enabled()
+                    .then(|| error_tracking::RequestContext::new(&request));
+                let mut response = next.run(request).await;
+
+                let Some(Panicked(message, captured)) =
+                    response.extensions_mut().remove::<Panicked>()
+                else {
+                    return response;
+                };
+                error!("Handler for {path} panicked during request {request_id}: {message}");
+                record(&route, &request_id, &message);
+                if let Some(captured) = captured {
+                    error_tracking::capture_handler_panic(captured, &route, &request_id, context);
+                }
+                crate::metrics::increment(
+                    "hypermangle_handler_panics_total",
+                    "Panics caught in handlers by route",
+                    &[("route", &route)],
+                );
+
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(PanicBody {
+                        error: "Internal Server Error",
+                        request_id: &request_id,
+                    }),
+                )
+                    .into_response()
+            },
+        ))
+}
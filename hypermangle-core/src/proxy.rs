@@ -0,0 +1,454 @@
+use std::{
+    future::Future,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody, StreamBody},
+    http::{header, request::Parts, HeaderMap, HeaderName, Method, Request, StatusCode},
+    response::{IntoResponse, Response},
+    routing::any,
+    Router,
+};
+use futures::{future::Either, StreamExt};
+use log::warn;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use tower::{retry::budget::Budget, Layer, Service};
+use tower_http::cors::{Cors, CorsLayer};
+
+use crate::{
+    circuit::{CircuitBreaker, CircuitBreakerConfig},
+    metrics,
+    routes::RouteRegistry,
+    ConfigError,
+};
+
+/// Requests under `prefix` that are forwarded to another HTTP server
+#[derive(Deserialize, Clone)]
+pub(crate) struct ProxyConfig {
+    prefix: String,
+    /// Such as `http://127.0.0.1:9000/v1`, which `{prefix}/users` is forwarded to as
+    /// `http://127.0.0.1:9000/v1/users`
+    upstream: String,
+    /// How long the upstream has to respond. Bodies that are streamed to it, such as
+    /// large uploads, may take longer, as long as none of their chunks takes this long
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+    /// Connection errors, timeouts and 5xx responses count as failures
+    #[serde(default)]
+    circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
+    retry: RetryConfig,
+    /// Leaves CORS to the upstream, such as a gRPC-web server that lists the headers
+    /// its clients send, so that preflights are forwarded to it instead of answered
+    /// with the `cors_origins` and `cors_methods` of the server
+    #[serde(default)]
+    cors_passthrough: bool,
+}
+
+fn default_timeout_ms() -> u64 {
+    30_000
+}
+
+impl ProxyConfig {
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        let prefix = self.prefix.trim_end_matches('/');
+        if !prefix.starts_with('/') {
+            return Err(ConfigError::ProxyPrefix(self.prefix.clone()));
+        }
+        // The most that tower's budgets allow
+        if !(0.0..=100_000.0).contains(&self.retry.budget_percent) {
+            return Err(ConfigError::RetryBudget {
+                prefix: prefix.to_owned(),
+                percent: self.retry.budget_percent,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// How requests are retried when the upstream could not be connected to or
+/// answered with 502 or 503. Only requests with idempotent methods or an
+/// `Idempotency-Key` header are retried, and only if their body is small enough to
+/// be kept for the next attempt
+#[derive(Deserialize, Clone)]
+struct RetryConfig {
+    /// 0 disables retries
+    #[serde(default = "default_max_retries")]
+    max_retries: u32,
+    /// Waited before the first retry, and doubled before each one after it
+    #[serde(default = "default_backoff_ms")]
+    backoff_ms: u64,
+    /// Retries may add at most this percentage to the requests of the last 10
+    /// seconds, so that a struggling upstream is not swamped with them
+    #[serde(default = "default_budget_percent")]
+    budget_percent: f32,
+    /// Retries allowed each second whatever the budget
+    #[serde(default = "default_min_retries_per_sec")]
+    min_retries_per_sec: u32,
+    #[serde(default = "default_max_body_bytes")]
+    max_body_bytes: u64,
+}
+
+fn default_max_retries() -> u32 {
+    2
+}
+
+fn default_backoff_ms() -> u64 {
+    50
+}
+
+fn default_budget_percent() -> f32 {
+    20.0
+}
+
+fn default_min_retries_per_sec() -> u32 {
+    10
+}
+
+fn default_max_body_bytes() -> u64 {
+    64 * 1024
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: default_max_retries(),
+            backoff_ms: default_backoff_ms(),
+            budget_percent: default_budget_percent(),
+            min_retries_per_sec: default_min_retries_per_sec(),
+            max_body_bytes: default_max_body_bytes(),
+        }
+    }
+}
+
+/// Whether sending the request twice does no more than sending it once
+fn is_idempotent(parts: &Parts) -> bool {
+    matches!(
+        parts.method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    ) || parts.headers.contains_key("idempotency-key")
+}
+
+/// Headers that only concern a single connection, so they are not forwarded. The
+/// server has already answered `Expect: 100-continue` by the time the body is read
+const HOP_BY_HOP: [HeaderName; 9] = [
+    header::CONNECTION,
+    header::EXPECT,
+    HeaderName::from_static("keep-alive"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+pub(crate) fn forwarded_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut headers = headers.clone();
+    for name in &HOP_BY_HOP {
+        headers.remove(name);
+    }
+    headers
+}
+
+struct Proxy {
+    prefix: String,
+    upstream: String,
+    timeout: Duration,
+    client: reqwest::Client,
+    breaker: CircuitBreaker,
+    retry: RetryConfig,
+    budget: Budget,
+}
+
+/// The body of a request on its way upstream
+enum Outbound {
+    /// Kept, so that the request can be retried
+    Buffered(Bytes),
+    /// Sent as it arrives, along with when its last chunk did
+    Streamed(Option<Body>, Arc<Mutex<Instant>>),
+}
+
+impl Outbound {
+    fn streamed(body: Body) -> Self {
+        Self::Streamed(Some(body), Arc::new(Mutex::new(Instant::now())))
+    }
+
+    fn take(&mut self) -> reqwest::Body {
+        match self {
+            Self::Buffered(bytes) => bytes.clone().into(),
+            Self::Streamed(body, last_chunk) => {
+                let last_chunk = last_chunk.clone();
+                reqwest::Body::wrap_stream(
+                    body.take()
+                        .expect("Streamed bodies should only be sent once")
+                        .inspect(move |_| *last_chunk.lock() = Instant::now()),
+                )
+            }
+        }
+    }
+
+    /// Waits for `response`, until `timeout` has passed since the request was sent
+    /// or since the last chunk of its body was, whichever is later
+    async fn within<T>(&self, timeout: Duration, response: impl Future<Output = T>) -> Option<T> {
+        let sent = Instant::now();
+        let last_activity = || match self {
+            Self::Buffered(_) => sent,
+            Self::Streamed(_, last_chunk) => sent.max(*last_chunk.lock()),
+        };
+        tokio::pin!(response);
+        loop {
+            let deadline = last_activity() + timeout;
+            tokio::select! {
+                output = &mut response => return Some(output),
+                _ = tokio::time::sleep_until(deadline.into()) => {
+                    if last_activity() + timeout <= Instant::now() {
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Proxy {
+    fn upstream_url(&self, parts: &Parts) -> String {
+        let path = parts
+            .uri
+            .path()
+            .strip_prefix(self.prefix.as_str())
+            .unwrap_or_default();
+        let mut url = format!("{}{path}", self.upstream);
+        if let Some(query) = parts.uri.query() {
+            url.push('?');
+            url.push_str(query);
+        }
+        url
+    }
+
+    /// Buffers the body if the request may be retried. Bodies of unknown length, such
+    /// as chunked ones and those of HTTP/2 requests without `Content-Length`, are
+    /// streamed, so that memory never holds more than `max_body_bytes` of them
+    async fn outbound(&self, parts: &Parts, body: Body) -> Result<Outbound, Response> {
+        if self.retry.max_retries == 0 || !is_idempotent(parts) {
+            return Ok(Outbound::streamed(body));
+        }
+        if body
+            .size_hint()
+            .upper()
+            .is_none_or(|x| x > self.retry.max_body_bytes)
+        {
+            return Ok(Outbound::streamed(body));
+        }
+        hyper::body::to_bytes(body)
+            .await
+            .map(Outbound::Buffered)
+            .map_err(|_| StatusCode::BAD_REQUEST.into_response())
+    }
+
+    async fn forward(self: Arc<Self>, request: Request<Body>) -> Response {
+        let (parts, body) = request.into_parts();
+        let url = self.upstream_url(&parts);
+        let mut headers = forwarded_headers(&parts.headers);
+        if let Some(host) = headers.remove(header::HOST) {
+            headers.insert(HeaderName::from_static("x-forwarded-host"), host);
+        }
+        let mut outbound = match self.outbound(&parts, body).await {
+            Ok(x) => x,
+            Err(response) => return response,
+        };
+        self.budget.deposit();
+
+        let mut retries = 0;
+        loop {
+            if !self.breaker.allow() {
+                return self.breaker.fallback();
+            }
+            let request = self
+                .client
+                .request(parts.method.clone(), &url)
+                .headers(headers.clone())
+                .body(outbound.take())
+                .send();
+            let Some(result) = outbound.within(self.timeout, request).await else {
+                self.breaker.record(false);
+                warn!("{url} did not respond within {:?}", self.timeout);
+                return StatusCode::GATEWAY_TIMEOUT.into_response();
+            };
+            self.breaker.record(match &result {
+                Ok(response) => !response.status().is_server_error(),
+                Err(_) => false,
+            });
+
+            // Timeouts are not retried, as the upstream may have acted on the request
+            let transient = match &result {
+                Ok(response) => matches!(
+                    response.status(),
+                    StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE
+                ),
+                Err(e) => e.is_connect(),
+            };
+            if transient
+                && matches!(outbound, Outbound::Buffered(_))
+                && retries < self.retry.max_retries
+            {
+                if self.budget.withdraw().is_ok() {
+                    metrics::increment(
+                        "hypermangle_proxy_retries_total",
+                        "Requests resent to proxied upstreams after transient failures",
+                        &[("proxy", &self.prefix)],
+                    );
+                    let backoff = self.retry.backoff_ms.saturating_mul(1 << retries.min(16));
+                    tokio::time::sleep(Duration::from_millis(backoff)).await;
+                    retries += 1;
+                    continue;
+                }
+                metrics::increment(
+                    "hypermangle_proxy_retry_budget_exhausted_total",
+                    "Transient upstream failures that were not retried for lack of budget",
+                    &[("proxy", &self.prefix)],
+                );
+            }
+            return Self::respond(result, &url);
+        }
+    }
+
+    fn respond(result: reqwest::Result<reqwest::Response>, url: &str) -> Response {
+        match result {
+            Ok(response) => {
+                let status = response.status();
+                let headers = forwarded_headers(response.headers());
+                let mut response =
+                    (status, StreamBody::new(response.bytes_stream())).into_response();
+                response.headers_mut().extend(headers);
+                response
+            }
+            Err(e) => {
+                warn!("Failed to forward a request to {url}: {e}");
+                StatusCode::BAD_GATEWAY.into_response()
+            }
+        }
+    }
+}
+
+/// The prefixes of the proxies whose upstreams handle CORS themselves
+pub(crate) fn cors_passthrough_prefixes(proxies: &[ProxyConfig]) -> Vec<String> {
+    proxies
+        .iter()
+        .filter(|x| x.cors_passthrough)
+        .map(|x| x.prefix.trim_end_matches('/').to_owned())
+        .collect()
+}
+
+/// Whether `path` is under one of the `prefixes` from `cors_passthrough_prefixes`
+pub(crate) fn is_cors_passthrough(prefixes: &[String], path: &str) -> bool {
+    prefixes.iter().any(|prefix| {
+        path.strip_prefix(prefix.as_str())
+            .is_some_and(|x| x.is_empty() || x.starts_with('/'))
+    })
+}
+
+/// Applies `CorsLayer` to every request but those under `prefixes`, which would
+/// otherwise never reach their upstream, as `CorsLayer` answers every `OPTIONS`
+/// request itself
+#[derive(Clone)]
+pub(crate) struct CorsExceptLayer {
+    cors: CorsLayer,
+    prefixes: Arc<[String]>,
+}
+
+impl CorsExceptLayer {
+    pub(crate) fn new(cors: CorsLayer, prefixes: Vec<String>) -> Self {
+        Self {
+            cors,
+            prefixes: prefixes.into(),
+        }
+    }
+}
+
+impl<S: Clone> Layer<S> for CorsExceptLayer {
+    type Service = CorsExcept<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CorsExcept {
+            cors: self.cors.layer(inner.clone()),
+            inner,
+            prefixes: self.prefixes.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct CorsExcept<S> {
+    cors: Cors<S>,
+    inner: S,
+    prefixes: Arc<[String]>,
+}
+
+impl<S, B, ResBody> Service<Request<B>> for CorsExcept<S>
+where
+    S: Service<Request<B>, Response = Response<ResBody>> + Clone,
+    ResBody: Default,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Either<<Cors<S> as Service<Request<B>>>::Future, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.cors.poll_ready(cx))?;
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        if is_cors_passthrough(&self.prefixes, request.uri().path()) {
+            Either::Right(self.inner.call(request))
+        } else {
+            Either::Left(self.cors.call(request))
+        }
+    }
+}
+
+pub(crate) fn register_routes(routes: &mut RouteRegistry, proxies: &[ProxyConfig]) {
+    for config in proxies {
+        let prefix = config.prefix.trim_end_matches('/');
+        routes.add_prefix(prefix, &format!("the proxy at {prefix}"));
+    }
+}
+
+/// Routes the `prefix` of every proxy, and every path below it, to its upstream
+pub(crate) fn route_proxies(mut router: Router, proxies: &[ProxyConfig]) -> Router {
+    if proxies.is_empty() {
+        return router;
+    }
+    // Shared, so that connections to upstreams are pooled across proxies
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("Proxy HTTP client should be buildable");
+
+    for config in proxies {
+        let prefix = config.prefix.trim_end_matches('/').to_owned();
+        let proxy = Arc::new(Proxy {
+            breaker: CircuitBreaker::new(format!("proxy:{prefix}"), config.circuit_breaker.clone()),
+            prefix: prefix.clone(),
+            upstream: config.upstream.trim_end_matches('/').to_owned(),
+            timeout: Duration::from_millis(config.timeout_ms),
+            client: client.clone(),
+            retry: config.retry.clone(),
+            budget: Budget::new(
+                Duration::from_secs(10),
+                config.retry.min_retries_per_sec,
+                config.retry.budget_percent / 100.0,
+            ),
+        });
+        let handler = any(move |request: Request<Body>| proxy.clone().forward(request));
+        router = router
+            .route(&prefix, handler.clone())
+            .route(&format!("{prefix}/*rest"), handler);
+    }
+    router
+}
@@ -1,43 +1,157 @@
 use std::{
     fs::read_to_string,
     path::{Path, PathBuf},
-    sync::OnceLock,
+    sync::{Arc, OnceLock},
+    time::Instant,
 };
 
+use arc_swap::{ArcSwap, ArcSwapOption};
 use axum::{
-    body::Bytes,
+    body::Body,
     extract::WebSocketUpgrade,
-    http::StatusCode,
+    http::{header::CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue, Request, StatusCode, Uri},
     response::{IntoResponse, Response},
-    Router,
+    Extension, Router,
 };
+#[cfg(feature = "hot-reload")]
 use fxhash::FxHashMap;
+use hypermangle_py::{buffers, ApiKey, RequestContext, ResponseStream, Upload, UploadedFile};
+#[cfg(feature = "hot-reload")]
 use parking_lot::RwLock;
-use pyo3::{intern, types::PyModule, PyErr, PyObject, Python, ToPyObject};
+use pyo3::{
+    intern,
+    types::{PyByteArray, PyBytes, PyDict, PyString},
+    IntoPy, Py, PyAny, PyCell, PyErr, PyObject, Python, ToPyObject,
+};
+
+use regex::Regex;
+
+use crate::{
+    client_address::{self, PeerAddress},
+    filters::RequestTags,
+    geoip::GeoInfo,
+    headers::CspNonce,
+    keys::AuthenticatedKey,
+    routes::{record_route, unavailable_status, RouteInfo, RouteRegistry},
+    runtime,
+    spans::{HandlerInfo, HandlerTimings},
+    task_locals, uploads, ScriptOptions, PY_TASK_LOCALS,
+};
+
+mod budget;
+mod cache;
+mod cache_control;
+mod codec;
+mod deadline;
+mod event_loop;
+mod negotiate;
+mod rewrite;
+mod streaming;
+mod testing;
+
+pub use event_loop::PythonRuntime;
 
-use crate::{u16_to_status, PY_TASK_LOCALS};
+pub(crate) use cache::precompile_scripts;
+pub(crate) use rewrite::route_through_script;
+pub(crate) use testing::run_tests;
 
 #[derive(Default, Clone, Debug)]
 struct PyHandlers {
     get: Option<PyObject>,
     post: Option<PyObject>,
+    upload: Option<PyObject>,
     ws: Option<PyObject>,
+    formats: Arc<negotiate::Formats>,
+    cache_control: Option<HeaderValue>,
     is_multi_pathed: bool,
 }
 
+/// The current handlers of a script. Each route captures these directly so that
+/// requests never have to look the script up, while hot reloading swaps them in place
+#[derive(Default)]
+struct HandlerSlots {
+    get: ArcSwapOption<PyObject>,
+    post: ArcSwapOption<PyObject>,
+    upload: ArcSwapOption<PyObject>,
+    ws: ArcSwapOption<PyObject>,
+    formats: ArcSwap<negotiate::Formats>,
+    cache_control: ArcSwapOption<HeaderValue>,
+}
+
+impl HandlerSlots {
+    fn store(&self, py_handlers: PyHandlers) {
+        self.get.store(py_handlers.get.map(Arc::new));
+        self.post.store(py_handlers.post.map(Arc::new));
+        self.upload.store(py_handlers.upload.map(Arc::new));
+        self.ws.store(py_handlers.ws.map(Arc::new));
+        self.formats.store(py_handlers.formats);
+        self.cache_control
+            .store(py_handlers.cache_control.map(Arc::new));
+    }
+}
+
+/// Holds why the script could not be imported, if it could not
+type LazyLoaded = Arc<tokio::sync::OnceCell<Result<(), LoadPyErr>>>;
+
 #[cfg(feature = "hot-reload")]
-static PY_HANDLERS: OnceLock<
-    RwLock<FxHashMap<PathBuf, (PyHandlers, std::sync::atomic::AtomicU8)>>,
-> = OnceLock::new();
+struct ReloadableScript {
+    slots: Arc<HandlerSlots>,
+    declared: DeclaredHandlers,
+    loaded: Option<LazyLoaded>,
+    change_id: std::sync::atomic::AtomicU8,
+}
+
+#[cfg(feature = "hot-reload")]
+static PY_HANDLERS: OnceLock<RwLock<FxHashMap<PathBuf, ReloadableScript>>> = OnceLock::new();
 
 #[derive(Debug)]
 enum LoadPyErr {
     PyErr(PyErr),
     NotAScript,
     InterferingHandlers,
+    InterferingUploadHandler,
+    /// An HTTP handler that is not a coroutine function
+    NotAsync(&'static str),
+    AsyncWsHandler,
+    Formats(String),
     ReadError(std::io::Error),
 }
 
+impl std::fmt::Display for LoadPyErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PyErr(e) => write!(f, "{e}"),
+            Self::NotAScript => write!(f, "Path is not a script"),
+            Self::InterferingHandlers => {
+                write!(
+                    f,
+                    "ws_handler cannot be defined alongside get_handler or post_handler, as websockets are upgraded from GET requests. Move them into scripts of their own"
+                )
+            }
+            Self::InterferingUploadHandler => {
+                write!(
+                    f,
+                    "upload_handler cannot be defined alongside post_handler, as both handle POST requests. Keep only one of them"
+                )
+            }
+            Self::NotAsync(handler) => {
+                write!(
+                    f,
+                    "{handler} should be declared with `async def`, as it is awaited"
+                )
+            }
+            Self::AsyncWsHandler => {
+                write!(
+                    f,
+                    "ws_handler should be declared with `def`, as it is called without being awaited"
+                )
+            }
+            Self::Formats(e) => write!(f, "{e}"),
+            Self::ReadError(e) => write!(f, "{e}"),
+        }
+    }
+}
+
 impl From<PyErr> for LoadPyErr {
     fn from(value: PyErr) -> Self {
         Self::PyErr(value)
@@ -52,13 +166,10 @@ impl From<std::io::Error> for LoadPyErr {
 
 fn load_py_handlers(path: &Path) -> Result<PyHandlers, LoadPyErr> {
     Python::with_gil(|py| {
-        let module = PyModule::from_code(
+        let module = cache::module_from_source(
             py,
+            path,
             &read_to_string(path)?,
-            path.file_name()
-                .ok_or(LoadPyErr::NotAScript)?
-                .to_str()
-                .expect("Script filename should be valid unicode"),
             path.file_prefix()
                 .ok_or(LoadPyErr::NotAScript)?
                 .to_str()
@@ -67,20 +178,39 @@ fn load_py_handlers(path: &Path) -> Result<PyHandlers, LoadPyErr> {
 
         let is_multi_pathed = module
             .getattr(intern!(py, "IS_MULTI_PATHED"))
-            .map(|x| x.is_true())
-            .flatten()
+            .and_then(|x| x.is_true())
             .unwrap_or_default();
 
         let get_name = intern!(py, "get_handler");
         let post_name = intern!(py, "post_handler");
+        let upload_name = intern!(py, "upload_handler");
 
         let has_get = module.hasattr(get_name)?;
         let has_post = module.hasattr(post_name)?;
+        let has_upload = module.hasattr(upload_name)?;
+
+        let is_coroutine_function = py
+            .import(intern!(py, "inspect"))?
+            .getattr(intern!(py, "iscoroutinefunction"))?;
+        for (name, handler) in [
+            ("get_handler", get_name),
+            ("post_handler", post_name),
+            ("upload_handler", upload_name),
+        ] {
+            if let Ok(handler) = module.getattr(handler) {
+                if !is_coroutine_function.call1((handler,))?.is_true()? {
+                    return Err(LoadPyErr::NotAsync(name));
+                }
+            }
+        }
 
         if let Ok(ws_handler) = module.getattr(intern!(py, "ws_handler")) {
-            if has_get || has_post {
+            if has_get || has_post || has_upload {
                 return Err(LoadPyErr::InterferingHandlers);
             }
+            if is_coroutine_function.call1((ws_handler,))?.is_true()? {
+                return Err(LoadPyErr::AsyncWsHandler);
+            }
 
             Ok(PyHandlers {
                 ws: Some(ws_handler.to_object(py)),
@@ -99,8 +229,23 @@ fn load_py_handlers(path: &Path) -> Result<PyHandlers, LoadPyErr> {
                 None
             };
 
-            let mut py_handlers = PyHandlers::default();
-            py_handlers.is_multi_pathed = is_multi_pathed;
+            if has_post && has_upload {
+                return Err(LoadPyErr::InterferingUploadHandler);
+            }
+
+            let mut py_handlers = PyHandlers {
+                upload: if has_upload {
+                    Some(module.getattr(upload_name)?.to_object(py))
+                } else {
+                    None
+                },
+                formats: Arc::new(
+                    negotiate::Formats::from_module(module, path).map_err(LoadPyErr::Formats)?,
+                ),
+                cache_control: cache_control::from_module(module).map_err(LoadPyErr::Formats)?,
+                is_multi_pathed,
+                ..Default::default()
+            };
 
             if let Some(get) = get {
                 py_handlers.get = Some(get.to_object(py))
@@ -113,141 +258,835 @@ fn load_py_handlers(path: &Path) -> Result<PyHandlers, LoadPyErr> {
     })
 }
 
-fn pyobject_to_response<'a>(py: Python<'a>, obj: PyObject, handler: &str) -> Response {
-    if let Ok((code, bytes)) = obj.extract::<(u16, Vec<u8>)>(py) {
+/// Imports the script at `path` and gets the function called `name` from it, for
+/// callers other than routes
+pub(crate) fn load_py_function(path: &Path, name: &str) -> Result<PyObject, String> {
+    let source = read_to_string(path).map_err(|e| e.to_string())?;
+    let module_name = path
+        .file_prefix()
+        .and_then(|x| x.to_str())
+        .ok_or("Path is not a script")?;
+    Python::with_gil(|py| {
+        cache::module_from_source(py, path, &source, module_name)?
+            .getattr(name)
+            .map(|x| x.to_object(py))
+    })
+    .map_err(|e| e.to_string())
+}
+
+/// Waits until the event loop, which is started alongside the server, is running
+pub(crate) async fn wait_for_event_loop() {
+    while PY_TASK_LOCALS.read().is_none() {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+}
+
+/// `format` encodes objects other than strings and bytes, and is `None` if the client
+/// accepts none of the formats available
+fn pyobject_to_response<'a>(
+    py: Python<'a>,
+    obj: PyObject,
+    handler: &'static str,
+    format: Option<negotiate::ResponseFormat>,
+    context: PyObject,
+) -> Response {
+    try_pyobject_to_response(py, obj, handler, format, context).unwrap_or_else(|e| panic!("{e}"))
+}
+
+/// Like `pyobject_to_response`, except that what the handler got wrong is returned
+/// instead of panicking
+fn try_pyobject_to_response<'a>(
+    py: Python<'a>,
+    obj: PyObject,
+    handler: &'static str,
+    format: Option<negotiate::ResponseFormat>,
+    context: PyObject,
+) -> Result<Response, String> {
+    let (code, headers, value) = if let Ok((code, value)) = obj.extract::<(u16, &PyAny)>(py) {
+        (code, None, value)
+    } else if let Ok((code, headers, value)) = obj.extract::<(u16, &PyDict, &PyAny)>(py) {
+        (code, Some(headers), value)
+    } else {
+        return Err(format!("{handler} should return a tuple: (Status Code, [headers], string/bytes/object), not: {obj}"));
+    };
+    let status = StatusCode::from_u16(code)
+        .map_err(|_| format!("{handler} should return a valid status code, not {code}"))?;
+
+    // Bodies are shared with the objects they came from, rather than copied
+    let mut response = if value.is_instance_of::<PyBytes>()
+        || value.is_instance_of::<PyByteArray>()
+        || buffers::is_memoryview(value)
+    {
+        let bytes = buffers::share(value).expect("Bytes should be shareable");
+        (status, bytes).into_response()
+    } else if let Some(string) = value
+        .downcast::<PyString>()
+        .ok()
+        .and_then(|_| buffers::share(value))
+    {
         (
-            u16_to_status(code, || {
-                format!("{handler} should return a valid status code, not {code}")
-            }),
-            bytes,
+            status,
+            [(CONTENT_TYPE, "text/plain; charset=utf-8")],
+            string,
         )
             .into_response()
-    } else if let Ok((code, string)) = obj.extract::<(u16, String)>(py) {
+    } else if let Ok(stream) = value.downcast::<PyCell<ResponseStream>>() {
+        let receiver = stream
+            .get()
+            .take_receiver()
+            .ok_or_else(|| format!("{handler} should only return a response stream once"))?;
+        (status, streaming::stream_written(receiver, handler)).into_response()
+    } else if value.hasattr(intern!(py, "__anext__")).unwrap_or_default() {
         (
-            u16_to_status(code, || {
-                format!("{handler} should return a valid status code, not {code}")
-            }),
-            string,
+            status,
+            streaming::stream_response(value.into_py(py), handler, context, is_ndjson(headers)),
         )
             .into_response()
+    } else if let Some(format) = format {
+        format
+            .encode(status, value)
+            .map_err(|e| format!("{handler} should return an encodable object: {e}"))?
     } else {
-        panic!("{handler} should return a tuple: (Status Code, string/bytes), not: {obj}")
+        negotiate::not_acceptable()
+    };
+
+    // Headers from the handler replace those hypermangle picked, such as the content type
+    for (name, value) in headers.into_iter().flatten() {
+        let (Ok(name), Ok(value)) = (name.extract::<&str>(), value.extract::<&str>()) else {
+            return Err(format!(
+                "{handler} should return headers as a dict of strings, not: {headers:?}"
+            ));
+        };
+        response.headers_mut().insert(
+            HeaderName::try_from(name)
+                .map_err(|_| format!("{handler} should return valid header names, not {name:?}"))?,
+            HeaderValue::try_from(value).map_err(|_| {
+                format!("{handler} should return valid header values, not {value:?}")
+            })?,
+        );
     }
+    Ok(response)
 }
 
-pub(crate) fn load_py_into_router(mut router: Router, path: &Path) -> Router {
-    let py_handlers = match load_py_handlers(path) {
-        Ok(x) => x,
-        Err(LoadPyErr::NotAScript) => return router,
-        e => e.expect("Python Script should be valid"),
+/// Whether the headers a handler returned make its streamed body NDJSON
+fn is_ndjson(headers: Option<&PyDict>) -> bool {
+    headers.into_iter().flatten().any(|(name, value)| {
+        name.extract::<&str>()
+            .is_ok_and(|x| x.eq_ignore_ascii_case("content-type"))
+            && value.extract::<&str>().is_ok_and(codec::is_ndjson)
+    })
+}
+
+/// Converts what `data` evaluates to as a Python literal into a response, for fuzz
+/// targets. Returns what a handler like it would have got wrong
+#[cfg(fuzzing)]
+pub(crate) fn fuzz_response(data: &[u8]) -> Result<Response, String> {
+    let Ok(source) = std::str::from_utf8(data) else {
+        return Err("Data is not valid UTF-8".into());
     };
+    Python::with_gil(|py| {
+        let obj = py
+            .import(intern!(py, "ast"))
+            .and_then(|x| x.call_method1(intern!(py, "literal_eval"), (source,)))
+            .map_err(|e| e.to_string())?
+            .to_object(py);
+        try_pyobject_to_response(
+            py,
+            obj,
+            "get_handler",
+            Some(negotiate::ResponseFormat::Json),
+            py.None(),
+        )
+    })
+}
 
-    let http_path = {
-        let mut components = path.components();
-        // Skip over scripts folder
-        components.next();
+/// Panics with the exception a handler raised and its traceback, which the panic
+/// capture layer turns into a 500 response
+fn raised(msg: &str, py: Python, e: &PyErr) -> ! {
+    if crate::error_tracking::is_enabled() {
+        crate::error_tracking::stash_exception(exception_of(py, e));
+    }
+    match e.traceback(py).and_then(|x| x.format().ok()) {
+        Some(traceback) => panic!("{msg}: {e}\n{}", traceback.trim_end()),
+        None => panic!("{msg}: {e}"),
+    }
+}
 
-        let path = components
-            .as_path()
-            .parent()
+/// The type, message and frames of `e`, for error tracking
+fn exception_of(py: Python, e: &PyErr) -> crate::error_tracking::Exception {
+    let ty = e.get_type(py);
+    let frames = e
+        .traceback(py)
+        .and_then(|tb| {
+            py.import(intern!(py, "traceback"))
+                .and_then(|x| x.call_method1(intern!(py, "extract_tb"), (tb,)))
+                .ok()
+        })
+        .and_then(|x| x.iter().ok())
+        .map(|frames| {
+            frames
+                .flatten()
+                .map(|frame| {
+                    let attr = |name: &str| frame.getattr(name).ok().filter(|x| !x.is_none());
+                    crate::error_tracking::Frame {
+                        filename: attr("filename")
+                            .and_then(|x| x.extract().ok())
+                            .unwrap_or_default(),
+                        function: attr("name").and_then(|x| x.extract().ok()),
+                        lineno: attr("lineno").and_then(|x| x.extract().ok()),
+                        colno: None,
+                        context_line: attr("line").and_then(|x| x.extract().ok()),
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    crate::error_tracking::Exception {
+        ty: ty.name().unwrap_or("Exception").to_owned(),
+        module: ty
+            .getattr(intern!(py, "__module__"))
+            .and_then(|x| x.extract::<String>())
+            .ok()
+            .filter(|x| x != "builtins"),
+        value: e.value(py).to_string(),
+        frames,
+    }
+}
+
+/// Whether `handler` takes a `RequestContext` after its `args` usual arguments
+fn takes_context(py: Python, handler: &PyObject, args: usize) -> bool {
+    handler
+        .getattr(py, intern!(py, "__code__"))
+        .and_then(|x| x.getattr(py, intern!(py, "co_argcount")))
+        .and_then(|x| x.extract::<usize>(py))
+        .is_ok_and(|x| x > args)
+}
+
+fn upload_to_py(py: Python, spooled: &uploads::Spooled) -> PyObject {
+    let files = spooled
+        .files
+        .iter()
+        .map(|file| {
+            Py::new(
+                py,
+                UploadedFile {
+                    path: file.path.to_string_lossy().into_owned(),
+                    field: file.field.clone(),
+                    filename: file.filename.clone(),
+                    content_type: file.content_type.clone(),
+                    size: file.size,
+                },
+            )
+            .expect("UploadedFile should be creatable")
+        })
+        .collect();
+    Upload {
+        files,
+        fields: spooled.fields.iter().cloned().collect(),
+    }
+    .into_py(py)
+}
+
+/// The contextvars that a handler is run in
+fn handler_context(
+    py: Python,
+    headers: &HeaderMap,
+    key: &Option<Extension<AuthenticatedKey>>,
+    deadline: Option<&deadline::Deadline>,
+) -> PyObject {
+    hypermangle_py::request_vars::new_context(
+        py,
+        headers.get("x-request-id").and_then(|x| x.to_str().ok()),
+        key.as_ref().map(|Extension(x)| x.name.as_str()),
+        deadline.map(|x| x.monotonic),
+    )
+    .expect("Handler context should be creatable")
+}
+
+fn request_context(
+    py: Python,
+    key: Option<Extension<AuthenticatedKey>>,
+    geo: Option<Extension<GeoInfo>>,
+    tags: Option<Extension<RequestTags>>,
+    nonce: Option<Extension<CspNonce>>,
+    tls: Option<Extension<crate::TlsInfo>>,
+) -> RequestContext {
+    let geo = geo.map(|Extension(x)| x).unwrap_or_default();
+    RequestContext {
+        api_key: key.map(|Extension(key)| {
+            Py::new(
+                py,
+                ApiKey {
+                    name: key.name,
+                    scopes: key.scopes,
+                },
+            )
+            .expect("ApiKey should be creatable")
+        }),
+        country: geo.country,
+        asn: geo.asn,
+        as_organization: geo.as_organization,
+        tags: tags.map(|Extension(RequestTags(x))| x).unwrap_or_default(),
+        csp_nonce: nonce.map(|Extension(CspNonce(x))| x.to_string()),
+        tls: tls.map(|Extension(x)| {
+            Py::new(
+                py,
+                hypermangle_py::TlsInfo {
+                    version: x.version,
+                    cipher: x.cipher,
+                    server_name: x.server_name,
+                    alpn: x.alpn,
+                    client_subject: x.client_subject,
+                    client_fingerprint: x.client_fingerprint,
+                },
+            )
+            .expect("TlsInfo should be creatable")
+        }),
+    }
+}
+
+pub(crate) fn write_stubs(output_dir: &Path) {
+    let path = output_dir.join("hypermangle_py.pyi");
+    std::fs::create_dir_all(output_dir).expect("Stubs directory should be writable");
+    std::fs::write(&path, hypermangle_py::PYI_STUB).expect("Stub file should be writable");
+    println!("Wrote {}", path.display());
+}
+
+/// The handlers a script defines, without the handlers themselves
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+struct DeclaredHandlers {
+    get: bool,
+    post: bool,
+    upload: bool,
+    ws: bool,
+    is_multi_pathed: bool,
+}
+
+impl From<&PyHandlers> for DeclaredHandlers {
+    fn from(value: &PyHandlers) -> Self {
+        Self {
+            get: value.get.is_some(),
+            post: value.post.is_some(),
+            upload: value.upload.is_some(),
+            ws: value.ws.is_some(),
+            is_multi_pathed: value.is_multi_pathed,
+        }
+    }
+}
+
+/// Finds the handlers a script declares by scanning its source instead of importing it
+fn scan_py_handlers(path: &Path) -> Result<DeclaredHandlers, LoadPyErr> {
+    static HANDLER_REGEX: OnceLock<Regex> = OnceLock::new();
+    static MULTI_PATHED_REGEX: OnceLock<Regex> = OnceLock::new();
+
+    let source = read_to_string(path)?;
+    let mut declared = DeclaredHandlers::default();
+    let mut misdeclared = None;
+
+    for captures in HANDLER_REGEX
+        .get_or_init(|| {
+            Regex::new(
+                r"(?m)^(async\s+)?def\s+(get_handler|post_handler|upload_handler|ws_handler)\b",
+            )
             .unwrap()
-            .to_str()
-            .expect("Path to scripts should be valid unicode")
-            .to_owned();
+        })
+        .captures_iter(&source)
+    {
+        let handler = match &captures[2] {
+            "get_handler" => {
+                declared.get = true;
+                "get_handler"
+            }
+            "post_handler" => {
+                declared.post = true;
+                "post_handler"
+            }
+            "upload_handler" => {
+                declared.upload = true;
+                "upload_handler"
+            }
+            _ => {
+                declared.ws = true;
+                "ws_handler"
+            }
+        };
+        // ws_handler is called, and the others are awaited
+        if (handler == "ws_handler") == captures.get(1).is_some() {
+            misdeclared.get_or_insert(handler);
+        }
+    }
+    declared.is_multi_pathed = MULTI_PATHED_REGEX
+        .get_or_init(|| Regex::new(r"(?m)^IS_MULTI_PATHED\s*(?::[^=]*)?=\s*True\b").unwrap())
+        .is_match(&source);
+
+    if declared.ws && (declared.get || declared.post || declared.upload) {
+        return Err(LoadPyErr::InterferingHandlers);
+    }
+    if declared.post && declared.upload {
+        return Err(LoadPyErr::InterferingUploadHandler);
+    }
+    match misdeclared {
+        Some("ws_handler") => return Err(LoadPyErr::AsyncWsHandler),
+        Some(handler) => return Err(LoadPyErr::NotAsync(handler)),
+        None => {}
+    }
+    if !declared.get && !declared.post && !declared.upload && !declared.ws {
+        return Err(LoadPyErr::NotAScript);
+    }
+    Ok(declared)
+}
 
-        String::from("/") + &path
+/// Imports a lazily loaded script the first time one of its routes is requested.
+/// Scripts that fail to import are answered with 500 until they are reloaded
+async fn ensure_loaded(
+    loaded: &tokio::sync::OnceCell<Result<(), LoadPyErr>>,
+    slots: &Arc<HandlerSlots>,
+    path: &Path,
+    declared: DeclaredHandlers,
+) -> Result<(), StatusCode> {
+    let result = loaded
+        .get_or_init(|| {
+            let path = path.to_owned();
+            let slots = slots.clone();
+            async move {
+                tokio::task::spawn_blocking(move || {
+                    let py_handlers = load_py_handlers(&path).map_err(|e| {
+                        log::error!("Python Script {path:?} should be valid: {e}");
+                        e
+                    })?;
+                    if DeclaredHandlers::from(&py_handlers) != declared {
+                        log::warn!("The handlers defined in {path:?} differ from those found when the server started, so the server must be restarted for this change to be reflected");
+                    }
+                    slots.store(py_handlers);
+                    Ok(())
+                })
+                .await
+                .expect("Python Script should have loaded without panicking")
+            }
+        })
+        .await;
+    // Hot reloading stores the handlers once the script is fixed
+    let reloaded = || {
+        slots.get.load().is_some()
+            || slots.post.load().is_some()
+            || slots.upload.load().is_some()
+            || slots.ws.load().is_some()
     };
+    if result.is_err() && !reloaded() {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok(())
+}
 
-    #[cfg(feature = "hot-reload")]
+/// `index.py` and `__init__.py` are bound to their directory like any other script,
+/// but also own the directory with a trailing slash, which would otherwise go to a
+/// multi-pathed parent. Multi-pathed scripts match it already
+fn index_http_path(path: &Path, http_path: &str, is_multi_pathed: bool) -> Option<String> {
+    let is_index = matches!(
+        path.file_prefix().and_then(|x| x.to_str()),
+        Some("index" | "__init__")
+    );
+    (is_index && !is_multi_pathed && http_path != "/").then(|| format!("{http_path}/"))
+}
+
+/// `prefix` is the route of `root`, and is either empty or starts with a slash
+fn script_http_path(root: &Path, prefix: &str, path: &Path) -> String {
+    let path = path
+        .strip_prefix(root)
+        .expect("Script should be inside the scripts folder")
+        .parent()
+        .unwrap()
+        .to_str()
+        .expect("Path to scripts should be valid unicode");
+
+    if path.is_empty() && !prefix.is_empty() {
+        prefix.to_owned()
+    } else {
+        format!("{prefix}/{path}")
+    }
+}
+
+fn collect_declared_routes(
+    root: &Path,
+    prefix: &str,
+    path: &Path,
+    routes: &mut RouteRegistry,
+    invalid: &mut Vec<String>,
+) {
+    for result in path
+        .read_dir()
+        .expect("Scripts directory should be readable")
     {
-        macro_rules! handler {
-            ($method: ident, $handler: literal) => {
-                if py_handlers.$method.is_some() {
-                    let path = path.to_owned();
-                    let handler = axum::routing::$method(move |body: Bytes| async move {
+        let entry = result.expect("Script or sub-directory should be readable");
+        let path = entry.path();
+        let file_type = entry
+            .file_type()
+            .expect("File type of script or sub-directory should be accessible");
+
+        if file_type.is_dir() {
+            collect_declared_routes(root, prefix, &path, routes, invalid);
+            continue;
+        }
+        if path.extension().and_then(std::ffi::OsStr::to_str) != Some("py") {
+            continue;
+        }
+        let declared = match scan_py_handlers(&path) {
+            Ok(x) => x,
+            Err(LoadPyErr::NotAScript) => continue,
+            Err(e) => {
+                invalid.push(format!("{}: {e}", path.display()));
+                continue;
+            }
+        };
+        let http_path = script_http_path(root, prefix, &path);
+        let mut methods = vec![];
+        if declared.get {
+            methods.push("GET");
+        }
+        if declared.post || declared.upload {
+            methods.push("POST");
+        }
+        // Websockets are upgraded from GET requests
+        if declared.ws {
+            methods.push("GET");
+        }
+        let index_path = index_http_path(&path, &http_path, declared.is_multi_pathed);
+        let source = path.display().to_string();
+        for http_path in std::iter::once(http_path).chain(index_path) {
+            for &method in &methods {
+                routes.add(&http_path, method, &source);
+            }
+        }
+    }
+}
+
+/// Adds the routes of the scripts in `root` to `routes`, returning every script that
+/// declares handlers that cannot go together
+pub(crate) fn collect_script_routes(
+    root: &Path,
+    prefix: &str,
+    routes: &mut RouteRegistry,
+) -> Vec<String> {
+    let mut invalid = vec![];
+    collect_declared_routes(root, prefix, root, routes, &mut invalid);
+    invalid
+}
+
+/// Panics with every script in `root` that is invalid, and every pair of scripts that
+/// would handle the same method on the same path, as loading would only report the
+/// first problem, and the router would not name the scripts involved
+pub(crate) fn check_scripts(root: &Path, prefix: &str) {
+    let mut routes = RouteRegistry::default();
+    let invalid = collect_script_routes(root, prefix, &mut routes);
+    crate::routes::check(invalid, &[(format!("Scripts in {root:?}"), routes)]);
+}
+
+pub(crate) fn register_embedded_module() {
+    static REGISTERED: std::sync::Once = std::sync::Once::new();
+    REGISTERED.call_once(|| {
+        Python::with_gil(|py| {
+            hypermangle_py::register_embedded_module(py)
+                .expect("hypermangle_py should be registrable as a module")
+        })
+    });
+}
+
+/// `root` is the scripts folder `path` is in, which the route of the script is
+/// relative to, and is routed at `prefix`
+pub(crate) fn load_py_into_router(
+    mut router: Router,
+    root: &Path,
+    prefix: &str,
+    path: &Path,
+    options: ScriptOptions,
+) -> Router {
+    // Run before routing instead, by `route_through_script`
+    if path == root.join(rewrite::ROUTER_SCRIPT) {
+        return router;
+    }
+    let (declared, py_handlers) = if options.lazy {
+        match scan_py_handlers(path) {
+            Ok(x) => (x, None),
+            Err(LoadPyErr::NotAScript) => return router,
+            Err(e) => panic!("Python Script {path:?} should be valid: {e}"),
+        }
+    } else {
+        match load_py_handlers(path) {
+            Ok(x) => ((&x).into(), Some(x)),
+            Err(LoadPyErr::NotAScript) => return router,
+            Err(e) => panic!("Python Script {path:?} should be valid: {e}"),
+        }
+    };
+
+    let http_path = script_http_path(root, prefix, path);
+    let index_path = index_http_path(path, &http_path, declared.is_multi_pathed);
+
+    let slots = Arc::new(HandlerSlots::default());
+    let script: Arc<Path> = path.into();
+    let loaded: Option<LazyLoaded> = options.lazy.then(Default::default);
+
+    macro_rules! handler {
+        ($method: ident, $handler: literal) => {
+            handler!(
+                $method,
+                $method,
+                $handler,
+                headers,
+                formats,
+                request: Request<Body> => match codec::RequestBody::from_request(
+                    &headers,
+                    request,
+                    &formats.protobuf,
+                )
+                .await
+                {
+                    Ok(x) => x,
+                    Err(response) => return response,
+                },
+                |py, body| body.into_object(py, formats.memoryview_bodies)
+            );
+        };
+        (
+            $routing: ident,
+            $method: ident,
+            $handler: literal,
+            $headers: ident,
+            $formats: ident,
+            $request: ident: $request_ty: ty => $prepare: expr,
+            |$py: ident, $arg: ident| $to_object: expr
+        ) => {
+            if declared.$method {
+                let path = path.to_owned();
+                let script = script.clone();
+                let slots = slots.clone();
+                let loaded = loaded.clone();
+                let route = http_path.clone();
+                let handler = axum::routing::$routing(
+                    move |key: Option<Extension<AuthenticatedKey>>,
+                          geo: Option<Extension<GeoInfo>>,
+                          tags: Option<Extension<RequestTags>>,
+                          nonce: Option<Extension<CspNonce>>,
+                          tls: Option<Extension<crate::TlsInfo>>,
+                          $headers: HeaderMap,
+                          $request: $request_ty| async move {
+                        if let Some(status) = unavailable_status(&route) {
+                            return status.into_response();
+                        }
+                        // Bodies can only be decoded once the script has declared how
+                        if let Some(loaded) = &loaded {
+                            if let Err(status) = ensure_loaded(loaded, &slots, &path, declared).await {
+                                return status.into_response();
+                            }
+                        }
+                        let $formats = slots.formats.load_full();
+                        let csp_nonce = nonce.as_ref().map(|Extension(CspNonce(x))| x.clone());
+                        let response_format =
+                            negotiate::ResponseFormat::negotiate(&$headers, &$formats)
+                                .map(|x| x.with_csp_nonce(csp_nonce));
+                        let $arg = $prepare;
+                        let handler_start = Instant::now();
+                        let queued = runtime::count_queued();
                         let exception_msg =
                             format!("{} should have ran without exceptions", $handler);
+                        let handler = slots
+                            .$method
+                            .load_full()
+                            .expect(concat!($handler, " should still be defined"));
+
+                        let (python_start, result, context, deadline) = Python::with_gil(|$py| {
+                            let python_start = Instant::now();
+                            drop(queued);
+                            let body = $to_object;
+                            let deadline = options.timeout.map(|x| deadline::Deadline::new($py, x));
+                            let context = handler_context($py, &$headers, &key, deadline.as_ref());
 
-                        let result = {
-                            let reader = PY_HANDLERS.get().unwrap().read();
+                            let result = if takes_context($py, &handler, 1) {
+                                handler.call1($py, (body, request_context($py, key, geo, tags, nonce, tls)))
+                            } else {
+                                handler.call1($py, (body,))
+                            }
+                            .unwrap_or_else(|e| raised(&exception_msg, $py, &e));
+                            let result = match options.cpu_budget {
+                                Some(budget) => {
+                                    Py::new($py, budget::BudgetedCoroutine::new(result, budget))
+                                        .expect("BudgetedCoroutine should be creatable")
+                                        .into_py($py)
+                                }
+                                None => result,
+                            };
+                            let result = match &deadline {
+                                Some(deadline) => deadline.wrap($py, result),
+                                None => result,
+                            };
 
+                            let result = pyo3_asyncio::into_future_with_locals(
+                                &task_locals().with_context(context.as_ref($py)),
+                                result.as_ref($py),
+                            )
+                            .expect(&format!("{} should be asynchronous", $handler));
+                            (python_start, result, context, deadline)
+                        });
+                        let result = match result.await {
+                            Err(e) if budget::is_exceeded(&e) => {
+                                return budget::exceeded_response(&path, $handler, &route);
+                            }
+                            Err(e) if deadline.as_ref().is_some_and(|x| x.is_exceeded(&e)) => {
+                                return deadline::exceeded_response(&path, $handler, &route);
+                            }
+                            Err(e) => Python::with_gil(|py| raised(&exception_msg, py, &e)),
+                            Ok(result) => result,
+                        };
+                        let serialization_start = Instant::now();
+
+                        let mut response =
                             Python::with_gil(|py| {
-                                let body = if let Ok(body) = std::str::from_utf8(&body) {
-                                    body.to_object(py)
-                                } else {
-                                    body.to_object(py)
-                                };
-
-                                let result = reader
-                                    .get(&path)
-                                    .unwrap()
-                                    .0
-                                    .$method
-                                    .as_ref()
-                                    .unwrap()
-                                    .call1(py, (body,))
-                                    .expect(&exception_msg);
-
-                                pyo3_asyncio::into_future_with_locals(
-                                    &PY_TASK_LOCALS.get().unwrap(),
-                                    result.as_ref(py),
-                                )
-                                .expect(&format!("{} should be asynchronous", $handler))
-                            })
+                                pyobject_to_response(py, result, $handler, response_format, context)
+                            });
+                        if stringify!($method) == "get" {
+                            if let Some(cache_control) = slots.cache_control.load_full() {
+                                response =
+                                    cache_control::apply(response, &cache_control, &$headers).await;
+                            }
                         }
-                        .await
-                        .expect(&exception_msg);
+                        response.extensions_mut().insert(HandlerInfo {
+                            script: script.clone(),
+                            handler: $handler,
+                            timings: Some(HandlerTimings {
+                                queue: python_start - handler_start,
+                                python: serialization_start - python_start,
+                                serialization: serialization_start.elapsed(),
+                            }),
+                        });
+                        response
+                    },
+                );
+                router = router.route(&http_path, handler.clone());
+                if let Some(index_path) = &index_path {
+                    router = router.route(index_path, handler.clone());
+                }
+
+                if declared.is_multi_pathed {
+                    router = router.route(&format!("{http_path}*path"), handler);
+                }
+            }
+        };
+    }
 
-                        Python::with_gil(|py| pyobject_to_response(py, result, $handler))
-                    });
-                    router = router.route(&http_path, handler.clone());
+    handler!(get, "get_handler");
+    handler!(post, "post_handler");
+    handler!(
+        post,
+        upload,
+        "upload_handler",
+        headers,
+        formats,
+        request: Request<Body> => match uploads::spool(request).await {
+            Ok(x) => x,
+            Err(response) => return response,
+        },
+        |py, spooled| upload_to_py(py, &spooled)
+    );
 
-                    if py_handlers.is_multi_pathed {
-                        router = router.route(&format!("{http_path}*path"), handler);
+    if declared.ws {
+        let path = path.to_owned();
+        let script = script.clone();
+        let slots = slots.clone();
+        let loaded = loaded.clone();
+        let route = http_path.clone();
+        let handler = axum::routing::get(
+            move |key: Option<Extension<AuthenticatedKey>>,
+                  geo: Option<Extension<GeoInfo>>,
+                  tags: Option<Extension<RequestTags>>,
+                  nonce: Option<Extension<CspNonce>>,
+                  tls: Option<Extension<crate::TlsInfo>>,
+                  peer: Option<Extension<PeerAddress>>,
+                  uri: Uri,
+                  headers: HeaderMap,
+                  ws: WebSocketUpgrade| async move {
+                if let Some(status) = unavailable_status(&route) {
+                    return status.into_response();
+                }
+                if let Some(loaded) = &loaded {
+                    if let Err(status) = ensure_loaded(loaded, &slots, &path, declared).await {
+                        return status.into_response();
                     }
                 }
-            };
-        }
+                let peer = client_address::for_peer(peer.as_deref(), &headers)
+                    .map_or_else(|| "unknown".to_owned(), |x| x.to_string());
+                let (ws, receiver) =
+                    hypermangle_py::WebSocket::new(ws, uri.path().to_owned(), peer);
+                let handler = slots
+                    .ws
+                    .load_full()
+                    .expect("ws_handler should still be defined");
 
-        handler!(get, "get_handler");
-        handler!(post, "post_handler");
+                tokio::task::spawn_blocking(move || {
+                    Python::with_gil(|py| {
+                        let context = handler_context(py, &headers, &key, None);
+                        let handler = handler.clone_ref(py);
+                        let run = intern!(py, "run");
+                        if takes_context(py, &handler, 1) {
+                            let request = request_context(py, key, geo, tags, nonce, tls);
+                            context.call_method1(py, run, (handler, ws, request))
+                        } else {
+                            context.call_method1(py, run, (handler, ws))
+                        }
+                        .unwrap_or_else(|e| {
+                            raised("ws_handler should have ran without exceptions", py, &e)
+                        });
+                    })
+                });
 
-        if py_handlers.ws.is_some() {
-            let path = path.to_owned();
-            router = router.route(
-                &http_path,
-                axum::routing::get(|ws: WebSocketUpgrade| async move {
-                    let (ws, receiver) = hypermangle_py::WebSocket::new(ws);
-
-                    tokio::task::spawn_blocking(move || {
-                        let reader = PY_HANDLERS.get().unwrap().read();
-
-                        Python::with_gil(|py| {
-                            reader
-                                .get(&path)
-                                .unwrap()
-                                .0
-                                .ws
-                                .as_ref()
-                                .unwrap()
-                                .call1(py, (ws,))
-                                .expect("ws_handler should have ran without exceptions");
-                        })
-                    });
-
-                    receiver
-                        .await
-                        .unwrap_or_else(|_| (StatusCode::SERVICE_UNAVAILABLE, ()).into_response())
-                }),
-            );
+                let mut response = receiver
+                    .await
+                    .unwrap_or_else(|_| (StatusCode::SERVICE_UNAVAILABLE, ()).into_response());
+                response.extensions_mut().insert(HandlerInfo {
+                    script,
+                    handler: "ws_handler",
+                    timings: None,
+                });
+                response
+            },
+        );
+        router = router.route(&http_path, handler.clone());
+        if let Some(index_path) = &index_path {
+            router = router.route(index_path, handler);
         }
+    }
+
+    let mut methods = vec![];
+    if declared.get {
+        methods.push("GET");
+    }
+    if declared.post || declared.upload {
+        methods.push("POST");
+    }
+    if declared.ws {
+        methods.push("WS");
+    }
+    record_route(RouteInfo {
+        http_path: http_path.clone(),
+        methods,
+        is_multi_pathed: declared.is_multi_pathed && !declared.ws,
+        lazy: options.lazy,
+        source: path.to_owned(),
+    });
 
-        PY_HANDLERS
-            .get_or_init(Default::default)
-            .write()
-            .insert(path.to_owned(), (py_handlers, Default::default()));
+    if let Some(py_handlers) = py_handlers {
+        slots.store(py_handlers);
     }
 
+    #[cfg(feature = "hot-reload")]
+    PY_HANDLERS.get_or_init(Default::default).write().insert(
+        path.to_owned(),
+        ReloadableScript {
+            slots,
+            declared,
+            loaded,
+            change_id: Default::default(),
+        },
+    );
+
     router
 }
 
@@ -256,8 +1095,7 @@ pub(crate) fn py_handle_notify_event(
     event: std::sync::Arc<notify::Event>,
     working_directory: PathBuf,
 ) {
-    use log::{error, info, warn};
-    use parking_lot::RwLockUpgradableReadGuard;
+    use std::sync::atomic::Ordering;
 
     use crate::SYNC_CHANGES_DELAY;
     let Some(py_handlers) = PY_HANDLERS.get() else {
@@ -266,73 +1104,188 @@ pub(crate) fn py_handle_notify_event(
 
     tokio::spawn(async move {
         for path in &event.paths {
-            let path = path.canonicalize().unwrap();
+            // Removed files, such as stale code caches, cannot be canonicalized
+            let Ok(path) = path.canonicalize() else {
+                continue;
+            };
             let Ok(path) = path.strip_prefix(&working_directory) else {
                 continue;
             };
 
             let id = {
                 let lock = py_handlers.read();
-                let Some((_, instant)) = lock.get(path) else {
-                    return;
+                let Some(script) = lock.get(path) else {
+                    continue;
                 };
-                instant.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1
+                if script.loaded.as_ref().is_some_and(|x| !x.initialized()) {
+                    // Not imported yet, so the first request will pick up the change
+                    continue;
+                }
+                script
+                    .change_id
+                    .fetch_add(1, Ordering::Relaxed)
+                    .wrapping_add(1)
             };
 
             tokio::time::sleep(SYNC_CHANGES_DELAY).await;
 
-            let lock = py_handlers.upgradable_read();
-
-            if lock
-                .get(path)
-                .unwrap()
-                .1
-                .load(std::sync::atomic::Ordering::Relaxed)
-                != id
-            {
-                return;
-            }
-            let mut lock = RwLockUpgradableReadGuard::upgrade(lock);
-            let (py_handler, _) = lock.get_mut(path).unwrap();
-
-            let new_py_handler = match load_py_handlers(&path) {
-                Ok(x) => x,
-                Err(e) => {
-                    error!("Faced error while reloading {path:?}: {e:?}");
-                    return;
-                }
-            };
-            if new_py_handler.is_multi_pathed != py_handler.is_multi_pathed {
-                warn!("The IS_MULTI_PATHED constant in {path:?} has changed, but the server must be restarted for this change to be reflected");
-            }
-            if let Some(new_get) = new_py_handler.get {
-                if let Some(old_get) = &mut py_handler.get {
-                    *old_get = new_get;
-                } else {
-                    warn!("get_handler has been added to {path:?}, but the server must be restarted for this change to be reflected");
-                }
-            } else if new_py_handler.get.is_some() {
-                warn!("get_handler has been removed from {path:?}, but the server must be restarted for this change to be reflected");
+            let lock = py_handlers.read();
+            let script = lock.get(path).unwrap();
+            if script.change_id.load(Ordering::Relaxed) != id {
+                continue;
             }
-            if let Some(new_post) = new_py_handler.post {
-                if let Some(old_post) = &mut py_handler.post {
-                    *old_post = new_post;
-                } else {
-                    warn!("post_handler has been added to {path:?}, but the server must be restarted for this change to be reflected");
-                }
-            } else if new_py_handler.post.is_some() {
-                warn!("post_handler has been removed from {path:?}, but the server must be restarted for this change to be reflected");
+
+            reload_script(path, script);
+        }
+    });
+}
+
+/// Imports the script at `path` again, swapping in its handlers
+#[cfg(feature = "hot-reload")]
+fn reload_script(path: &Path, script: &ReloadableScript) -> bool {
+    use log::{error, info, warn};
+
+    let mut new_py_handlers = match load_py_handlers(path) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Faced error while reloading {path:?}: {e}");
+            return false;
+        }
+    };
+    if new_py_handlers.is_multi_pathed != script.declared.is_multi_pathed {
+        warn!("The IS_MULTI_PATHED constant in {path:?} has changed, but the server must be restarted for this change to be reflected");
+    }
+
+    macro_rules! reload {
+        ($method: ident, $handler: literal) => {
+            match (new_py_handlers.$method.take(), script.declared.$method) {
+                (Some(new), true) => script.slots.$method.store(Some(Arc::new(new))),
+                (Some(_), false) => warn!(
+                    concat!($handler, " has been added to {:?}, but the server must be restarted for this change to be reflected"),
+                    path
+                ),
+                (None, true) => warn!(
+                    concat!($handler, " has been removed from {:?}, but the server must be restarted for this change to be reflected"),
+                    path
+                ),
+                (None, false) => {}
             }
-            if let Some(new_ws) = new_py_handler.ws {
-                if let Some(old_ws) = &mut py_handler.ws {
-                    *old_ws = new_ws;
-                } else {
-                    warn!("ws_handler has been added to {path:?}, but the server must be restarted for this change to be reflected");
-                }
-            } else if new_py_handler.ws.is_some() {
-                warn!("ws_handler has been removed from {path:?}, but the server must be restarted for this change to be reflected");
+        };
+    }
+
+    reload!(get, "get_handler");
+    reload!(post, "post_handler");
+    reload!(upload, "upload_handler");
+    reload!(ws, "ws_handler");
+    info!("Successfully reloaded {path:?}");
+    true
+}
+
+/// Reloads every script that has been imported, returning how many reloaded
+#[cfg(feature = "hot-reload")]
+pub(crate) fn reload_all() -> usize {
+    let Some(py_handlers) = PY_HANDLERS.get() else {
+        return 0;
+    };
+    py_handlers
+        .read()
+        .iter()
+        // Scripts that were not imported yet import the new source on their first request
+        .filter(|(_, script)| !script.loaded.as_ref().is_some_and(|x| !x.initialized()))
+        .filter(|(path, script)| reload_script(path, script))
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A scripts folder of `scripts`, by their paths in it, which is removed on drop
+    struct Scripts(PathBuf);
+
+    impl Scripts {
+        fn new(name: &str, scripts: &[(&str, &str)]) -> Self {
+            let root =
+                std::env::temp_dir().join(format!("hypermangle-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&root);
+            for (path, source) in scripts {
+                let path = root.join(path);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, source).unwrap();
             }
-            info!("Successfully reloaded {path:?}");
+            Self(root)
         }
-    });
+
+        fn conflicts(&self, prefix: &str) -> (Vec<String>, Vec<String>) {
+            let mut routes = RouteRegistry::default();
+            let invalid = collect_script_routes(&self.0, prefix, &mut routes);
+            (invalid, routes.conflicts())
+        }
+    }
+
+    impl Drop for Scripts {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    const GET: &str = "async def get_handler(): ...\n";
+    const POST: &str = "async def post_handler(): ...\n";
+    const WS: &str = "def ws_handler(ws): ...\n";
+
+    #[test]
+    fn scripts_in_the_same_folder_conflict_on_shared_methods() {
+        let scripts = Scripts::new(
+            "shared-methods",
+            &[
+                ("users/list.py", GET),
+                ("users/create.py", POST),
+                ("users/socket.py", WS),
+                ("users/README.txt", GET),
+            ],
+        );
+        let (invalid, conflicts) = scripts.conflicts("/api");
+        assert!(invalid.is_empty());
+        let root = scripts.0.display();
+        assert_eq!(
+            conflicts,
+            [format!(
+                "GET /api/users: {root}/users/list.py, {root}/users/socket.py"
+            )]
+        );
+    }
+
+    #[test]
+    fn index_scripts_conflict_with_their_parent_folder() {
+        let scripts = Scripts::new(
+            "index",
+            &[("docs/index.py", GET), ("docs/__init__.py", GET)],
+        );
+        let (_, conflicts) = scripts.conflicts("");
+        assert_eq!(conflicts.len(), 2, "{conflicts:?}");
+        assert!(conflicts[0].starts_with("GET /docs: "));
+        assert!(conflicts[1].starts_with("GET /docs/: "));
+    }
+
+    #[test]
+    fn invalid_scripts_are_all_reported() {
+        let scripts = Scripts::new(
+            "invalid",
+            &[
+                ("a/a.py", "def get_handler(): ...\n"),
+                (
+                    "b/b.py",
+                    "async def get_handler(): ...\nasync def ws_handler(): ...\n",
+                ),
+                ("c/helpers.py", "def helper(): ...\n"),
+                ("d/d.py", GET),
+            ],
+        );
+        let (mut invalid, conflicts) = scripts.conflicts("");
+        invalid.sort();
+        assert!(conflicts.is_empty());
+        assert_eq!(invalid.len(), 2, "{invalid:?}");
+        assert!(invalid[0].contains("a.py"));
+        assert!(invalid[1].contains("b.py"));
+    }
 }
@@ -0,0 +1,193 @@
+//! CPU budgets for handlers, which are measured as the time the event loop spends
+//! running each step of a handler's coroutine
+
+use std::{
+    cell::Cell,
+    ffi::c_long,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use hypermangle_py::CpuBudgetExceeded;
+use log::error;
+use parking_lot::Mutex;
+use pyo3::{intern, prelude::*, type_object::PyTypeInfo, types::PyTuple, AsPyPointer};
+
+struct RunningStep {
+    id: u64,
+    deadline: Instant,
+    thread: c_long,
+}
+
+/// The step being run on the event loop. Only changed while holding the GIL, so the
+/// watchdog sees a consistent value while holding it too
+static RUNNING: Mutex<Option<RunningStep>> = Mutex::new(None);
+/// The coroutine the watchdog raised `CpuBudgetExceeded` in, or 0
+static KILLED: AtomicU64 = AtomicU64::new(0);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static THREAD_IDENT: Cell<Option<c_long>> = const { Cell::new(None) };
+}
+
+fn thread_ident(py: Python) -> c_long {
+    THREAD_IDENT.with(|ident| {
+        ident.get().unwrap_or_else(|| {
+            let x = py
+                .import("threading")
+                .and_then(|x| x.call_method0("get_ident"))
+                .and_then(|x| x.extract())
+                .expect("threading.get_ident should return the thread id");
+            ident.set(Some(x));
+            x
+        })
+    })
+}
+
+/// Raises `CpuBudgetExceeded` in steps that run past their deadline, which is the
+/// only way to stop a loop that never awaits
+fn spawn_watchdog() {
+    static SPAWNED: std::sync::Once = std::sync::Once::new();
+    SPAWNED.call_once(|| {
+        std::thread::spawn(|| loop {
+            std::thread::sleep(Duration::from_millis(5));
+            let Some(id) = RUNNING
+                .lock()
+                .as_ref()
+                .filter(|x| x.deadline <= Instant::now() && KILLED.load(Ordering::Relaxed) != x.id)
+                .map(|x| x.id)
+            else {
+                continue;
+            };
+
+            Python::with_gil(|py| {
+                let running = RUNNING.lock();
+                let Some(step) = running.as_ref().filter(|x| x.id == id) else {
+                    return;
+                };
+                KILLED.store(id, Ordering::Relaxed);
+                unsafe {
+                    pyo3::ffi::PyThreadState_SetAsyncExc(
+                        step.thread,
+                        CpuBudgetExceeded::type_object(py).as_ptr(),
+                    );
+                }
+            });
+        });
+    });
+}
+
+/// Wraps a handler's coroutine, aborting it with `CpuBudgetExceeded` once its steps
+/// have taken longer than the budget altogether
+#[pyclass]
+pub(super) struct BudgetedCoroutine {
+    inner: PyObject,
+    id: u64,
+    remaining: Duration,
+}
+
+impl BudgetedCoroutine {
+    pub(super) fn new(inner: PyObject, budget: Duration) -> Self {
+        spawn_watchdog();
+        Self {
+            inner,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            remaining: budget,
+        }
+    }
+
+    fn step(
+        &mut self,
+        py: Python,
+        f: impl FnOnce(&PyAny) -> PyResult<PyObject>,
+    ) -> PyResult<PyObject> {
+        let thread = thread_ident(py);
+        let start = Instant::now();
+        *RUNNING.lock() = Some(RunningStep {
+            id: self.id,
+            deadline: start + self.remaining,
+            thread,
+        });
+        let result = f(self.inner.as_ref(py));
+        *RUNNING.lock() = None;
+        if KILLED.load(Ordering::Relaxed) == self.id {
+            // The exception may not have been raised yet, and would otherwise hit
+            // whatever runs on the event loop next
+            unsafe {
+                pyo3::ffi::PyThreadState_SetAsyncExc(thread, std::ptr::null_mut());
+            }
+            KILLED.store(0, Ordering::Relaxed);
+        }
+
+        let elapsed = start.elapsed();
+        if elapsed < self.remaining {
+            self.remaining -= elapsed;
+            return result;
+        }
+        self.remaining = Duration::ZERO;
+        match result {
+            Ok(_) => {
+                self.inner.call_method0(py, intern!(py, "close"))?;
+                Err(CpuBudgetExceeded::new_err(()))
+            }
+            Err(e) if e.is_instance_of::<CpuBudgetExceeded>(py) => Err(e),
+            // Finished just in time
+            Err(e) if e.is_instance_of::<pyo3::exceptions::PyStopIteration>(py) => Err(e),
+            Err(_) => Err(CpuBudgetExceeded::new_err(())),
+        }
+    }
+}
+
+#[pymethods]
+impl BudgetedCoroutine {
+    fn __await__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python) -> PyResult<Option<PyObject>> {
+        self.send(py, py.None()).map(Some)
+    }
+
+    fn send(&mut self, py: Python, value: PyObject) -> PyResult<PyObject> {
+        self.step(py, |inner| {
+            inner
+                .call_method1(intern!(py, "send"), (value,))
+                .map(Into::into)
+        })
+    }
+
+    #[pyo3(signature = (*args))]
+    fn throw(&mut self, py: Python, args: &PyTuple) -> PyResult<PyObject> {
+        self.step(py, |inner| {
+            inner
+                .call_method1(intern!(py, "throw"), args)
+                .map(Into::into)
+        })
+    }
+
+    fn close(&mut self, py: Python) -> PyResult<()> {
+        self.inner.call_method0(py, intern!(py, "close")).map(drop)
+    }
+}
+
+pub(super) fn is_exceeded(error: &PyErr) -> bool {
+    Python::with_gil(|py| error.is_instance_of::<CpuBudgetExceeded>(py))
+}
+
+pub(super) fn exceeded_response(script: &std::path::Path, handler: &str, route: &str) -> Response {
+    error!("{handler} in {script:?} ran out of CPU budget, so it was aborted");
+    crate::metrics::increment(
+        "hypermangle_cpu_budget_exceeded_total",
+        "Handler invocations aborted for running out of CPU budget, by route",
+        &[("route", route)],
+    );
+    StatusCode::SERVICE_UNAVAILABLE.into_response()
+}
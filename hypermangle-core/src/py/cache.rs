@@ -0,0 +1,241 @@
+use std::{
+    ffi::CString,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Instant,
+};
+
+use fxhash::FxHashMap;
+use log::{debug, info};
+use parking_lot::Mutex;
+use pyo3::{
+    intern,
+    types::{PyBytes, PyModule},
+    AsPyPointer, PyObject, PyResult, Python,
+};
+
+/// Compiled code objects of every script, along with the source they were compiled
+/// from
+static CODE_CACHE: OnceLock<Mutex<FxHashMap<PathBuf, (SourceKey, PyObject)>>> = OnceLock::new();
+
+const CACHE_EXTENSION: &str = "hmcode";
+
+/// Tells sources apart by their length and SHA-256 digest, so that code is never
+/// reused for a source it was not compiled from
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct SourceKey {
+    len: usize,
+    digest: [u8; 32],
+}
+
+impl SourceKey {
+    fn of(source: &str) -> Self {
+        Self {
+            len: source.len(),
+            digest: openssl::sha::sha256(source.as_bytes()),
+        }
+    }
+}
+
+impl std::fmt::Display for SourceKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:x}-", self.len)?;
+        self.digest.iter().try_for_each(|x| write!(f, "{x:02x}"))
+    }
+}
+
+/// Marshaled code objects are stored next to the script in `__pycache__`, tagged
+/// with the interpreter version since the marshal format is not stable across them
+fn disk_cache_path(py: Python, path: &Path, key: SourceKey) -> PyResult<PathBuf> {
+    let cache_tag: String = py
+        .import(intern!(py, "sys"))?
+        .getattr(intern!(py, "implementation"))?
+        .getattr(intern!(py, "cache_tag"))?
+        .extract()?;
+    let file_name = path.file_name().unwrap().to_string_lossy();
+
+    Ok(path
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join("__pycache__")
+        .join(format!("{file_name}.{key}.{cache_tag}.{CACHE_EXTENSION}")))
+}
+
+/// Removes cached code of older versions of the script at `path`
+fn remove_stale(path: &Path, current: &Path) {
+    let Some(dir) = current.parent() else {
+        return;
+    };
+    let Ok(entries) = dir.read_dir() else {
+        return;
+    };
+    let prefix = format!("{}.", path.file_name().unwrap().to_string_lossy());
+
+    for entry in entries.flatten() {
+        let entry_path = entry.path();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if entry_path != current
+            && name.starts_with(&prefix)
+            && name.ends_with(CACHE_EXTENSION)
+            // Guard against scripts whose names are prefixes of other scripts
+            && name[prefix.len()..].split('.').count() == 3
+        {
+            let _ = std::fs::remove_file(entry_path);
+        }
+    }
+}
+
+fn compile_uncached(py: Python, path: &Path, source: &str, key: SourceKey) -> PyResult<PyObject> {
+    let marshal = py.import(intern!(py, "marshal"))?;
+    let disk_path = disk_cache_path(py, path, key)?;
+
+    if let Ok(bytes) = std::fs::read(&disk_path) {
+        match marshal.call_method1(intern!(py, "loads"), (PyBytes::new(py, &bytes),)) {
+            Ok(code) => return Ok(code.into()),
+            Err(e) => debug!("Ignoring unreadable code cache {disk_path:?}: {e}"),
+        }
+    }
+
+    let code = py.import(intern!(py, "builtins"))?.call_method1(
+        intern!(py, "compile"),
+        (source, path.to_string_lossy(), "exec"),
+    )?;
+
+    let bytes = marshal.call_method1(intern!(py, "dumps"), (code,))?;
+    let bytes = bytes.downcast::<PyBytes>()?.as_bytes();
+    let written = std::fs::create_dir_all(disk_path.parent().unwrap())
+        .and_then(|_| std::fs::write(&disk_path, bytes));
+    match written {
+        Ok(()) => remove_stale(path, &disk_path),
+        Err(e) => debug!("Failed to write code cache {disk_path:?}: {e}"),
+    }
+
+    Ok(code.into())
+}
+
+fn compile(py: Python, path: &Path, source: &str) -> PyResult<PyObject> {
+    let key = SourceKey::of(source);
+    let cache = CODE_CACHE.get_or_init(Default::default);
+
+    if let Some((cached_key, code)) = cache.lock().get(path) {
+        if *cached_key == key {
+            return Ok(code.clone_ref(py));
+        }
+    }
+
+    let code = compile_uncached(py, path, source, key)?;
+    cache
+        .lock()
+        .insert(path.to_owned(), (key, code.clone_ref(py)));
+    Ok(code)
+}
+
+/// Equivalent to `PyModule::from_code`, except that the compiled code is reused
+/// whenever the source has not changed
+pub(super) fn module_from_source<'py>(
+    py: Python<'py>,
+    path: &Path,
+    source: &str,
+    module_name: &str,
+) -> PyResult<&'py PyModule> {
+    let code = compile(py, path, source)?;
+    let tenant = crate::tenants::for_script(path);
+    // Tenants get their own modules, even for scripts with the same names
+    let module_name = match tenant {
+        Some(tenant) => format!("hypermangle_tenants.{}.{module_name}", tenant.name),
+        None => module_name.to_owned(),
+    };
+    let module_name = CString::new(module_name).expect("Script filename should not contain NUL");
+    let file_name = CString::new(path.to_string_lossy().as_bytes())
+        .expect("Script path should not contain NUL");
+
+    let builtins = crate::sandbox::restricted_builtins(py);
+    if builtins.is_some() || tenant.is_some() {
+        let module: &PyModule = unsafe {
+            py.from_borrowed_ptr_or_err(pyo3::ffi::PyImport_AddModule(module_name.as_ptr()))?
+        };
+        // Code is only executed with the builtins it finds in its module if there are any
+        if let Some(builtins) = builtins {
+            module
+                .dict()
+                .set_item(intern!(py, "__builtins__"), builtins)?;
+        }
+    }
+    crate::sandbox::register_script(py, &code, tenant.map(|x| x.name.as_str()))?;
+
+    unsafe {
+        py.from_owned_ptr_or_err(pyo3::ffi::PyImport_ExecCodeModuleEx(
+            module_name.as_ptr(),
+            code.as_ptr(),
+            file_name.as_ptr(),
+        ))
+    }
+}
+
+fn collect_scripts(dir: &Path, scripts: &mut Vec<PathBuf>) {
+    let Ok(entries) = dir.read_dir() else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(x) if x.is_dir() => collect_scripts(&path, scripts),
+            Ok(x) if x.is_file() && path.extension().is_some_and(|x| x == "py") => {
+                scripts.push(path)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Reads every script under `root` on a thread per core, then compiles them into the
+/// cache one after another, so that loading them into the router does not pay for
+/// compilation
+pub(crate) fn precompile_scripts(root: &Path) {
+    let start = Instant::now();
+    let mut paths = vec![];
+    collect_scripts(root, &mut paths);
+    if paths.is_empty() {
+        return;
+    }
+
+    let threads = std::thread::available_parallelism()
+        .map(Into::into)
+        .unwrap_or(1usize);
+    let chunk_size = paths.len().div_ceil(threads);
+    let sources: Vec<_> = std::thread::scope(|s| {
+        let handles: Vec<_> = paths
+            .chunks(chunk_size)
+            .map(|chunk| {
+                s.spawn(move || {
+                    chunk
+                        .iter()
+                        .filter_map(|path| Some((path, read_to_string(path).ok()?)))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|x| x.join().expect("Script reader thread should not panic"))
+            .collect()
+    });
+
+    // Compiling holds the GIL, so only reading is parallel
+    Python::with_gil(|py| {
+        for (path, source) in &sources {
+            if let Err(e) = compile(py, path, source) {
+                // Reported properly when the script gets loaded
+                debug!("Failed to precompile {path:?}: {e}");
+            }
+        }
+    });
+
+    info!(
+        "Precompiled {} scripts in {:?}",
+        sources.len(),
+        start.elapsed()
+    );
+}
@@ -0,0 +1,168 @@
+//! `CACHE_CONTROL`, which scripts set to let clients and proxies cache the responses
+//! of their `get_handler`, and revalidate them with ETags
+
+use axum::{
+    body::Body,
+    http::{
+        header::{CACHE_CONTROL, CONTENT_LENGTH, ETAG, IF_NONE_MATCH},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use pyo3::{intern, types::PyModule};
+
+use crate::compression::StreamedBody;
+
+/// The `CACHE_CONTROL` of `module`, if it declares one
+pub(super) fn from_module(module: &PyModule) -> Result<Option<HeaderValue>, String> {
+    let Ok(value) = module.getattr(intern!(module.py(), "CACHE_CONTROL")) else {
+        return Ok(None);
+    };
+    let value: &str = value
+        .extract()
+        .map_err(|_| format!("CACHE_CONTROL should be a string, not {value}"))?;
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| format!("CACHE_CONTROL should be a valid header value, not {value:?}"))
+}
+
+/// A weak ETag, as compression changes the bytes sent but not what they mean
+fn etag(body: &[u8]) -> HeaderValue {
+    let digest = openssl::sha::sha256(body);
+    let hex: String = digest[..16].iter().map(|x| format!("{x:02x}")).collect();
+    HeaderValue::try_from(format!("W/\"{hex}\"")).expect("ETag should be a valid header value")
+}
+
+/// Whether `If-None-Match` names `etag`, compared weakly
+fn none_match(request_headers: &HeaderMap, etag: &HeaderValue) -> bool {
+    let strip = |x: &str| x.trim().trim_start_matches("W/").to_owned();
+    let Ok(etag) = etag.to_str().map(strip) else {
+        return false;
+    };
+    request_headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .any(|x| x.trim() == "*" || strip(x) == etag)
+}
+
+/// Sends `cache_control` with successful responses that did not set their own, along
+/// with an ETag of the body, so that clients holding the same body get 304 instead.
+/// Streamed bodies are not known until they are sent, so they get no ETag
+pub(super) async fn apply(
+    response: Response,
+    cache_control: &HeaderValue,
+    request_headers: &HeaderMap,
+) -> Response {
+    if !response.status().is_success() {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let cache_control = parts
+        .headers
+        .entry(CACHE_CONTROL)
+        .or_insert(cache_control.clone())
+        .clone();
+    let no_store = cache_control.to_str().is_ok_and(|x| {
+        x.split(',')
+            .any(|x| x.trim().eq_ignore_ascii_case("no-store"))
+    });
+    if no_store || parts.extensions.get::<StreamedBody>().is_some() {
+        return Response::from_parts(parts, body);
+    }
+
+    let Ok(bytes) = hyper::body::to_bytes(body).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let etag = parts
+        .headers
+        .entry(ETAG)
+        .or_insert_with(|| etag(&bytes))
+        .clone();
+    if none_match(request_headers, &etag) {
+        // The length the body would have had, rather than that of the empty one
+        parts.status = StatusCode::NOT_MODIFIED;
+        parts.headers.insert(CONTENT_LENGTH, bytes.len().into());
+        return Response::from_parts(parts, axum::body::boxed(Body::empty()));
+    }
+    Response::from_parts(parts, axum::body::boxed(Body::from(bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBLIC: HeaderValue = HeaderValue::from_static("public, max-age=60");
+
+    fn request_headers(if_none_match: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(x) = if_none_match {
+            headers.insert(IF_NONE_MATCH, x.parse().unwrap());
+        }
+        headers
+    }
+
+    async fn body(response: Response) -> Vec<u8> {
+        hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    #[tokio::test]
+    async fn successful_responses_get_cache_control_and_an_etag() {
+        let response = apply("hello".into_response(), &PUBLIC, &request_headers(None)).await;
+        assert_eq!(response.headers()[CACHE_CONTROL], PUBLIC);
+        assert_eq!(response.headers()[ETAG], etag(b"hello"));
+        assert_eq!(body(response).await, b"hello");
+    }
+
+    #[tokio::test]
+    async fn matching_etags_get_304_with_the_length_of_the_body() {
+        let tag = etag(b"hello");
+        let strong = tag.to_str().unwrap().trim_start_matches("W/").to_owned();
+        for if_none_match in [tag.to_str().unwrap(), &strong, "\"other\", *"] {
+            let headers = request_headers(Some(if_none_match));
+            let response = apply("hello".into_response(), &PUBLIC, &headers).await;
+            assert_eq!(
+                response.status(),
+                StatusCode::NOT_MODIFIED,
+                "{if_none_match}"
+            );
+            assert_eq!(response.headers()[CONTENT_LENGTH], "5");
+            assert!(body(response).await.is_empty());
+        }
+
+        let headers = request_headers(Some("\"other\""));
+        let response = apply("hello".into_response(), &PUBLIC, &headers).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn responses_keep_their_own_headers() {
+        let response = ([(CACHE_CONTROL, "private"), (ETAG, "\"mine\"")], "hello").into_response();
+        let headers = request_headers(Some("\"mine\""));
+        let response = apply(response, &PUBLIC, &headers).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[CACHE_CONTROL], "private");
+    }
+
+    #[tokio::test]
+    async fn errors_no_store_and_streams_get_no_etag() {
+        let response = (StatusCode::NOT_FOUND, "missing").into_response();
+        let response = apply(response, &PUBLIC, &request_headers(None)).await;
+        assert!(!response.headers().contains_key(CACHE_CONTROL));
+
+        let no_store = HeaderValue::from_static("no-cache, No-Store");
+        let response = apply("hello".into_response(), &no_store, &request_headers(None)).await;
+        assert!(!response.headers().contains_key(ETAG));
+
+        let mut response = "hello".into_response();
+        response.extensions_mut().insert(StreamedBody);
+        let response = apply(response, &PUBLIC, &request_headers(Some("*"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(ETAG));
+    }
+}
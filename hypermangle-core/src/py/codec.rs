@@ -0,0 +1,594 @@
+use std::path::Path;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::FromRequest,
+    http::{header::CONTENT_TYPE, HeaderMap, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use hypermangle_py::{buffers, NdJsonLines};
+use pyo3::{
+    intern,
+    types::{PyBytes, PyModule},
+    IntoPy, Py, PyAny, PyObject, PyResult, Python, ToPyObject,
+};
+
+#[cfg(feature = "protobuf")]
+type Message = prost_reflect::MessageDescriptor;
+#[cfg(not(feature = "protobuf"))]
+type Message = std::convert::Infallible;
+
+/// The message types of a script's protobuf bodies. A script declares them by setting
+/// `PROTOBUF_DESCRIPTOR_SET` to a compiled descriptor set, relative to the script, and
+/// `PROTOBUF_REQUEST` and `PROTOBUF_RESPONSE` to the full names of messages in it
+#[derive(Debug, Default)]
+pub(super) struct ProtobufMessages {
+    request: Option<Message>,
+    pub(super) response: Option<Message>,
+}
+
+impl ProtobufMessages {
+    pub(super) fn from_module(module: &PyModule, script: &Path) -> Result<Self, String> {
+        let py = module.py();
+        let Ok(descriptor_set) = module.getattr(intern!(py, "PROTOBUF_DESCRIPTOR_SET")) else {
+            return Ok(Self::default());
+        };
+        let descriptor_set: std::path::PathBuf =
+            descriptor_set.extract().map_err(|e| e.to_string())?;
+        let message_name = |name| match module.getattr(name) {
+            Ok(x) => x.extract().map(Some).map_err(|e| e.to_string()),
+            Err(_) => Ok(None),
+        };
+
+        Self::load(
+            &script
+                .parent()
+                .expect("Script should be in a directory")
+                .join(descriptor_set),
+            message_name(intern!(py, "PROTOBUF_REQUEST"))?,
+            message_name(intern!(py, "PROTOBUF_RESPONSE"))?,
+        )
+    }
+
+    #[cfg(feature = "protobuf")]
+    fn load(
+        path: &Path,
+        request: Option<String>,
+        response: Option<String>,
+    ) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("{path:?} should be readable: {e}"))?;
+        let pool = prost_reflect::DescriptorPool::decode(bytes.as_slice())
+            .map_err(|e| format!("{path:?} should be a descriptor set: {e}"))?;
+        let message = |name: Option<String>| {
+            name.map(|name| {
+                pool.get_message_by_name(&name)
+                    .ok_or_else(|| format!("{path:?} does not define {name}"))
+            })
+            .transpose()
+        };
+        Ok(Self {
+            request: message(request)?,
+            response: message(response)?,
+        })
+    }
+
+    #[cfg(not(feature = "protobuf"))]
+    fn load(_: &Path, _: Option<String>, _: Option<String>) -> Result<Self, String> {
+        Err(
+            "PROTOBUF_DESCRIPTOR_SET needs hypermangle to be built with the `protobuf` feature"
+                .into(),
+        )
+    }
+}
+
+/// Binary formats that bodies are decoded from and responses are encoded to, so
+/// that handlers can work with native objects
+#[derive(Clone)]
+pub(super) enum Codec {
+    #[cfg(feature = "msgpack")]
+    MsgPack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+    #[cfg(feature = "protobuf")]
+    Protobuf(Message),
+}
+
+impl Codec {
+    /// `protobuf` is the message that protobuf bodies are expected to be, if the
+    /// script declared one
+    #[cfg_attr(not(feature = "protobuf"), allow(unused_variables))]
+    pub(super) fn from_media_type(media_type: &str, protobuf: Option<&Message>) -> Option<Self> {
+        let media_type = media_type.split(';').next().unwrap_or_default().trim();
+        match media_type.to_ascii_lowercase().as_str() {
+            #[cfg(feature = "msgpack")]
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Self::MsgPack)
+            }
+            #[cfg(feature = "cbor")]
+            "application/cbor" => Some(Self::Cbor),
+            #[cfg(feature = "protobuf")]
+            "application/x-protobuf"
+            | "application/protobuf"
+            | "application/vnd.google.protobuf" => protobuf.cloned().map(Self::Protobuf),
+            _ => None,
+        }
+    }
+
+    pub(super) fn of_request(headers: &HeaderMap, protobuf: Option<&Message>) -> Option<Self> {
+        Self::from_media_type(headers.get(CONTENT_TYPE)?.to_str().ok()?, protobuf)
+    }
+
+    pub(super) fn content_type(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "msgpack")]
+            Self::MsgPack => "application/msgpack",
+            #[cfg(feature = "cbor")]
+            Self::Cbor => "application/cbor",
+            #[cfg(feature = "protobuf")]
+            Self::Protobuf(_) => "application/x-protobuf",
+        }
+    }
+
+    /// Protobuf messages that only have default values are encoded as nothing
+    fn decodes_empty(&self) -> bool {
+        match *self {
+            #[cfg(feature = "protobuf")]
+            Self::Protobuf(_) => true,
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    /// Decodes a single value, rejecting any bytes after it. Without any codec
+    /// features there are no variants, so `body` goes unused
+    #[cfg_attr(
+        not(any(feature = "msgpack", feature = "cbor", feature = "protobuf")),
+        allow(unused_variables)
+    )]
+    fn decode(&self, body: &[u8]) -> Result<PyObject, ()> {
+        match *self {
+            #[cfg(feature = "msgpack")]
+            Self::MsgPack => {
+                let mut reader = body;
+                let value = rmpv::decode::read_value(&mut reader).map_err(|_| ())?;
+                if !reader.is_empty() {
+                    return Err(());
+                }
+                Python::with_gil(|py| msgpack::to_py(py, value)).map_err(|_| ())
+            }
+            #[cfg(feature = "cbor")]
+            Self::Cbor => {
+                let mut reader = body;
+                let value = ciborium::from_reader(&mut reader).map_err(|_| ())?;
+                if !reader.is_empty() {
+                    return Err(());
+                }
+                Python::with_gil(|py| cbor::to_py(py, value)).map_err(|_| ())
+            }
+            #[cfg(feature = "protobuf")]
+            Self::Protobuf(ref message) => {
+                let message =
+                    prost_reflect::DynamicMessage::decode(message.clone(), body).map_err(|_| ())?;
+                Python::with_gil(|py| protobuf::to_py(py, &message)).map_err(|_| ())
+            }
+        }
+    }
+
+    #[cfg_attr(
+        not(any(feature = "msgpack", feature = "cbor", feature = "protobuf")),
+        allow(unused_variables)
+    )]
+    pub(super) fn encode_body(&self, obj: &PyAny) -> PyResult<Vec<u8>> {
+        match *self {
+            #[cfg(feature = "msgpack")]
+            Self::MsgPack => {
+                let mut body = vec![];
+                rmpv::encode::write_value(&mut body, &msgpack::from_py(obj)?)
+                    .expect("Writing to a Vec should not fail");
+                Ok(body)
+            }
+            #[cfg(feature = "cbor")]
+            Self::Cbor => {
+                let mut body = vec![];
+                ciborium::into_writer(&cbor::from_py(obj)?, &mut body)
+                    .expect("Writing to a Vec should not fail");
+                Ok(body)
+            }
+            #[cfg(feature = "protobuf")]
+            Self::Protobuf(ref message) => {
+                use prost_reflect::prost::Message;
+                Ok(protobuf::from_py(obj, message)?.encode_to_vec())
+            }
+        }
+    }
+}
+
+/// Lines of NDJSON bodies, which are not buffered whole, can be as long as other
+/// bodies can by default
+const MAX_NDJSON_LINE_BYTES: usize = 2 * 1024 * 1024;
+
+/// Whether `media_type` is that of newline delimited JSON
+pub(super) fn is_ndjson(media_type: &str) -> bool {
+    let media_type = media_type.split(';').next().unwrap_or_default().trim();
+    [
+        "application/x-ndjson",
+        "application/ndjson",
+        "application/jsonl",
+    ]
+    .iter()
+    .any(|x| media_type.eq_ignore_ascii_case(x))
+}
+
+/// A request body as it is passed to a handler
+pub(super) enum RequestBody {
+    Raw(Bytes),
+    Decoded(PyObject),
+    /// Parsed a line at a time as the handler iterates over it
+    NdJson(Body),
+}
+
+impl RequestBody {
+    /// Reads the body of `request`, unless it is NDJSON, which is streamed to the
+    /// handler instead
+    pub(super) async fn from_request(
+        headers: &HeaderMap,
+        request: Request<Body>,
+        protobuf: &ProtobufMessages,
+    ) -> Result<Self, Response> {
+        if headers
+            .get(CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok())
+            .is_some_and(is_ndjson)
+        {
+            return Ok(Self::NdJson(request.into_body()));
+        }
+        let body = Bytes::from_request(request, &())
+            .await
+            .map_err(IntoResponse::into_response)?;
+        Self::parse(headers, body, protobuf).map_err(IntoResponse::into_response)
+    }
+
+    /// Decodes bodies sent in the format of a codec, responding with 400 if they are
+    /// not valid. Protobuf bodies stay raw unless the script declared a request message
+    fn parse(
+        headers: &HeaderMap,
+        body: Bytes,
+        protobuf: &ProtobufMessages,
+    ) -> Result<Self, StatusCode> {
+        match Codec::of_request(headers, protobuf.request.as_ref()) {
+            Some(codec) if !body.is_empty() || codec.decodes_empty() => codec
+                .decode(&body)
+                .map(Self::Decoded)
+                .map_err(|()| StatusCode::BAD_REQUEST),
+            _ => Ok(Self::Raw(body)),
+        }
+    }
+
+    /// Raw bodies are passed as `str` when they are UTF-8, and `bytes` otherwise,
+    /// unless `memoryview` is set
+    pub(super) fn into_object(self, py: Python, memoryview: bool) -> PyObject {
+        match self {
+            Self::Raw(body) if memoryview => {
+                buffers::memoryview(py, body).expect("Request bodies should be viewable")
+            }
+            Self::Raw(body) => {
+                if let Ok(body) = std::str::from_utf8(&body) {
+                    body.to_object(py)
+                } else {
+                    // Not `to_object`, which makes a list of ints
+                    PyBytes::new(py, &body).into()
+                }
+            }
+            Self::Decoded(x) => x,
+            Self::NdJson(body) => Py::new(py, NdJsonLines::new(body, MAX_NDJSON_LINE_BYTES))
+                .expect("NdJsonLines should be creatable")
+                .into_py(py),
+        }
+    }
+}
+
+#[cfg(feature = "msgpack")]
+mod msgpack {
+    use pyo3::{
+        exceptions::{PyOverflowError, PyTypeError},
+        types::{PyBool, PyBytes, PyDict, PyFloat, PyList, PyLong, PyString, PyTuple},
+        IntoPy, PyAny, PyObject, PyResult, Python,
+    };
+    use rmpv::Value;
+
+    pub(super) fn to_py(py: Python, value: Value) -> PyResult<PyObject> {
+        Ok(match value {
+            Value::Nil => py.None(),
+            Value::Boolean(x) => x.into_py(py),
+            Value::Integer(x) => match x.as_i64() {
+                Some(x) => x.into_py(py),
+                None => x.as_u64().into_py(py),
+            },
+            Value::F32(x) => x.into_py(py),
+            Value::F64(x) => x.into_py(py),
+            Value::String(x) => match x.as_str() {
+                Some(x) => x.into_py(py),
+                None => PyBytes::new(py, x.as_bytes()).into(),
+            },
+            Value::Binary(x) => PyBytes::new(py, &x).into(),
+            Value::Array(x) => {
+                let items = x
+                    .into_iter()
+                    .map(|x| to_py(py, x))
+                    .collect::<PyResult<Vec<_>>>()?;
+                PyList::new(py, items).into()
+            }
+            Value::Map(x) => {
+                let dict = PyDict::new(py);
+                for (key, value) in x {
+                    dict.set_item(to_py(py, key)?, to_py(py, value)?)?;
+                }
+                dict.into()
+            }
+            Value::Ext(kind, data) => (kind, PyBytes::new(py, &data)).into_py(py),
+        })
+    }
+
+    pub(super) fn from_py(obj: &PyAny) -> PyResult<Value> {
+        Ok(if obj.is_none() {
+            Value::Nil
+        } else if let Ok(x) = obj.downcast::<PyBool>() {
+            Value::Boolean(x.is_true())
+        } else if obj.is_instance_of::<PyLong>() {
+            match obj.extract::<i64>() {
+                Ok(x) => x.into(),
+                Err(_) => obj
+                    .extract::<u64>()
+                    .map_err(|_| PyOverflowError::new_err("int is too large for MessagePack"))?
+                    .into(),
+            }
+        } else if let Ok(x) = obj.downcast::<PyFloat>() {
+            Value::F64(x.value())
+        } else if let Ok(x) = obj.downcast::<PyString>() {
+            Value::String(x.to_str()?.into())
+        } else if let Ok(x) = obj.downcast::<PyBytes>() {
+            Value::Binary(x.as_bytes().to_vec())
+        } else if let Ok(x) = obj.downcast::<PyDict>() {
+            Value::Map(
+                x.iter()
+                    .map(|(k, v)| Ok((from_py(k)?, from_py(v)?)))
+                    .collect::<PyResult<_>>()?,
+            )
+        } else if obj.is_instance_of::<PyList>() || obj.is_instance_of::<PyTuple>() {
+            Value::Array(obj.iter()?.map(|x| from_py(x?)).collect::<PyResult<_>>()?)
+        } else {
+            return Err(PyTypeError::new_err(format!(
+                "{} cannot be encoded as MessagePack",
+                obj.get_type().name()?
+            )));
+        })
+    }
+}
+
+#[cfg(feature = "cbor")]
+mod cbor {
+    use ciborium::Value;
+    use pyo3::{
+        exceptions::{PyOverflowError, PyTypeError},
+        types::{PyBool, PyBytes, PyDict, PyFloat, PyList, PyLong, PyString, PyTuple},
+        IntoPy, PyAny, PyObject, PyResult, Python,
+    };
+
+    pub(super) fn to_py(py: Python, value: Value) -> PyResult<PyObject> {
+        Ok(match value {
+            Value::Null => py.None(),
+            Value::Bool(x) => x.into_py(py),
+            Value::Integer(x) => i128::from(x).into_py(py),
+            Value::Float(x) => x.into_py(py),
+            Value::Text(x) => x.into_py(py),
+            Value::Bytes(x) => PyBytes::new(py, &x).into(),
+            Value::Array(x) => {
+                let items = x
+                    .into_iter()
+                    .map(|x| to_py(py, x))
+                    .collect::<PyResult<Vec<_>>>()?;
+                PyList::new(py, items).into()
+            }
+            Value::Map(x) => {
+                let dict = PyDict::new(py);
+                for (key, value) in x {
+                    dict.set_item(to_py(py, key)?, to_py(py, value)?)?;
+                }
+                dict.into()
+            }
+            // Tags such as dates are not interpreted
+            Value::Tag(_, x) => to_py(py, *x)?,
+            _ => return Err(PyTypeError::new_err("Unsupported CBOR value")),
+        })
+    }
+
+    pub(super) fn from_py(obj: &PyAny) -> PyResult<Value> {
+        Ok(if obj.is_none() {
+            Value::Null
+        } else if let Ok(x) = obj.downcast::<PyBool>() {
+            Value::Bool(x.is_true())
+        } else if obj.is_instance_of::<PyLong>() {
+            let x: i128 = obj.extract()?;
+            Value::Integer(
+                x.try_into()
+                    .map_err(|_| PyOverflowError::new_err("int is too large for CBOR"))?,
+            )
+        } else if let Ok(x) = obj.downcast::<PyFloat>() {
+            Value::Float(x.value())
+        } else if let Ok(x) = obj.downcast::<PyString>() {
+            Value::Text(x.to_str()?.to_owned())
+        } else if let Ok(x) = obj.downcast::<PyBytes>() {
+            Value::Bytes(x.as_bytes().to_vec())
+        } else if let Ok(x) = obj.downcast::<PyDict>() {
+            Value::Map(
+                x.iter()
+                    .map(|(k, v)| Ok((from_py(k)?, from_py(v)?)))
+                    .collect::<PyResult<_>>()?,
+            )
+        } else if obj.is_instance_of::<PyList>() || obj.is_instance_of::<PyTuple>() {
+            Value::Array(obj.iter()?.map(|x| from_py(x?)).collect::<PyResult<_>>()?)
+        } else {
+            return Err(PyTypeError::new_err(format!(
+                "{} cannot be encoded as CBOR",
+                obj.get_type().name()?
+            )));
+        })
+    }
+}
+
+/// Messages are dicts of the fields that are set, keyed by the names in the `.proto`.
+/// Enums are their names, or numbers if they are unknown
+#[cfg(feature = "protobuf")]
+mod protobuf {
+    use prost_reflect::{DynamicMessage, Kind, MapKey, MessageDescriptor, Value};
+    use pyo3::{
+        exceptions::{PyTypeError, PyValueError},
+        types::{PyBytes, PyDict, PyList},
+        IntoPy, PyAny, PyObject, PyResult, Python,
+    };
+
+    pub(super) fn to_py(py: Python, message: &DynamicMessage) -> PyResult<PyObject> {
+        let dict = PyDict::new(py);
+        for (field, value) in message.fields() {
+            dict.set_item(field.name(), value_to_py(py, &field.kind(), value)?)?;
+        }
+        Ok(dict.into())
+    }
+
+    /// `kind` is the type of a single element for lists, and the entry type of maps
+    fn value_to_py(py: Python, kind: &Kind, value: &Value) -> PyResult<PyObject> {
+        Ok(match value {
+            Value::Bool(x) => x.into_py(py),
+            Value::I32(x) => x.into_py(py),
+            Value::I64(x) => x.into_py(py),
+            Value::U32(x) => x.into_py(py),
+            Value::U64(x) => x.into_py(py),
+            Value::F32(x) => x.into_py(py),
+            Value::F64(x) => x.into_py(py),
+            Value::String(x) => x.into_py(py),
+            Value::Bytes(x) => PyBytes::new(py, x).into(),
+            Value::EnumNumber(x) => match kind {
+                Kind::Enum(descriptor) => match descriptor.get_value(*x) {
+                    Some(value) => value.name().into_py(py),
+                    None => x.into_py(py),
+                },
+                _ => x.into_py(py),
+            },
+            Value::Message(x) => to_py(py, x)?,
+            Value::List(x) => {
+                let items = x
+                    .iter()
+                    .map(|x| value_to_py(py, kind, x))
+                    .collect::<PyResult<Vec<_>>>()?;
+                PyList::new(py, items).into()
+            }
+            Value::Map(x) => {
+                let Kind::Message(entry) = kind else {
+                    unreachable!("Maps should have an entry message")
+                };
+                let value_kind = entry.map_entry_value_field().kind();
+                let dict = PyDict::new(py);
+                for (key, value) in x {
+                    let key = match key {
+                        MapKey::Bool(x) => x.into_py(py),
+                        MapKey::I32(x) => x.into_py(py),
+                        MapKey::I64(x) => x.into_py(py),
+                        MapKey::U32(x) => x.into_py(py),
+                        MapKey::U64(x) => x.into_py(py),
+                        MapKey::String(x) => x.into_py(py),
+                    };
+                    dict.set_item(key, value_to_py(py, &value_kind, value)?)?;
+                }
+                dict.into()
+            }
+        })
+    }
+
+    /// Fields that are `None` are left unset
+    pub(super) fn from_py(obj: &PyAny, descriptor: &MessageDescriptor) -> PyResult<DynamicMessage> {
+        let mut message = DynamicMessage::new(descriptor.clone());
+        for (name, value) in obj.downcast::<PyDict>()? {
+            let name: &str = name.extract()?;
+            let field = descriptor.get_field_by_name(name).ok_or_else(|| {
+                PyValueError::new_err(format!(
+                    "{} has no field called {name}",
+                    descriptor.full_name()
+                ))
+            })?;
+            if value.is_none() {
+                continue;
+            }
+
+            let value = if field.is_map() {
+                let Kind::Message(entry) = field.kind() else {
+                    unreachable!("Maps should have an entry message")
+                };
+                let key_kind = entry.map_entry_key_field().kind();
+                let value_kind = entry.map_entry_value_field().kind();
+                Value::Map(
+                    value
+                        .downcast::<PyDict>()?
+                        .iter()
+                        .map(|(k, v)| {
+                            Ok((key_from_py(k, &key_kind)?, value_from_py(v, &value_kind)?))
+                        })
+                        .collect::<PyResult<_>>()?,
+                )
+            } else if field.is_list() {
+                Value::List(
+                    value
+                        .iter()?
+                        .map(|x| value_from_py(x?, &field.kind()))
+                        .collect::<PyResult<_>>()?,
+                )
+            } else {
+                value_from_py(value, &field.kind())?
+            };
+            message
+                .try_set_field(&field, value)
+                .map_err(|e| PyTypeError::new_err(format!("{name}: {e}")))?;
+        }
+        Ok(message)
+    }
+
+    fn value_from_py(obj: &PyAny, kind: &Kind) -> PyResult<Value> {
+        Ok(match kind {
+            Kind::Double => Value::F64(obj.extract()?),
+            Kind::Float => Value::F32(obj.extract()?),
+            Kind::Int32 | Kind::Sint32 | Kind::Sfixed32 => Value::I32(obj.extract()?),
+            Kind::Int64 | Kind::Sint64 | Kind::Sfixed64 => Value::I64(obj.extract()?),
+            Kind::Uint32 | Kind::Fixed32 => Value::U32(obj.extract()?),
+            Kind::Uint64 | Kind::Fixed64 => Value::U64(obj.extract()?),
+            Kind::Bool => Value::Bool(obj.extract()?),
+            Kind::String => Value::String(obj.extract()?),
+            Kind::Bytes => Value::Bytes(obj.downcast::<PyBytes>()?.as_bytes().to_vec().into()),
+            Kind::Message(descriptor) => Value::Message(from_py(obj, descriptor)?),
+            Kind::Enum(descriptor) => match obj.extract::<&str>() {
+                Ok(name) => Value::EnumNumber(
+                    descriptor
+                        .get_value_by_name(name)
+                        .ok_or_else(|| {
+                            PyValueError::new_err(format!(
+                                "{} has no value called {name}",
+                                descriptor.full_name()
+                            ))
+                        })?
+                        .number(),
+                ),
+                Err(_) => Value::EnumNumber(obj.extract()?),
+            },
+        })
+    }
+
+    fn key_from_py(obj: &PyAny, kind: &Kind) -> PyResult<MapKey> {
+        Ok(match value_from_py(obj, kind)? {
+            Value::Bool(x) => MapKey::Bool(x),
+            Value::I32(x) => MapKey::I32(x),
+            Value::I64(x) => MapKey::I64(x),
+            Value::U32(x) => MapKey::U32(x),
+            Value::U64(x) => MapKey::U64(x),
+            Value::String(x) => MapKey::String(x),
+            _ => unreachable!("Map keys should be integers, bools or strings"),
+        })
+    }
+}
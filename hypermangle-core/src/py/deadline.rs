@@ -0,0 +1,63 @@
+//! Deadlines for handlers, by which they are cancelled if they have not responded
+
+use std::time::{Duration, Instant};
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use log::error;
+use pyo3::{exceptions::PyTimeoutError, intern, prelude::*};
+
+/// When a handler started with `timeout` should have responded, both as an `Instant`
+/// and as the `time.monotonic()` that scripts compare against
+pub(super) struct Deadline {
+    at: Instant,
+    pub(super) monotonic: f64,
+    timeout: Duration,
+}
+
+impl Deadline {
+    pub(super) fn new(py: Python, timeout: Duration) -> Self {
+        let now = py
+            .import(intern!(py, "time"))
+            .and_then(|x| x.call_method0(intern!(py, "monotonic")))
+            .and_then(|x| x.extract::<f64>())
+            .expect("time.monotonic should be callable");
+        Self {
+            at: Instant::now() + timeout,
+            monotonic: now + timeout.as_secs_f64(),
+            timeout,
+        }
+    }
+
+    /// Wraps a handler's coroutine so that it is cancelled at the deadline, which
+    /// `asyncio.wait_for` then raises as `TimeoutError`
+    pub(super) fn wrap(&self, py: Python, coroutine: PyObject) -> PyObject {
+        py.import(intern!(py, "asyncio"))
+            .and_then(|x| {
+                x.call_method1(
+                    intern!(py, "wait_for"),
+                    (coroutine, self.timeout.as_secs_f64()),
+                )
+            })
+            .expect("asyncio.wait_for should be callable")
+            .into()
+    }
+
+    /// Whether `error` is the handler being cancelled, and not a timeout of its own
+    pub(super) fn is_exceeded(&self, error: &PyErr) -> bool {
+        Instant::now() >= self.at
+            && Python::with_gil(|py| error.is_instance_of::<PyTimeoutError>(py))
+    }
+}
+
+pub(super) fn exceeded_response(script: &std::path::Path, handler: &str, route: &str) -> Response {
+    error!("{handler} in {script:?} did not respond before its deadline, so it was cancelled");
+    crate::metrics::increment(
+        "hypermangle_handler_timeouts_total",
+        "Handler invocations cancelled for not responding before their deadline, by route",
+        &[("route", route)],
+    );
+    StatusCode::GATEWAY_TIMEOUT.into_response()
+}
@@ -0,0 +1,122 @@
+use std::thread::JoinHandle;
+
+use log::error;
+use pyo3::{PyObject, PyResult, Python};
+use pyo3_asyncio::TaskLocals;
+
+use crate::PY_TASK_LOCALS;
+
+/// The thread running the asyncio event loop that Python handlers are awaited on.
+/// Dropping it stops the event loop and waits for the thread, so that embedders
+/// starting several servers in one process, such as in tests, do not leak threads
+pub struct PythonRuntime {
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PythonRuntime {
+    /// Starts the event loop, returning once it is running, or why it could not be
+    /// started. Only one runtime can run at a time, as handlers find it through a
+    /// global
+    pub fn start() -> Result<Self, String> {
+        if Self::is_running() {
+            return Err("The Python runtime is already running".into());
+        }
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("python-event-loop".into())
+            .spawn(move || {
+                let event_loop = match Python::with_gil(new_event_loop) {
+                    Ok(x) => {
+                        let _ = sender.send(Ok(()));
+                        x
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Err(e.to_string()));
+                        return;
+                    }
+                };
+                let result = Python::with_gil(|py| {
+                    let event_loop = event_loop.as_ref(py);
+                    let result = event_loop.call_method0("run_forever");
+                    *PY_TASK_LOCALS.write() = None;
+                    event_loop.call_method0("close")?;
+                    result.map(drop)
+                });
+                if let Err(e) = result {
+                    error!("The Python event loop failed: {e}");
+                }
+            })
+            .map_err(|e| format!("Failed to spawn the Python runtime thread: {e}"))?;
+
+        match receiver.recv() {
+            Ok(Ok(())) => Ok(Self {
+                thread: Some(thread),
+            }),
+            Ok(Err(e)) => Err(format!("Failed to start the Python runtime: {e}")),
+            Err(_) => Err("The Python runtime thread panicked while starting".into()),
+        }
+    }
+
+    pub fn is_running() -> bool {
+        PY_TASK_LOCALS.read().is_some()
+    }
+
+    /// Stops the event loop once the callbacks already scheduled on it have run, and
+    /// waits for its thread. Handlers that are still awaiting are abandoned
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        let Some(thread) = self.thread.take() else {
+            return;
+        };
+        let locals = PY_TASK_LOCALS.read().clone();
+        if let Some(locals) = locals {
+            let result = Python::with_gil(|py| {
+                let event_loop = locals.event_loop(py);
+                event_loop
+                    .call_method1("call_soon_threadsafe", (event_loop.getattr("stop")?,))
+                    .map(drop)
+            });
+            if let Err(e) = result {
+                error!("Failed to stop the Python event loop: {e}");
+                return;
+            }
+        }
+        if thread.join().is_err() {
+            error!("The Python runtime thread panicked");
+        }
+    }
+}
+
+impl Drop for PythonRuntime {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn new_event_loop(py: Python) -> PyResult<PyObject> {
+    // Disable Ctrl-C handling. Handlers can only be set from the thread that
+    // initialized Python, which is a server thread if one got to the interpreter
+    // first, and setting it would then fail
+    let threading = py.import("threading")?;
+    if threading
+        .call_method0("current_thread")?
+        .is(threading.call_method0("main_thread")?)
+    {
+        let signal_module = py.import("signal")?;
+        signal_module.call_method1(
+            "signal",
+            (
+                signal_module.getattr("SIGINT")?,
+                signal_module.getattr("SIG_DFL")?,
+            ),
+        )?;
+    }
+
+    let event_loop = py.import("asyncio")?.call_method0("new_event_loop")?;
+    crate::sandbox::prepare_event_loop(event_loop)?;
+    *PY_TASK_LOCALS.write() = Some(TaskLocals::new(event_loop));
+    Ok(event_loop.into())
+}
@@ -0,0 +1,349 @@
+use std::{
+    fs::read_to_string,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::{
+    http::{
+        header::{ACCEPT, CONTENT_TYPE, VARY},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use pyo3::{
+    exceptions::{PyTypeError, PyValueError},
+    intern,
+    types::{PyBool, PyBytes, PyDict, PyFloat, PyList, PyLong, PyModule, PyString, PyTuple},
+    PyAny, PyResult,
+};
+
+use super::codec::{Codec, ProtobufMessages};
+
+/// Media types that wildcards in `Accept` stand for, in order of preference
+const MEDIA_TYPES: [&str; 5] = [
+    "application/json",
+    "text/html",
+    "application/msgpack",
+    "application/cbor",
+    "application/x-protobuf",
+];
+
+/// What a script declares about the formats of its bodies
+#[derive(Debug, Default)]
+pub(super) struct Formats {
+    pub(super) protobuf: ProtobufMessages,
+    /// Renders returned objects as HTML. Declared by setting `HTML_TEMPLATE` to a
+    /// template relative to the script
+    template: Option<Arc<Template>>,
+    /// Passes request bodies as read-only memoryviews of the bytes they were received
+    /// in. Declared by setting `MEMORYVIEW_BODIES = True`
+    pub(super) memoryview_bodies: bool,
+}
+
+impl Formats {
+    pub(super) fn from_module(module: &PyModule, script: &Path) -> Result<Self, String> {
+        let template = match module.getattr(intern!(module.py(), "HTML_TEMPLATE")) {
+            Ok(path) => {
+                let path: PathBuf = path.extract().map_err(|e| e.to_string())?;
+                let path = script
+                    .parent()
+                    .expect("Script should be in a directory")
+                    .join(path);
+                Some(Arc::new(Template::load(&path)?))
+            }
+            Err(_) => None,
+        };
+
+        Ok(Self {
+            protobuf: ProtobufMessages::from_module(module, script)?,
+            template,
+            memoryview_bodies: match module.getattr(intern!(module.py(), "MEMORYVIEW_BODIES")) {
+                Ok(x) => x.extract().map_err(|e| e.to_string())?,
+                Err(_) => false,
+            },
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct Template {
+    environment: minijinja::Environment<'static>,
+}
+
+impl Template {
+    fn load(path: &Path) -> Result<Self, String> {
+        let source =
+            read_to_string(path).map_err(|e| format!("{path:?} should be readable: {e}"))?;
+        let mut environment = minijinja::Environment::new();
+        // Whatever the template is called, it is rendered as HTML
+        environment.set_auto_escape_callback(|_| minijinja::AutoEscape::Html);
+        // Escaping would turn the slashes of URLs into entities
+        environment.add_function("asset_url", |path: String| {
+            minijinja::Value::from_safe_string(crate::static_files::asset_url(&path))
+        });
+        environment
+            .add_template_owned("template", source)
+            .map_err(|e| format!("{path:?} should be a valid template: {e}"))?;
+        Ok(Self { environment })
+    }
+
+    /// Dicts are the context of the template, and other objects are `value` in it.
+    /// `csp_nonce` is the nonce of the request, if it has one
+    fn render(&self, obj: &PyAny, csp_nonce: Option<&str>) -> PyResult<String> {
+        let value = template_value(obj)?;
+        let context = if obj.is_instance_of::<PyDict>() {
+            value
+        } else {
+            minijinja::context! { value }
+        };
+        let context = minijinja::context! { csp_nonce, ..context };
+        self.environment
+            .get_template("template")
+            .expect("Template should have been added")
+            .render(context)
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+}
+
+fn template_value(obj: &PyAny) -> PyResult<minijinja::Value> {
+    use minijinja::Value;
+
+    Ok(if obj.is_none() {
+        Value::from(())
+    } else if let Ok(x) = obj.downcast::<PyBool>() {
+        Value::from(x.is_true())
+    } else if obj.is_instance_of::<PyLong>() {
+        Value::from(obj.extract::<i128>()?)
+    } else if let Ok(x) = obj.downcast::<PyFloat>() {
+        Value::from(x.value())
+    } else if let Ok(x) = obj.downcast::<PyString>() {
+        Value::from(x.to_str()?)
+    } else if let Ok(x) = obj.downcast::<PyBytes>() {
+        Value::from_bytes(x.as_bytes().to_vec())
+    } else if let Ok(x) = obj.downcast::<PyDict>() {
+        x.iter()
+            .map(|(k, v)| Ok((template_value(k)?, template_value(v)?)))
+            .collect::<PyResult<Vec<_>>>()?
+            .into_iter()
+            .collect()
+    } else if obj.is_instance_of::<PyList>() || obj.is_instance_of::<PyTuple>() {
+        Value::from(
+            obj.iter()?
+                .map(|x| template_value(x?))
+                .collect::<PyResult<Vec<_>>>()?,
+        )
+    } else {
+        return Err(PyTypeError::new_err(format!(
+            "{} cannot be rendered in a template",
+            obj.get_type().name()?
+        )));
+    })
+}
+
+/// A media range in `Accept` and its quality
+fn parse_media_range(range: &str) -> Option<(String, f32)> {
+    let mut parts = range.split(';');
+    let media_type = parts.next()?.trim().to_ascii_lowercase();
+    if media_type.is_empty() {
+        return None;
+    }
+    let quality = parts
+        .find_map(|x| x.trim().strip_prefix("q="))
+        .and_then(|x| x.trim().parse().ok())
+        .unwrap_or(1.0);
+    Some((media_type, quality))
+}
+
+/// How an object that a handler returned is encoded
+pub(super) enum ResponseFormat {
+    Json,
+    /// Along with the CSP nonce of the request
+    Html(Arc<Template>, Option<Arc<str>>),
+    Codec(Codec),
+}
+
+impl ResponseFormat {
+    fn from_media_type(media_type: &str, formats: &Formats) -> Option<Self> {
+        match media_type {
+            "application/json" => Some(Self::Json),
+            "text/html" => formats.template.clone().map(|x| Self::Html(x, None)),
+            _ => Codec::from_media_type(media_type, formats.protobuf.response.as_ref())
+                .map(Self::Codec),
+        }
+    }
+
+    pub(super) fn with_csp_nonce(self, csp_nonce: Option<Arc<str>>) -> Self {
+        match self {
+            Self::Html(template, _) => Self::Html(template, csp_nonce),
+            other => other,
+        }
+    }
+
+    /// Picks the format the client prefers, by quality and then by the order of
+    /// `Accept`. Wildcards stand for the format of the request body, then for
+    /// `MEDIA_TYPES`, skipping those refused with `q=0`. Without `Accept`, bodies are
+    /// answered in their own format or as JSON. `None` means nothing acceptable is
+    /// available
+    pub(super) fn negotiate(headers: &HeaderMap, formats: &Formats) -> Option<Self> {
+        let request_codec = Codec::of_request(headers, formats.protobuf.response.as_ref());
+        let mut accepted: Vec<_> = headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|x| x.to_str().ok())
+            .flat_map(|x| x.split(','))
+            .filter_map(parse_media_range)
+            .collect();
+        if accepted.is_empty() {
+            return Some(request_codec.map_or(Self::Json, Self::Codec));
+        }
+        // The sort is stable, so ranges of the same quality stay in order
+        accepted.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        let refused = |media_type: &str| {
+            accepted
+                .iter()
+                .any(|(range, quality)| *quality <= 0.0 && range == media_type)
+        };
+        let candidates: Vec<&str> = request_codec
+            .as_ref()
+            .map(Codec::content_type)
+            .into_iter()
+            .chain(MEDIA_TYPES)
+            .collect();
+
+        accepted
+            .iter()
+            .filter(|(_, quality)| *quality > 0.0)
+            .find_map(|(range, _)| match range.strip_suffix("/*") {
+                Some(major) => candidates
+                    .iter()
+                    .filter(|x| major == "*" || x.split('/').next() == Some(major))
+                    .filter(|x| !refused(x))
+                    .find_map(|x| Self::from_media_type(x, formats)),
+                None => Self::from_media_type(range, formats),
+            })
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Html(..) => "text/html; charset=utf-8",
+            Self::Codec(codec) => codec.content_type(),
+        }
+    }
+
+    /// Encodes what a handler returned instead of a string or bytes
+    pub(super) fn encode(&self, status: StatusCode, obj: &PyAny) -> PyResult<Response> {
+        let body = match self {
+            Self::Json => obj
+                .py()
+                .import("json")?
+                .call_method1("dumps", (obj,))?
+                .extract::<String>()?
+                .into_bytes(),
+            Self::Html(template, csp_nonce) => {
+                template.render(obj, csp_nonce.as_deref())?.into_bytes()
+            }
+            Self::Codec(codec) => codec.encode_body(obj)?,
+        };
+        Ok((
+            status,
+            [
+                (CONTENT_TYPE, HeaderValue::from_static(self.content_type())),
+                (VARY, HeaderValue::from_static("accept")),
+            ],
+            body,
+        )
+            .into_response())
+    }
+}
+
+/// For objects returned to clients that accept none of the formats available
+pub(super) fn not_acceptable() -> Response {
+    (
+        StatusCode::NOT_ACCEPTABLE,
+        [(VARY, HeaderValue::from_static("accept"))],
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_template() -> Formats {
+        Formats {
+            template: Some(Arc::new(Template {
+                environment: minijinja::Environment::new(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// The content type `accept` is answered with
+    fn negotiated(accept: Option<&str>, formats: &Formats) -> Option<&'static str> {
+        let mut headers = HeaderMap::new();
+        if let Some(x) = accept {
+            headers.insert(ACCEPT, x.parse().unwrap());
+        }
+        ResponseFormat::negotiate(&headers, formats).map(|x| x.content_type())
+    }
+
+    const JSON: Option<&str> = Some("application/json");
+    const HTML: Option<&str> = Some("text/html; charset=utf-8");
+
+    #[test]
+    fn json_is_the_default() {
+        let formats = with_template();
+        assert_eq!(negotiated(None, &formats), JSON);
+        assert_eq!(negotiated(Some("*/*"), &formats), JSON);
+        assert_eq!(negotiated(Some("application/*"), &formats), JSON);
+    }
+
+    #[test]
+    fn ranges_are_picked_by_quality_then_order() {
+        let formats = with_template();
+        assert_eq!(
+            negotiated(Some("text/html, application/json"), &formats),
+            HTML
+        );
+        assert_eq!(
+            negotiated(Some("application/json, text/html"), &formats),
+            JSON
+        );
+        assert_eq!(
+            negotiated(Some("application/json;q=0.5, text/html"), &formats),
+            HTML
+        );
+        assert_eq!(
+            negotiated(Some("TEXT/HTML; q=0.9, */*;q=0.1"), &formats),
+            HTML
+        );
+    }
+
+    #[test]
+    fn wildcards_skip_refused_types() {
+        let formats = with_template();
+        assert_eq!(
+            negotiated(Some("*/*, application/json;q=0"), &formats),
+            HTML
+        );
+        assert_eq!(negotiated(Some("text/*"), &formats), HTML);
+    }
+
+    #[test]
+    fn unavailable_formats_are_not_acceptable() {
+        let formats = Formats::default();
+        assert_eq!(negotiated(Some("text/html"), &formats), None);
+        assert_eq!(negotiated(Some("text/*"), &formats), None);
+        assert_eq!(
+            negotiated(Some("image/png, application/json;q=0"), &formats),
+            None
+        );
+        assert_eq!(
+            negotiated(Some("text/html, application/json;q=0.1"), &formats),
+            JSON
+        );
+    }
+}
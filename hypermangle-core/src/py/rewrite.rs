@@ -0,0 +1,191 @@
+//! Lets `_router.py` at the root of a scripts folder rewrite requests, or respond to
+//! them itself, before they are routed
+
+use std::{fs::read_to_string, path::Path, sync::Arc};
+
+use axum::{
+    body::Body,
+    http::{header, uri::PathAndQuery, HeaderName, HeaderValue, Request, Uri},
+    response::Response,
+    Router,
+};
+use hypermangle_py::IncomingRequest;
+use parking_lot::Mutex;
+use pyo3::{intern, types::PyDict, Py, PyObject, Python, ToPyObject};
+
+use super::{cache, handler_context, negotiate, pyobject_to_response, raised};
+use crate::{shared_router::SharedRouter, task_locals};
+
+pub(crate) const ROUTER_SCRIPT: &str = "_router.py";
+
+const EXCEPTION_MSG: &str = "route should have ran without exceptions";
+
+fn load_route(path: &Path) -> PyObject {
+    let source = read_to_string(path).unwrap_or_else(|_| panic!("{path:?} should be readable"));
+    Python::with_gil(|py| {
+        let route = cache::module_from_source(py, path, &source, "_router")
+            .and_then(|x| x.getattr(intern!(py, "route")))
+            .unwrap_or_else(|e| panic!("{path:?} should define route: {e}"));
+        let is_async = py
+            .import(intern!(py, "inspect"))
+            .and_then(|x| x.call_method1(intern!(py, "iscoroutinefunction"), (route,)))
+            .and_then(|x| x.is_true())
+            .unwrap_or_default();
+        assert!(
+            is_async,
+            "route in {path:?} should be declared with `async def`"
+        );
+        route.to_object(py)
+    })
+}
+
+/// The headers of `request` by lowercase name, joined as in [`IncomingRequest`]
+fn headers_to_py(py: Python, request: &Request<Body>) -> Py<PyDict> {
+    let headers = PyDict::new(py);
+    for name in request.headers().keys() {
+        let separator = if name == header::COOKIE { "; " } else { ", " };
+        let value = request
+            .headers()
+            .get_all(name)
+            .iter()
+            .map(|x| String::from_utf8_lossy(x.as_bytes()))
+            .collect::<Vec<_>>()
+            .join(separator);
+        headers
+            .set_item(name.as_str(), value)
+            .expect("Header should be settable");
+    }
+    headers.into()
+}
+
+/// Applies what `route` changed in `incoming` to `request`, leaving the headers it
+/// did not touch as they were sent
+fn apply_changes(
+    py: Python,
+    incoming: &IncomingRequest,
+    original: &PyDict,
+    request: &mut Request<Body>,
+) {
+    let path = incoming.path.lock().clone();
+    let query = incoming.query.lock().clone();
+    if path != request.uri().path() || query != request.uri().query().unwrap_or_default() {
+        let path_and_query = if query.is_empty() {
+            path
+        } else {
+            format!("{path}?{query}")
+        };
+        let mut parts = request.uri().clone().into_parts();
+        parts.path_and_query = Some(
+            PathAndQuery::try_from(path_and_query)
+                .expect("Path set by route should have been validated"),
+        );
+        *request.uri_mut() = Uri::from_parts(parts).expect("Rewritten URI should be valid");
+    }
+
+    let headers = incoming.headers.as_ref(py);
+    for (name, value) in headers {
+        let name: &str = name
+            .extract()
+            .unwrap_or_else(|_| panic!("Header names set by route should be strings, not {name}"));
+        let value: &str = value
+            .extract()
+            .unwrap_or_else(|_| panic!("Header {name} set by route should be a string"));
+        let unchanged = original
+            .get_item(name)
+            .and_then(|x| x.extract::<&str>().ok())
+            .is_some_and(|x| x == value);
+        if unchanged {
+            continue;
+        }
+        let name = HeaderName::try_from(name)
+            .unwrap_or_else(|_| panic!("Header name {name:?} set by route should be valid"));
+        let value = HeaderValue::try_from(value)
+            .unwrap_or_else(|_| panic!("Header {name} set by route should have a valid value"));
+        request.headers_mut().insert(name, value);
+    }
+    for name in original.keys() {
+        if !headers.contains(name).unwrap_or(true) {
+            let name: &str = name.extract().expect("Header names should be strings");
+            request.headers_mut().remove(name);
+        }
+    }
+}
+
+async fn rewrite(route: &PyObject, router: &SharedRouter, mut request: Request<Body>) -> Response {
+    let (result, context, incoming, original) = Python::with_gil(|py| {
+        let headers = headers_to_py(py, &request);
+        let original: Py<PyDict> = headers
+            .as_ref(py)
+            .copy()
+            .expect("Headers should be copyable")
+            .into();
+        let incoming = Py::new(
+            py,
+            IncomingRequest {
+                method: request.method().to_string(),
+                path: Mutex::new(request.uri().path().to_owned()),
+                query: Mutex::new(request.uri().query().unwrap_or_default().to_owned()),
+                headers,
+            },
+        )
+        .expect("IncomingRequest should be creatable");
+        let context = handler_context(py, request.headers(), &None, None);
+
+        let result = route
+            .call1(py, (incoming.clone_ref(py),))
+            .unwrap_or_else(|e| raised(EXCEPTION_MSG, py, &e));
+        let result = pyo3_asyncio::into_future_with_locals(
+            &task_locals().with_context(context.as_ref(py)),
+            result.as_ref(py),
+        )
+        .expect("route should be asynchronous");
+        (result, context, incoming, original)
+    });
+    let result = match result.await {
+        Ok(result) => result,
+        Err(e) => Python::with_gil(|py| raised(EXCEPTION_MSG, py, &e)),
+    };
+
+    let response = Python::with_gil(|py| {
+        if !result.is_none(py) {
+            return Some(pyobject_to_response(
+                py,
+                result,
+                "route",
+                Some(negotiate::ResponseFormat::Json),
+                context,
+            ));
+        }
+        apply_changes(py, incoming.get(), original.as_ref(py), &mut request);
+        None
+    });
+    if let Some(response) = response {
+        return response;
+    }
+    router.route(request).await
+}
+
+/// Passes every request through `route` in the `_router.py` of the first of `dirs`
+/// that has one, before `router` routes it and checks who may make it
+pub(crate) fn route_through_script<'a>(
+    router: Router,
+    dirs: impl IntoIterator<Item = &'a Path>,
+) -> Router {
+    let Some(path) = dirs
+        .into_iter()
+        .map(|x| x.join(ROUTER_SCRIPT))
+        .find(|x| x.is_file())
+    else {
+        return router;
+    };
+    super::register_embedded_module();
+    let route = Arc::new(load_route(&path));
+    let router = SharedRouter::new(router);
+
+    // The panics of the handlers are caught inside, but not those of `route`
+    crate::panics::layer_panic_capture(Router::new().fallback(move |request: Request<Body>| {
+        let route = route.clone();
+        let router = router.clone();
+        async move { rewrite(&route, &router, request).await }
+    }))
+}
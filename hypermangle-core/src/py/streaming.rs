@@ -0,0 +1,111 @@
+use axum::{
+    body::{Bytes, StreamBody},
+    response::{IntoResponse, Response},
+};
+use futures::Stream;
+use hypermangle_py::{
+    buffers,
+    response_streams::{StreamFrame, StreamReceiver},
+};
+use pyo3::{
+    exceptions::{PyStopAsyncIteration, PyTypeError},
+    intern,
+    types::PyString,
+    PyErr, PyObject, Python,
+};
+
+use crate::{compression::StreamedBody, task_locals};
+
+/// Streams the chunks an async iterator yields, so that the headers of the response
+/// are sent before the handler has produced the whole body. The connection is cut
+/// short if the iterator raises, so that clients do not take the body as complete.
+/// The iterator runs in `context`, the contextvars of the handler. With `ndjson`, it
+/// yields objects that are sent as lines of JSON
+pub(super) fn stream_response(
+    iterator: PyObject,
+    handler: &'static str,
+    context: PyObject,
+    ndjson: bool,
+) -> Response {
+    let chunks = futures::stream::unfold(Some((iterator, context)), move |state| async move {
+        let (iterator, context) = state?;
+        match next_chunk(&iterator, &context, ndjson).await {
+            Ok(Some(chunk)) => Some((Ok(chunk), Some((iterator, context)))),
+            Ok(None) => None,
+            Err(e) => {
+                log::error!("{handler} raised while streaming its body: {e}");
+                Some((Err(e), None))
+            }
+        }
+    });
+    streamed(chunks)
+}
+
+/// Streams the chunks a `ResponseStream` is flushed with. Like with iterators, the
+/// connection is cut short if the handler raised or dropped the stream unclosed
+pub(super) fn stream_written(receiver: StreamReceiver, handler: &'static str) -> Response {
+    let chunks = futures::stream::unfold(Some(receiver), move |receiver| async move {
+        let mut receiver = receiver?;
+        let e = match receiver.recv().await {
+            Some(StreamFrame::Data(chunk)) => return Some((Ok(chunk.into()), Some(receiver))),
+            Some(StreamFrame::End) => return None,
+            Some(StreamFrame::Abort(e)) => format!("{handler} raised while writing its body: {e}"),
+            None => format!("{handler} dropped its response stream without closing it"),
+        };
+        log::error!("{e}");
+        Some((Err(e), None))
+    });
+    streamed(chunks)
+}
+
+/// Every chunk is flushed to the client on its own, compressed or not
+fn streamed<E>(chunks: impl Stream<Item = Result<Bytes, E>> + Send + 'static) -> Response
+where
+    E: Into<axum::BoxError>,
+{
+    let mut response = StreamBody::new(chunks).into_response();
+    response.extensions_mut().insert(StreamedBody);
+    response
+}
+
+async fn next_chunk(
+    iterator: &PyObject,
+    context: &PyObject,
+    ndjson: bool,
+) -> Result<Option<Bytes>, PyErr> {
+    let next = Python::with_gil(|py| {
+        let awaitable = iterator.call_method0(py, intern!(py, "__anext__"))?;
+        pyo3_asyncio::into_future_with_locals(
+            &task_locals().with_context(context.as_ref(py)),
+            awaitable.as_ref(py),
+        )
+    })?;
+
+    let next = next.await;
+    Python::with_gil(|py| match next {
+        Ok(chunk) => {
+            let chunk = chunk.as_ref(py);
+            if ndjson {
+                let mut line = py
+                    .import(intern!(py, "json"))?
+                    .call_method1(intern!(py, "dumps"), (chunk,))?
+                    .extract::<String>()?;
+                line.push('\n');
+                Ok(Some(line.into()))
+            } else if let Ok(x) = chunk.downcast::<PyString>() {
+                // Surrogates cannot be encoded, so they are raised
+                x.to_str()?;
+                Ok(buffers::share(chunk))
+            } else if let Some(chunk) = buffers::share(chunk) {
+                Ok(Some(chunk))
+            } else {
+                Err(PyTypeError::new_err(format!(
+                    "Streamed chunks should be str or bytes, not {}",
+                    chunk.get_type().name()?
+                )))
+            }
+        }
+        Err(e) if e.is_instance_of::<PyStopAsyncIteration>(py) => Ok(None),
+        Err(e) => Err(e),
+    })
+}
@@ -0,0 +1,158 @@
+use std::{
+    fs::read_to_string,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use axum::{body::Body, http::Request, Router};
+use hypermangle_py::{
+    clock,
+    testing::{TestResult, SENDER, SNAPSHOTS},
+};
+use parking_lot::Mutex;
+use pyo3::{intern, PyObject, Python, ToPyObject};
+use tower::ServiceExt;
+
+/// The router that `TestClient` sends requests through, behind a lock as routers are
+/// not `Sync`
+static ROUTER: Mutex<Option<Router>> = Mutex::new(None);
+
+fn send(
+    request: Request<Body>,
+) -> std::pin::Pin<Box<dyn std::future::Future<Output = TestResult> + Send>> {
+    let router = ROUTER
+        .lock()
+        .clone()
+        .expect("Test router should be set before tests run");
+    Box::pin(async move {
+        let response = match router.oneshot(request).await {
+            Ok(x) => x,
+            Err(never) => match never {},
+        };
+        let (parts, body) = response.into_parts();
+        TestResult {
+            status: parts.status.as_u16(),
+            headers: parts
+                .headers
+                .iter()
+                .map(|(name, value)| {
+                    (
+                        name.to_string(),
+                        String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                    )
+                })
+                .collect(),
+            body: hyper::body::to_bytes(body)
+                .await
+                .map(|x| x.to_vec())
+                .unwrap_or_default(),
+        }
+    })
+}
+
+fn collect_tests(dir: &Path, tests: &mut Vec<PathBuf>) {
+    let Ok(entries) = dir.read_dir() else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        match entry.file_type() {
+            Ok(x) if x.is_dir() => collect_tests(&path, tests),
+            Ok(x) if x.is_file() && name.starts_with("test_") && name.ends_with(".py") => {
+                tests.push(path)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The `test_*` functions of the script at `path`, in the order they were defined
+fn load_tests(path: &Path) -> Result<Vec<(String, PyObject)>, String> {
+    let source = read_to_string(path).map_err(|e| e.to_string())?;
+    let module_name = path
+        .file_prefix()
+        .and_then(|x| x.to_str())
+        .ok_or("Path is not a script")?;
+    Python::with_gil(|py| {
+        let module = super::cache::module_from_source(py, path, &source, module_name)?;
+        let mut tests = vec![];
+        for (name, value) in module.dict() {
+            let name: String = name.extract()?;
+            if name.starts_with("test_") && value.is_callable() {
+                tests.push((name, value.to_object(py)));
+            }
+        }
+        Ok(tests)
+    })
+    .map_err(|e: pyo3::PyErr| e.to_string())
+}
+
+/// Calls `test`, awaiting it if it is a coroutine function
+async fn run_test(test: &PyObject) -> Result<(), String> {
+    let future = Python::with_gil(|py| {
+        let result = test.call0(py)?;
+        let is_coroutine = py
+            .import(intern!(py, "inspect"))?
+            .call_method1(intern!(py, "iscoroutine"), (&result,))?
+            .is_true()?;
+        if !is_coroutine {
+            return Ok(None);
+        }
+        pyo3_asyncio::into_future_with_locals(&crate::task_locals(), result.as_ref(py)).map(Some)
+    })
+    .map_err(|e| e.to_string())?;
+    if let Some(future) = future {
+        future.await.map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Runs every test under `dir` against `router`, returning whether all of them passed
+pub(crate) async fn run_tests(router: Router, dir: &Path, update_snapshots: bool) -> bool {
+    *ROUTER.lock() = Some(router);
+    let _ = SENDER.set(send);
+    let _ = SNAPSHOTS.set((dir.join("snapshots"), update_snapshots));
+    clock::enable_test_mode();
+    super::wait_for_event_loop().await;
+
+    let mut scripts = vec![];
+    collect_tests(dir, &mut scripts);
+    scripts.sort();
+
+    let mut passed = 0;
+    let mut failed = 0;
+    for script in scripts {
+        let tests = match load_tests(&script) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("ERROR {}\n    {e}", script.display());
+                failed += 1;
+                continue;
+            }
+        };
+        for (name, test) in tests {
+            clock::reset();
+            let start = Instant::now();
+            let result = run_test(&test).await;
+            let elapsed = start.elapsed();
+            match result {
+                Ok(()) => {
+                    println!("PASS  {}::{name} ({elapsed:.1?})", script.display());
+                    passed += 1;
+                }
+                Err(e) => {
+                    println!(
+                        "FAIL  {}::{name} ({elapsed:.1?})\n    {}",
+                        script.display(),
+                        e.replace('\n', "\n    ")
+                    );
+                    failed += 1;
+                }
+            }
+        }
+    }
+    println!("{passed} passed, {failed} failed");
+    failed == 0
+}
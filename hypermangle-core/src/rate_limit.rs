@@ -0,0 +1,317 @@
+use std::{
+    error::Error,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use axum::{
+    body::Body,
+    http::{HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Router,
+};
+use fxhash::FxHashMap;
+use log::warn;
+use parking_lot::Mutex;
+use redis::aio::ConnectionManager;
+use serde::Deserialize;
+use tokio::{sync::OnceCell, time::timeout};
+
+use crate::{bearer::VerifiedToken, keys::hash_token, tenants, ConfigError};
+
+const BACKEND_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub type BackendError = Box<dyn Error + Send + Sync>;
+
+/// Where request counts are kept. Requests are counted in fixed windows, which are
+/// numbered from the UNIX epoch so that every instance agrees on them
+#[async_trait]
+pub trait RateLimitBackend: Send + Sync {
+    /// Counts a request against `key` in `window`, returning how many requests have
+    /// been counted against it in that window so far
+    async fn hit(
+        &self,
+        key: &str,
+        window: u64,
+        window_length: Duration,
+    ) -> Result<u64, BackendError>;
+}
+
+/// Counts requests in this process only
+#[derive(Default)]
+pub struct MemoryBackend {
+    counts: Mutex<FxHashMap<String, (u64, u64)>>,
+    last_window: AtomicU64,
+}
+
+#[async_trait]
+impl RateLimitBackend for MemoryBackend {
+    async fn hit(
+        &self,
+        key: &str,
+        window: u64,
+        _window_length: Duration,
+    ) -> Result<u64, BackendError> {
+        let mut counts = self.counts.lock();
+        if self.last_window.swap(window, Ordering::Relaxed) != window {
+            counts.retain(|_, (x, _)| *x == window);
+        }
+        let count = counts.entry(key.to_owned()).or_insert((window, 0));
+        if count.0 != window {
+            *count = (window, 0);
+        }
+        count.1 += 1;
+        Ok(count.1)
+    }
+}
+
+/// Counts requests in Redis, so that limits are shared by every instance using the
+/// same server
+pub struct RedisBackend {
+    client: redis::Client,
+    connection: OnceCell<ConnectionManager>,
+}
+
+impl RedisBackend {
+    pub fn new(url: &str) -> redis::RedisResult<Self> {
+        Ok(Self {
+            client: redis::Client::open(url)?,
+            connection: OnceCell::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl RateLimitBackend for RedisBackend {
+    async fn hit(
+        &self,
+        key: &str,
+        window: u64,
+        window_length: Duration,
+    ) -> Result<u64, BackendError> {
+        let mut connection = self
+            .connection
+            // Requests wait for the connection, so give up on it quickly
+            .get_or_try_init(|| ConnectionManager::new_with_backoff(self.client.clone(), 2, 100, 1))
+            .await?
+            .clone();
+        let key = format!("hypermangle:rate-limit:{key}:{window}");
+        // Every window has its own key, so the expiry only has to outlive the window
+        let (count,): (u64,) = redis::pipe()
+            .atomic()
+            .incr(&key, 1u64)
+            .pexpire(&key, window_length.as_millis() as usize * 2)
+            .ignore()
+            .query_async(&mut connection)
+            .await?;
+        Ok(count)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct RateLimitConfig {
+    /// Requests allowed per window for each client
+    requests: u64,
+    #[serde(default = "default_window_secs")]
+    window_secs: u64,
+    /// Requests are counted in the Redis of the cluster if empty, or in memory outside
+    /// cluster mode
+    #[serde(default)]
+    redis_url: String,
+    /// Requests allowed per window for specific bearer tokens, keyed by the hex
+    /// SHA-256 of the token, as the `hash` of keys in the key store, so that tokens
+    /// are not written in the config
+    #[serde(default)]
+    quotas: FxHashMap<String, u64>,
+}
+
+fn default_window_secs() -> u64 {
+    60
+}
+
+impl RateLimitConfig {
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        if !self.redis_url.is_empty() {
+            redis::Client::open(self.redis_url.as_str()).map_err(|error| {
+                ConfigError::RedisUrl {
+                    url: self.redis_url.clone(),
+                    error,
+                }
+            })?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn options(&self) -> RateLimitOptions {
+        RateLimitOptions {
+            requests: self.requests,
+            window: Duration::from_secs(self.window_secs),
+            quotas: self.quotas.clone(),
+        }
+    }
+
+    pub(crate) fn backend(&self) -> Arc<dyn RateLimitBackend> {
+        let redis_url = if self.redis_url.is_empty() {
+            crate::cluster::redis_url()
+        } else {
+            Some(self.redis_url.as_str())
+        };
+        match redis_url {
+            Some(url) => {
+                Arc::new(RedisBackend::new(url).expect("Redis URL should have been validated"))
+            }
+            None => Arc::new(MemoryBackend::default()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitOptions {
+    pub requests: u64,
+    pub window: Duration,
+    /// Overrides `requests` for specific bearer tokens, keyed by the hex SHA-256 of
+    /// the token
+    pub quotas: FxHashMap<String, u64>,
+}
+
+/// Requests are limited by their bearer token once it has been verified, or by the
+/// address of their client, along with the hash of the token if there is one. Tokens
+/// are hashed so that backends never store them
+fn client_key(request: &Request<Body>) -> (String, Option<String>) {
+    if let Some(VerifiedToken(token)) = request.extensions().get() {
+        let hash = hash_token(token);
+        return (format!("token:{}", &hash[..32]), Some(hash));
+    }
+    match crate::client_address::of(request) {
+        Some(address) => (format!("address:{address}"), None),
+        None => ("address:unknown".to_owned(), None),
+    }
+}
+
+fn set_headers(headers: &mut HeaderMap, limit: u64, remaining: u64, reset: u64) {
+    headers.insert("x-ratelimit-limit", limit.into());
+    headers.insert("x-ratelimit-remaining", remaining.into());
+    headers.insert("x-ratelimit-reset", reset.into());
+}
+
+/// Responds with 429 to clients that exceed their quota in the current window. Requests
+/// are let through if the backend fails, as an unreachable backend should not take the
+/// server down with it
+pub fn layer_rate_limit(
+    router: Router,
+    backend: Arc<dyn RateLimitBackend>,
+    options: RateLimitOptions,
+) -> Router {
+    layer_rate_limits(router, Some((backend, Arc::new(options))))
+}
+
+/// Like `layer_rate_limit`, except that the routes of tenants with their own rate
+/// limit are limited by that instead of `global`. Applied inside the bearer auth, so
+/// that only verified tokens get buckets of their own
+pub(crate) fn layer_rate_limits(
+    router: Router,
+    global: Option<(Arc<dyn RateLimitBackend>, Arc<RateLimitOptions>)>,
+) -> Router {
+    router.layer(axum::middleware::from_fn(
+        move |request: Request<Body>, next: Next<Body>| {
+            let tenant = tenants::for_request_path(request.uri().path())
+                .and_then(|x| Some((x.rate_limit.clone()?, x.name.as_str())));
+            let (limiter, namespace) = match tenant {
+                Some((limiter, name)) => (Some(limiter), format!("tenant:{name}:")),
+                None => (global.clone(), String::new()),
+            };
+            async move {
+                let Some((backend, options)) = limiter else {
+                    return next.run(request).await;
+                };
+                let (key, hash) = client_key(&request);
+                let key = namespace + &key;
+                let limit = hash
+                    .and_then(|x| options.quotas.get(&x))
+                    .copied()
+                    .unwrap_or(options.requests);
+
+                let window_secs = options.window.as_secs().max(1);
+                let window_length = Duration::from_secs(window_secs);
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("System time should be after the UNIX epoch")
+                    .as_secs();
+                let window = now / window_secs;
+                let reset = (window + 1) * window_secs - now;
+
+                let count = match timeout(BACKEND_TIMEOUT, backend.hit(&key, window, window_length))
+                    .await
+                {
+                    Ok(Ok(x)) => x,
+                    Ok(Err(e)) => {
+                        warn!("Rate limit backend failed, so the request was let through: {e}");
+                        return next.run(request).await;
+                    }
+                    Err(_) => {
+                        warn!("Rate limit backend timed out, so the request was let through");
+                        return next.run(request).await;
+                    }
+                };
+                let remaining = limit.saturating_sub(count);
+
+                if count > limit {
+                    crate::metrics::increment(
+                        "hypermangle_rate_limited_total",
+                        "Requests rejected for exceeding their rate limit",
+                        &[],
+                    );
+                    let mut response = StatusCode::TOO_MANY_REQUESTS.into_response();
+                    let headers = response.headers_mut();
+                    set_headers(headers, limit, remaining, reset);
+                    headers.insert("retry-after", reset.into());
+                    return response;
+                }
+
+                let mut response: Response = next.run(request).await;
+                set_headers(response.headers_mut(), limit, remaining, reset);
+                response
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn limit_of(router: &Router, token: &str) -> String {
+        let mut request = Request::new(Body::empty());
+        request
+            .extensions_mut()
+            .insert(VerifiedToken(token.to_owned()));
+        let response = router.clone().oneshot(request).await.unwrap();
+        response.headers()["x-ratelimit-limit"]
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[tokio::test]
+    async fn quotas_are_keyed_by_token_hashes() {
+        let quotas = [(hash_token("hashed"), 100), ("plaintext".to_owned(), 100)];
+        let router = layer_rate_limit(
+            Router::new().route("/", axum::routing::get(|| async { "ok" })),
+            Arc::new(MemoryBackend::default()),
+            RateLimitOptions {
+                requests: 10,
+                window: Duration::from_secs(60),
+                quotas: quotas.into_iter().collect(),
+            },
+        );
+        assert_eq!(limit_of(&router, "hashed").await, "100");
+        assert_eq!(limit_of(&router, "plaintext").await, "10");
+    }
+}
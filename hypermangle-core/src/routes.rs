@@ -0,0 +1,320 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use axum::http::StatusCode;
+use parking_lot::{Mutex, RwLock};
+use regex::RegexSet;
+
+static ROUTE_TABLE: Mutex<Vec<RouteInfo>> = Mutex::new(Vec::new());
+static PRINT_ROUTES: AtomicBool = AtomicBool::new(false);
+/// Routes taken out of service, and the status they respond with instead
+static DISABLED_ROUTES: RwLock<BTreeMap<String, StatusCode>> = RwLock::new(BTreeMap::new());
+static ACCESS: RwLock<Option<Access>> = RwLock::new(None);
+/// Makes every route respond with 503, such as while a database is migrated
+static MAINTENANCE: AtomicBool = AtomicBool::new(false);
+
+/// Who can reach routes, for the route table
+struct Access {
+    /// `None` if requests need no token
+    public_paths: Option<RegexSet>,
+    signed_url_prefix: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct RouteInfo {
+    pub http_path: String,
+    pub methods: Vec<&'static str>,
+    pub is_multi_pathed: bool,
+    /// Only imported when first requested
+    pub lazy: bool,
+    pub source: PathBuf,
+}
+
+#[cfg_attr(not(feature = "python"), allow(dead_code))]
+pub(crate) fn record_route(route: RouteInfo) {
+    ROUTE_TABLE.lock().push(route);
+}
+
+/// All the routes that were loaded from scripts so far
+pub fn route_table() -> Vec<RouteInfo> {
+    ROUTE_TABLE.lock().clone()
+}
+
+/// Every route registered into one router, and what registered it, so that routes
+/// registered more than once are all reported together. The router would only stop at
+/// the first, without saying what registered it
+#[derive(Default)]
+pub(crate) struct RouteRegistry {
+    /// The methods at each path, where `*` is any method, and what registered them
+    routes: BTreeMap<String, Vec<(&'static str, String)>>,
+    /// Paths that everything below is routed to, and what registered them
+    prefixes: Vec<(String, String)>,
+}
+
+impl RouteRegistry {
+    pub(crate) fn add(&mut self, http_path: &str, method: &'static str, source: &str) {
+        self.routes
+            .entry(http_path.to_owned())
+            .or_default()
+            .push((method, source.to_owned()));
+    }
+
+    /// Claims `prefix` and every path below it for any method
+    pub(crate) fn add_prefix(&mut self, prefix: &str, source: &str) {
+        self.add(prefix, "*", source);
+        self.prefixes.push((prefix.to_owned(), source.to_owned()));
+    }
+
+    /// One line for each method at each path that more than one source registered,
+    /// and for each path below a prefix that something else registered
+    pub(crate) fn conflicts(&self) -> Vec<String> {
+        let mut conflicts = vec![];
+        for (http_path, routes) in &self.routes {
+            let mut methods: Vec<_> = routes.iter().map(|(x, _)| *x).collect();
+            methods.sort_unstable();
+            methods.dedup();
+            // Any method overlaps every other, so those are reported as one
+            if methods.contains(&"*") {
+                methods = vec!["*"];
+            }
+            for method in methods {
+                let mut sources: Vec<_> = routes
+                    .iter()
+                    .filter(|(x, _)| method == "*" || *x == method)
+                    .map(|(_, x)| x.as_str())
+                    .collect();
+                sources.sort_unstable();
+                sources.dedup();
+                if sources.len() > 1 {
+                    conflicts.push(format!("{method} {http_path}: {}", sources.join(", ")));
+                }
+            }
+        }
+        for (prefix, owner) in &self.prefixes {
+            for (http_path, routes) in self.routes.range(format!("{prefix}/")..) {
+                if !http_path.starts_with(&format!("{prefix}/")) {
+                    break;
+                }
+                let mut sources: Vec<_> = routes
+                    .iter()
+                    .map(|(_, x)| x.as_str())
+                    .filter(|x| x != owner)
+                    .collect();
+                sources.sort_unstable();
+                sources.dedup();
+                if !sources.is_empty() {
+                    conflicts.push(format!(
+                        "{http_path} is below {prefix} of {owner}: {}",
+                        sources.join(", ")
+                    ));
+                }
+            }
+        }
+        conflicts
+    }
+}
+
+/// Panics with every invalid script, and every conflict in each of the `routers`,
+/// which are named by what they serve
+pub(crate) fn check(mut invalid: Vec<String>, routers: &[(String, RouteRegistry)]) {
+    let mut problems = String::new();
+    if !invalid.is_empty() {
+        invalid.sort();
+        // Folders shared by several hosts are checked for each
+        invalid.dedup();
+        problems += "\nThese scripts are invalid:";
+        for script in invalid {
+            problems += &format!("\n  {script}");
+        }
+    }
+    for (name, routes) in routers {
+        let conflicts = routes.conflicts();
+        if conflicts.is_empty() {
+            continue;
+        }
+        problems += &format!("\nThese routes of {name} are registered more than once:");
+        for conflict in conflicts {
+            problems += &format!("\n  {conflict}");
+        }
+    }
+    if !problems.is_empty() {
+        panic!("Scripts should be valid and routes should not be shared:{problems}");
+    }
+}
+
+/// Makes every route at `http_path` respond with `status` without running its
+/// handlers, in every virtual host. Returns false if no script is routed there
+pub(crate) fn disable_route(http_path: &str, status: StatusCode) -> bool {
+    if !ROUTE_TABLE.lock().iter().any(|x| x.http_path == http_path) {
+        return false;
+    }
+    DISABLED_ROUTES.write().insert(http_path.to_owned(), status);
+    true
+}
+
+/// Returns false if the route at `http_path` was not disabled
+pub(crate) fn enable_route(http_path: &str) -> bool {
+    DISABLED_ROUTES.write().remove(http_path).is_some()
+}
+
+pub(crate) fn disabled_status(http_path: &str) -> Option<StatusCode> {
+    DISABLED_ROUTES.read().get(http_path).copied()
+}
+
+pub(crate) fn set_maintenance(value: bool) {
+    MAINTENANCE.store(value, Ordering::Relaxed);
+}
+
+pub(crate) fn in_maintenance() -> bool {
+    MAINTENANCE.load(Ordering::Relaxed)
+}
+
+/// What the route at `http_path` responds with instead of running its handlers, if
+/// it is disabled or the server is in maintenance mode
+#[cfg_attr(not(feature = "python"), allow(dead_code))]
+pub(crate) fn unavailable_status(http_path: &str) -> Option<StatusCode> {
+    if in_maintenance() {
+        return Some(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    disabled_status(http_path)
+}
+
+/// `public_paths` is `None` if requests need no token
+pub(crate) fn set_access(public_paths: Option<RegexSet>, signed_url_prefix: &str) {
+    *ACCESS.write() = Some(Access {
+        public_paths,
+        signed_url_prefix: signed_url_prefix.to_owned(),
+    });
+}
+
+pub(crate) fn access(http_path: &str) -> &'static str {
+    let access = ACCESS.read();
+    let Some(access) = access.as_ref() else {
+        return "open";
+    };
+    if !access.signed_url_prefix.is_empty() && http_path.starts_with(&access.signed_url_prefix) {
+        return "signed URL";
+    }
+    match &access.public_paths {
+        None => "open",
+        Some(x) if x.is_match(http_path) => "public",
+        Some(_) => "token",
+    }
+}
+
+pub(crate) fn set_print_routes(value: bool) {
+    PRINT_ROUTES.store(value, Ordering::Relaxed);
+}
+
+pub(crate) fn should_print_routes() -> bool {
+    PRINT_ROUTES.load(Ordering::Relaxed)
+}
+
+pub(crate) fn format_route_table() -> String {
+    let routes = route_table();
+    if routes.is_empty() {
+        return "No routes were loaded from scripts\n".into();
+    }
+
+    let disabled = DISABLED_ROUTES.read();
+    let rows: Vec<_> = routes
+        .iter()
+        .map(|route| {
+            let mut http_path = route.http_path.clone();
+            if route.is_multi_pathed {
+                http_path.push('*');
+            }
+            let mut flags = vec![];
+            if route.lazy {
+                flags.push("lazy".to_owned());
+            }
+            if let Some(status) = disabled.get(&route.http_path) {
+                flags.push(format!("disabled, responds {}", status.as_u16()));
+            }
+            let mut source = route.source.display().to_string();
+            if !flags.is_empty() {
+                source += &format!("  ({})", flags.join(", "));
+            }
+            (
+                route.methods.join(", "),
+                http_path,
+                access(&route.http_path),
+                source,
+            )
+        })
+        .collect();
+    drop(disabled);
+
+    let methods_width = rows
+        .iter()
+        .map(|(x, _, _, _)| x.len())
+        .max()
+        .unwrap_or_default();
+    let path_width = rows
+        .iter()
+        .map(|(_, x, _, _)| x.len())
+        .max()
+        .unwrap_or_default();
+    let access_width = rows
+        .iter()
+        .map(|(_, _, x, _)| x.len())
+        .max()
+        .unwrap_or_default();
+
+    let mut out = String::new();
+    if in_maintenance() {
+        out += "In maintenance mode, so every route responds with 503\n";
+    }
+    out += "Routes:\n";
+    for (methods, http_path, access, source) in rows {
+        out += &format!(
+            "  {methods:methods_width$}  {http_path:path_width$}  {access:access_width$}  {source}\n"
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn methods_registered_twice_conflict() {
+        let mut registry = RouteRegistry::default();
+        registry.add("/users", "GET", "scripts/users.py");
+        registry.add("/users", "POST", "api/users.py");
+        assert!(registry.conflicts().is_empty());
+
+        registry.add("/users", "GET", "api/users.py");
+        assert_eq!(
+            registry.conflicts(),
+            ["GET /users: api/users.py, scripts/users.py"]
+        );
+    }
+
+    #[test]
+    fn any_method_conflicts_with_every_method() {
+        let mut registry = RouteRegistry::default();
+        registry.add("/metrics", "GET", "metrics_path");
+        registry.add_prefix("/metrics", "proxy /metrics");
+        assert_eq!(
+            registry.conflicts(),
+            ["* /metrics: metrics_path, proxy /metrics"]
+        );
+    }
+
+    #[test]
+    fn routes_below_prefixes_conflict() {
+        let mut registry = RouteRegistry::default();
+        registry.add_prefix("/static", "static_files static");
+        registry.add("/static/app", "GET", "scripts/static/app/index.py");
+        registry.add("/statically", "GET", "scripts/statically.py");
+        assert_eq!(
+            registry.conflicts(),
+            ["/static/app is below /static of static_files static: scripts/static/app/index.py"]
+        );
+    }
+}
@@ -0,0 +1,316 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+    Router,
+};
+use hypermangle_py::runtime::{EVENT_LOOP_LAG_MICROS, IN_FLIGHT, QUEUE_DEPTH};
+use log::{error, warn};
+use serde::Deserialize;
+
+use crate::metrics;
+
+/// Counts itself in `counter` until dropped, so that cancelled requests are not
+/// counted forever
+pub(crate) struct Counted(&'static AtomicU64);
+
+impl Counted {
+    pub(crate) fn new(counter: &'static AtomicU64) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg_attr(not(feature = "python"), allow(dead_code))]
+pub(crate) fn count_queued() -> Counted {
+    Counted::new(&QUEUE_DEPTH)
+}
+
+pub(crate) fn layer_in_flight(router: Router) -> Router {
+    router.layer(axum::middleware::from_fn(
+        |request: Request<Body>, next: Next<Body>| async move {
+            let _counted = Counted::new(&IN_FLIGHT);
+            next.run(request).await
+        },
+    ))
+}
+
+/// Set while the resident memory of the process is above the hard limit
+static OVER_HARD_LIMIT: AtomicBool = AtomicBool::new(false);
+
+/// The resident set size of this process, which includes the Python interpreter.
+/// Only available on Linux
+fn resident_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kib: u64 = status
+        .lines()
+        .find_map(|x| x.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+/// Checks the resident memory once a second, warning when it rises above
+/// `soft_limit` and rejecting requests with `layer_memory_guard` while it is above
+/// `hard_limit`. Limits of 0 are disabled
+pub(crate) async fn monitor_memory(soft_limit: u64, hard_limit: u64) {
+    let mut over_soft_limit = false;
+
+    loop {
+        let Some(rss) = resident_bytes() else {
+            error!("Memory usage is not available on this platform, so memory limits are ignored");
+            return;
+        };
+        let mib = rss / 1024 / 1024;
+
+        let over = soft_limit > 0 && rss > soft_limit;
+        if over && !over_soft_limit {
+            warn!("Memory usage of {mib} MiB is above the soft limit");
+        }
+        over_soft_limit = over;
+
+        let over = hard_limit > 0 && rss > hard_limit;
+        if OVER_HARD_LIMIT.swap(over, Ordering::Relaxed) != over {
+            if over {
+                error!("Memory usage of {mib} MiB is above the hard limit, so new requests are rejected");
+            } else {
+                warn!("Memory usage of {mib} MiB is below the hard limit again, so requests are accepted");
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// Responds with 503 while `monitor_memory` finds the hard limit exceeded, except at
+/// `metrics_path` so that the memory usage can still be scraped
+pub(crate) fn layer_memory_guard(router: Router, metrics_path: String) -> Router {
+    router.layer(axum::middleware::from_fn(
+        move |request: Request<Body>, next: Next<Body>| {
+            let exempt = !metrics_path.is_empty() && request.uri().path() == metrics_path;
+            async move {
+                if OVER_HARD_LIMIT.load(Ordering::Relaxed) && !exempt {
+                    metrics::increment(
+                        "hypermangle_memory_rejected_total",
+                        "Requests rejected while memory usage was above the hard limit",
+                        &[],
+                    );
+                    return StatusCode::SERVICE_UNAVAILABLE.into_response();
+                }
+                next.run(request).await
+            }
+        },
+    ))
+}
+
+/// Seconds of CPU time this process has used, counting every thread. Only available
+/// on Linux
+fn cpu_seconds() -> Option<f64> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // utime and stime are the 14th and 15th fields, and the 2nd is the command name in
+    // parentheses, which may contain spaces
+    let mut fields = stat.rsplit_once(')')?.1.split_whitespace().skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    // In USER_HZ, which is 100 on every architecture Linux supports
+    Some((utime + stime) as f64 / 100.0)
+}
+
+/// Thousandths of a core used over the last second
+static CPU_PERMILLE: AtomicU64 = AtomicU64::new(0);
+
+/// Cores worth of CPU time used over the last second, while `monitor_cpu` runs
+pub(crate) fn cpu_usage() -> f64 {
+    CPU_PERMILLE.load(Ordering::Relaxed) as f64 / 1000.0
+}
+
+pub(crate) async fn monitor_cpu() {
+    let Some(mut last) = cpu_seconds() else {
+        warn!("CPU usage can only be measured on Linux");
+        return;
+    };
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let Some(now) = cpu_seconds() else {
+            return;
+        };
+        CPU_PERMILLE.store(((now - last) * 1000.0) as u64, Ordering::Relaxed);
+        last = now;
+    }
+}
+
+/// Copies the runtime stats into gauges, which is only worth doing when they are
+/// about to be rendered
+pub(crate) fn publish_metrics() {
+    metrics::set_gauge(
+        "hypermangle_requests_in_flight",
+        "Requests that have been received but not responded to",
+        &[],
+        IN_FLIGHT.load(Ordering::Relaxed),
+    );
+    metrics::set_gauge(
+        "hypermangle_requests_queued",
+        "Requests waiting for their script to be imported or for the GIL",
+        &[],
+        QUEUE_DEPTH.load(Ordering::Relaxed),
+    );
+    metrics::set_gauge(
+        "hypermangle_event_loop_lag_microseconds",
+        "How long the last callback scheduled onto the Python event loop waited to run",
+        &[],
+        EVENT_LOOP_LAG_MICROS.load(Ordering::Relaxed),
+    );
+    if let Some(rss) = resident_bytes() {
+        metrics::set_gauge(
+            "hypermangle_resident_memory_bytes",
+            "Resident set size of the server, including the Python interpreter",
+            &[],
+            rss,
+        );
+    }
+}
+
+/// Measures how long callbacks wait to run on the Python event loop, once a second
+#[cfg(feature = "python")]
+pub(crate) async fn monitor_event_loop() {
+    use std::time::{Duration, Instant};
+
+    use parking_lot::Mutex;
+    use pyo3::{types::PyCFunction, Python};
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let Some(locals) = crate::PY_TASK_LOCALS.read().clone() else {
+            continue;
+        };
+
+        let (sender, receiver) = tokio::sync::oneshot::channel::<()>();
+        let sender = Mutex::new(Some(sender));
+        let scheduled = Python::with_gil(|py| {
+            let callback = PyCFunction::new_closure(py, None, None, move |_, _| {
+                if let Some(sender) = sender.lock().take() {
+                    let _ = sender.send(());
+                }
+            })?;
+            let start = Instant::now();
+            locals
+                .event_loop(py)
+                .call_method1("call_soon_threadsafe", (callback,))
+                .map(|_| start)
+        });
+        let start = match scheduled {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Failed to measure the event loop lag: {e}");
+                return;
+            }
+        };
+        if receiver.await.is_err() {
+            return;
+        }
+        EVENT_LOOP_LAG_MICROS.store(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// The threads of the tokio runtimes that `auto_main` starts. Applications running
+/// hypermangle on their own runtime tune that one themselves
+#[derive(Deserialize, Clone)]
+pub(crate) struct RuntimeConfig {
+    /// Threads running requests and Rust tasks. Defaults to one for each core
+    #[serde(default)]
+    worker_threads: Option<usize>,
+    /// Threads for blocking work, of which every open websocket with a `ws_handler`
+    /// holds one, so this also caps how many such websockets are served at once.
+    /// Upgrades beyond it wait for a thread
+    #[serde(default = "default_max_blocking_threads")]
+    max_blocking_threads: usize,
+    /// The name of the threads serving requests, while those of the runtime driving
+    /// Python's futures end in `-py`. Linux shows at most 15 characters of them
+    #[serde(default = "default_thread_name")]
+    thread_name: String,
+    /// Threads of the runtime driving the futures that Python awaits, such as
+    /// `ResponseStream.flush`. Handlers mostly wait on the GIL, so one or two are
+    /// usually enough. Defaults to one for each core
+    #[serde(default)]
+    #[cfg_attr(not(feature = "python"), allow(dead_code))]
+    python_worker_threads: Option<usize>,
+}
+
+fn default_max_blocking_threads() -> usize {
+    512
+}
+
+fn default_thread_name() -> String {
+    "hypermangle".into()
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            worker_threads: None,
+            max_blocking_threads: default_max_blocking_threads(),
+            thread_name: default_thread_name(),
+            python_worker_threads: None,
+        }
+    }
+}
+
+impl RuntimeConfig {
+    fn builder(
+        thread_name: String,
+        worker_threads: Option<usize>,
+        setting: &str,
+    ) -> tokio::runtime::Builder {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.enable_all().thread_name(thread_name);
+        if let Some(threads) = worker_threads {
+            assert!(threads > 0, "runtime.{setting} should be more than 0");
+            builder.worker_threads(threads);
+        }
+        builder
+    }
+
+    /// The runtime that serves requests
+    pub(crate) fn build(&self) -> tokio::runtime::Runtime {
+        assert!(
+            self.max_blocking_threads > 0,
+            "runtime.max_blocking_threads should be more than 0"
+        );
+        Self::builder(
+            self.thread_name.clone(),
+            self.worker_threads,
+            "worker_threads",
+        )
+        .max_blocking_threads(self.max_blocking_threads)
+        .build()
+        .expect("Tokio runtime should be buildable")
+    }
+
+    /// Configures the runtime that futures awaited by Python run on, before it is
+    /// first used
+    #[cfg(feature = "python")]
+    pub(crate) fn init_python(&self) {
+        pyo3_asyncio::tokio::init(Self::builder(
+            format!("{}-py", self.thread_name),
+            self.python_worker_threads,
+            "python_worker_threads",
+        ));
+    }
+}
@@ -0,0 +1,234 @@
+"""Restricts what scripts can do once installed. Audit hooks cannot be removed, so
+this applies to all Python code in the server, which only runs on behalf of scripts.
+
+This raises the bar for semi-trusted scripts, but CPython was not designed to
+contain hostile code, so it is not a substitute for isolating the process itself.
+"""
+
+import builtins
+import os
+import sys
+
+BLOCKED_EVENTS = frozenset(
+    {
+        "subprocess.Popen",
+        "os.system",
+        "os.exec",
+        "os.posix_spawn",
+        "os.spawn",
+        "os.fork",
+        "os.forkpty",
+        "os.kill",
+        "os.killpg",
+        "pty.spawn",
+        "ctypes.dlopen",
+        "ctypes.dlsym",
+        "ctypes.call_function",
+    }
+)
+NETWORK_EVENTS = frozenset(
+    {
+        "socket.connect",
+        "socket.bind",
+        "socket.sendto",
+        "socket.sendmsg",
+        "socket.getaddrinfo",
+        "socket.gethostbyname",
+    }
+)
+# How many of the leading arguments of each event are paths
+PATH_EVENTS = {
+    "open": 1,
+    "os.listdir": 1,
+    "os.scandir": 1,
+    "os.mkdir": 1,
+    "os.rmdir": 1,
+    "os.remove": 1,
+    "os.chmod": 1,
+    "os.chown": 1,
+    "os.truncate": 1,
+    "os.utime": 1,
+    "os.rename": 2,
+    "os.link": 2,
+    "os.symlink": 2,
+}
+WRITE_FLAGS = os.O_WRONLY | os.O_RDWR | os.O_CREAT | os.O_APPEND | os.O_TRUNC
+
+
+def install(blocked_modules, allowed_paths, allow_network):
+    """Installs the audit hook and returns the builtins that scripts run with."""
+    allowed = [os.path.realpath(os.fspath(x)) for x in allowed_paths]
+    blocked_events = BLOCKED_EVENTS if allow_network else BLOCKED_EVENTS | NETWORK_EVENTS
+    blocked_modules = frozenset(blocked_modules)
+    if not allow_network:
+        blocked_modules |= {"socket", "_socket"}
+    getframe = sys._getframe
+    realpath = os.path.realpath
+    sep = os.sep
+
+    def is_allowed(path):
+        path = realpath(os.fsdecode(path))
+        return any(path == x or path.startswith(x + sep) for x in allowed)
+
+    def in_import_machinery():
+        frame = getframe(2)
+        while frame is not None:
+            if frame.f_code.co_filename.startswith("<frozen importlib"):
+                return True
+            frame = frame.f_back
+        return False
+
+    def is_write(event, args):
+        if event in ("os.listdir", "os.scandir"):
+            return False
+        if event != "open":
+            return True
+        mode, flags = args[1], args[2]
+        if isinstance(mode, str):
+            return any(x in mode for x in "wax+")
+        return bool(flags & WRITE_FLAGS)
+
+    def hook(event, args):
+        if event in blocked_events:
+            raise PermissionError(f"{event} is not allowed in the sandbox")
+        count = PATH_EVENTS.get(event)
+        if count is None:
+            return
+        paths = [x for x in args[:count] if isinstance(x, (str, bytes, os.PathLike))]
+        if all(is_allowed(x) for x in paths):
+            return
+        # Imports read modules from anywhere on sys.path, and cache their bytecode
+        if in_import_machinery() and (
+            not is_write(event, args)
+            or all(f"{sep}__pycache__{sep}" in os.fsdecode(x) for x in paths)
+        ):
+            return
+        raise PermissionError(f"{paths[0]!r} is outside of the paths allowed in the sandbox")
+
+    sys.addaudithook(hook)
+
+    real_import = builtins.__import__
+
+    def sandboxed_import(name, globals=None, locals=None, fromlist=(), level=0):
+        if level == 0 and name.partition(".")[0] in blocked_modules:
+            raise ImportError(f"{name} cannot be imported in the sandbox", name=name)
+        return real_import(name, globals, locals, fromlist, level)
+
+    restricted = dict(vars(builtins))
+    restricted["__import__"] = sandboxed_import
+    for name in ("breakpoint", "input"):
+        restricted.pop(name, None)
+    return restricted
+
+
+def host_matches(pattern, host):
+    if pattern.startswith("*."):
+        return host.partition(".")[2] == pattern[2:]
+    return pattern == host
+
+
+def restrict_outbound_hosts(allowed_by_tenant):
+    """Limits the hosts the scripts of each tenant can connect to. Returns the
+    function that registers the code of each script with its tenant, which is None
+    for scripts without one, and the task factory of event loops.
+
+    Scripts are identified by the registered code on the stack, and tasks by the
+    scripts that created them. Connections made without either, such as from threads
+    or executors, are refused, so scripts cannot escape by running code elsewhere.
+    Scripts that reach into the interpreter, like through `gc`, can still get around
+    this, so it guards against mistakes rather than isolating tenants."""
+    import asyncio
+    import socket
+    import weakref
+    from types import CodeType
+
+    allowed_by_tenant = {
+        tenant: [x.lower() for x in hosts] for tenant, hosts in allowed_by_tenant.items()
+    }
+    # Addresses are only known to belong to a host after resolving it, which asyncio
+    # does on another thread, so remember the host each address was resolved from
+    resolved = {}
+    real_getaddrinfo = socket.getaddrinfo
+    getframe = sys._getframe
+    # Keyed by the id of each code object, along with a weak reference to tell it
+    # apart from code that took its id after it was freed
+    owners = {}
+    task_tenants = weakref.WeakKeyDictionary()
+
+    def getaddrinfo(host, *args, **kwargs):
+        results = real_getaddrinfo(host, *args, **kwargs)
+        if isinstance(host, (str, bytes)):
+            name = os.fsdecode(host).lower()
+            for *_, address in results:
+                resolved[address[0]] = name
+        return results
+
+    socket.getaddrinfo = getaddrinfo
+
+    def register(code, tenant):
+        pending = [code]
+        while pending:
+            code = pending.pop()
+            key = id(code)
+            owners[key] = (weakref.ref(code, lambda _, key=key: owners.pop(key, None)), tenant)
+            pending.extend(x for x in code.co_consts if isinstance(x, CodeType))
+
+    def running_tenants():
+        """The tenants of the scripts running, which are empty for scripts without
+        one, or None if no script is running"""
+        tenants = None
+        frame = getframe(1)
+        while frame is not None:
+            owner = owners.get(id(frame.f_code))
+            if owner is not None and owner[0]() is frame.f_code:
+                tenants = tenants or set()
+                if owner[1] is not None:
+                    tenants.add(owner[1])
+            frame = frame.f_back
+        try:
+            task = asyncio.current_task()
+        except RuntimeError:
+            task = None
+        inherited = task_tenants.get(task) if task is not None else None
+        if inherited is not None:
+            tenants = (tenants or set()) | inherited
+        return tenants
+
+    def task_factory(loop, coro, **kwargs):
+        task = asyncio.Task(coro, loop=loop, **kwargs)
+        tenants = running_tenants()
+        if tenants is not None:
+            task_tenants[task] = frozenset(tenants)
+        return task
+
+    def check(tenants, host):
+        for tenant in tenants:
+            allowed = allowed_by_tenant.get(tenant)
+            if allowed is not None and not any(host_matches(x, host) for x in allowed):
+                raise PermissionError(f"Tenant {tenant} may not connect to {host}")
+
+    def hook(event, args):
+        if event == "socket.getaddrinfo":
+            host = args[0]
+            if not isinstance(host, (str, bytes)):
+                return
+            # asyncio resolves on executor threads, which cannot tell who asked, so
+            # resolving is allowed and connecting to the result is checked instead
+            tenants = running_tenants()
+            if tenants:
+                check(tenants, os.fsdecode(host).lower())
+        elif event in ("socket.connect", "socket.sendto"):
+            tenants = running_tenants()
+            if tenants is None:
+                raise PermissionError(
+                    "Connections can only be made from scripts or their tasks while tenants have allowed_hosts"
+                )
+            if not any(allowed_by_tenant.get(x) is not None for x in tenants):
+                return
+            address = args[1]
+            if not isinstance(address, tuple):
+                raise PermissionError("Tenants with allowed_hosts may only connect to network hosts")
+            check(tenants, resolved.get(address[0], str(address[0]).lower()))
+
+    sys.addaudithook(hook)
+    return register, task_factory
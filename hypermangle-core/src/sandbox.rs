@@ -0,0 +1,141 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+/// Opt-in restrictions on what scripts can do, for deployments where scripts are only
+/// semi-trusted
+#[derive(Deserialize, Clone)]
+#[cfg_attr(not(feature = "python"), allow(dead_code))]
+pub(crate) struct SandboxConfig {
+    /// Modules that scripts cannot import directly. `socket` is also blocked unless
+    /// `allow_network` is set
+    #[serde(default = "default_blocked_modules")]
+    blocked_modules: Vec<String>,
+    /// Where scripts may read and write files. Modules can still be imported from
+    /// anywhere on `sys.path`
+    #[serde(default)]
+    allowed_paths: Vec<PathBuf>,
+    #[serde(default)]
+    allow_network: bool,
+}
+
+fn default_blocked_modules() -> Vec<String> {
+    [
+        "subprocess",
+        "_posixsubprocess",
+        "ctypes",
+        "_ctypes",
+        "multiprocessing",
+        "pty",
+    ]
+    .map(Into::into)
+    .to_vec()
+}
+
+#[cfg(feature = "python")]
+static RESTRICTED_BUILTINS: std::sync::OnceLock<pyo3::PyObject> = std::sync::OnceLock::new();
+
+#[cfg(feature = "python")]
+fn sandbox_module(py: pyo3::Python<'_>) -> &pyo3::types::PyModule {
+    static MODULE: std::sync::OnceLock<pyo3::Py<pyo3::types::PyModule>> =
+        std::sync::OnceLock::new();
+    MODULE
+        .get_or_init(|| {
+            pyo3::types::PyModule::from_code(
+                py,
+                include_str!("sandbox.py"),
+                "hypermangle_sandbox.py",
+                "hypermangle_sandbox",
+            )
+            .expect("Sandbox module should be valid")
+            .into()
+        })
+        .as_ref(py)
+}
+
+/// Installs the sandbox into the interpreter, which cannot be undone
+#[cfg(feature = "python")]
+pub(crate) fn install(config: &SandboxConfig) {
+    RESTRICTED_BUILTINS.get_or_init(|| {
+        pyo3::Python::with_gil(|py| {
+            sandbox_module(py)
+                .call_method1(
+                    "install",
+                    (
+                        config.blocked_modules.clone(),
+                        config.allowed_paths.clone(),
+                        config.allow_network,
+                    ),
+                )
+                .expect("Sandbox should be installable")
+                .into()
+        })
+    });
+}
+
+/// The builtins scripts should be executed with, if the sandbox is installed
+#[cfg(feature = "python")]
+pub(crate) fn restricted_builtins(py: pyo3::Python<'_>) -> Option<&pyo3::PyAny> {
+    RESTRICTED_BUILTINS.get().map(|x| x.as_ref(py))
+}
+
+/// The functions that register the code of scripts and the tasks they create, once
+/// outbound hosts are restricted
+#[cfg(feature = "python")]
+static OUTBOUND_HOSTS: std::sync::OnceLock<(pyo3::PyObject, pyo3::PyObject)> =
+    std::sync::OnceLock::new();
+
+/// Limits the hosts that the scripts of each tenant can connect to, keyed by the
+/// tenant name, which cannot be undone. Connections made from outside of scripts are
+/// refused from then on
+#[cfg(feature = "python")]
+pub(crate) fn restrict_outbound_hosts(allowed_hosts: fxhash::FxHashMap<String, Vec<String>>) {
+    OUTBOUND_HOSTS.get_or_init(|| {
+        pyo3::Python::with_gil(|py| {
+            let functions = sandbox_module(py)
+                .call_method1("restrict_outbound_hosts", (allowed_hosts,))
+                .and_then(|x| x.extract())
+                .expect("Outbound hosts should be restrictable");
+            let locals = crate::PY_TASK_LOCALS.read().clone();
+            if let Some(locals) = locals {
+                set_task_factory(locals.event_loop(py), &functions)
+                    .expect("Task factory should be settable");
+            }
+            functions
+        })
+    });
+}
+
+#[cfg(feature = "python")]
+fn set_task_factory(
+    event_loop: &pyo3::PyAny,
+    (_, task_factory): &(pyo3::PyObject, pyo3::PyObject),
+) -> pyo3::PyResult<()> {
+    event_loop
+        .call_method1("set_task_factory", (task_factory,))
+        .map(drop)
+}
+
+/// Lets the tasks of a new event loop be traced back to the scripts that created
+/// them, if outbound hosts are restricted
+#[cfg(feature = "python")]
+pub(crate) fn prepare_event_loop(event_loop: &pyo3::PyAny) -> pyo3::PyResult<()> {
+    match OUTBOUND_HOSTS.get() {
+        Some(functions) => set_task_factory(event_loop, functions),
+        None => Ok(()),
+    }
+}
+
+/// Tells the sandbox which tenant the compiled code of a script belongs to, if
+/// outbound hosts are restricted
+#[cfg(feature = "python")]
+pub(crate) fn register_script(
+    py: pyo3::Python<'_>,
+    code: &pyo3::PyObject,
+    tenant: Option<&str>,
+) -> pyo3::PyResult<()> {
+    match OUTBOUND_HOSTS.get() {
+        Some((register, _)) => register.call1(py, (code, tenant)).map(drop),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,444 @@
+//! Sends copies of requests to a rewritten handler or another server, logging where
+//! their responses differ from those that were sent, so that the rewrite can be
+//! trusted before it replaces what it shadows
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    body::{Body, Bytes, Full, HttpBody},
+    http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Router,
+};
+use fxhash::FxHashMap;
+use log::{debug, warn};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use tower::ServiceExt;
+use tower_http::catch_panic::CatchPanicLayer;
+
+use crate::{error_tracking::is_sampled, metrics, proxy::forwarded_headers, ScriptOptions};
+
+/// Set on shadowed requests, so that their handlers can skip side effects
+const SHADOW_HEADER: &str = "x-hypermangle-shadow";
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct ShadowConfig {
+    /// Requests to this path, and every path below it, are shadowed
+    path: String,
+    /// Scripts routed like those of `scripts`, such as a rewrite of the script
+    /// being shadowed
+    #[serde(default)]
+    scripts_dir: String,
+    /// Such as `http://127.0.0.1:9000`, which requests are sent to with the same path
+    /// instead of `scripts_dir`
+    #[serde(default)]
+    upstream: String,
+    /// Requests with other methods are not shadowed, as they tend to change state
+    #[serde(default = "default_methods")]
+    methods: Vec<String>,
+    /// The fraction of requests that are shadowed, from 0 to 1
+    #[serde(default = "default_sample_rate")]
+    sample_rate: f64,
+    /// When to stop shadowing, in RFC 3339. Never if empty
+    #[serde(default)]
+    until: String,
+    /// Requests with larger bodies are not shadowed, and only the statuses of larger
+    /// responses are compared
+    #[serde(default = "default_max_body_bytes")]
+    max_body_bytes: u64,
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+}
+
+fn default_methods() -> Vec<String> {
+    vec!["GET".into(), "HEAD".into()]
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+fn default_max_body_bytes() -> u64 {
+    1024 * 1024
+}
+
+fn default_timeout_ms() -> u64 {
+    10_000
+}
+
+enum Target {
+    /// Locked, as routers are not `Sync`, and shared by the shadows with the same
+    /// `scripts_dir`
+    Scripts(Arc<Mutex<Router>>),
+    Upstream {
+        url: String,
+        client: reqwest::Client,
+    },
+}
+
+struct Shadow {
+    path: String,
+    /// The scripts folder or upstream, for logs
+    name: String,
+    target: Target,
+    methods: Vec<Method>,
+    sample_rate: f64,
+    until: Option<SystemTime>,
+    max_body_bytes: u64,
+    timeout: Duration,
+}
+
+/// What is compared between the response that was sent and that of its shadow
+struct Observed {
+    status: StatusCode,
+    content_type: Option<HeaderValue>,
+    /// `None` if it was too large to be compared
+    body: Option<Bytes>,
+}
+
+impl Observed {
+    fn is_json(&self) -> bool {
+        self.content_type
+            .as_ref()
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.split(';').next())
+            .is_some_and(|x| x == "application/json" || x.ends_with("+json"))
+    }
+}
+
+/// The first place where `a` and `b` differ, as a JSON pointer
+fn json_difference(a: &serde_json::Value, b: &serde_json::Value, at: String) -> Option<String> {
+    use serde_json::Value;
+
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => {
+            for (key, value) in a {
+                let at = format!("{at}/{key}");
+                match b.get(key) {
+                    Some(other) => {
+                        if let Some(at) = json_difference(value, other, at) {
+                            return Some(at);
+                        }
+                    }
+                    None => return Some(at),
+                }
+            }
+            b.keys()
+                .find(|key| !a.contains_key(*key))
+                .map(|key| format!("{at}/{key}"))
+        }
+        (Value::Array(a), Value::Array(b)) => {
+            for (i, (value, other)) in a.iter().zip(b).enumerate() {
+                if let Some(at) = json_difference(value, other, format!("{at}/{i}")) {
+                    return Some(at);
+                }
+            }
+            (a.len() != b.len()).then(|| format!("{at}/{}", a.len().min(b.len())))
+        }
+        (a, b) => (a != b).then_some(at),
+    }
+}
+
+/// How the response of the shadow differs from the one that was sent
+fn differences(sent: &Observed, shadow: &Observed) -> Vec<String> {
+    let mut differences = vec![];
+    if sent.status != shadow.status {
+        differences.push(format!("status {} != {}", sent.status, shadow.status));
+    }
+    if sent.content_type != shadow.content_type {
+        differences.push(format!(
+            "content type {:?} != {:?}",
+            sent.content_type, shadow.content_type
+        ));
+    }
+    let (Some(sent_body), Some(shadow_body)) = (&sent.body, &shadow.body) else {
+        return differences;
+    };
+    if sent_body == shadow_body {
+        return differences;
+    }
+    if sent.is_json() && shadow.is_json() {
+        if let (Ok(a), Ok(b)) = (
+            serde_json::from_slice::<serde_json::Value>(sent_body),
+            serde_json::from_slice::<serde_json::Value>(shadow_body),
+        ) {
+            if let Some(at) = json_difference(&a, &b, String::new()) {
+                differences.push(format!("JSON body differs at {at:?}"));
+            }
+            return differences;
+        }
+    }
+    let at = sent_body
+        .iter()
+        .zip(shadow_body.iter())
+        .position(|(a, b)| a != b)
+        .unwrap_or(sent_body.len().min(shadow_body.len()));
+    differences.push(format!(
+        "body of {} bytes != {} bytes, from byte {at}",
+        sent_body.len(),
+        shadow_body.len()
+    ));
+    differences
+}
+
+fn content_type(headers: &HeaderMap) -> Option<HeaderValue> {
+    headers.get(header::CONTENT_TYPE).cloned()
+}
+
+impl Shadow {
+    fn new(
+        config: &ShadowConfig,
+        options: ScriptOptions,
+        loaded: &mut FxHashMap<String, Arc<Mutex<Router>>>,
+    ) -> Self {
+        let path = config.path.trim_end_matches('/').to_owned();
+        assert!(
+            path.is_empty() || path.starts_with('/'),
+            "Shadow path should start with /, not {:?}",
+            config.path
+        );
+        let timeout = Duration::from_millis(config.timeout_ms);
+        let target = match (config.scripts_dir.is_empty(), config.upstream.is_empty()) {
+            (false, true) => Target::Scripts(
+                loaded
+                    .entry(config.scripts_dir.clone())
+                    .or_insert_with_key(|dir| {
+                        Arc::new(Mutex::new(
+                            crate::load_scripts_into_router_with_options(
+                                Router::new(),
+                                dir.as_ref(),
+                                options,
+                            )
+                            .layer(CatchPanicLayer::new()),
+                        ))
+                    })
+                    .clone(),
+            ),
+            (true, false) => Target::Upstream {
+                url: config.upstream.trim_end_matches('/').to_owned(),
+                client: reqwest::Client::builder()
+                    .timeout(timeout)
+                    .build()
+                    .expect("Shadow HTTP client should be buildable"),
+            },
+            _ => panic!(
+                "Shadow of {:?} should have either scripts_dir or upstream",
+                config.path
+            ),
+        };
+        Self {
+            name: format!("{}{}", config.scripts_dir, config.upstream),
+            target,
+            methods: config
+                .methods
+                .iter()
+                .map(|x| {
+                    x.parse()
+                        .expect("Shadow method should be a valid HTTP Method")
+                })
+                .collect(),
+            sample_rate: config.sample_rate,
+            until: (!config.until.is_empty()).then(|| {
+                humantime::parse_rfc3339(&config.until).unwrap_or_else(|e| {
+                    panic!(
+                        "Shadow until should be a time in RFC 3339, not {:?}: {e}",
+                        config.until
+                    )
+                })
+            }),
+            max_body_bytes: config.max_body_bytes,
+            timeout,
+            path,
+        }
+    }
+
+    fn covers(&self, path: &str) -> bool {
+        path.strip_prefix(self.path.as_str())
+            .is_some_and(|x| x.is_empty() || x.starts_with('/'))
+    }
+
+    fn wants(&self, request: &Request<Body>) -> bool {
+        self.methods.contains(request.method())
+            && !request.headers().contains_key(header::UPGRADE)
+            && self.until.is_none_or(|x| SystemTime::now() < x)
+            && request
+                .body()
+                .size_hint()
+                .exact()
+                .is_some_and(|x| x <= self.max_body_bytes)
+            && is_sampled(self.sample_rate)
+    }
+
+    async fn send(&self, request: Request<Bytes>) -> Result<Observed, String> {
+        match &self.target {
+            Target::Scripts(router) => {
+                let request = request.map(Body::from);
+                let router = router.lock().clone();
+                let response = tokio::time::timeout(self.timeout, router.oneshot(request))
+                    .await
+                    .map_err(|_| format!("timed out after {:?}", self.timeout))?
+                    .expect("Routers should be infallible");
+                let (parts, body) = response.into_parts();
+                let body = match body.size_hint().exact() {
+                    Some(len) if len <= self.max_body_bytes => Some(
+                        hyper::body::to_bytes(body)
+                            .await
+                            .map_err(|e| e.to_string())?,
+                    ),
+                    _ => None,
+                };
+                Ok(Observed {
+                    status: parts.status,
+                    content_type: content_type(&parts.headers),
+                    body,
+                })
+            }
+            Target::Upstream { url, client } => {
+                let (parts, body) = request.into_parts();
+                let mut headers = forwarded_headers(&parts.headers);
+                headers.remove(header::HOST);
+                headers.remove(header::CONTENT_LENGTH);
+                let path = parts
+                    .uri
+                    .path_and_query()
+                    .map(|x| x.as_str())
+                    .unwrap_or("/");
+                let response = client
+                    .request(parts.method, format!("{url}{path}"))
+                    .headers(headers)
+                    .body(body)
+                    .send()
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let status = response.status();
+                let content_type = content_type(response.headers());
+                let body = match response.content_length() {
+                    Some(len) if len <= self.max_body_bytes => {
+                        Some(response.bytes().await.map_err(|e| e.to_string())?)
+                    }
+                    _ => None,
+                };
+                Ok(Observed {
+                    status,
+                    content_type,
+                    body,
+                })
+            }
+        }
+    }
+
+    async fn compare(&self, request: Request<Bytes>, sent: Observed) {
+        let method = request.method().clone();
+        let path = request.uri().path().to_owned();
+        let request_id = request
+            .headers()
+            .get("x-request-id")
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or_default()
+            .to_owned();
+
+        let outcome = match self.send(request).await {
+            Ok(shadow) => {
+                let differences = differences(&sent, &shadow);
+                if differences.is_empty() {
+                    debug!("Shadow of {method} {path} matched during request {request_id}");
+                    "matched"
+                } else {
+                    warn!(
+                        "Shadow of {method} {path} by {} differs during request {request_id}: {}",
+                        self.name,
+                        differences.join(", ")
+                    );
+                    "differed"
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "Shadow of {method} {path} by {} failed during request {request_id}: {e}",
+                    self.name
+                );
+                "failed"
+            }
+        };
+        metrics::increment(
+            "hypermangle_shadow_requests_total",
+            "Shadowed requests by whether the response of their shadow matched",
+            &[("path", &self.path), ("outcome", outcome)],
+        );
+    }
+}
+
+/// Shadows the requests to the routes in `router` that are covered by `shadows`
+pub(crate) fn layer_shadows(
+    router: Router,
+    shadows: &[ShadowConfig],
+    options: ScriptOptions,
+) -> Router {
+    if shadows.is_empty() {
+        return router;
+    }
+    let mut loaded = FxHashMap::default();
+    let shadows: Arc<[Arc<Shadow>]> = shadows
+        .iter()
+        .map(|x| Arc::new(Shadow::new(x, options, &mut loaded)))
+        .collect();
+
+    router.layer(axum::middleware::from_fn(
+        move |request: Request<Body>, next: Next<Body>| {
+            let shadow = shadows
+                .iter()
+                .find(|x| x.covers(request.uri().path()))
+                .filter(|x| x.wants(&request))
+                .cloned();
+            async move {
+                let Some(shadow) = shadow else {
+                    return next.run(request).await;
+                };
+                let (parts, body) = request.into_parts();
+                let body = match hyper::body::to_bytes(body).await {
+                    Ok(body) => body,
+                    Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+                };
+
+                let mut shadowed = Request::builder()
+                    .method(parts.method.clone())
+                    .uri(parts.uri.clone())
+                    .version(parts.version)
+                    .body(body.clone())
+                    .expect("Shadowed requests should be valid");
+                *shadowed.headers_mut() = parts.headers.clone();
+                shadowed
+                    .headers_mut()
+                    .insert(SHADOW_HEADER, HeaderValue::from_static("1"));
+
+                let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+                let (parts, body) = response.into_parts();
+                let (body, buffered) = match body.size_hint().exact() {
+                    Some(len) if len <= shadow.max_body_bytes => {
+                        match hyper::body::to_bytes(body).await {
+                            Ok(bytes) => (axum::body::boxed(Full::new(bytes.clone())), Some(bytes)),
+                            Err(e) => {
+                                warn!("Failed to read the response to shadow: {e}");
+                                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                            }
+                        }
+                    }
+                    _ => (body, None),
+                };
+                let sent = Observed {
+                    status: parts.status,
+                    content_type: content_type(&parts.headers),
+                    body: buffered,
+                };
+                tokio::spawn(async move { shadow.compare(shadowed, sent).await });
+
+                Response::from_parts(parts, body)
+            }
+        },
+    ))
+}
@@ -0,0 +1,75 @@
+//! Routers that requests are sent to from inside other handlers, such as the router of
+//! a virtual host
+
+use std::{
+    cell::RefCell,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use axum::{body::Body, http::Request, response::Response, Router};
+use fxhash::FxHashMap;
+use parking_lot::Mutex;
+use tower::Service;
+
+thread_local! {
+    /// The clones of each router this thread has routed with, by id
+    static CLONES: RefCell<FxHashMap<u64, Router>> = RefCell::default();
+}
+
+/// A router that can be routed with from any thread. Routers are not `Sync`, and
+/// cloning one clones every route, so each thread clones it once, the first time it
+/// routes with it, instead of cloning it under a lock for every request
+#[derive(Clone)]
+pub(crate) struct SharedRouter {
+    id: u64,
+    router: Arc<Mutex<Router>>,
+}
+
+impl SharedRouter {
+    pub(crate) fn new(router: Router) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            router: Arc::new(Mutex::new(router)),
+        }
+    }
+
+    pub(crate) async fn route(&self, request: Request<Body>) -> Response {
+        // Routers are always ready, and their futures do not borrow them
+        let future = CLONES.with(|clones| {
+            clones
+                .borrow_mut()
+                .entry(self.id)
+                .or_insert_with(|| self.router.lock().clone())
+                .call(request)
+        });
+        match future.await {
+            Ok(response) => response,
+            Err(e) => match e {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn requests_are_routed_from_every_thread() {
+        let router =
+            SharedRouter::new(Router::new().route("/", axum::routing::get(|| async { "hello" })));
+        let requests = (0..64).map(|_| {
+            let router = router.clone();
+            tokio::spawn(async move {
+                let response = router.route(Request::new(Body::empty())).await;
+                hyper::body::to_bytes(response.into_body()).await.unwrap()
+            })
+        });
+        for body in futures::future::join_all(requests).await {
+            assert_eq!(body.unwrap(), "hello");
+        }
+    }
+}
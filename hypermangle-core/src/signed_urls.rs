@@ -0,0 +1,195 @@
+use std::{
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+    Router,
+};
+use constant_time_eq::constant_time_eq;
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+
+static KEY: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Marks requests whose URL was signed, which do not need a bearer token
+#[derive(Clone, Copy)]
+pub(crate) struct SignedUrl;
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time should be after the UNIX epoch")
+        .as_secs()
+}
+
+/// Hex encoded HMAC-SHA256 of `data`
+pub(crate) fn hmac_sha256_hex(key: &[u8], data: &[u8]) -> String {
+    let key = PKey::hmac(key).expect("Signing key should be usable for HMAC");
+    let mut signer =
+        Signer::new(MessageDigest::sha256(), &key).expect("HMAC signer should be creatable");
+    signer.update(data).expect("HMAC signer should accept data");
+    signer
+        .sign_to_vec()
+        .expect("HMAC signer should produce a signature")
+        .iter()
+        .map(|x| format!("{x:02x}"))
+        .collect()
+}
+
+fn split_param(pair: &str) -> (&str, &str) {
+    pair.split_once('=').unwrap_or((pair, ""))
+}
+
+/// The parameters of `query` that are signed, which are all but `expires` and
+/// `signature`, in their order
+fn signed_query(query: &str) -> String {
+    query
+        .split('&')
+        .filter(|x| !x.is_empty() && !matches!(split_param(x).0, "expires" | "signature"))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// The value of the parameter `name`, if it is given exactly once
+fn single_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    let mut values = query.split('&').filter_map(|x| {
+        let (key, value) = split_param(x);
+        (key == name).then_some(value)
+    });
+    let value = values.next()?;
+    values.next().is_none().then_some(value)
+}
+
+fn signature(key: &[u8], path: &str, query: &str, expires: u64) -> String {
+    hmac_sha256_hex(key, format!("{path}\n{query}\n{expires}").as_bytes())
+}
+
+/// Appends an expiry and a signature to `path`, which covers the path along with the
+/// query string it may already have
+fn sign_url(path: &str, expires: u64) -> String {
+    let key = KEY.get().expect("URL signing key should be set");
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+    let query = signed_query(query);
+    let signature = signature(key, path, &query, expires);
+    if query.is_empty() {
+        format!("{path}?expires={expires}&signature={signature}")
+    } else {
+        format!("{path}?{query}&expires={expires}&signature={signature}")
+    }
+}
+
+pub(crate) fn init(key: &str) {
+    if KEY.set(key.as_bytes().to_vec()).is_ok() {
+        let _ = hypermangle_py::signing::SIGNER.set(sign_url);
+    }
+}
+
+fn is_valid(request: &Request<Body>, key: &[u8]) -> bool {
+    let query = request.uri().query().unwrap_or_default();
+    let (Some(expires), Some(given)) = (
+        single_param(query, "expires"),
+        single_param(query, "signature"),
+    ) else {
+        return false;
+    };
+    let Ok(expires) = expires.parse::<u64>() else {
+        return false;
+    };
+    let expected = signature(key, request.uri().path(), &signed_query(query), expires);
+    expires > unix_now() && constant_time_eq(expected.as_bytes(), given.as_bytes())
+}
+
+/// Responds with 403 to requests under `prefix` that do not have a valid, unexpired
+/// signature from `sign_url`
+pub(crate) fn layer_signed_urls(router: Router, prefix: String) -> Router {
+    router.layer(axum::middleware::from_fn(
+        move |mut request: Request<Body>, next: Next<Body>| {
+            let path = request.uri().path();
+            let protected = path
+                .strip_prefix(prefix.as_str())
+                .is_some_and(|x| x.is_empty() || x.starts_with('/') || prefix.ends_with('/'));
+            async move {
+                if protected {
+                    let key = KEY.get().expect("URL signing key should be set");
+                    if !is_valid(&request, key) {
+                        return StatusCode::FORBIDDEN.into_response();
+                    }
+                    request.extensions_mut().insert(SignedUrl);
+                }
+                next.run(request).await
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_FOR_TESTS: &str = "test signing key";
+
+    fn request(uri: &str) -> Request<Body> {
+        Request::builder().uri(uri).body(Body::empty()).unwrap()
+    }
+
+    fn signed(path: &str, expires: u64) -> String {
+        init(KEY_FOR_TESTS);
+        sign_url(path, expires)
+    }
+
+    #[test]
+    fn signed_urls_are_valid_until_they_expire() {
+        let url = signed("/files/report.pdf", unix_now() + 60);
+        assert!(is_valid(&request(&url), KEY_FOR_TESTS.as_bytes()));
+
+        let url = signed("/files/report.pdf", unix_now() - 1);
+        assert!(!is_valid(&request(&url), KEY_FOR_TESTS.as_bytes()));
+    }
+
+    #[test]
+    fn tampered_queries_are_invalid() {
+        let url = signed("/files/report.pdf?download=1", unix_now() + 60);
+        let key = KEY_FOR_TESTS.as_bytes();
+        assert!(url.starts_with("/files/report.pdf?download=1&expires="));
+        assert!(is_valid(&request(&url), key));
+
+        assert!(!is_valid(
+            &request(&url.replace("download=1", "download=0")),
+            key
+        ));
+        assert!(!is_valid(&request(&url.replace("download=1&", "")), key));
+        assert!(!is_valid(&request(&format!("{url}&user=admin")), key));
+    }
+
+    #[test]
+    fn repeated_expiries_and_signatures_are_invalid() {
+        let url = signed("/files/report.pdf", unix_now() + 60);
+        let key = KEY_FOR_TESTS.as_bytes();
+        let (_, params) = url.split_once('?').unwrap();
+        assert!(!is_valid(&request(&format!("{url}&{params}")), key));
+
+        let later = format!("expires={}", unix_now() + 3600);
+        let earlier = url.replacen("expires=", &format!("{later}&expires="), 1);
+        assert!(!is_valid(&request(&earlier), key));
+    }
+
+    #[test]
+    fn tampered_urls_are_invalid() {
+        let expires = unix_now() + 60;
+        let url = signed("/files/report.pdf", expires);
+        let key = KEY_FOR_TESTS.as_bytes();
+
+        let other_path = url.replace("/files/report.pdf", "/files/secret.pdf");
+        assert!(!is_valid(&request(&other_path), key));
+
+        let later = url.replace(&expires.to_string(), &(expires + 3600).to_string());
+        assert!(!is_valid(&request(&later), key));
+
+        assert!(!is_valid(&request(&url), b"another key"));
+        assert!(!is_valid(&request("/files/report.pdf"), key));
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    http::{header::USER_AGENT, Request, Response},
+    middleware::Next,
+    Router,
+};
+use log::warn;
+use tracing::{field::Empty, Span};
+
+use crate::geoip::GeoInfo;
+
+/// Attached to the responses of script handlers so that the trace layer can report
+/// which script handled a request
+#[derive(Clone)]
+#[cfg_attr(not(feature = "python"), allow(dead_code))]
+pub(crate) struct HandlerInfo {
+    pub(crate) script: Arc<Path>,
+    pub(crate) handler: &'static str,
+    /// Not known for websocket handlers, which keep running after the response
+    pub(crate) timings: Option<HandlerTimings>,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct HandlerTimings {
+    /// Time spent importing lazy scripts and waiting for the GIL
+    pub(crate) queue: Duration,
+    /// Time spent calling and awaiting the handler
+    pub(crate) python: Duration,
+    /// Time spent converting the returned object into a response
+    pub(crate) serialization: Duration,
+}
+
+pub(crate) fn make_span<B>(request: &Request<B>) -> Span {
+    tracing::debug_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+        script = Empty,
+        handler = Empty,
+        python_ms = Empty,
+    )
+}
+
+pub(crate) fn on_response<B>(response: &Response<B>, latency: Duration, span: &Span) {
+    let status = response.status().as_u16();
+    let latency = latency.as_millis() as u64;
+    let geo = response.extensions().get::<GeoInfo>();
+    let country = geo.and_then(|x| x.country.as_deref());
+    let asn = geo.and_then(|x| x.asn);
+
+    let Some(info) = response.extensions().get::<HandlerInfo>() else {
+        tracing::debug!(
+            parent: span,
+            latency_ms = latency,
+            status,
+            country,
+            asn,
+            "finished processing request"
+        );
+        return;
+    };
+    let script = info.script.display();
+    let python_ms = info.timings.map(|x| x.python.as_millis() as u64);
+    // Without a subscriber, every record would be logged as its own line on top of the
+    // event below
+    if !span.is_disabled() {
+        span.record("script", tracing::field::display(&script));
+        span.record("handler", info.handler);
+        if let Some(python_ms) = python_ms {
+            span.record("python_ms", python_ms);
+        }
+    }
+
+    tracing::debug!(
+        parent: span,
+        latency_ms = latency,
+        python_ms,
+        status,
+        country,
+        asn,
+        %script,
+        handler = %info.handler,
+        "finished processing request"
+    );
+}
+
+/// Identifies the client of a request, for logs
+pub(crate) fn client_info<B>(request: &Request<B>) -> String {
+    let mut info = match crate::client_address::of(request) {
+        Some(address) => format!("client {address}"),
+        None => "unknown client".to_owned(),
+    };
+    if let Some(user_agent) = request
+        .headers()
+        .get(USER_AGENT)
+        .and_then(|x| x.to_str().ok())
+    {
+        info += &format!(" ({user_agent})");
+    }
+    info
+}
+
+/// Warns about every request that takes longer than `threshold` to respond to
+pub(crate) fn layer_slow_requests(router: Router, threshold: Duration) -> Router {
+    router.layer(axum::middleware::from_fn(
+        move |request: Request<Body>, next: Next<Body>| async move {
+            let start = Instant::now();
+            let route = format!("{} {}", request.method(), request.uri().path());
+            let client = client_info(&request);
+            let response = next.run(request).await;
+
+            let elapsed = start.elapsed();
+            if elapsed < threshold {
+                return response;
+            }
+            let handler = match response.extensions().get::<HandlerInfo>() {
+                Some(HandlerInfo {
+                    script,
+                    handler,
+                    timings: Some(timings),
+                }) => format!(
+                    " in {handler} of {} (queue {:?}, python {:?}, serialization {:?})",
+                    script.display(),
+                    timings.queue,
+                    timings.python,
+                    timings.serialization
+                ),
+                Some(HandlerInfo {
+                    script, handler, ..
+                }) => format!(" in {handler} of {}", script.display()),
+                None => String::new(),
+            };
+            warn!(
+                "Slow request: {route} took {elapsed:?}{handler} for {client}, returning {}",
+                response.status()
+            );
+            response
+        },
+    ))
+}
@@ -0,0 +1,439 @@
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use axum::{
+    body::Body,
+    http::{
+        header::{ACCEPT, CACHE_CONTROL, CONTENT_TYPE, LOCATION},
+        HeaderValue, Request, StatusCode, Uri,
+    },
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use fxhash::FxHashMap;
+use log::{error, info};
+use regex::RegexSet;
+use serde::{Deserialize, Serialize};
+use tower::ServiceExt;
+use tower_http::services::ServeDir;
+
+use crate::{headers::CspNonce, routes::RouteRegistry, ConfigError};
+
+mod assets;
+mod markdown;
+
+#[cfg(feature = "python")]
+pub(crate) use assets::asset_url;
+
+/// A folder whose files are served as they are, or as their `.br` or `.gz` sibling
+/// to clients that accept it
+#[derive(Deserialize, Clone)]
+pub(crate) struct StaticMount {
+    /// Such as `/assets`
+    prefix: String,
+    dir: String,
+    /// Lists the contents of folders without an `index.html`, as HTML or as JSON if
+    /// the client accepts it or asks with `?format=json`
+    #[serde(default)]
+    auto_index: bool,
+    /// How listings are sorted when the query does not say, such as with
+    /// `?sort=size&order=desc`
+    #[serde(default)]
+    sort: SortKey,
+    /// Whether files starting with `.` are listed and served
+    #[serde(default)]
+    show_hidden: bool,
+    /// Regexes of file names that are neither listed nor served
+    #[serde(default)]
+    hide: Vec<String>,
+    /// Writes `.br` and `.gz` siblings of text files at startup, unless they are up
+    /// to date
+    #[serde(default)]
+    precompress: bool,
+    /// Serves every file under a name with a hash of its contents too, such as
+    /// `app.3f2a1b9c.css`, which is cached forever. `asset_url` in templates gives
+    /// the hashed URL of a file, as hashed when the server started
+    #[serde(default)]
+    hashed_names: bool,
+    /// Renders `.md` files as HTML, unless asked for with `?raw`, and `index.md` in
+    /// folders without an `index.html`
+    #[serde(default)]
+    markdown: bool,
+    /// A Jinja template that Markdown is rendered into, given the HTML as `content`,
+    /// the first heading as `title` and the URL path as `path`. Defaults to a plain page
+    #[serde(default)]
+    markdown_template: String,
+}
+
+impl StaticMount {
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        if !self.prefix.trim_end_matches('/').starts_with('/') {
+            return Err(ConfigError::StaticPrefix(self.prefix.clone()));
+        }
+        RegexSet::new(&self.hide).map_err(|error| ConfigError::StaticHide {
+            dir: self.dir.clone(),
+            error,
+        })?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum SortKey {
+    #[default]
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortKey {
+    fn from_query(value: &str) -> Option<Self> {
+        match value {
+            "name" => Some(Self::Name),
+            "size" => Some(Self::Size),
+            "modified" => Some(Self::Modified),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    /// In RFC 3339
+    modified: Option<String>,
+    #[serde(skip)]
+    modified_time: Option<SystemTime>,
+}
+
+struct Mount {
+    prefix: String,
+    dir: PathBuf,
+    auto_index: bool,
+    sort: SortKey,
+    show_hidden: bool,
+    hide: RegexSet,
+    serve_dir: ServeDir,
+    /// The files that hashed names stand for, relative to `dir`
+    hashed: FxHashMap<String, String>,
+    markdown: Option<markdown::MarkdownRenderer>,
+}
+
+fn query_param<'a>(uri: &'a Uri, name: &str) -> Option<&'a str> {
+    uri.query()?
+        .split('&')
+        .find_map(|x| x.strip_prefix(name)?.strip_prefix('='))
+}
+
+fn has_query_flag(uri: &Uri, name: &str) -> bool {
+    uri.query().is_some_and(|x| {
+        x.split('&')
+            .any(|x| x == name || x.strip_prefix(name).is_some_and(|x| x.starts_with('=')))
+    })
+}
+
+fn percent_encode(name: &str) -> String {
+    name.bytes()
+        .map(|x| match x {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (x as char).to_string()
+            }
+            _ => format!("%{x:02X}"),
+        })
+        .collect()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl Mount {
+    fn is_hidden(&self, name: &str) -> bool {
+        (!self.show_hidden && name.starts_with('.')) || self.hide.is_match(name)
+    }
+
+    /// The file under the mount that `path` names, unless it escapes the mount or
+    /// goes through hidden files
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let decoded = percent_decode(path)?;
+        let mut resolved = self.dir.clone();
+        for component in Path::new(&decoded).components() {
+            match component {
+                Component::Normal(name) => {
+                    if self.is_hidden(name.to_str()?) {
+                        return None;
+                    }
+                    resolved.push(name);
+                }
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => return None,
+            }
+        }
+        Some(resolved)
+    }
+
+    async fn list(&self, dir: &Path) -> std::io::Result<Vec<Entry>> {
+        let mut entries = vec![];
+        let mut read_dir = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if self.is_hidden(&name) {
+                continue;
+            }
+            let Ok(metadata) = entry.metadata().await else {
+                continue;
+            };
+            let modified_time = metadata.modified().ok();
+            entries.push(Entry {
+                name,
+                is_dir: metadata.is_dir(),
+                size: if metadata.is_dir() { 0 } else { metadata.len() },
+                modified: modified_time.map(|x| humantime::format_rfc3339_seconds(x).to_string()),
+                modified_time,
+            });
+        }
+        Ok(entries)
+    }
+
+    async fn serve(self: Arc<Self>, request: Request<Body>) -> Response {
+        let mut path = request
+            .uri()
+            .path()
+            .strip_prefix(self.prefix.as_str())
+            .unwrap_or_default()
+            .to_owned();
+        let original = self.hashed.get(path.trim_start_matches('/'));
+        let immutable = original.is_some();
+        if let Some(original) = original {
+            path = format!("/{original}");
+        }
+        let Some(resolved) = self.resolve(&path) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+
+        if resolved.is_dir() && !resolved.join("index.html").is_file() {
+            let index_md = resolved.join("index.md");
+            let index_md = (self.markdown.is_some() && index_md.is_file()).then_some(index_md);
+            if self.auto_index || index_md.is_some() {
+                // Relative links in listings only work below a trailing slash
+                if !request.uri().path().ends_with('/') {
+                    let location = format!("{}/", request.uri().path());
+                    return (StatusCode::MOVED_PERMANENTLY, [(LOCATION, location)]).into_response();
+                }
+                if let Some(index_md) = index_md {
+                    return self.render_markdown(&request, &index_md).await;
+                }
+                return self.index(&request, &resolved, &path).await;
+            }
+        }
+        if self.markdown.is_some()
+            && resolved.extension().is_some_and(|x| x == "md")
+            && !has_query_flag(request.uri(), "raw")
+            && resolved.is_file()
+        {
+            return self.render_markdown(&request, &resolved).await;
+        }
+
+        let mut parts = request.uri().clone().into_parts();
+        let path_and_query = match request.uri().query() {
+            Some(query) => format!("/{}?{query}", path.trim_start_matches('/')),
+            None => format!("/{}", path.trim_start_matches('/')),
+        };
+        parts.path_and_query = path_and_query.parse().ok();
+        let (mut request_parts, body) = request.into_parts();
+        request_parts.uri = Uri::from_parts(parts).expect("Stripped URI should be valid");
+        let request = Request::from_parts(request_parts, body);
+
+        let mut response = match self.serve_dir.clone().oneshot(request).await {
+            Ok(response) => response.map(axum::body::boxed),
+            Err(never) => match never {},
+        };
+        if immutable && response.status().is_success() {
+            response.headers_mut().insert(
+                CACHE_CONTROL,
+                HeaderValue::from_static("public, max-age=31536000, immutable"),
+            );
+        }
+        response
+    }
+
+    async fn render_markdown(&self, request: &Request<Body>, file: &Path) -> Response {
+        let renderer = self
+            .markdown
+            .as_ref()
+            .expect("Markdown should only be rendered by mounts that render it");
+        let Ok(source) = tokio::fs::read_to_string(file).await else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        let csp_nonce = request.extensions().get::<CspNonce>().map(|x| &*x.0);
+        match renderer.render(file, &source, request.uri().path(), csp_nonce) {
+            Ok(html) => (
+                [(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/html; charset=utf-8"),
+                )],
+                html,
+            )
+                .into_response(),
+            Err(e) => {
+                error!("Failed to render {file:?}: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+
+    async fn index(&self, request: &Request<Body>, dir: &Path, path: &str) -> Response {
+        let mut entries = match self.list(dir).await {
+            Ok(x) => x,
+            Err(_) => return StatusCode::NOT_FOUND.into_response(),
+        };
+        let uri = request.uri();
+        let sort = query_param(uri, "sort")
+            .and_then(SortKey::from_query)
+            .unwrap_or(self.sort);
+        let descending = query_param(uri, "order") == Some("desc");
+        entries.sort_by(|a, b| {
+            let order = match sort {
+                SortKey::Name => a.name.cmp(&b.name),
+                SortKey::Size => a.size.cmp(&b.size),
+                SortKey::Modified => a.modified_time.cmp(&b.modified_time),
+            }
+            .then_with(|| a.name.cmp(&b.name));
+            let order = if descending { order.reverse() } else { order };
+            // Folders always come first
+            b.is_dir.cmp(&a.is_dir).then(order)
+        });
+
+        let wants_json = query_param(uri, "format") == Some("json")
+            || request
+                .headers()
+                .get(ACCEPT)
+                .and_then(|x| x.to_str().ok())
+                .is_some_and(|x| x.contains("application/json"));
+        if wants_json {
+            return (
+                [(CONTENT_TYPE, HeaderValue::from_static("application/json"))],
+                serde_json::to_string(&entries).expect("Listings should be serializable"),
+            )
+                .into_response();
+        }
+
+        let title = escape_html(&format!("Index of {}{path}", self.prefix));
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title></head>\n<body><h1>{title}</h1>\n<table>\n<tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n"
+        );
+        if !path.trim_matches('/').is_empty() {
+            html += "<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n";
+        }
+        for entry in &entries {
+            let slash = if entry.is_dir { "/" } else { "" };
+            let size = if entry.is_dir {
+                String::new()
+            } else {
+                entry.size.to_string()
+            };
+            html += &format!(
+                "<tr><td><a href=\"{}{slash}\">{}{slash}</a></td><td>{size}</td><td>{}</td></tr>\n",
+                percent_encode(&entry.name),
+                escape_html(&entry.name),
+                entry.modified.as_deref().unwrap_or_default()
+            );
+        }
+        html += "</table>\n</body></html>\n";
+        (
+            [(
+                CONTENT_TYPE,
+                HeaderValue::from_static("text/html; charset=utf-8"),
+            )],
+            html,
+        )
+            .into_response()
+    }
+}
+
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+pub(crate) fn register_routes(routes: &mut RouteRegistry, mounts: &[StaticMount]) {
+    for config in mounts {
+        routes.add_prefix(
+            config.prefix.trim_end_matches('/'),
+            &format!("the static files of {:?}", config.dir),
+        );
+    }
+}
+
+/// Routes the `prefix` of every mount, and every path below it, to its folder
+pub(crate) fn route_static_mounts(mut router: Router, mounts: &[StaticMount]) -> Router {
+    for config in mounts {
+        let prefix = config.prefix.trim_end_matches('/').to_owned();
+        let dir = PathBuf::from(&config.dir);
+        let hide = RegexSet::new(&config.hide).expect("Hidden files should have been validated");
+        let is_hidden =
+            |name: &str| (!config.show_hidden && name.starts_with('.')) || hide.is_match(name);
+        let mut files = vec![];
+        if config.precompress || config.hashed_names {
+            assets::walk(&dir, &is_hidden, &mut files);
+        }
+        if config.precompress {
+            let mut written = 0;
+            for file in &files {
+                match assets::precompress(file) {
+                    Ok(true) => written += 1,
+                    Ok(false) => {}
+                    Err(e) => panic!("{file:?} should be compressible into its folder: {e}"),
+                }
+            }
+            info!("Compressed {written} static files in {dir:?}");
+        }
+        let hashed = if config.hashed_names {
+            assets::hash_names(&prefix, &dir, &files)
+        } else {
+            FxHashMap::default()
+        };
+
+        let mount = Arc::new(Mount {
+            prefix: prefix.clone(),
+            serve_dir: ServeDir::new(&dir).precompressed_br().precompressed_gzip(),
+            dir,
+            auto_index: config.auto_index,
+            sort: config.sort,
+            show_hidden: config.show_hidden,
+            hide,
+            hashed,
+            markdown: config
+                .markdown
+                .then(|| markdown::MarkdownRenderer::new(&config.markdown_template)),
+        });
+        let handler = get(move |request: Request<Body>| mount.clone().serve(request));
+        router = router
+            .route(&prefix, handler.clone())
+            .route(&format!("{prefix}/"), handler.clone())
+            .route(&format!("{prefix}/*path"), handler);
+    }
+    router
+}
@@ -0,0 +1,133 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use fxhash::FxHashMap;
+use parking_lot::RwLock;
+
+/// Files worth compressing ahead of time. Images and fonts are compressed already
+const COMPRESSIBLE: [&str; 11] = [
+    "html", "css", "js", "mjs", "json", "svg", "txt", "xml", "wasm", "map", "md",
+];
+
+/// The hashed URL of every asset by its plain URL, for `asset_url` in templates
+static ASSET_URLS: RwLock<BTreeMap<String, String>> = RwLock::new(BTreeMap::new());
+
+/// The files below `dir`, skipping those whose name `skip` is true for, and the
+/// compressed siblings of other files
+pub(super) fn walk(dir: &Path, skip: &dyn Fn(&str) -> bool, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if skip(name) || name.ends_with(".gz") || name.ends_with(".br") {
+            continue;
+        }
+        let path = entry.path();
+        if path.is_dir() {
+            walk(&path, skip, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+fn is_stale(sibling: &Path, modified: std::time::SystemTime) -> bool {
+    std::fs::metadata(sibling)
+        .and_then(|x| x.modified())
+        .map_or(true, |x| x < modified)
+}
+
+/// Writes `.gz` and `.br` siblings of `file` unless they are up to date, returning
+/// whether any were written
+pub(super) fn precompress(file: &Path) -> std::io::Result<bool> {
+    let compressible = file
+        .extension()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| COMPRESSIBLE.contains(&x));
+    if !compressible {
+        return Ok(false);
+    }
+    let modified = std::fs::metadata(file)?.modified()?;
+    let mut sibling = file.as_os_str().to_owned();
+    sibling.push(".gz");
+    let gz = PathBuf::from(sibling);
+    let mut sibling = file.as_os_str().to_owned();
+    sibling.push(".br");
+    let br = PathBuf::from(sibling);
+
+    let mut written = false;
+    if is_stale(&gz, modified) {
+        let contents = std::fs::read(file)?;
+        let mut encoder =
+            flate2::write::GzEncoder::new(File::create(&gz)?, flate2::Compression::best());
+        encoder.write_all(&contents)?;
+        encoder.finish()?.flush()?;
+        written = true;
+    }
+    if is_stale(&br, modified) {
+        let contents = std::fs::read(file)?;
+        let mut encoder =
+            brotli::CompressorWriter::new(BufWriter::new(File::create(&br)?), 4096, 11, 22);
+        encoder.write_all(&contents)?;
+        encoder.flush()?;
+        written = true;
+    }
+    Ok(written)
+}
+
+/// Such as `css/app.3f2a1b9c.css` for `css/app.css`
+fn hashed_name(relative: &str, contents: &[u8]) -> String {
+    let digest = openssl::sha::sha256(contents);
+    let hash: String = digest[..4].iter().map(|x| format!("{x:02x}")).collect();
+    let (dir, name) = match relative.rsplit_once('/') {
+        Some((dir, name)) => (format!("{dir}/"), name),
+        None => (String::new(), relative),
+    };
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => format!("{dir}{stem}.{hash}.{extension}"),
+        _ => format!("{dir}{name}.{hash}"),
+    }
+}
+
+/// Gives every file a name that changes along with its contents, so that it can be
+/// cached forever. Returns the file each hashed name stands for, relative to `dir`
+pub(super) fn hash_names(prefix: &str, dir: &Path, files: &[PathBuf]) -> FxHashMap<String, String> {
+    let mut hashed = FxHashMap::default();
+    let mut urls = ASSET_URLS.write();
+    for file in files {
+        let Ok(contents) = std::fs::read(file) else {
+            continue;
+        };
+        let Some(relative) = file
+            .strip_prefix(dir)
+            .ok()
+            .and_then(|x| x.to_str())
+            .map(|x| x.replace('\\', "/"))
+        else {
+            continue;
+        };
+        let name = hashed_name(&relative, &contents);
+        urls.insert(format!("{prefix}/{relative}"), format!("{prefix}/{name}"));
+        hashed.insert(name, relative);
+    }
+    hashed
+}
+
+/// The hashed URL of the static file at `path`, such as `/assets/app.css`, or
+/// `path` itself if it has no hashed name
+#[cfg(feature = "python")]
+pub(crate) fn asset_url(path: &str) -> String {
+    ASSET_URLS
+        .read()
+        .get(path)
+        .cloned()
+        .unwrap_or_else(|| path.to_owned())
+}
@@ -0,0 +1,86 @@
+use std::path::Path;
+
+use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd};
+
+/// Used when a mount renders Markdown without a template of its own
+const DEFAULT_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>{{ title }}</title></head>
+<body>
+{{ content }}
+</body>
+</html>
+"#;
+
+/// Renders Markdown files into a Jinja template, which gets the HTML as `content`,
+/// the first heading as `title`, the URL path of the file as `path` and the CSP
+/// nonce of the request, if any, as `csp_nonce`
+pub(super) struct MarkdownRenderer {
+    environment: minijinja::Environment<'static>,
+}
+
+impl MarkdownRenderer {
+    pub(super) fn new(template_path: &str) -> Self {
+        let source = if template_path.is_empty() {
+            DEFAULT_TEMPLATE.to_owned()
+        } else {
+            std::fs::read_to_string(template_path).unwrap_or_else(|e| {
+                panic!("Markdown template {template_path:?} should be readable: {e}")
+            })
+        };
+        let mut environment = minijinja::Environment::new();
+        environment.set_auto_escape_callback(|_| minijinja::AutoEscape::Html);
+        environment
+            .add_template_owned("markdown", source)
+            .unwrap_or_else(|e| {
+                panic!("Markdown template {template_path:?} should be a valid template: {e}")
+            });
+        Self { environment }
+    }
+
+    pub(super) fn render(
+        &self,
+        file: &Path,
+        source: &str,
+        path: &str,
+        csp_nonce: Option<&str>,
+    ) -> Result<String, String> {
+        let mut options = Options::empty();
+        options.insert(Options::ENABLE_TABLES);
+        options.insert(Options::ENABLE_FOOTNOTES);
+        options.insert(Options::ENABLE_STRIKETHROUGH);
+        options.insert(Options::ENABLE_TASKLISTS);
+        options.insert(Options::ENABLE_HEADING_ATTRIBUTES);
+        let events: Vec<_> = Parser::new_ext(source, options).collect();
+
+        let mut title = String::new();
+        let mut in_heading = false;
+        for event in &events {
+            match event {
+                Event::Start(Tag::Heading { .. }) => in_heading = true,
+                Event::End(TagEnd::Heading(_)) => break,
+                Event::Text(text) | Event::Code(text) if in_heading => title += text,
+                _ => {}
+            }
+        }
+        if title.is_empty() {
+            title = file
+                .file_stem()
+                .map(|x| x.to_string_lossy().into_owned())
+                .unwrap_or_default();
+        }
+
+        let mut content = String::new();
+        pulldown_cmark::html::push_html(&mut content, events.into_iter());
+        self.environment
+            .get_template("markdown")
+            .expect("Markdown template should have been added")
+            .render(minijinja::context! {
+                content => minijinja::Value::from_safe_string(content),
+                title,
+                path,
+                csp_nonce,
+            })
+            .map_err(|e| e.to_string())
+    }
+}
@@ -0,0 +1,346 @@
+//! Forwards raw TCP and UDP ports to other servers, so that hypermangle can be the
+//! only process exposed on a small deployment
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use fxhash::FxHashMap;
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time::timeout,
+};
+use tokio_rustls::rustls::ServerConfig;
+
+use crate::{metrics, tls::CertResolver, ConfigError};
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Protocol {
+    Tcp,
+    Udp,
+}
+
+fn default_protocol() -> Protocol {
+    Protocol::Tcp
+}
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct StreamProxyConfig {
+    listen: SocketAddr,
+    /// Such as `127.0.0.1:5432` or `db.internal:5432`
+    upstream: String,
+    #[serde(default = "default_protocol")]
+    protocol: Protocol,
+    /// Terminates TLS with the certificates of the server before forwarding, which
+    /// needs `cert_path` and `key_path`. Only for TCP
+    #[serde(default)]
+    tls: bool,
+    #[serde(default = "default_connect_timeout_ms")]
+    connect_timeout_ms: u64,
+    /// How long UDP clients are remembered without sending or receiving anything
+    #[serde(default = "default_idle_timeout_secs")]
+    idle_timeout_secs: u64,
+    /// The most UDP clients that are remembered at once. Datagrams from new clients
+    /// are dropped while there are this many
+    #[serde(default = "default_max_sessions")]
+    max_sessions: usize,
+}
+
+impl StreamProxyConfig {
+    /// `has_certificate` is whether the server has a certificate to terminate TLS with
+    pub(crate) fn validate(&self, has_certificate: bool) -> Result<(), ConfigError> {
+        let reason = if !self.tls {
+            return Ok(());
+        } else if self.protocol != Protocol::Tcp {
+            "use TCP to terminate TLS"
+        } else if !has_certificate {
+            "only terminate TLS when cert_path and key_path are set"
+        } else {
+            return Ok(());
+        };
+        Err(ConfigError::StreamProxyTls {
+            listen: self.listen,
+            reason,
+        })
+    }
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    60
+}
+
+fn default_max_sessions() -> usize {
+    1024
+}
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most a UDP datagram can carry
+const MAX_DATAGRAM: usize = 65_535;
+
+fn count_connection(listen: &str, outcome: &str) {
+    metrics::increment(
+        "hypermangle_stream_proxy_connections_total",
+        "Connections and UDP sessions to forwarded ports, by whether they reached the upstream",
+        &[("listen", listen), ("outcome", outcome)],
+    );
+}
+
+fn count_bytes(listen: &str, direction: &str, amount: u64) {
+    metrics::increment_by(
+        "hypermangle_stream_proxy_bytes_total",
+        "Bytes forwarded through forwarded ports, by direction",
+        &[("listen", listen), ("direction", direction)],
+        amount,
+    );
+}
+
+async fn forward_tcp<S>(mut client: S, config: &StreamProxyConfig, listen: &str)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let connect = TcpStream::connect(config.upstream.as_str());
+    let mut upstream =
+        match timeout(Duration::from_millis(config.connect_timeout_ms), connect).await {
+            Ok(Ok(upstream)) => upstream,
+            Ok(Err(e)) => {
+                warn!("Failed to connect to {} for {listen}: {e}", config.upstream);
+                count_connection(listen, "unreachable");
+                return;
+            }
+            Err(_) => {
+                warn!("Timed out connecting to {} for {listen}", config.upstream);
+                count_connection(listen, "unreachable");
+                return;
+            }
+        };
+    let _ = upstream.set_nodelay(true);
+    count_connection(listen, "forwarded");
+
+    match tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+        Ok((sent, received)) => {
+            count_bytes(listen, "upstream", sent);
+            count_bytes(listen, "client", received);
+        }
+        Err(e) => debug!("Forwarded connection on {listen} ended with {e}"),
+    }
+}
+
+async fn run_tcp(
+    listener: TcpListener,
+    config: StreamProxyConfig,
+    tls: Option<tokio_rustls::TlsAcceptor>,
+) {
+    info!(
+        "Forwarding TCP{} on {} to {}",
+        if tls.is_some() { " with TLS" } else { "" },
+        config.listen,
+        config.upstream
+    );
+    let config = Arc::new(config);
+    let listen: Arc<str> = config.listen.to_string().into();
+
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to accept TCP connection on {listen}: {e}");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let _ = stream.set_nodelay(true);
+        let config = config.clone();
+        let listen = listen.clone();
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let Some(tls) = tls else {
+                forward_tcp(stream, &config, &listen).await;
+                return;
+            };
+            match timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(stream)).await {
+                Ok(Ok(stream)) => forward_tcp(stream, &config, &listen).await,
+                Ok(Err(e)) => {
+                    debug!("TLS handshake on {listen} failed: {e}");
+                    count_connection(&listen, "handshake_failed");
+                }
+                Err(_) => {
+                    debug!("Client on {listen} did not finish the TLS handshake in time");
+                    count_connection(&listen, "handshake_failed");
+                }
+            }
+        });
+    }
+}
+
+/// An upstream socket for each client, which replies are read from
+type UdpSessions = Arc<Mutex<FxHashMap<SocketAddr, Arc<UdpSocket>>>>;
+
+/// The address of the upstream of a UDP proxy, which is only resolved once as every
+/// session needs it, and resolving would otherwise hold up the datagrams of others
+async fn resolve_udp_upstream(config: &StreamProxyConfig) -> SocketAddr {
+    let mut delay = Duration::from_millis(100);
+    loop {
+        let resolved = tokio::net::lookup_host(config.upstream.as_str())
+            .await
+            .and_then(|mut x| {
+                x.next()
+                    .ok_or_else(|| std::io::Error::other("upstream resolved to no addresses"))
+            });
+        match resolved {
+            Ok(upstream) => return upstream,
+            Err(e) => {
+                warn!(
+                    "Failed to resolve {} for {}: {e}",
+                    config.upstream, config.listen
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(Duration::from_secs(30));
+            }
+        }
+    }
+}
+
+async fn run_udp(socket: UdpSocket, config: StreamProxyConfig) {
+    let socket = Arc::new(socket);
+    let upstream_address = resolve_udp_upstream(&config).await;
+    info!(
+        "Forwarding UDP on {} to {} at {upstream_address}",
+        config.listen, config.upstream
+    );
+    let listen: Arc<str> = config.listen.to_string().into();
+    let idle_timeout = Duration::from_secs(config.idle_timeout_secs);
+    let sessions = UdpSessions::default();
+    let mut buf = vec![0; MAX_DATAGRAM];
+
+    loop {
+        let (len, client) = match socket.recv_from(&mut buf).await {
+            Ok(x) => x,
+            Err(e) => {
+                debug!("Failed to receive UDP datagram on {listen}: {e}");
+                continue;
+            }
+        };
+        let session = sessions.lock().get(&client).cloned();
+        let upstream = match session {
+            Some(upstream) => upstream,
+            None => {
+                if sessions.lock().len() >= config.max_sessions {
+                    debug!("Dropped a UDP datagram from {client} on {listen}, as max_sessions was reached");
+                    count_connection(&listen, "limited");
+                    continue;
+                }
+                let upstream = match connect_udp(upstream_address).await {
+                    Ok(x) => Arc::new(x),
+                    Err(e) => {
+                        warn!("Failed to reach {} for {listen}: {e}", config.upstream);
+                        count_connection(&listen, "unreachable");
+                        continue;
+                    }
+                };
+                sessions.lock().insert(client, upstream.clone());
+                count_connection(&listen, "forwarded");
+                tokio::spawn(relay_replies(
+                    socket.clone(),
+                    upstream.clone(),
+                    client,
+                    sessions.clone(),
+                    idle_timeout,
+                    listen.clone(),
+                ));
+                upstream
+            }
+        };
+        match upstream.send(&buf[..len]).await {
+            Ok(sent) => count_bytes(&listen, "upstream", sent as u64),
+            Err(e) => debug!("Failed to forward UDP datagram on {listen}: {e}"),
+        }
+    }
+}
+
+/// Binding and connecting UDP sockets does not wait on the network
+async fn connect_udp(upstream: SocketAddr) -> std::io::Result<UdpSocket> {
+    let local: SocketAddr = if upstream.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(upstream).await?;
+    Ok(socket)
+}
+
+/// Sends what the upstream replies to `client` until the session has been idle for
+/// `idle_timeout`
+async fn relay_replies(
+    socket: Arc<UdpSocket>,
+    upstream: Arc<UdpSocket>,
+    client: SocketAddr,
+    sessions: UdpSessions,
+    idle_timeout: Duration,
+    listen: Arc<str>,
+) {
+    let mut buf = vec![0; MAX_DATAGRAM];
+    loop {
+        match timeout(idle_timeout, upstream.recv(&mut buf)).await {
+            Ok(Ok(len)) => match socket.send_to(&buf[..len], client).await {
+                Ok(sent) => count_bytes(&listen, "client", sent as u64),
+                Err(e) => debug!("Failed to send UDP reply on {listen}: {e}"),
+            },
+            // Such as ICMP port unreachable, which a later datagram may not get
+            Ok(Err(e)) => debug!("Failed to receive UDP reply on {listen}: {e}"),
+            Err(_) => break,
+        }
+    }
+    // Datagrams from the client that arrived since the timeout go to its next session
+    sessions.lock().remove(&client);
+}
+
+/// Starts forwarding the ports in `proxies`. `resolver` has the certificates of the
+/// server, for the ports that terminate TLS
+pub(crate) fn start(proxies: &[StreamProxyConfig], resolver: Option<&Arc<CertResolver>>) {
+    let mut tls = None;
+    for config in proxies {
+        let acceptor = if config.tls {
+            let resolver = resolver.expect("Stream proxies should have been validated");
+            let acceptor = tls.get_or_insert_with(|| {
+                let config = ServerConfig::builder()
+                    .with_safe_defaults()
+                    .with_no_client_auth()
+                    .with_cert_resolver(resolver.clone());
+                tokio_rustls::TlsAcceptor::from(Arc::new(config))
+            });
+            Some(acceptor.clone())
+        } else {
+            None
+        };
+        // Bound here, so that the server does not start without its ports
+        match config.protocol {
+            Protocol::Tcp => {
+                let listener = std::net::TcpListener::bind(config.listen)
+                    .and_then(|x| {
+                        x.set_nonblocking(true)?;
+                        TcpListener::from_std(x)
+                    })
+                    .unwrap_or_else(|e| panic!("Stream proxy should bind {}: {e}", config.listen));
+                tokio::spawn(run_tcp(listener, config.clone(), acceptor))
+            }
+            Protocol::Udp => {
+                let socket = std::net::UdpSocket::bind(config.listen)
+                    .and_then(|x| {
+                        x.set_nonblocking(true)?;
+                        UdpSocket::from_std(x)
+                    })
+                    .unwrap_or_else(|e| panic!("Stream proxy should bind {}: {e}", config.listen));
+                tokio::spawn(run_udp(socket, config.clone()))
+            }
+        };
+    }
+}
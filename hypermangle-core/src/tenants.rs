@@ -0,0 +1,141 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+};
+
+use axum::http::HeaderValue;
+use fxhash::FxHashMap;
+use serde::Deserialize;
+
+use crate::{
+    rate_limit::{RateLimitBackend, RateLimitConfig, RateLimitOptions},
+    ConfigError, Mount,
+};
+
+/// Settings for the scripts in one top-level subdirectory of a scripts folder, which
+/// override the global settings for the routes of those scripts
+#[derive(Deserialize, Clone, Default)]
+pub(crate) struct TenantConfig {
+    /// Replaces `api_token` for the routes of this tenant, if not empty
+    #[serde(default)]
+    api_token: String,
+    /// Replaces `rate_limit` for the routes of this tenant
+    #[serde(default)]
+    rate_limit: Option<RateLimitConfig>,
+    /// Hosts the scripts of this tenant may connect to, where `*.example.com` matches
+    /// any single subdomain of example.com. Any host is allowed if not set. Once any
+    /// tenant sets this, connections that cannot be traced back to a script, such as
+    /// from threads and executors, are refused for every script
+    #[serde(default)]
+    allowed_hosts: Option<Vec<String>>,
+}
+
+pub(crate) struct Tenant {
+    pub(crate) name: String,
+    /// The folders of this tenant, along with the route they are mounted at
+    dirs: Vec<(PathBuf, String)>,
+    pub(crate) api_token: Option<HeaderValue>,
+    pub(crate) rate_limit: Option<(Arc<dyn RateLimitBackend>, Arc<RateLimitOptions>)>,
+    #[cfg_attr(not(feature = "python"), allow(dead_code))]
+    pub(crate) allowed_hosts: Option<Vec<String>>,
+}
+
+static TENANTS: OnceLock<Vec<Tenant>> = OnceLock::new();
+
+fn dirs_of(mounts: &[Mount], name: &str) -> Vec<(PathBuf, String)> {
+    mounts
+        .iter()
+        .map(|x| {
+            (
+                Path::new(&x.dir).join(name),
+                format!("{}/{name}", x.prefix.trim_end_matches('/')),
+            )
+        })
+        .filter(|(dir, _)| dir.is_dir())
+        .collect()
+}
+
+pub(crate) fn validate(
+    mounts: &[Mount],
+    configs: &FxHashMap<String, TenantConfig>,
+) -> Result<(), ConfigError> {
+    for (name, config) in configs {
+        if dirs_of(mounts, name).is_empty() {
+            return Err(ConfigError::TenantFolder(name.clone()));
+        }
+        if HeaderValue::try_from(config.api_token.as_str()).is_err() {
+            return Err(ConfigError::TenantApiToken(name.clone()));
+        }
+        if let Some(rate_limit) = &config.rate_limit {
+            rate_limit.validate()?;
+        }
+    }
+    Ok(())
+}
+
+/// Registers the tenants in `configs`, keyed by the name of their folder in any of
+/// the `mounts`
+pub(crate) fn init(mounts: &[Mount], configs: &FxHashMap<String, TenantConfig>) {
+    TENANTS.get_or_init(|| {
+        configs
+            .iter()
+            .map(|(name, config)| Tenant {
+                name: name.clone(),
+                dirs: dirs_of(mounts, name),
+                api_token: (!config.api_token.is_empty()).then(|| {
+                    config
+                        .api_token
+                        .parse()
+                        .expect("Tenant api_token should have been validated")
+                }),
+                rate_limit: config
+                    .rate_limit
+                    .as_ref()
+                    .map(|x| (x.backend(), Arc::new(x.options()))),
+                allowed_hosts: config.allowed_hosts.clone(),
+            })
+            .collect()
+    });
+}
+
+pub(crate) fn tenants() -> &'static [Tenant] {
+    TENANTS.get().map(Vec::as_slice).unwrap_or_default()
+}
+
+/// The tenant whose routes `path` is under
+pub(crate) fn for_request_path(path: &str) -> Option<&'static Tenant> {
+    tenants().iter().find(|tenant| {
+        tenant.dirs.iter().any(|(_, prefix)| {
+            path.strip_prefix(prefix.as_str())
+                .is_some_and(|x| x.is_empty() || x.starts_with('/'))
+        })
+    })
+}
+
+/// The tenant the script at `path` belongs to
+#[cfg_attr(not(feature = "python"), allow(dead_code))]
+pub(crate) fn for_script(path: &Path) -> Option<&'static Tenant> {
+    tenants()
+        .iter()
+        .find(|tenant| tenant.dirs.iter().any(|(dir, _)| path.starts_with(dir)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_are_joined_without_doubled_slashes() {
+        let scripts = std::env::temp_dir().join("hypermangle-test-tenants");
+        std::fs::create_dir_all(scripts.join("acme")).unwrap();
+        let mounts: Vec<_> = ["", "/api", "/api/"]
+            .into_iter()
+            .map(|prefix| Mount {
+                dir: scripts.to_str().unwrap().to_owned(),
+                prefix: prefix.to_owned(),
+            })
+            .collect();
+        let prefixes: Vec<_> = dirs_of(&mounts, "acme").into_iter().map(|x| x.1).collect();
+        assert_eq!(prefixes, ["/acme", "/api/acme", "/api/acme"]);
+    }
+}
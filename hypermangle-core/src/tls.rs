@@ -1,45 +1,558 @@
 use std::{
+    fs::File,
+    future::Future,
+    io::BufReader,
     net::SocketAddr,
-    sync::Arc,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, OnceLock},
     task::{self, Poll},
+    time::{Duration, Instant},
 };
 
+use arc_swap::ArcSwapOption;
 use futures::{stream::FuturesUnordered, StreamExt};
+use fxhash::FxHashMap;
 use hyper::server::accept::Accept;
 use log::{debug, warn};
-use tokio::net::{TcpListener, TcpStream};
+use openssl::{
+    pkey::PKey,
+    symm::{self, Cipher},
+    x509::X509,
+};
+use parking_lot::{Mutex, RwLock};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    time::{sleep, timeout, Sleep, Timeout},
+};
 use tokio_rustls::{
-    rustls::{Certificate, ServerConfig},
+    rustls::{
+        server::{
+            AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+            NoServerSessionStorage, ProducesTickets, ResolvesServerCert, ServerSessionMemoryCache,
+            StoresServerSessions,
+        },
+        sign::{self, CertifiedKey},
+        Certificate, PrivateKey, ProtocolVersion, RootCertStore, ServerConfig,
+    },
     server::TlsStream,
 };
 
+use crate::{acme::OnDemand, metrics};
+
+/// How long to wait before accepting again after the listener fails, such as when
+/// the process runs out of file descriptors
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+pub struct TlsOptions {
+    pub max_pending_handshakes: usize,
+    pub handshake_timeout: Duration,
+    /// Number of sessions kept for session ID resumption. 0 disables it
+    pub session_cache_size: usize,
+    pub session_tickets: bool,
+    /// How often the session ticket encryption key is replaced
+    pub ticket_rotation: Duration,
+    /// Whether to answer TLS-ALPN-01 challenges
+    pub acme_tls_alpn: bool,
+    /// PEM certificates of the CAs that client certificates are verified against.
+    /// Clients are only asked for certificates if it is set
+    pub client_ca_path: Option<PathBuf>,
+    /// Rejects clients without a certificate, instead of only verifying those that
+    /// send one
+    pub client_auth_required: bool,
+}
+
+/// Details of the TLS connection a request came over, in the extensions of every
+/// request to a server that terminates TLS
+#[derive(Clone, Debug)]
+pub struct TlsInfo {
+    /// Such as `TLSv1.3`
+    pub version: String,
+    /// Such as `TLS13_AES_128_GCM_SHA256`
+    pub cipher: String,
+    /// The SNI name the client asked for
+    pub server_name: Option<String>,
+    pub alpn: Option<String>,
+    /// The subject of the certificate the client authenticated with, such as
+    /// `CN=alice,O=Example`
+    pub client_subject: Option<String>,
+    /// The SHA-256 digest of the client certificate, in lowercase hex
+    pub client_fingerprint: Option<String>,
+}
+
+impl TlsInfo {
+    pub(crate) fn of(stream: &TlsStream<TcpStream>) -> Self {
+        let connection = stream.get_ref().1;
+        let version = match connection.protocol_version() {
+            Some(ProtocolVersion::TLSv1_2) => "TLSv1.2".to_owned(),
+            Some(ProtocolVersion::TLSv1_3) => "TLSv1.3".to_owned(),
+            Some(version) => format!("{version:?}"),
+            None => String::new(),
+        };
+        let cipher = connection
+            .negotiated_cipher_suite()
+            .map(|x| {
+                x.suite()
+                    .as_str()
+                    .map_or_else(|| format!("{:?}", x.suite()), str::to_owned)
+            })
+            .unwrap_or_default();
+        let client_certificate = connection
+            .peer_certificates()
+            .and_then(|x| x.first())
+            .map(|x| x.0.as_slice());
+        Self {
+            version,
+            cipher,
+            server_name: connection.server_name().map(str::to_owned),
+            alpn: connection
+                .alpn_protocol()
+                .map(|x| String::from_utf8_lossy(x).into_owned()),
+            client_subject: client_certificate.and_then(|der| {
+                let certificate = X509::from_der(der).ok()?;
+                let subject = certificate
+                    .subject_name()
+                    .entries()
+                    .map(|entry| {
+                        let key = entry.object().nid().short_name().unwrap_or("?");
+                        let value = entry.data().to_string().unwrap_or_default();
+                        format!("{key}={value}")
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",");
+                Some(subject)
+            }),
+            client_fingerprint: client_certificate.map(|der| {
+                openssl::sha::sha256(der)
+                    .iter()
+                    .map(|x| format!("{x:02x}"))
+                    .collect()
+            }),
+        }
+    }
+}
+
+/// The CAs at `ca_path`, which client certificates are verified against
+fn client_roots(ca_path: &Path) -> RootCertStore {
+    let file = File::open(ca_path).expect("tls_client_ca_path should be readable");
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut BufReader::new(file))
+        .expect("tls_client_ca_path should be a valid PEM file")
+    {
+        roots
+            .add(&Certificate(cert))
+            .expect("Client CA certificate should be valid");
+    }
+    assert!(
+        !roots.is_empty(),
+        "tls_client_ca_path should have at least one certificate"
+    );
+    roots
+}
+
+/// Session ID storage that counts how often clients resume sessions
+struct CountingSessionStorage(Arc<dyn StoresServerSessions>);
+
+impl StoresServerSessions for CountingSessionStorage {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.0.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        count_resumption("session_id", self.0.get(key))
+    }
+
+    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+        count_resumption("session_id", self.0.take(key))
+    }
+
+    fn can_cache(&self) -> bool {
+        self.0.can_cache()
+    }
+}
+
+fn count_resumption(mechanism: &str, result: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    metrics::increment(
+        "hypermangle_tls_resumption_attempts_total",
+        "TLS session resumption attempts by mechanism and result",
+        &[
+            ("mechanism", mechanism),
+            ("result", if result.is_some() { "hit" } else { "miss" }),
+        ],
+    );
+    result
+}
+
+/// Encrypts session tickets with ChaCha20-Poly1305, sending the random nonce of each
+/// ticket before it
+struct TicketKey([u8; 32]);
+
+impl TicketKey {
+    const NONCE_LEN: usize = 12;
+    const TAG_LEN: usize = 16;
+
+    fn generate() -> Result<Self, openssl::error::ErrorStack> {
+        let mut key = [0; 32];
+        openssl::rand::rand_bytes(&mut key)?;
+        Ok(Self(key))
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let mut nonce = [0; Self::NONCE_LEN];
+        openssl::rand::rand_bytes(&mut nonce).ok()?;
+        let mut tag = [0; Self::TAG_LEN];
+        let cipher = symm::encrypt_aead(
+            Cipher::chacha20_poly1305(),
+            &self.0,
+            Some(&nonce),
+            &[],
+            plain,
+            &mut tag,
+        )
+        .ok()?;
+        Some([&nonce[..], &cipher, &tag].concat())
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        if cipher.len() < Self::NONCE_LEN + Self::TAG_LEN {
+            return None;
+        }
+        let (nonce, cipher) = cipher.split_at(Self::NONCE_LEN);
+        let (cipher, tag) = cipher.split_at(cipher.len() - Self::TAG_LEN);
+        symm::decrypt_aead(
+            Cipher::chacha20_poly1305(),
+            &self.0,
+            Some(nonce),
+            &[],
+            cipher,
+            tag,
+        )
+        .ok()
+    }
+}
+
+struct TicketKeys {
+    current: Arc<TicketKey>,
+    previous: Option<Arc<TicketKey>>,
+    rotated_at: Instant,
+}
+
+/// Replaces the ticket encryption key every `rotation`, while still accepting tickets
+/// encrypted with the key it replaced
+struct RotatingTicketer {
+    keys: Mutex<TicketKeys>,
+    rotation: Duration,
+}
+
+impl RotatingTicketer {
+    fn new(rotation: Duration) -> Self {
+        Self {
+            keys: Mutex::new(TicketKeys {
+                current: Arc::new(
+                    TicketKey::generate().expect("Session ticket keys should be generatable"),
+                ),
+                previous: None,
+                rotated_at: Instant::now(),
+            }),
+            rotation,
+        }
+    }
+
+    fn keys(&self) -> (Arc<TicketKey>, Option<Arc<TicketKey>>) {
+        let mut keys = self.keys.lock();
+        if keys.rotated_at.elapsed() >= self.rotation {
+            match TicketKey::generate() {
+                Ok(new) => {
+                    let old = std::mem::replace(&mut keys.current, Arc::new(new));
+                    keys.previous = Some(old);
+                    keys.rotated_at = Instant::now();
+                    debug!("Rotated session ticket keys");
+                }
+                Err(e) => warn!("Failed to rotate session ticket keys: {e}"),
+            }
+        }
+        (keys.current.clone(), keys.previous.clone())
+    }
+}
+
+impl ProducesTickets for RotatingTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.rotation.as_secs().try_into().unwrap_or(u32::MAX)
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.keys().0.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let (current, previous) = self.keys();
+        let plain = current
+            .decrypt(cipher)
+            .or_else(|| previous.and_then(|x| x.decrypt(cipher)));
+        count_resumption("ticket", plain)
+    }
+}
+
+/// The ALPN protocol of TLS-ALPN-01 validation connections
+pub(crate) const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// Picks the certificate whose subject alternative names match the SNI name sent by
+/// the client, falling back to the first certificate
+struct SniCertificates {
+    names: FxHashMap<String, Arc<CertifiedKey>>,
+    /// Keyed by the part after `*.`
+    wildcards: FxHashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+impl SniCertificates {
+    fn new(certificates: Vec<(Vec<Certificate>, PrivateKey)>) -> Option<Self> {
+        let mut names = FxHashMap::default();
+        let mut wildcards = FxHashMap::default();
+        let mut default = None;
+
+        for (certs, key) in certificates {
+            let leaf = X509::from_der(
+                &certs
+                    .first()
+                    .expect("Certificate chain should not be empty")
+                    .0,
+            )
+            .expect("Certificate should be valid");
+            let key = sign::any_supported_type(&key).expect("Private Key should be valid");
+            let certified = Arc::new(CertifiedKey::new(certs, key));
+
+            for name in leaf.subject_alt_names().into_iter().flatten() {
+                let Some(name) = name.dnsname() else {
+                    continue;
+                };
+                let name = name.to_ascii_lowercase();
+                match name.strip_prefix("*.") {
+                    Some(base) => wildcards.entry(base.to_owned()),
+                    None => names.entry(name),
+                }
+                .or_insert_with(|| certified.clone());
+            }
+            default.get_or_insert(certified);
+        }
+
+        Some(Self {
+            names,
+            wildcards,
+            default: default?,
+        })
+    }
+
+    /// The certificate covering `name`, if any does
+    fn get(&self, name: &str) -> Option<&Arc<CertifiedKey>> {
+        let name = name.to_ascii_lowercase();
+        self.names.get(&name).or_else(|| {
+            let (_, base) = name.split_once('.')?;
+            self.wildcards.get(base)
+        })
+    }
+
+    fn resolve(&self, name: Option<&str>) -> Arc<CertifiedKey> {
+        name.and_then(|x| self.get(x))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+/// The certificates served by a [`TlsAcceptor`], which can be replaced while it is
+/// running, along with the certificates answering TLS-ALPN-01 challenges
+#[derive(Default)]
+pub(crate) struct CertResolver {
+    certificates: ArcSwapOption<SniCertificates>,
+    challenges: RwLock<FxHashMap<String, Arc<CertifiedKey>>>,
+    on_demand: OnceLock<Arc<OnDemand>>,
+}
+
+impl CertResolver {
+    pub(crate) fn new(certificates: Vec<(Vec<Certificate>, PrivateKey)>) -> Self {
+        let resolver = Self::default();
+        resolver.set_certificates(certificates);
+        resolver
+    }
+
+    pub(crate) fn set_certificates(&self, certificates: Vec<(Vec<Certificate>, PrivateKey)>) {
+        self.certificates
+            .store(SniCertificates::new(certificates).map(Arc::new));
+    }
+
+    pub(crate) fn set_on_demand(&self, on_demand: Arc<OnDemand>) {
+        if self.on_demand.set(on_demand).is_err() {
+            panic!("On-demand certificates should only be set once");
+        }
+    }
+
+    pub(crate) fn set_challenge(&self, domain: &str, certified: Option<Arc<CertifiedKey>>) {
+        let mut challenges = self.challenges.write();
+        match certified {
+            Some(certified) => challenges.insert(domain.to_ascii_lowercase(), certified),
+            None => challenges.remove(&domain.to_ascii_lowercase()),
+        };
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let is_challenge = client_hello
+            .alpn()
+            .is_some_and(|mut x| x.any(|x| x == ACME_TLS_ALPN));
+        if is_challenge {
+            let name = client_hello.server_name()?.to_ascii_lowercase();
+            return self.challenges.read().get(&name).cloned();
+        }
+
+        let certificates = self.certificates.load();
+        if let (Some(on_demand), Some(name)) = (self.on_demand.get(), client_hello.server_name()) {
+            if !certificates.as_ref().is_some_and(|x| x.get(name).is_some()) {
+                if let Some(certified) = on_demand.get(name) {
+                    return Some(certified);
+                }
+                // Handshakes fail until the certificate has been obtained
+                if on_demand.request(name) {
+                    return None;
+                }
+            }
+        }
+
+        // No certificates yet means they are still being acquired
+        Some(certificates.as_ref()?.resolve(client_hello.server_name()))
+    }
+}
+
+/// Reads a PEM certificate chain and its PKCS8 private key. Encrypted keys are
+/// decrypted with `passphrase`
+pub(crate) fn load_certificate(
+    cert_path: &Path,
+    key_path: &Path,
+    passphrase: Option<&[u8]>,
+) -> (Vec<Certificate>, PrivateKey) {
+    let file = File::open(cert_path).expect("Cert path should be readable");
+    let mut reader = BufReader::new(file);
+    let certs = rustls_pemfile::certs(&mut reader).expect("Cert file should be valid");
+    let certs: Vec<_> = certs.into_iter().map(Certificate).collect();
+
+    let pem = std::fs::read(key_path).expect("Key path should be readable");
+    // Both PKCS8 and the legacy OpenSSL encryption headers
+    let is_encrypted = pem.windows(9).any(|x| x == b"ENCRYPTED");
+    if is_encrypted {
+        let Some(passphrase) = passphrase else {
+            panic!("Key file is encrypted, but no passphrase was configured");
+        };
+        let key = PKey::private_key_from_pem_passphrase(&pem, passphrase)
+            .expect("Key file should be decryptable with the configured passphrase");
+        return (
+            certs,
+            PrivateKey(
+                key.private_key_to_pkcs8()
+                    .expect("Decrypted key should be encodable"),
+            ),
+        );
+    }
+
+    let mut keys =
+        rustls_pemfile::pkcs8_private_keys(&mut pem.as_slice()).expect("Key file should be valid");
+
+    let key = match keys.len() {
+        0 => panic!("No PKCS8-encoded private key found in key file"),
+        1 => PrivateKey(keys.remove(0)),
+        _ => panic!("More than one PKCS8-encoded private key found in key file"),
+    };
+
+    (certs, key)
+}
+
 pub struct TlsAcceptor {
     acceptor: tokio_rustls::TlsAcceptor,
     listener: TcpListener,
-    accepting: FuturesUnordered<tokio_rustls::Accept<TcpStream>>,
+    accepting: FuturesUnordered<Timeout<tokio_rustls::Accept<TcpStream>>>,
+    max_pending_handshakes: usize,
+    handshake_timeout: Duration,
+    backoff: Option<Pin<Box<Sleep>>>,
 }
 
 impl TlsAcceptor {
-    pub async fn new(
-        certs: Vec<Certificate>,
-        key: tokio_rustls::rustls::PrivateKey,
+    pub(crate) async fn new(
+        resolver: Arc<CertResolver>,
         bind_address: &SocketAddr,
+        options: &TlsOptions,
     ) -> Self {
         if bind_address.port() != 443 {
             warn!("Warning! Serving HTTPS on non-traditional port");
         }
+
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match &options.client_ca_path {
+            Some(ca_path) if options.client_auth_required => builder.with_client_cert_verifier(
+                AllowAnyAuthenticatedClient::new(client_roots(ca_path)).boxed(),
+            ),
+            Some(ca_path) => builder.with_client_cert_verifier(
+                AllowAnyAnonymousOrAuthenticatedClient::new(client_roots(ca_path)).boxed(),
+            ),
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_cert_resolver(resolver);
+        if options.acme_tls_alpn {
+            config.alpn_protocols = vec![b"http/1.1".to_vec(), ACME_TLS_ALPN.to_vec()];
+        }
+
+        config.session_storage = if options.session_cache_size == 0 {
+            Arc::new(NoServerSessionStorage {})
+        } else {
+            Arc::new(CountingSessionStorage(ServerSessionMemoryCache::new(
+                options.session_cache_size,
+            )))
+        };
+        if options.session_tickets {
+            config.ticketer = Arc::new(RotatingTicketer::new(options.ticket_rotation));
+        } else {
+            config.send_tls13_tickets = 0;
+        }
+
         Self {
-            acceptor: tokio_rustls::TlsAcceptor::from(Arc::new(
-                ServerConfig::builder()
-                    .with_safe_defaults()
-                    .with_no_client_auth()
-                    .with_single_cert(certs, key)
-                    .expect("Certificate and Key should be valid"),
-            )),
+            acceptor: tokio_rustls::TlsAcceptor::from(Arc::new(config)),
             listener: TcpListener::bind(bind_address)
                 .await
                 .expect("TcpListener should be binded"),
             accepting: Default::default(),
+            max_pending_handshakes: options.max_pending_handshakes.max(1),
+            handshake_timeout: options.handshake_timeout,
+            backoff: None,
+        }
+    }
+
+    /// Accepts as many pending TCP connections as the handshake cap allows
+    fn drain_listener(&mut self, cx: &mut task::Context<'_>) {
+        if let Some(backoff) = &mut self.backoff {
+            if backoff.as_mut().poll(cx).is_pending() {
+                return;
+            }
+            self.backoff = None;
+        }
+
+        while self.accepting.len() < self.max_pending_handshakes {
+            match self.listener.poll_accept(cx) {
+                Poll::Ready(Ok((stream, _))) => self.accepting.push(timeout(
+                    self.handshake_timeout,
+                    self.acceptor.accept(stream),
+                )),
+                Poll::Ready(Err(e)) => {
+                    warn!("Failed to accept TCP connection: {e}");
+                    let mut backoff = Box::pin(sleep(ACCEPT_ERROR_BACKOFF));
+                    // Registers the waker for when the backoff ends
+                    let _ = backoff.as_mut().poll(cx);
+                    self.backoff = Some(backoff);
+                    return;
+                }
+                Poll::Pending => return,
+            }
         }
     }
 }
@@ -50,24 +563,156 @@ impl Accept for TlsAcceptor {
     type Error = std::io::Error;
 
     fn poll_accept(
-        mut self: std::pin::Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut task::Context<'_>,
     ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
-        if let Poll::Ready(result) = self.listener.poll_accept(cx) {
-            let (stream, _) = result?;
-            self.accepting.push(self.acceptor.accept(stream));
-        };
+        loop {
+            self.drain_listener(cx);
 
-        let Poll::Ready(Some(result)) = self.accepting.poll_next_unpin(cx) else {
-            return Poll::Pending;
-        };
-
-        match result {
-            Err(e) => {
-                debug!("client Error: {e:?}");
-                Poll::Pending
+            match self.accepting.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(Ok(stream)))) => {
+                    if stream.get_ref().1.alpn_protocol() == Some(ACME_TLS_ALPN) {
+                        // The CA only needed the handshake
+                        debug!("Answered a TLS-ALPN-01 challenge");
+                        continue;
+                    }
+                    metrics::increment(
+                        "hypermangle_tls_handshakes_total",
+                        "Completed TLS handshakes",
+                        &[],
+                    );
+                    return Poll::Ready(Some(Ok(stream)));
+                }
+                Poll::Ready(Some(Ok(Err(e)))) => debug!("client Error: {e:?}"),
+                Poll::Ready(Some(Err(_))) => {
+                    debug!("client did not finish the TLS handshake in time")
+                }
+                // Either no handshakes are pending, or none are done. The listener and
+                // the pending handshakes have registered the waker either way
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
             }
-            ok => Poll::Ready(Some(ok)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use openssl::{
+        asn1::Asn1Time,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        x509::{extension::SubjectAlternativeName, X509Builder},
+    };
+
+    use super::*;
+
+    /// A self-signed certificate for `names`
+    fn certificate(names: &[&str]) -> (Vec<Certificate>, PrivateKey) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(Asn1Time::days_from_now(0).unwrap().as_ref())
+            .unwrap();
+        builder
+            .set_not_after(Asn1Time::days_from_now(1).unwrap().as_ref())
+            .unwrap();
+        let mut alt_names = SubjectAlternativeName::new();
+        for name in names {
+            alt_names.dns(name);
+        }
+        let alt_names = alt_names
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(alt_names).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        (
+            vec![Certificate(builder.build().to_der().unwrap())],
+            PrivateKey(key.private_key_to_pkcs8().unwrap()),
+        )
+    }
+
+    fn sni_certificates() -> SniCertificates {
+        SniCertificates::new(vec![
+            certificate(&["example.com", "www.example.com"]),
+            certificate(&["*.example.com"]),
+            certificate(&["Other.org"]),
+        ])
+        .unwrap()
+    }
+
+    /// Which of the certificates of `sni_certificates` `name` resolves to
+    fn resolved(certificates: &SniCertificates, name: Option<&str>) -> usize {
+        let resolved = certificates.resolve(name);
+        ["example.com", "api.example.com", "other.org"]
+            .iter()
+            .position(|x| Arc::ptr_eq(certificates.get(x).unwrap(), &resolved))
+            .unwrap()
+    }
+
+    #[test]
+    fn exact_names_are_preferred_over_wildcards() {
+        let certificates = sni_certificates();
+        assert_eq!(resolved(&certificates, Some("example.com")), 0);
+        assert_eq!(resolved(&certificates, Some("www.example.com")), 0);
+        assert_eq!(resolved(&certificates, Some("api.example.com")), 1);
+    }
+
+    #[test]
+    fn names_are_compared_without_case() {
+        let certificates = sni_certificates();
+        assert_eq!(resolved(&certificates, Some("OTHER.org")), 2);
+        assert_eq!(resolved(&certificates, Some("API.Example.COM")), 1);
+    }
+
+    #[test]
+    fn wildcards_cover_one_label() {
+        let certificates = sni_certificates();
+        assert!(certificates.get("a.b.example.com").is_none());
+        assert!(certificates.get("example.org").is_none());
+    }
+
+    /// Makes the next use of `ticketer` rotate its keys
+    fn age(ticketer: &RotatingTicketer) {
+        ticketer.keys.lock().rotated_at -= ticketer.rotation;
+    }
+
+    #[test]
+    fn tickets_are_accepted_until_their_key_is_replaced_twice() {
+        let ticketer = RotatingTicketer::new(Duration::from_secs(3600));
+        let ticket = ticketer.encrypt(b"session").unwrap();
+        assert_eq!(ticketer.decrypt(&ticket).as_deref(), Some(&b"session"[..]));
+
+        age(&ticketer);
+        let newer = ticketer.encrypt(b"newer").unwrap();
+        assert_eq!(ticketer.decrypt(&ticket).as_deref(), Some(&b"session"[..]));
+
+        age(&ticketer);
+        assert_eq!(ticketer.decrypt(&ticket), None);
+        assert_eq!(ticketer.decrypt(&newer).as_deref(), Some(&b"newer"[..]));
+    }
+
+    #[test]
+    fn tampered_tickets_are_rejected() {
+        let ticketer = RotatingTicketer::new(Duration::from_secs(3600));
+        let mut ticket = ticketer.encrypt(b"session").unwrap();
+        *ticket.last_mut().unwrap() ^= 1;
+        assert_eq!(ticketer.decrypt(&ticket), None);
+        assert_eq!(ticketer.decrypt(&ticket[..TicketKey::NONCE_LEN]), None);
+        assert_ne!(
+            ticketer.encrypt(b"session").unwrap(),
+            ticketer.encrypt(b"session").unwrap()
+        );
+    }
+
+    #[test]
+    fn unknown_names_get_the_first_certificate() {
+        let certificates = sni_certificates();
+        assert_eq!(resolved(&certificates, Some("unknown.net")), 0);
+        assert_eq!(resolved(&certificates, None), 0);
+        assert!(SniCertificates::new(vec![]).is_none());
+    }
+}
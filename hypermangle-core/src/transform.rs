@@ -0,0 +1,131 @@
+//! Transforms the bodies of requests and responses in Rust before scripts see them,
+//! such as to decrypt fields or scrub personal data, for applications embedding
+//! hypermangle
+
+use std::sync::Arc;
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header, request, response, HeaderMap, HeaderValue, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Router,
+};
+use log::warn;
+
+/// Changes the bodies of the requests and responses of the routes it is registered
+/// for with [`BodyTransform`]. Bodies are buffered whole before they are passed in,
+/// so transformers should be quick, as they run on the runtime
+pub trait BodyTransformer: Send + Sync {
+    /// The body a request is routed with. Returning a response rejects the request
+    /// with it instead, such as when a field fails to decrypt
+    #[allow(clippy::result_large_err)]
+    fn request(&self, _parts: &request::Parts, body: Bytes) -> Result<Bytes, Response> {
+        Ok(body)
+    }
+
+    /// The body a response is sent with. Event streams and upgrades are not passed
+    /// in, as they do not end, nor are responses to HEAD
+    fn response(&self, _parts: &response::Parts, body: Bytes) -> Bytes {
+        body
+    }
+}
+
+/// A transformer and the path it applies to, which covers the paths below it
+#[derive(Clone)]
+pub struct BodyTransform {
+    path: String,
+    transformer: Arc<dyn BodyTransformer>,
+}
+
+impl BodyTransform {
+    /// `path` is such as `/api/users`, or `/` for every route
+    pub fn new(path: &str, transformer: impl BodyTransformer + 'static) -> Self {
+        assert!(
+            path.starts_with('/'),
+            "Body transform path should start with /, not {path:?}"
+        );
+        Self {
+            path: path.trim_end_matches('/').to_owned(),
+            transformer: Arc::new(transformer),
+        }
+    }
+
+    fn covers(&self, path: &str) -> bool {
+        path.strip_prefix(self.path.as_str())
+            .is_some_and(|x| x.is_empty() || x.starts_with('/'))
+    }
+}
+
+fn is_unending(parts: &response::Parts) -> bool {
+    parts.status == StatusCode::SWITCHING_PROTOCOLS
+        || parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok())
+            .is_some_and(|x| x.starts_with("text/event-stream"))
+}
+
+/// Declares the length of a transformed body, which is no longer chunked
+fn set_length(headers: &mut HeaderMap, body: &Bytes) {
+    headers.remove(header::TRANSFER_ENCODING);
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+}
+
+/// Passes the bodies of the routes in `router` that `transforms` cover through their
+/// transformers. Requests go through them in order, and responses in reverse
+pub(crate) fn layer_body_transforms(router: Router, transforms: &[BodyTransform]) -> Router {
+    if transforms.is_empty() {
+        return router;
+    }
+    let transforms: Arc<[BodyTransform]> = transforms.into();
+
+    router.layer(axum::middleware::from_fn(
+        move |request: Request<Body>, next: Next<Body>| {
+            let transformers: Vec<_> = transforms
+                .iter()
+                .filter(|x| x.covers(request.uri().path()))
+                .map(|x| x.transformer.clone())
+                .collect();
+            async move {
+                if transformers.is_empty() {
+                    return next.run(request).await;
+                }
+                let (mut parts, body) = request.into_parts();
+                let mut body = match hyper::body::to_bytes(body).await {
+                    Ok(body) => body,
+                    Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+                };
+                for transformer in &transformers {
+                    body = match transformer.request(&parts, body) {
+                        Ok(body) => body,
+                        Err(response) => return response,
+                    };
+                }
+                if !body.is_empty() || parts.headers.contains_key(header::CONTENT_LENGTH) {
+                    set_length(&mut parts.headers, &body);
+                }
+                // Responses to HEAD have the length of a body that is not sent
+                let is_head = parts.method == Method::HEAD;
+
+                let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+                let (mut parts, body) = response.into_parts();
+                if is_head || is_unending(&parts) {
+                    return Response::from_parts(parts, body);
+                }
+                let mut body = match hyper::body::to_bytes(body).await {
+                    Ok(body) => body,
+                    Err(e) => {
+                        warn!("Failed to read the response to transform: {e}");
+                        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                    }
+                };
+                for transformer in transformers.iter().rev() {
+                    body = transformer.response(&parts, body);
+                }
+                set_length(&mut parts.headers, &body);
+                Response::from_parts(parts, axum::body::boxed(axum::body::Full::new(body)))
+            }
+        },
+    ))
+}
@@ -0,0 +1,278 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header::CONTENT_TYPE, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures::{Stream, StreamExt};
+use log::error;
+use serde::Deserialize;
+use tokio::{fs::File, io::AsyncWriteExt};
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct UploadConfig {
+    /// Where uploads are spooled, which is the system temporary folder if empty
+    #[serde(default)]
+    dir: String,
+    #[serde(default = "default_max_file_bytes")]
+    max_file_bytes: u64,
+    /// The most a whole upload can be, including every file and field in it
+    #[serde(default = "default_max_total_bytes")]
+    max_total_bytes: u64,
+    /// The most files a multipart upload can have
+    #[serde(default = "default_max_files")]
+    max_files: usize,
+    /// The most each field of a multipart upload that is not a file can be, as they
+    /// are kept in memory
+    #[serde(default = "default_max_field_bytes")]
+    max_field_bytes: usize,
+    /// Content types that can be uploaded, where `image/*` matches any image. Any
+    /// type can be uploaded if empty
+    #[serde(default)]
+    content_types: Vec<String>,
+}
+
+fn default_max_file_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_max_total_bytes() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_max_files() -> usize {
+    32
+}
+
+fn default_max_field_bytes() -> usize {
+    64 * 1024
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            dir: String::new(),
+            max_file_bytes: default_max_file_bytes(),
+            max_total_bytes: default_max_total_bytes(),
+            max_files: default_max_files(),
+            max_field_bytes: default_max_field_bytes(),
+            content_types: vec![],
+        }
+    }
+}
+
+static CONFIG: OnceLock<UploadConfig> = OnceLock::new();
+
+pub(crate) fn init(config: &UploadConfig) {
+    if !config.dir.is_empty() {
+        std::fs::create_dir_all(&config.dir)
+            .unwrap_or_else(|e| panic!("Upload folder {:?} should be creatable: {e}", config.dir));
+    }
+    let _ = CONFIG.set(config.clone());
+}
+
+fn config() -> &'static UploadConfig {
+    CONFIG.get_or_init(Default::default)
+}
+
+impl UploadConfig {
+    fn dir(&self) -> PathBuf {
+        if self.dir.is_empty() {
+            std::env::temp_dir()
+        } else {
+            self.dir.clone().into()
+        }
+    }
+
+    fn allows(&self, content_type: Option<&str>) -> bool {
+        if self.content_types.is_empty() {
+            return true;
+        }
+        let Some(content_type) = content_type else {
+            return false;
+        };
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        self.content_types
+            .iter()
+            .any(|x| match x.strip_suffix("/*") {
+                Some(kind) => essence
+                    .split_once('/')
+                    .is_some_and(|(x, _)| x.eq_ignore_ascii_case(kind)),
+                None => x.eq_ignore_ascii_case(essence),
+            })
+    }
+}
+
+pub(crate) struct SpooledFile {
+    pub(crate) path: PathBuf,
+    pub(crate) field: Option<String>,
+    pub(crate) filename: Option<String>,
+    pub(crate) content_type: Option<String>,
+    pub(crate) size: u64,
+}
+
+/// The files of an upload, which are deleted when this is dropped
+#[derive(Default)]
+pub(crate) struct Spooled {
+    pub(crate) files: Vec<SpooledFile>,
+    pub(crate) fields: Vec<(String, String)>,
+}
+
+impl Drop for Spooled {
+    fn drop(&mut self) {
+        for file in &self.files {
+            // Handlers may have moved the file already
+            let _ = std::fs::remove_file(&file.path);
+        }
+    }
+}
+
+fn spool_path(dir: &Path) -> PathBuf {
+    let mut bytes = [0u8; 16];
+    openssl::rand::rand_bytes(&mut bytes).expect("Random bytes should be available");
+    let name: String = bytes.iter().map(|x| format!("{x:02x}")).collect();
+    dir.join(format!("hypermangle-upload-{name}"))
+}
+
+fn io_error(e: std::io::Error) -> Response {
+    error!("Failed to spool upload: {e}");
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
+
+/// Writes `chunks` to a new file in the upload folder, which is recorded in `spooled`
+/// even if writing fails so that it gets deleted
+async fn spool_file(
+    spooled: &mut Spooled,
+    mut file: SpooledFile,
+    chunks: impl Stream<Item = Result<Bytes, StatusCode>>,
+) -> Result<(), Response> {
+    let config = config();
+    if !config.allows(file.content_type.as_deref()) {
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response());
+    }
+
+    file.path = spool_path(&config.dir());
+    let mut writer = File::options()
+        .write(true)
+        .create_new(true)
+        .open(&file.path)
+        .await
+        .map_err(io_error)?;
+    spooled.files.push(file);
+    let index = spooled.files.len() - 1;
+
+    let mut chunks = std::pin::pin!(chunks);
+    let mut size = 0;
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(IntoResponse::into_response)?;
+        size += chunk.len() as u64;
+        if size > config.max_file_bytes.min(config.max_total_bytes) {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+        }
+        writer.write_all(&chunk).await.map_err(io_error)?;
+    }
+    writer.flush().await.map_err(io_error)?;
+    spooled.files[index].size = size;
+    Ok(())
+}
+
+fn multipart_status(e: multer::Error) -> StatusCode {
+    match e {
+        multer::Error::FieldSizeExceeded { .. } | multer::Error::StreamSizeExceeded { .. } => {
+            StatusCode::PAYLOAD_TOO_LARGE
+        }
+        // Fields report the limit of the whole stream wrapped in a read failure
+        multer::Error::StreamReadFailed(e) => match e.downcast::<multer::Error>() {
+            Ok(e) => multipart_status(*e),
+            Err(_) => StatusCode::BAD_REQUEST,
+        },
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+/// Spools every file of a multipart request, or the whole body of any other request,
+/// to the upload folder
+pub(crate) async fn spool(request: Request<Body>) -> Result<Spooled, Response> {
+    let mut spooled = Spooled::default();
+    let content_type = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .map(str::to_owned);
+
+    if !content_type
+        .as_deref()
+        .is_some_and(|x| x.starts_with("multipart/form-data"))
+    {
+        let file = SpooledFile {
+            path: PathBuf::new(),
+            field: None,
+            filename: None,
+            content_type,
+            size: 0,
+        };
+        let body = request
+            .into_body()
+            .map(|x| x.map_err(|_| StatusCode::BAD_REQUEST));
+        spool_file(&mut spooled, file, body).await?;
+        return Ok(spooled);
+    }
+
+    let Ok(boundary) = multer::parse_boundary(content_type.as_deref().unwrap_or_default()) else {
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    };
+    let config = config();
+    let limit = multer::SizeLimit::new()
+        .whole_stream(config.max_total_bytes)
+        .per_field(config.max_file_bytes);
+    let mut multipart = multer::Multipart::with_constraints(
+        request.into_body(),
+        boundary,
+        multer::Constraints::new().size_limit(limit),
+    );
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(x)) => x,
+            Ok(None) => break,
+            Err(e) => return Err(multipart_status(e).into_response()),
+        };
+        let name = field.name().map(str::to_owned);
+        if field.file_name().is_none() {
+            let mut value = vec![];
+            while let Some(chunk) = field
+                .chunk()
+                .await
+                .map_err(|e| multipart_status(e).into_response())?
+            {
+                if value.len() + chunk.len() > config.max_field_bytes {
+                    return Err(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+                }
+                value.extend_from_slice(&chunk);
+            }
+            let value = String::from_utf8_lossy(&value).into_owned();
+            spooled.fields.push((name.unwrap_or_default(), value));
+            continue;
+        }
+        if spooled.files.len() >= config.max_files {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+        }
+        let file = SpooledFile {
+            path: PathBuf::new(),
+            field: name,
+            filename: field.file_name().map(str::to_owned),
+            content_type: field.content_type().map(ToString::to_string),
+            size: 0,
+        };
+        spool_file(
+            &mut spooled,
+            file,
+            field.map(|x| x.map_err(multipart_status)),
+        )
+        .await?;
+    }
+    Ok(spooled)
+}
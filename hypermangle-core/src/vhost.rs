@@ -0,0 +1,86 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    http::{header::HOST, Request},
+    Router,
+};
+use fxhash::FxHashMap;
+use serde::Deserialize;
+
+use crate::{headers::HeaderRule, shared_router::SharedRouter};
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct VirtualHost {
+    /// Host names served by this virtual host. `*.example.com` matches any single
+    /// subdomain of example.com
+    pub(crate) hosts: Vec<String>,
+    pub(crate) scripts_dir: String,
+    #[serde(default)]
+    pub(crate) headers: Vec<HeaderRule>,
+}
+
+struct Hosts {
+    names: FxHashMap<String, SharedRouter>,
+    /// Keyed by the part after `*.`
+    wildcards: FxHashMap<String, SharedRouter>,
+    default: SharedRouter,
+}
+
+impl Hosts {
+    fn get(&self, host: &str) -> &SharedRouter {
+        self.names
+            .get(host)
+            .or_else(|| {
+                let (_, base) = host.split_once('.')?;
+                self.wildcards.get(base)
+            })
+            .unwrap_or(&self.default)
+    }
+}
+
+pub(crate) fn request_host(request: &Request<Body>) -> Option<String> {
+    // HTTP/2 requests carry the host in the URI instead of a Host header
+    let host = match request.uri().host() {
+        Some(host) => host,
+        None => {
+            let host = request.headers().get(HOST)?.to_str().ok()?;
+            // IPv6 literals end with ] when they have no port
+            if host.ends_with(']') {
+                host
+            } else {
+                host.rsplit_once(':').map_or(host, |x| x.0)
+            }
+        }
+    };
+    Some(host.trim_end_matches('.').to_ascii_lowercase())
+}
+
+/// Dispatches every request to the router registered for its host name, falling back
+/// to `default` for unknown hosts
+pub fn route_by_host(default: Router, hosts: impl IntoIterator<Item = (String, Router)>) -> Router {
+    let mut names = FxHashMap::default();
+    let mut wildcards = FxHashMap::default();
+    for (host, router) in hosts {
+        let router = SharedRouter::new(router);
+        let host = host.to_ascii_lowercase();
+        match host.strip_prefix("*.") {
+            Some(base) => wildcards.insert(base.to_owned(), router),
+            None => names.insert(host, router),
+        };
+    }
+    let hosts = Arc::new(Hosts {
+        names,
+        wildcards,
+        default: SharedRouter::new(default),
+    });
+
+    Router::new().fallback(move |request: Request<Body>| {
+        let router = match request_host(&request) {
+            Some(host) => hosts.get(&host),
+            None => &hosts.default,
+        }
+        .clone();
+        async move { router.route(request).await }
+    })
+}
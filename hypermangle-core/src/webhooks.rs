@@ -0,0 +1,294 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use fxhash::FxHashMap;
+use log::{error, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    circuit::{CircuitBreaker, CircuitBreakerConfig},
+    metrics, signed_urls,
+};
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct WebhookConfig {
+    /// Where pending deliveries are kept, so that they are retried after a restart
+    queue_dir: String,
+    /// Key for the `X-Hypermangle-Signature` header. Deliveries are unsigned if empty
+    #[serde(default)]
+    signing_key: String,
+    /// Deliveries that fail this many times are moved aside as `{id}.failed`
+    #[serde(default = "default_max_attempts")]
+    max_attempts: u32,
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+    /// The delay between attempts doubles from 1 second up to this
+    #[serde(default = "default_max_backoff_secs")]
+    max_backoff_secs: u64,
+    /// Kept for each subscriber host, so that deliveries to a host that keeps
+    /// failing wait for their next attempt without being sent
+    #[serde(default)]
+    circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
+    subscribers: Vec<Subscriber>,
+}
+
+#[derive(Deserialize, Clone)]
+struct Subscriber {
+    url: String,
+    /// Events sent to this subscriber, where `order.*` matches any event starting
+    /// with `order.`. Every event is sent if empty
+    #[serde(default)]
+    events: Vec<String>,
+}
+
+fn default_max_attempts() -> u32 {
+    10
+}
+
+fn default_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_max_backoff_secs() -> u64 {
+    3600
+}
+
+impl Subscriber {
+    fn wants(&self, event: &str) -> bool {
+        self.events.is_empty()
+            || self.events.iter().any(|x| match x.strip_suffix('*') {
+                Some(prefix) => event.starts_with(prefix),
+                None => x == event,
+            })
+    }
+}
+
+/// A single event on its way to a single subscriber, as stored in the queue folder
+#[derive(Serialize, Deserialize)]
+struct Delivery {
+    id: String,
+    url: String,
+    event: String,
+    /// JSON, sent as is
+    payload: String,
+    attempts: u32,
+}
+
+struct Webhooks {
+    config: WebhookConfig,
+    client: reqwest::Client,
+    runtime: tokio::runtime::Handle,
+    breakers: Mutex<FxHashMap<String, Arc<CircuitBreaker>>>,
+}
+
+static WEBHOOKS: OnceLock<Webhooks> = OnceLock::new();
+
+impl Webhooks {
+    fn path(&self, id: &str, extension: &str) -> PathBuf {
+        Path::new(&self.config.queue_dir).join(format!("{id}.{extension}"))
+    }
+
+    /// Replaces the stored delivery atomically, so that a crash cannot leave half
+    /// of it behind
+    fn store(&self, delivery: &Delivery) -> std::io::Result<()> {
+        let tmp = self.path(&delivery.id, "tmp");
+        std::fs::write(
+            &tmp,
+            serde_json::to_vec(delivery).expect("Deliveries should be serializable"),
+        )?;
+        std::fs::rename(tmp, self.path(&delivery.id, "json"))
+    }
+
+    fn backoff(&self, attempts: u32) -> Duration {
+        let secs = 1u64
+            .checked_shl(attempts.saturating_sub(1))
+            .unwrap_or(u64::MAX)
+            .min(self.config.max_backoff_secs);
+        Duration::from_secs(secs)
+    }
+
+    fn breaker(&self, url: &str) -> Arc<CircuitBreaker> {
+        let host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|x| x.host_str().map(str::to_owned))
+            .unwrap_or_default();
+        self.breakers
+            .lock()
+            .entry(host)
+            .or_insert_with_key(|host| {
+                Arc::new(CircuitBreaker::new(
+                    format!("webhook:{host}"),
+                    self.config.circuit_breaker.clone(),
+                ))
+            })
+            .clone()
+    }
+
+    async fn attempt(&self, delivery: &Delivery) -> Result<(), String> {
+        let breaker = self.breaker(&delivery.url);
+        if !breaker.allow() {
+            return Err(format!(
+                "circuit is open for another {:?}",
+                breaker.retry_after()
+            ));
+        }
+        let result = self.send(delivery).await;
+        breaker.record(result.is_ok());
+        result
+    }
+
+    async fn send(&self, delivery: &Delivery) -> Result<(), String> {
+        let mut request = self
+            .client
+            .post(&delivery.url)
+            .header("content-type", "application/json")
+            .header("x-hypermangle-event", &delivery.event)
+            .header("x-hypermangle-delivery", &delivery.id)
+            .body(delivery.payload.clone());
+        if !self.config.signing_key.is_empty() {
+            let timestamp = signed_urls::unix_now();
+            let signature = signed_urls::hmac_sha256_hex(
+                self.config.signing_key.as_bytes(),
+                format!("{timestamp}.{}", delivery.payload).as_bytes(),
+            );
+            request = request
+                .header("x-hypermangle-timestamp", timestamp)
+                .header("x-hypermangle-signature", format!("sha256={signature}"));
+        }
+        match request.send().await {
+            Ok(response) if response.status().is_success() => Ok(()),
+            Ok(response) => Err(format!("responded with {}", response.status())),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// Retries `delivery` until it succeeds or runs out of attempts
+    async fn deliver(&'static self, mut delivery: Delivery) {
+        loop {
+            let error = match self.attempt(&delivery).await {
+                Ok(()) => {
+                    metrics::increment(
+                        "hypermangle_webhook_deliveries_total",
+                        "Webhook deliveries by whether they succeeded or ran out of attempts",
+                        &[("outcome", "delivered")],
+                    );
+                    let _ = std::fs::remove_file(self.path(&delivery.id, "json"));
+                    return;
+                }
+                Err(e) => e,
+            };
+            delivery.attempts += 1;
+
+            if delivery.attempts >= self.config.max_attempts {
+                error!(
+                    "Giving up on delivering {} to {} after {} attempts: {error}",
+                    delivery.event, delivery.url, delivery.attempts
+                );
+                metrics::increment(
+                    "hypermangle_webhook_deliveries_total",
+                    "Webhook deliveries by whether they succeeded or ran out of attempts",
+                    &[("outcome", "failed")],
+                );
+                let _ = std::fs::rename(
+                    self.path(&delivery.id, "json"),
+                    self.path(&delivery.id, "failed"),
+                );
+                return;
+            }
+
+            let backoff = self.backoff(delivery.attempts);
+            warn!(
+                "Failed to deliver {} to {}, retrying in {backoff:?}: {error}",
+                delivery.event, delivery.url
+            );
+            if let Err(e) = self.store(&delivery) {
+                error!("Failed to update webhook delivery {}: {e}", delivery.id);
+            }
+            tokio::time::sleep(backoff).await;
+        }
+    }
+}
+
+fn delivery_id() -> String {
+    let mut bytes = [0u8; 16];
+    openssl::rand::rand_bytes(&mut bytes).expect("Random bytes should be available");
+    bytes.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+/// Queues `event` for every subscriber that wants it, returning how many that was
+fn emit(event: &str, payload: String) -> std::io::Result<usize> {
+    let webhooks = WEBHOOKS.get().expect("Webhooks should be initialized");
+    let mut queued = 0;
+
+    for subscriber in &webhooks.config.subscribers {
+        if !subscriber.wants(event) {
+            continue;
+        }
+        let delivery = Delivery {
+            id: delivery_id(),
+            url: subscriber.url.clone(),
+            event: event.to_owned(),
+            payload: payload.clone(),
+            attempts: 0,
+        };
+        webhooks.store(&delivery)?;
+        webhooks.runtime.spawn(webhooks.deliver(delivery));
+        queued += 1;
+    }
+    Ok(queued)
+}
+
+/// Resumes the deliveries left in the queue folder and lets scripts `emit` events.
+/// Must be called from within the tokio runtime
+pub(crate) fn init(config: &WebhookConfig) {
+    std::fs::create_dir_all(&config.queue_dir).unwrap_or_else(|e| {
+        panic!(
+            "Webhook queue folder {:?} should be creatable: {e}",
+            config.queue_dir
+        )
+    });
+    let client = reqwest::Client::builder()
+        .user_agent(concat!(
+            env!("CARGO_PKG_NAME"),
+            "/",
+            env!("CARGO_PKG_VERSION")
+        ))
+        .timeout(Duration::from_millis(config.timeout_ms))
+        .build()
+        .expect("Webhook HTTP client should be buildable");
+    let webhooks = Webhooks {
+        config: config.clone(),
+        client,
+        runtime: tokio::runtime::Handle::current(),
+        breakers: Default::default(),
+    };
+    if WEBHOOKS.set(webhooks).is_err() {
+        return;
+    }
+    let webhooks = WEBHOOKS.get().unwrap();
+
+    let entries = std::fs::read_dir(&config.queue_dir)
+        .unwrap_or_else(|e| panic!("Webhook queue folder should be readable: {e}"));
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !path.extension().is_some_and(|x| x == "json") {
+            continue;
+        }
+        let delivery = std::fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|x| serde_json::from_slice::<Delivery>(&x).map_err(|e| e.to_string()));
+        match delivery {
+            Ok(delivery) => {
+                webhooks.runtime.spawn(webhooks.deliver(delivery));
+            }
+            Err(e) => error!("Ignoring unreadable webhook delivery {path:?}: {e}"),
+        }
+    }
+
+    let _ = hypermangle_py::events::EMITTER.set(emit);
+}
@@ -1,5 +1,3 @@
-#![feature(async_fn_in_trait)]
-
 use axum::Router;
 use clap::Parser;
 use hypermangle_core::{
@@ -18,5 +16,5 @@ impl ExecutableArgs for Args {
 }
 
 fn main() {
-    auto_main::<Args>(|| Router::new());
+    auto_main::<Args>(Router::new);
 }
@@ -0,0 +1,93 @@
+//! Compares how long taking the contents of large `bytes`, `str` and `memoryview`
+//! objects takes, and how much it allocates, the way bodies used to be taken and the
+//! way they are
+//!
+//!     cargo bench -p hypermangle-py --bench buffers
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use hypermangle_py::buffers;
+use pyo3::{types::PyBytes, PyAny, Python};
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const SIZE: usize = 8 * 1024 * 1024;
+const ROUNDS: u32 = 5;
+
+/// Runs `take` `ROUNDS` times, printing how long each took and what it allocated
+fn measure<T>(name: &str, obj: &PyAny, take: impl Fn(&PyAny) -> T) {
+    let mut elapsed = Duration::ZERO;
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        drop(take(obj));
+        elapsed += start.elapsed();
+    }
+    println!(
+        "{name:<32} {:>10.3?} {:>8} allocations {:>12} bytes",
+        elapsed / ROUNDS,
+        (ALLOCATIONS.load(Ordering::Relaxed) - allocations) / ROUNDS as usize,
+        (ALLOCATED.load(Ordering::Relaxed) - allocated) / ROUNDS as usize,
+    );
+}
+
+fn main() {
+    pyo3::prepare_freethreaded_python();
+    Python::with_gil(|py| {
+        let bytes: &PyAny = PyBytes::new_with(py, SIZE, |x| {
+            x.fill(b'x');
+            Ok(())
+        })
+        .expect("Bytes should be creatable");
+        let string = bytes
+            .call_method1("decode", ("ascii",))
+            .expect("Bytes should be decodable");
+        println!("{} MiB bodies, averaged over {ROUNDS} rounds", SIZE >> 20);
+
+        measure("bytes: extract::<Vec<u8>>", bytes, |x| {
+            x.extract::<Vec<u8>>().expect("Bytes should be extractable")
+        });
+        measure("bytes: buffers::to_vec", bytes, buffers::to_vec);
+        measure("bytes: buffers::share", bytes, buffers::share);
+        measure("str: extract::<String>", string, |x| {
+            x.extract::<String>().expect("str should be extractable")
+        });
+        measure("str: buffers::share", string, buffers::share);
+
+        // A request body passed with `MEMORYVIEW_BODIES`, and a slice of it returned
+        let body = buffers::memoryview(py, vec![b'x'; SIZE].into())
+            .expect("Bodies should be viewable")
+            .into_ref(py);
+        measure("memoryview: buffers::memoryview", bytes, |x| {
+            let x = buffers::share(x).expect("Bytes should be shareable");
+            buffers::memoryview(py, x).expect("Bodies should be viewable")
+        });
+        let slice = body
+            .get_item(pyo3::types::PySlice::new(py, 4, SIZE as isize, 1))
+            .expect("Memoryviews should be sliceable");
+        measure("memoryview: buffers::share", slice, buffers::share);
+    });
+}
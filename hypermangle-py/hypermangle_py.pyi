@@ -0,0 +1,452 @@
+"""Type information for the objects hypermangle hands to scripts.
+
+Scripts declare handlers as module level coroutines:
+
+    async def get_handler(body: Body) -> HttpResponse: ...
+    async def post_handler(body: Body) -> HttpResponse: ...
+    async def upload_handler(upload: Upload) -> HttpResponse: ...
+    def ws_handler(ws: WebSocket) -> None: ...
+
+and may set `IS_MULTI_PATHED = True` to also handle every path below their own,
+or `SITEMAP = False` to be left out of the `/sitemap.xml` the server can generate.
+Handlers that take a second argument are also passed a `RequestContext`.
+
+Request bodies are passed as `str` when they are valid UTF-8, and as `bytes`
+otherwise. Scripts that set `MEMORYVIEW_BODIES = True` get every body that is
+not decoded from a codec as a read-only `memoryview` of the bytes it was
+received in, which is not copied. `upload_handler` handles POST requests like
+`post_handler`, except that the body is spooled to disk, so it cannot be defined
+alongside it.
+
+Bodies returned as `bytes`, `str` or `memoryview`s of `bytes` are sent without
+being copied, including views of the request body, such as `body[4:]`.
+`bytearray` and views of anything else are copied, as they can change after
+returning.
+
+Handlers can return `(status, object)` instead of a string or bytes, and the
+object is encoded in the format the client prefers in `Accept`, with
+`Vary: Accept`. JSON is always available, and HTML is too if the script sets
+`HTML_TEMPLATE` to a Jinja template relative to it, which dicts are rendered
+with as the context and other objects as `value`. Clients without `Accept` get
+the format of their body, or JSON, and those accepting none of the formats get
+406. Templates can call `asset_url("/assets/app.css")` for the hashed URL of a
+file in a static mount with `hashed_names`. When `content_security_policy`
+mentions `{nonce}`, templates get the nonce of the request as `csp_nonce`, for
+inline scripts such as `<script nonce="{{ csp_nonce }}">`.
+
+Handlers can also return `(status, headers, body)`, where `headers` is a dict
+that replaces the headers hypermangle would send. A body that is an async
+iterator of `str` or `bytes` is streamed, so the headers reach the client before
+the rest of the handler has run:
+
+    async def get_handler(body):
+        async def page():
+            yield "<head>...</head>"
+            yield render(await slow_query())
+        return 200, {"content-type": "text/html"}, page()
+
+Handlers can also return a `ResponseStream` that a task they started writes to,
+which flushes whatever it wrote when it should reach the client:
+
+    async def get_handler(body):
+        events = ResponseStream()
+        async def send_events():
+            async with events:
+                async for event in watch():
+                    events.write(f"data: {event}\n\n")
+                    await events.flush()
+        asyncio.get_running_loop().create_task(send_events())
+        return 200, {"content-type": "text/event-stream"}, events
+
+Bodies sent as `application/x-ndjson` are passed as `NdJsonLines`, which parses
+each line as it arrives, so that log shippers can send bodies of any length.
+Returning `application/x-ndjson` in the headers along with an async iterator
+sends every object it yields as a line of JSON:
+
+    async def post_handler(body: NdJsonLines):
+        async def results():
+            async for record in body:
+                yield {"stored": await store(record)}
+        return 200, {"content-type": "application/x-ndjson"}, results()
+
+Streamed bodies are compressed for clients that accept brotli or gzip, one chunk
+at a time, so that every chunk yielded or flushed reaches the client right away.
+Setting `content-encoding` in the headers, such as to `identity`, sends them as
+they are.
+
+Scripts can set `CACHE_CONTROL = "public, max-age=60"` to send it with the
+successful responses of `get_handler` that do not set their own. Those that are
+not streamed also get a weak `ETag` of their body, and clients sending it back
+in `If-None-Match` get 304 without the body. `no-store` responses get no `ETag`.
+
+When hypermangle is built with the `msgpack` or `cbor` features, those formats
+are available too, and bodies sent as `application/msgpack` or
+`application/cbor` are passed as the objects they decode to.
+
+With the `protobuf` feature, scripts can point `PROTOBUF_DESCRIPTOR_SET` at a
+descriptor set compiled with `protoc --descriptor_set_out`, relative to the
+script, and name messages in it:
+
+    PROTOBUF_DESCRIPTOR_SET = "fleet.desc"
+    PROTOBUF_REQUEST = "fleet.Telemetry"
+    PROTOBUF_RESPONSE = "fleet.Ack"
+
+`application/x-protobuf` bodies are then passed as dicts of the fields that are
+set, with enums as their names, and returned dicts are encoded as the response
+message.
+
+Handlers run in a `contextvars.Context` of their own, along with the tasks they
+start and the bodies they stream, where `request_id_var`, `principal_var` and
+`deadline_var` are set for the request, so that logging libraries can attach
+them:
+
+    structlog.contextvars.bind_contextvars(request_id=request_id_var.get())
+
+A `_router.py` at the root of the scripts folder can define `route`, which is
+awaited with every request before it is routed. It can change where the
+request goes by setting its path, query and headers, or respond itself by
+returning what a handler would, instead of `None`:
+
+    async def route(request: IncomingRequest) -> HttpResponse | None:
+        if request.path.startswith("/fr/"):
+            request.path = request.path[3:]
+            request.headers["accept-language"] = "fr"
+
+Requests then go through Python before any route, including static files, so
+the function should be quick. It runs before tokens, signed URLs and rate
+limits are checked, so those apply to the rewritten path. It is only loaded
+when the server starts.
+
+Queue consumers call `message_handler` unless configured otherwise:
+
+    async def message_handler(message: QueueMessage) -> bool | None: ...
+
+`hypermangle test` runs the `test_*` functions, which may be coroutines, of the
+`test_*.py` scripts in `tests` against the server, without binding its address:
+
+    client = TestClient(latency_budgets={"/api": 0.05})
+
+    async def test_hello():
+        response = await client.get("/api/hello")
+        response.assert_status(200).assert_json({"hello": "world"})
+        response.assert_snapshot("hello")
+
+Tests run in test mode, where `now` stands still at 2000-01-01T00:00:00Z until
+moved with `set_time` or `advance_time`, and `new_id` counts up from
+`00000000-0000-4000-8000-000000000001`. Both are reset before every test.
+
+Scripts can fan messages out with `publish` and `subscribe`, such as to send a
+chat message to every websocket in a room. With `[cluster]` configured, messages
+reach the subscribers of every instance sharing its Redis server:
+
+    async def ws_loop(ws: WebSocket, room: str):
+        subscription = subscribe(room)
+        while True:
+            await ws.send_msg(await subscription.recv())
+
+`Body`, `HttpResponse` and the handler aliases only exist for type checkers, so
+import them under `typing.TYPE_CHECKING`.
+"""
+
+from contextvars import ContextVar
+from typing import Any, AsyncIterator, Awaitable, Callable, Sequence, TypeAlias
+
+Body: TypeAlias = str | bytes | memoryview | "NdJsonLines"
+HttpResponse: TypeAlias = (
+    tuple[
+        int,
+        str | bytes | memoryview | AsyncIterator[str | bytes] | "ResponseStream" | Any,
+    ]
+    | tuple[
+        int,
+        dict[str, str],
+        str | bytes | memoryview | AsyncIterator[str | bytes] | "ResponseStream" | Any,
+    ]
+)
+HttpHandler: TypeAlias = (
+    Callable[[Body], Awaitable[HttpResponse]]
+    | Callable[[Body, "RequestContext"], Awaitable[HttpResponse]]
+)
+UploadHandler: TypeAlias = (
+    Callable[["Upload"], Awaitable[HttpResponse]]
+    | Callable[["Upload", "RequestContext"], Awaitable[HttpResponse]]
+)
+WsHandler: TypeAlias = (
+    Callable[["WebSocket"], None] | Callable[["WebSocket", "RequestContext"], None]
+)
+
+class ClosedWebSocket(Exception):
+    """Raised by `WebSocket.recv_msg` and `WebSocket.send_msg` once the connection
+    has been closed, either by the client or with the `ws kill` console command."""
+
+class WebSocketError(Exception):
+    """Raised when the underlying websocket connection fails."""
+
+class NotYetAccepted(Exception):
+    """Raised when receiving or sending before `WebSocket.accept` was called."""
+
+class AlreadyAccepted(Exception):
+    """Raised when `WebSocket.accept` is called more than once."""
+
+class CpuBudgetExceeded(BaseException):
+    """Raised inside a handler that has run for longer than `handler_cpu_budget_ms`.
+    Catching it does not help, as the handler is aborted with a 503 either way."""
+
+class WebSocketMessage:
+    def as_string(self) -> str | None:
+        """The message contents if it is a text message."""
+    def as_bytes(self) -> bytes | None:
+        """The message contents if it is a binary message."""
+
+class WebSocket:
+    def accept(self) -> None:
+        """Completes the websocket handshake. Must be called before any other method."""
+    def recv_msg(self) -> Awaitable[WebSocketMessage]: ...
+    def send_msg(self, msg: str | bytes) -> Awaitable[None]: ...
+    def bridge(
+        self,
+        url: str,
+        to_upstream: Callable[[str | bytes], str | bytes | None] | None = None,
+        to_client: Callable[[str | bytes], str | bytes | None] | None = None,
+        headers: dict[str, str] | None = None,
+    ) -> Awaitable[None]:
+        """Connects to the websocket at `url`, sending it `headers`, and forwards
+        messages between it and this websocket until either side closes. Messages
+        are passed through `to_upstream` or `to_client` on the way, which drop them
+        by returning `None`. The hooks are called with the GIL held, so should be
+        quick. Both sides are closed once it returns, and it raises
+        `WebSocketError` if either fails."""
+
+class ResponseStream:
+    """A response body that is written to instead of yielded, which ends when it is
+    closed. Leaving `async with` closes it, or cuts the response short if the block
+    raised, so that the client does not take the body as complete."""
+
+    def __init__(self) -> None: ...
+    def write(self, data: str | bytes) -> None:
+        """Holds `data` until the next flush."""
+    def flush(self) -> Awaitable[None]:
+        """Sends what was written since the last flush, waiting while the client has
+        fallen behind. Raises `ConnectionResetError` once the client has gone."""
+    def close(self) -> Awaitable[None]:
+        """Flushes and ends the body."""
+    async def __aenter__(self) -> "ResponseStream": ...
+    async def __aexit__(self, exc_type: Any, exc: Any, traceback: Any) -> bool: ...
+
+class NdJsonLines:
+    """The objects on the lines of an NDJSON request body, skipping blank lines.
+    Raises `ValueError` for lines that are not valid JSON, or are longer than 2 MiB,
+    and `ConnectionError` if the client stops sending the body."""
+
+    def __aiter__(self) -> "NdJsonLines": ...
+    def __anext__(self) -> Awaitable[Any]: ...
+
+class ApiKey:
+    """An API key from the key store of the server."""
+    name: str
+    scopes: list[str]
+    def has_scope(self, scope: str) -> bool: ...
+
+class RequestContext:
+    api_key: ApiKey | None
+    """The key the request was authenticated with, unless it needed no key or used
+    the static `api_token`."""
+    country: str | None
+    """The ISO code of the country of the client, looked up from the address
+    forwarded by a proxy when `geoip` is configured."""
+    asn: int | None
+    as_organization: str | None
+    tags: list[str]
+    """The names of the `tag` rules in `[filters]` that the request matched."""
+    csp_nonce: str | None
+    """The nonce that `content_security_policy` allows inline scripts and styles
+    with, if it mentions `{nonce}`."""
+    tls: TlsInfo | None
+    """The TLS connection the request came over, unless the server is serving plain
+    HTTP."""
+
+class TlsInfo:
+    version: str
+    """Such as `TLSv1.3`."""
+    cipher: str
+    """Such as `TLS13_AES_128_GCM_SHA256`."""
+    server_name: str | None
+    """The SNI name the client asked for."""
+    alpn: str | None
+    client_subject: str | None
+    """The subject of the certificate the client authenticated with, such as
+    `CN=alice,O=Example`, if `tls_client_ca_path` is set and it sent one."""
+    client_fingerprint: str | None
+    """The SHA-256 digest of that certificate, in lowercase hex."""
+
+class IncomingRequest:
+    """A request before it is routed, as passed to `route` in `_router.py`."""
+
+    method: str
+    path: str
+    """Setting it routes the request to another path. It should start with `/`."""
+    query: str
+    """Without the leading `?`."""
+    headers: dict[str, str]
+    """By lowercase names, with repeated headers joined by commas, or semicolons
+    for `cookie`. Changes to it are kept, and headers removed from it are
+    removed from the request."""
+
+class UploadedFile:
+    """A file spooled to disk, which is deleted once the handler returns unless the
+    handler moved it elsewhere."""
+
+    path: str
+    field: str | None
+    """The form field of the file, for multipart uploads."""
+    filename: str | None
+    content_type: str | None
+    size: int
+
+class Upload:
+    files: list[UploadedFile]
+    """The file fields of a multipart upload, or the whole body otherwise."""
+    fields: dict[str, str]
+    """The form fields that are not files, for multipart uploads."""
+
+class QueueMessage:
+    """Passed to the message handler of a queue consumer. The message is acknowledged
+    if the handler returns normally, and rejected to be redelivered if it raises or
+    returns `False`."""
+
+    subject: str
+    """The subject, routing key or topic the message was published to."""
+    body: bytes
+    redelivered: bool
+
+class RuntimeStats:
+    in_flight: int
+    """Requests that have been received but not responded to."""
+    queue_depth: int
+    """Requests waiting for their script to be imported or for the GIL."""
+    event_loop_lag: float
+    """Seconds the last callback scheduled onto the event loop waited to run."""
+
+class Subscription:
+    def recv(self) -> Awaitable[str | bytes]:
+        """The next message published to the topic. Subscriptions that fall far
+        behind skip the oldest messages they have not received."""
+
+class TestResponse:
+    """A response to a `TestClient`. The assertions raise `AssertionError`, and
+    return the response otherwise so that they can be chained."""
+
+    status: int
+    headers: dict[str, str]
+    body: bytes
+    elapsed: float
+    """Seconds the server took to respond."""
+    def text(self) -> str: ...
+    def json(self) -> Any: ...
+    def assert_status(self, status: int) -> TestResponse: ...
+    def assert_header(self, name: str, value: str | None = None) -> TestResponse:
+        """Checks that the header is present, and has `value` if given."""
+    def assert_json(self, expected: Any) -> TestResponse: ...
+    def assert_latency(self, budget: float | None = None) -> TestResponse:
+        """Checks that the response took at most `budget` seconds, or the budget of
+        its path in the client if not given."""
+    def assert_snapshot(self, name: str) -> TestResponse:
+        """Compares the status, headers and body to `tests/snapshots/{name}.snap`,
+        leaving out `date` and `x-request-id`. The snapshot is written if it does not
+        exist yet, or if `hypermangle test` is run with `--update-snapshots`."""
+
+class TestClient:
+    """Sends requests to the server that `hypermangle test` runs tests against."""
+
+    def __init__(
+        self,
+        headers: dict[str, str] | None = None,
+        latency_budgets: dict[str, float] | None = None,
+    ) -> None:
+        """`headers` are sent with every request. `latency_budgets` maps path
+        prefixes to the seconds that requests to them may take, where the longest
+        matching prefix applies, and requests over their budget raise
+        `AssertionError`."""
+    def get(
+        self, path: str, headers: dict[str, str] | None = None
+    ) -> Awaitable[TestResponse]: ...
+    def post(
+        self, path: str, body: Any = None, headers: dict[str, str] | None = None
+    ) -> Awaitable[TestResponse]: ...
+    def request(
+        self,
+        method: str,
+        path: str,
+        body: Any = None,
+        headers: dict[str, str] | None = None,
+    ) -> Awaitable[TestResponse]:
+        """Bodies other than `str` and `bytes` are sent as JSON."""
+
+request_id_var: ContextVar[str | None]
+"""The `x-request-id` of the request, as sent by the client or made up for it."""
+principal_var: ContextVar[str | None]
+"""The name of the API key the request was authenticated with."""
+deadline_var: ContextVar[float | None]
+"""The `time.monotonic()` by which the handler is cancelled with 504, when
+`handler_timeout_ms` is configured."""
+
+def deadline() -> float | None:
+    """Seconds left before the handler is cancelled, or `None` if it has no
+    deadline. Passing it as the timeout of the calls a handler makes lets them give
+    up in time, instead of each waiting for a timeout of its own:
+
+        async with httpx.AsyncClient(timeout=deadline()) as client: ...
+    """
+
+def now() -> float:
+    """The current UNIX timestamp in seconds, which only moves with `set_time` and
+    `advance_time` in test mode."""
+
+def new_id() -> str:
+    """A unique id in the format set by `[ids]` in hypermangle.toml: a random UUID
+    by default, a UUIDv7 that sorts by when it was made with `format = "uuid7"`, or
+    a Snowflake id as a decimal string with `format = "snowflake"`, which sets
+    instances apart by `node_id`. Ids count up from
+    `00000000-0000-4000-8000-000000000001` in test mode, whatever the format."""
+
+def set_time(timestamp: float) -> None:
+    """Moves the clock of test mode to the UNIX timestamp."""
+
+def advance_time(seconds: float) -> None:
+    """Moves the clock of test mode forward."""
+
+def publish(topic: str, message: str | bytes) -> None:
+    """Sends `message` to every subscription to `topic`, on every instance in
+    cluster mode."""
+
+def subscribe(topic: str) -> Subscription:
+    """Receives the messages published to `topic` from now on."""
+
+def runtime_stats() -> RuntimeStats:
+    """How loaded the server currently is, so that handlers can shed load."""
+
+def sign_url(path: str, expires_in: float) -> str:
+    """Signs `path`, so that it can be requested without a token for `expires_in`
+    seconds. Only the path is signed, so an existing query string can be changed."""
+
+def hash_password(password: str, scheme: str = "argon2") -> Awaitable[str]:
+    """Hashes `password` with a random salt, with argon2id or with `"bcrypt"`, on a
+    thread of its own so that the event loop keeps running. bcrypt only uses the
+    first 72 bytes of a password."""
+
+def verify_password(password: str, hash: str) -> Awaitable[bool]:
+    """Whether `password` is the one `hash` was made from by `hash_password`, of
+    either scheme. Raises `ValueError` if `hash` is not a password hash."""
+
+def emit(event: str, payload: Any) -> int:
+    """Queues `event` for every webhook subscriber that wants it, returning how many
+    that was. `payload` is encoded as JSON, and delivery is retried until it succeeds
+    or runs out of attempts, even across restarts."""
+
+def enqueue(
+    func_name: str, args: Sequence[Any] | None = None, delay: float | None = None
+) -> int:
+    """Queues a call to `func_name`, given as `path/to/script.py:function`, returning
+    the id of the job. The function is awaited with `args`, which are encoded as
+    JSON, after `delay` seconds if given. Jobs that raise are retried with a backoff,
+    and survive restarts."""
@@ -1,5 +1,4 @@
-#![feature(exclusive_wrapper)]
-
+use std::collections::HashMap;
 use std::mem::replace;
 use std::ops::Deref;
 use std::ops::DerefMut;
@@ -8,10 +7,16 @@ use std::sync::Arc;
 use axum::extract::ws::Message;
 use axum::extract::WebSocketUpgrade;
 use axum::response::Response;
+use futures_util::{SinkExt, StreamExt};
 use pyo3::create_exception;
 use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
+use pyo3::types::PyBytes;
 use tokio::sync::Mutex;
+use tokio_tungstenite::tungstenite::{self, client::IntoClientRequest};
+
+/// Type stubs for this module, for IDEs and type checkers
+pub const PYI_STUB: &str = include_str!("../hypermangle_py.pyi");
 
 create_exception!(
     hypermangle_py,
@@ -33,16 +38,26 @@ create_exception!(
     AlreadyAccepted,
     pyo3::exceptions::PyException
 );
+// Not an Exception, so that handlers catching every Exception are still aborted
+create_exception!(
+    hypermangle_py,
+    CpuBudgetExceeded,
+    pyo3::exceptions::PyBaseException
+);
 
 enum WebSocketInner {
     Pending((WebSocketUpgrade, tokio::sync::oneshot::Sender<Response>)),
     Accepting,
-    Accepted(axum::extract::ws::WebSocket),
+    Accepted(axum::extract::ws::WebSocket, connections::Registration),
+    /// The connection was closed, by either side
+    Closed,
 }
 
 #[pyclass(frozen)]
 pub struct WebSocket {
     inner: Arc<Mutex<WebSocketInner>>,
+    path: String,
+    peer: String,
 }
 
 #[pyclass(frozen)]
@@ -83,10 +98,12 @@ impl WebSocket {
         else {
             unreachable!()
         };
+        let path = self.path.clone();
+        let peer = self.peer.clone();
 
         sender
             .send(ws.on_upgrade(move |ws| async move {
-                *lock = WebSocketInner::Accepted(ws);
+                *lock = WebSocketInner::Accepted(ws, connections::register(path, peer));
             }))
             .expect("WebSocket Response Receiver should not have been dropped yet");
 
@@ -98,16 +115,39 @@ impl WebSocket {
 
         pyo3_asyncio::tokio::future_into_py(py, async move {
             let mut lock = inner.lock().await;
-            let WebSocketInner::Accepted(ws) = lock.deref_mut() else {
-                return Err(NotYetAccepted::new_err(()));
+            let WebSocketInner::Accepted(ws, registration) = lock.deref_mut() else {
+                return Err(not_accepted(&lock));
             };
-            let Some(result) = ws.recv().await else {
+            let connection = registration.connection.clone();
+            // Created before checking the flag so that a kill in between is not missed
+            let killed = connection.kill.notified();
+            if connection.is_killed() {
+                let _ = ws.send(Message::Close(None)).await;
+                *lock = WebSocketInner::Closed;
+                return Err(ClosedWebSocket::new_err(()));
+            }
+
+            let result = tokio::select! {
+                result = ws.recv() => result,
+                _ = killed => {
+                    let _ = ws.send(Message::Close(None)).await;
+                    None
+                }
+            };
+            let Some(result) = result else {
+                *lock = WebSocketInner::Closed;
                 return Err(ClosedWebSocket::new_err(()));
             };
 
             match result {
-                Ok(msg) => Ok(WebSocketMessage { msg }),
-                Err(e) => Err(WebSocketError::new_err(e.to_string())),
+                Ok(msg) => {
+                    connection.count_in(&msg);
+                    Ok(WebSocketMessage { msg })
+                }
+                Err(e) => {
+                    *lock = WebSocketInner::Closed;
+                    Err(WebSocketError::new_err(e.to_string()))
+                }
             }
         })
     }
@@ -115,7 +155,7 @@ impl WebSocket {
     fn send_msg<'a>(&self, py: Python<'a>, msg: &'a PyAny) -> PyResult<&'a PyAny> {
         let msg = if let Ok(msg) = msg.extract::<String>() {
             Message::Text(msg)
-        } else if let Ok(msg) = msg.extract::<Vec<u8>>() {
+        } else if let Some(msg) = buffers::to_vec(msg) {
             Message::Binary(msg)
         } else {
             return Err(PyValueError::new_err(
@@ -125,35 +165,1866 @@ impl WebSocket {
         let inner = self.inner.clone();
         pyo3_asyncio::tokio::future_into_py(py, async move {
             let mut lock = inner.lock().await;
-            let WebSocketInner::Accepted(ws) = lock.deref_mut() else {
-                return Err(NotYetAccepted::new_err(()));
+            let WebSocketInner::Accepted(ws, registration) = lock.deref_mut() else {
+                return Err(not_accepted(&lock));
+            };
+            let connection = registration.connection.clone();
+            if connection.is_killed() {
+                let _ = ws.send(Message::Close(None)).await;
+                *lock = WebSocketInner::Closed;
+                return Err(ClosedWebSocket::new_err(()));
+            }
+            connection.count_out(&msg);
+            let result = ws.send(msg).await;
+            if result.is_err() {
+                *lock = WebSocketInner::Closed;
+            }
+            result.map_err(|e| WebSocketError::new_err(e.to_string()))
+        })
+    }
+
+    #[pyo3(signature = (url, to_upstream=None, to_client=None, headers=None))]
+    fn bridge<'a>(
+        &self,
+        py: Python<'a>,
+        url: String,
+        to_upstream: Option<PyObject>,
+        to_client: Option<PyObject>,
+        headers: Option<HashMap<String, String>>,
+    ) -> PyResult<&'a PyAny> {
+        let mut request = url.as_str().into_client_request().map_err(|e| {
+            PyValueError::new_err(format!("{url:?} should be a websocket URL: {e}"))
+        })?;
+        for (name, value) in headers.unwrap_or_default() {
+            let Ok(name) = axum::http::HeaderName::try_from(&name) else {
+                return Err(PyValueError::new_err(format!(
+                    "{name:?} is not a valid header name"
+                )));
+            };
+            let Ok(value) = axum::http::HeaderValue::try_from(&value) else {
+                return Err(PyValueError::new_err(format!(
+                    "Header {name} has an invalid value"
+                )));
+            };
+            request.headers_mut().insert(name, value);
+        }
+        let inner = self.inner.clone();
+
+        pyo3_asyncio::tokio::future_into_py(py, async move {
+            let mut lock = inner.lock().await;
+            let WebSocketInner::Accepted(ws, registration) = lock.deref_mut() else {
+                return Err(not_accepted(&lock));
             };
-            ws.send(msg)
-                .await
-                .map_err(|e| WebSocketError::new_err(e.to_string()))
+            let connection = registration.connection.clone();
+            let mut upstream = match tokio_tungstenite::connect_async(request).await {
+                Ok((upstream, _)) => upstream,
+                Err(e) => {
+                    let _ = ws.send(Message::Close(None)).await;
+                    *lock = WebSocketInner::Closed;
+                    return Err(WebSocketError::new_err(format!(
+                        "Upstream websocket failed to connect: {e}"
+                    )));
+                }
+            };
+
+            let killed = connection.kill.notified();
+            tokio::pin!(killed);
+            if connection.is_killed() {
+                let _ = ws.send(Message::Close(None)).await;
+                let _ = upstream.close(None).await;
+                *lock = WebSocketInner::Closed;
+                return Err(ClosedWebSocket::new_err(()));
+            }
+
+            // Pings are answered by each side on its own, so only the other
+            // messages are forwarded
+            let result = loop {
+                tokio::select! {
+                    msg = ws.recv() => {
+                        let msg = match msg {
+                            Some(Ok(msg)) => msg,
+                            Some(Err(e)) => break Err(WebSocketError::new_err(e.to_string())),
+                            None => break Ok(()),
+                        };
+                        connection.count_in(&msg);
+                        let is_close = matches!(msg, Message::Close(_));
+                        if matches!(msg, Message::Ping(_) | Message::Pong(_)) {
+                            continue;
+                        }
+                        let msg = match transform(&to_upstream, msg) {
+                            Ok(Some(msg)) => msg,
+                            Ok(None) => continue,
+                            Err(e) => break Err(e),
+                        };
+                        if let Err(e) = upstream.send(to_upstream_msg(msg)).await {
+                            break Err(WebSocketError::new_err(format!("Upstream websocket failed: {e}")));
+                        }
+                        if is_close {
+                            break Ok(());
+                        }
+                    }
+                    msg = upstream.next() => {
+                        let msg = match msg {
+                            Some(Ok(msg)) => msg,
+                            Some(Err(e)) => break Err(WebSocketError::new_err(format!("Upstream websocket failed: {e}"))),
+                            None => break Ok(()),
+                        };
+                        let Some(msg) = from_upstream_msg(msg) else {
+                            continue;
+                        };
+                        let is_close = matches!(msg, Message::Close(_));
+                        if matches!(msg, Message::Ping(_) | Message::Pong(_)) {
+                            continue;
+                        }
+                        let msg = match transform(&to_client, msg) {
+                            Ok(Some(msg)) => msg,
+                            Ok(None) => continue,
+                            Err(e) => break Err(e),
+                        };
+                        connection.count_out(&msg);
+                        if let Err(e) = ws.send(msg).await {
+                            break Err(WebSocketError::new_err(e.to_string()));
+                        }
+                        if is_close {
+                            break Ok(());
+                        }
+                    }
+                    _ = &mut killed => break Err(ClosedWebSocket::new_err(())),
+                }
+            };
+
+            // Closing an already closed side only fails, but flushes the reply to
+            // the close frame it got
+            if ws.send(Message::Close(None)).await.is_err() {
+                let _ = ws.flush().await;
+            }
+            let _ = upstream.close(None).await;
+            *lock = WebSocketInner::Closed;
+            result
         })
     }
 }
 
+/// What a bridge hook returned for a message, which is dropped if `None`
+fn transform(hook: &Option<PyObject>, msg: Message) -> PyResult<Option<Message>> {
+    let Some(hook) = hook else {
+        return Ok(Some(msg));
+    };
+    Python::with_gil(|py| {
+        let result = match &msg {
+            Message::Text(x) => hook.call1(py, (x.as_str(),))?,
+            Message::Binary(x) => hook.call1(py, (PyBytes::new(py, x),))?,
+            _ => return Ok(Some(msg)),
+        };
+        let result = result.as_ref(py);
+        if result.is_none() {
+            Ok(None)
+        } else if let Ok(x) = result.extract::<String>() {
+            Ok(Some(Message::Text(x)))
+        } else if let Some(x) = buffers::to_vec(result) {
+            Ok(Some(Message::Binary(x)))
+        } else {
+            Err(PyValueError::new_err(
+                "Bridge hooks should return a String, Bytes or None",
+            ))
+        }
+    })
+}
+
+fn to_upstream_msg(msg: Message) -> tungstenite::Message {
+    match msg {
+        Message::Text(x) => tungstenite::Message::Text(x),
+        Message::Binary(x) => tungstenite::Message::Binary(x),
+        Message::Ping(x) => tungstenite::Message::Ping(x),
+        Message::Pong(x) => tungstenite::Message::Pong(x),
+        Message::Close(frame) => {
+            tungstenite::Message::Close(frame.map(|x| tungstenite::protocol::CloseFrame {
+                code: x.code.into(),
+                reason: x.reason,
+            }))
+        }
+    }
+}
+
+/// `None` for raw frames, which are never read from upstream
+fn from_upstream_msg(msg: tungstenite::Message) -> Option<Message> {
+    Some(match msg {
+        tungstenite::Message::Text(x) => Message::Text(x),
+        tungstenite::Message::Binary(x) => Message::Binary(x),
+        tungstenite::Message::Ping(x) => Message::Ping(x),
+        tungstenite::Message::Pong(x) => Message::Pong(x),
+        tungstenite::Message::Close(frame) => {
+            Message::Close(frame.map(|x| axum::extract::ws::CloseFrame {
+                code: x.code.into(),
+                reason: x.reason,
+            }))
+        }
+        tungstenite::Message::Frame(_) => return None,
+    })
+}
+
+fn not_accepted(inner: &WebSocketInner) -> PyErr {
+    if let WebSocketInner::Closed = inner {
+        ClosedWebSocket::new_err(())
+    } else {
+        NotYetAccepted::new_err(())
+    }
+}
+
 impl WebSocket {
-    pub fn new(ws: WebSocketUpgrade) -> (Self, tokio::sync::oneshot::Receiver<Response>) {
+    /// `path` and `peer` describe the connection in the `connections` registry
+    pub fn new(
+        ws: WebSocketUpgrade,
+        path: String,
+        peer: String,
+    ) -> (Self, tokio::sync::oneshot::Receiver<Response>) {
         let (sender, receiver) = tokio::sync::oneshot::channel();
         (
             Self {
                 inner: Arc::new(Mutex::new(WebSocketInner::Pending((ws, sender)))),
+                path,
+                peer,
             },
             receiver,
         )
     }
 }
 
+/// The contents of `str`, `bytes` and `bytearray` objects, taken without
+/// `Vec<u8>: FromPyObject`, which extracts them one `int` at a time
+pub mod buffers {
+    use std::os::raw::c_int;
+
+    use axum::body::Bytes;
+    use pyo3::{
+        buffer::PyBuffer,
+        exceptions::PyBufferError,
+        ffi, intern,
+        prelude::*,
+        types::{PyByteArray, PyBytes, PyString},
+        AsPyPointer,
+    };
+
+    /// The buffer behind the memoryviews `memoryview` makes
+    #[pyclass(frozen)]
+    struct SharedBytes {
+        bytes: Bytes,
+    }
+
+    #[pymethods]
+    impl SharedBytes {
+        unsafe fn __getbuffer__(
+            slf: PyRef<Self>,
+            view: *mut ffi::Py_buffer,
+            flags: c_int,
+        ) -> PyResult<()> {
+            if view.is_null() {
+                return Err(PyBufferError::new_err("View should not be null"));
+            }
+            if flags & ffi::PyBUF_WRITABLE == ffi::PyBUF_WRITABLE {
+                return Err(PyBufferError::new_err("Object is not writable"));
+            }
+            let bytes = &slf.bytes;
+            if ffi::PyBuffer_FillInfo(
+                view,
+                slf.as_ptr(),
+                bytes.as_ptr() as *mut _,
+                bytes.len() as ffi::Py_ssize_t,
+                1,
+                flags,
+            ) == -1
+            {
+                return Err(PyErr::fetch(slf.py()));
+            }
+            Ok(())
+        }
+
+        // The bytes are only freed once the object is
+        unsafe fn __releasebuffer__(&self, _view: *mut ffi::Py_buffer) {}
+    }
+
+    /// A read-only memoryview of `bytes`, which Python reads without them being copied
+    pub fn memoryview(py: Python, bytes: Bytes) -> PyResult<PyObject> {
+        let shared = Py::new(py, SharedBytes { bytes })?;
+        // SAFETY: the object is alive, and the result is a new reference or null
+        unsafe {
+            PyObject::from_owned_ptr_or_err(py, ffi::PyMemoryView_FromObject(shared.as_ptr()))
+        }
+    }
+
+    // pyo3 0.19 has no type for memoryviews
+    pub fn is_memoryview(obj: &PyAny) -> bool {
+        // SAFETY: the object is alive
+        unsafe { ffi::PyMemoryView_Check(obj.as_ptr()) != 0 }
+    }
+
+    /// The buffer of a memoryview, as unsigned bytes
+    fn view_of(obj: &PyAny) -> Option<PyBuffer<u8>> {
+        PyBuffer::get(obj).ok().or_else(|| {
+            let cast = obj.call_method1(intern!(obj.py(), "cast"), ("B",)).ok()?;
+            PyBuffer::get(cast).ok()
+        })
+    }
+
+    /// Copies the contents of `bytes`, `bytearray` and `memoryview` objects
+    pub fn to_vec(obj: &PyAny) -> Option<Vec<u8>> {
+        if let Ok(x) = obj.downcast::<PyBytes>() {
+            Some(x.as_bytes().to_vec())
+        } else if let Ok(x) = obj.downcast::<PyByteArray>() {
+            Some(x.to_vec())
+        } else if is_memoryview(obj) {
+            match view_of(obj) {
+                Some(view) => view.to_vec(obj.py()).ok(),
+                None => to_vec(obj.call_method0(intern!(obj.py(), "tobytes")).ok()?),
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Keeps the object whose contents `data` are alive
+    struct Owner<T> {
+        _object: T,
+        data: &'static [u8],
+    }
+
+    impl<T: 'static> AsRef<[u8]> for Owner<T> {
+        fn as_ref(&self) -> &[u8] {
+            self.data
+        }
+    }
+
+    /// The contents of a contiguous memoryview of `bytes`, which can never change.
+    /// Views of request bodies share the bytes the body was received in. Views of
+    /// anything else are copied, as even a read-only view of a `bytearray` can see it
+    /// change or be resized while the body is sent
+    fn share_view(obj: &PyAny) -> Option<Bytes> {
+        let exporter = obj.getattr(intern!(obj.py(), "obj")).ok()?;
+        let shared = exporter.downcast::<PyCell<SharedBytes>>().ok();
+        if shared.is_none() && !exporter.is_exact_instance_of::<PyBytes>() {
+            return to_vec(obj).map(Into::into);
+        }
+        // Views of other item types that cannot be cast to bytes
+        let Some(view) = view_of(obj) else {
+            return share(obj.call_method0(intern!(obj.py(), "tobytes")).ok()?);
+        };
+        if !view.is_c_contiguous() {
+            return view.to_vec(obj.py()).ok().map(Into::into);
+        }
+        // SAFETY: the buffer is held until `data` is dropped, and belongs to an object
+        // whose contents never change
+        let data: &'static [u8] =
+            unsafe { std::slice::from_raw_parts(view.buf_ptr() as *const u8, view.len_bytes()) };
+        if let Some(shared) = shared {
+            return Some(shared.get().bytes.slice_ref(data));
+        }
+        Some(Bytes::from_owner(Owner {
+            _object: view,
+            data,
+        }))
+    }
+
+    /// The contents of `bytes`, `memoryview`s of them, or the UTF-8 of `str`, without
+    /// copying them, as the `Bytes` keeps the object alive. `bytearray` objects and
+    /// views of them can change, so they are copied
+    pub fn share(obj: &PyAny) -> Option<Bytes> {
+        let data = if let Ok(x) = obj.downcast::<PyBytes>() {
+            x.as_bytes()
+        } else if let Ok(x) = obj.downcast::<PyString>() {
+            x.to_str().ok()?.as_bytes()
+        } else if is_memoryview(obj) {
+            return share_view(obj);
+        } else {
+            return to_vec(obj).map(Into::into);
+        };
+        // SAFETY: bytes and str objects never change or move their contents, which
+        // live as long as the object the owner holds
+        let data: &'static [u8] = unsafe { std::mem::transmute(data) };
+        Some(Bytes::from_owner(Owner::<PyObject> {
+            _object: obj.into(),
+            data,
+        }))
+    }
+}
+
+/// Open websocket connections, so that they can be inspected and terminated from
+/// the console
+pub mod connections {
+    use std::{
+        collections::BTreeMap,
+        sync::{
+            atomic::{AtomicBool, AtomicU64, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use axum::extract::ws::Message;
+    use parking_lot::Mutex;
+    use tokio::sync::Notify;
+
+    pub(crate) struct Connection {
+        path: String,
+        peer: String,
+        opened: Instant,
+        bytes_in: AtomicU64,
+        bytes_out: AtomicU64,
+        killed: AtomicBool,
+        pub(crate) kill: Notify,
+    }
+
+    impl Connection {
+        pub(crate) fn is_killed(&self) -> bool {
+            self.killed.load(Ordering::Relaxed)
+        }
+
+        pub(crate) fn count_in(&self, msg: &Message) {
+            self.bytes_in.fetch_add(message_len(msg), Ordering::Relaxed);
+        }
+
+        pub(crate) fn count_out(&self, msg: &Message) {
+            self.bytes_out
+                .fetch_add(message_len(msg), Ordering::Relaxed);
+        }
+    }
+
+    fn message_len(msg: &Message) -> u64 {
+        match msg {
+            Message::Text(x) => x.len() as u64,
+            Message::Binary(x) | Message::Ping(x) | Message::Pong(x) => x.len() as u64,
+            Message::Close(_) => 0,
+        }
+    }
+
+    /// Keeps a connection in the registry until dropped
+    pub(crate) struct Registration {
+        id: u64,
+        pub(crate) connection: Arc<Connection>,
+    }
+
+    impl Drop for Registration {
+        fn drop(&mut self) {
+            CONNECTIONS.lock().remove(&self.id);
+        }
+    }
+
+    static CONNECTIONS: Mutex<BTreeMap<u64, Arc<Connection>>> = Mutex::new(BTreeMap::new());
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+    pub(crate) fn register(path: String, peer: String) -> Registration {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let connection = Arc::new(Connection {
+            path,
+            peer,
+            opened: Instant::now(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            killed: AtomicBool::new(false),
+            kill: Notify::new(),
+        });
+        CONNECTIONS.lock().insert(id, connection.clone());
+        Registration { id, connection }
+    }
+
+    pub struct ConnectionInfo {
+        pub id: u64,
+        pub path: String,
+        pub peer: String,
+        pub duration: Duration,
+        /// Payload bytes received from the client
+        pub bytes_in: u64,
+        /// Payload bytes sent to the client
+        pub bytes_out: u64,
+    }
+
+    /// How many connections are open
+    pub fn count() -> usize {
+        CONNECTIONS.lock().len()
+    }
+
+    /// Every open connection, oldest first
+    pub fn list() -> Vec<ConnectionInfo> {
+        CONNECTIONS
+            .lock()
+            .iter()
+            .map(|(&id, x)| ConnectionInfo {
+                id,
+                path: x.path.clone(),
+                peer: x.peer.clone(),
+                duration: x.opened.elapsed(),
+                bytes_in: x.bytes_in.load(Ordering::Relaxed),
+                bytes_out: x.bytes_out.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Closes the connection the next time its handler receives or sends, which
+    /// includes a receive that is already waiting. Returns false if there is no
+    /// connection with `id`
+    pub fn kill(id: u64) -> bool {
+        let Some(connection) = CONNECTIONS.lock().get(&id).cloned() else {
+            return false;
+        };
+        connection.killed.store(true, Ordering::Relaxed);
+        connection.kill.notify_waiters();
+        true
+    }
+}
+
+/// The API key a request was authenticated with
+#[pyclass(frozen)]
+pub struct ApiKey {
+    #[pyo3(get)]
+    pub name: String,
+    #[pyo3(get)]
+    pub scopes: Vec<String>,
+}
+
+#[pymethods]
+impl ApiKey {
+    fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|x| x == scope)
+    }
+}
+
+/// Passed to handlers that take a second argument
+#[pyclass(frozen)]
+pub struct RequestContext {
+    #[pyo3(get)]
+    pub api_key: Option<Py<ApiKey>>,
+    /// ISO code of the country of the client, if `geoip` is configured
+    #[pyo3(get)]
+    pub country: Option<String>,
+    #[pyo3(get)]
+    pub asn: Option<u32>,
+    #[pyo3(get)]
+    pub as_organization: Option<String>,
+    /// The names of the `tag` filter rules the request matched
+    #[pyo3(get)]
+    pub tags: Vec<String>,
+    /// For the `nonce` attribute of inline scripts and styles, if the
+    /// `content_security_policy` of the server has a nonce
+    #[pyo3(get)]
+    pub csp_nonce: Option<String>,
+    /// The TLS connection the request came over, if the server terminates TLS
+    #[pyo3(get)]
+    pub tls: Option<Py<TlsInfo>>,
+}
+
+#[pyclass(frozen)]
+pub struct TlsInfo {
+    #[pyo3(get)]
+    pub version: String,
+    #[pyo3(get)]
+    pub cipher: String,
+    #[pyo3(get)]
+    pub server_name: Option<String>,
+    #[pyo3(get)]
+    pub alpn: Option<String>,
+    #[pyo3(get)]
+    pub client_subject: Option<String>,
+    #[pyo3(get)]
+    pub client_fingerprint: Option<String>,
+}
+
+/// Passed to `route` in `_router.py` before the request is routed, where changes to
+/// the path, query and headers are kept
+#[pyclass(frozen)]
+pub struct IncomingRequest {
+    #[pyo3(get)]
+    pub method: String,
+    pub path: parking_lot::Mutex<String>,
+    pub query: parking_lot::Mutex<String>,
+    /// By lowercase names, with repeated headers joined
+    #[pyo3(get)]
+    pub headers: Py<pyo3::types::PyDict>,
+}
+
+#[pymethods]
+impl IncomingRequest {
+    #[getter]
+    fn path(&self) -> String {
+        self.path.lock().clone()
+    }
+
+    #[setter]
+    fn set_path(&self, path: String) -> PyResult<()> {
+        if !path.starts_with('/') || path.contains(['?', '#']) {
+            return Err(PyValueError::new_err(format!(
+                "path should start with / and have no query or fragment, not {path:?}"
+            )));
+        }
+        if axum::http::uri::PathAndQuery::try_from(path.as_str()).is_err() {
+            return Err(PyValueError::new_err(format!(
+                "path should be valid in a URI, not {path:?}"
+            )));
+        }
+        *self.path.lock() = path;
+        Ok(())
+    }
+
+    /// Without the leading `?`
+    #[getter]
+    fn query(&self) -> String {
+        self.query.lock().clone()
+    }
+
+    #[setter]
+    fn set_query(&self, query: String) -> PyResult<()> {
+        let query = query.strip_prefix('?').unwrap_or(&query);
+        if axum::http::uri::PathAndQuery::try_from(format!("/?{query}")).is_err()
+            || query.contains('#')
+        {
+            return Err(PyValueError::new_err(format!(
+                "query should be valid in a URI, not {query:?}"
+            )));
+        }
+        *self.query.lock() = query.to_owned();
+        Ok(())
+    }
+}
+
+/// A file from a request to an `upload_handler`, spooled to disk. The file is deleted
+/// once the handler returns, unless the handler moved it elsewhere
+#[pyclass(frozen)]
+pub struct UploadedFile {
+    #[pyo3(get)]
+    pub path: String,
+    /// The form field of the file, for multipart uploads
+    #[pyo3(get)]
+    pub field: Option<String>,
+    #[pyo3(get)]
+    pub filename: Option<String>,
+    #[pyo3(get)]
+    pub content_type: Option<String>,
+    #[pyo3(get)]
+    pub size: u64,
+}
+
+/// Passed to `upload_handler` instead of the request body
+#[pyclass(frozen)]
+pub struct Upload {
+    #[pyo3(get)]
+    pub files: Vec<Py<UploadedFile>>,
+    /// Form fields that are not files, for multipart uploads
+    #[pyo3(get)]
+    pub fields: std::collections::HashMap<String, String>,
+}
+
+/// Counters the server keeps up to date for `runtime_stats`
+pub mod runtime {
+    use std::sync::atomic::AtomicU64;
+
+    /// Requests that have been received but not responded to
+    pub static IN_FLIGHT: AtomicU64 = AtomicU64::new(0);
+    /// Requests waiting for a script to be imported or for the GIL
+    pub static QUEUE_DEPTH: AtomicU64 = AtomicU64::new(0);
+    /// How long the last callback scheduled onto the event loop waited to run
+    pub static EVENT_LOOP_LAG_MICROS: AtomicU64 = AtomicU64::new(0);
+}
+
+/// Context variables set around every handler, so that logging and tracing libraries
+/// can attach the request they are called in
+pub mod request_vars {
+    use pyo3::{prelude::*, sync::GILOnceCell, types::PyDict};
+
+    struct Vars {
+        request_id: PyObject,
+        /// The name of the API key the request was authenticated with
+        principal: PyObject,
+        /// The `time.monotonic()` by which the handler should have responded
+        deadline: PyObject,
+    }
+
+    static VARS: GILOnceCell<Vars> = GILOnceCell::new();
+
+    fn vars(py: Python) -> PyResult<&'static Vars> {
+        VARS.get_or_try_init(py, || {
+            let context_var = py.import("contextvars")?.getattr("ContextVar")?;
+            let new = |name: &str| -> PyResult<PyObject> {
+                let kwargs = PyDict::new(py);
+                kwargs.set_item("default", py.None())?;
+                Ok(context_var.call((name,), Some(kwargs))?.into())
+            };
+            Ok(Vars {
+                request_id: new("hypermangle_request_id")?,
+                principal: new("hypermangle_principal")?,
+                deadline: new("hypermangle_deadline")?,
+            })
+        })
+    }
+
+    /// A context of its own for a handler to run in, so that nothing it sets leaks
+    /// into other requests
+    pub fn new_context(
+        py: Python,
+        request_id: Option<&str>,
+        principal: Option<&str>,
+        deadline: Option<f64>,
+    ) -> PyResult<PyObject> {
+        let vars = vars(py)?;
+        let context = py.import("contextvars")?.getattr("Context")?.call0()?;
+        for (var, value) in [
+            (&vars.request_id, request_id.into_py(py)),
+            (&vars.principal, principal.into_py(py)),
+            (&vars.deadline, deadline.into_py(py)),
+        ] {
+            context.call_method1("run", (var.getattr(py, "set")?, value))?;
+        }
+        Ok(context.into())
+    }
+
+    /// Seconds left before the deadline of the handler being run, if it has one
+    pub(crate) fn remaining(py: Python) -> PyResult<Option<f64>> {
+        let deadline: Option<f64> = vars(py)?.deadline.call_method0(py, "get")?.extract(py)?;
+        let Some(deadline) = deadline else {
+            return Ok(None);
+        };
+        let now: f64 = py.import("time")?.call_method0("monotonic")?.extract()?;
+        Ok(Some((deadline - now).max(0.0)))
+    }
+
+    pub(crate) fn add_to_module(py: Python, m: &PyModule) -> PyResult<()> {
+        let vars = vars(py)?;
+        m.add("request_id_var", &vars.request_id)?;
+        m.add("principal_var", &vars.principal)?;
+        m.add("deadline_var", &vars.deadline)
+    }
+}
+
+/// Passed to the message handler of a queue consumer. The message is acknowledged if
+/// the handler returns normally, and rejected to be redelivered if it raises or
+/// returns `False`
+#[pyclass(frozen)]
+pub struct QueueMessage {
+    /// The subject, routing key or topic the message was published to
+    #[pyo3(get)]
+    pub subject: String,
+    #[pyo3(get)]
+    pub body: Py<PyBytes>,
+    #[pyo3(get)]
+    pub redelivered: bool,
+}
+
+/// Lets the server sign URLs for `sign_url`, as the signing key is part of its config
+pub mod signing {
+    use std::sync::OnceLock;
+
+    /// Signs a path so that it is valid until the given UNIX timestamp
+    pub static SIGNER: OnceLock<fn(&str, u64) -> String> = OnceLock::new();
+}
+
+/// Signs `path` so that it can be requested without a token for `expires_in` seconds
+#[pyfunction]
+fn sign_url(path: &str, expires_in: f64) -> PyResult<String> {
+    let Some(signer) = signing::SIGNER.get() else {
+        return Err(pyo3::exceptions::PyRuntimeError::new_err(
+            "url_signing_key should be set to sign URLs",
+        ));
+    };
+    if expires_in.is_nan() || expires_in <= 0.0 {
+        return Err(PyValueError::new_err("expires_in should be positive"));
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("System time should be after the UNIX epoch");
+    Ok(signer(path, (now.as_secs_f64() + expires_in).ceil() as u64))
+}
+
+/// Runs `hash` on a blocking thread, as password hashes take long enough to stall the
+/// event loop, and do not need the GIL
+fn hash_off_loop<T>(
+    py: Python<'_>,
+    hash: impl FnOnce() -> PyResult<T> + Send + 'static,
+) -> PyResult<&PyAny>
+where
+    T: IntoPy<PyObject> + Send + 'static,
+{
+    pyo3_asyncio::tokio::future_into_py(py, async move {
+        tokio::task::spawn_blocking(hash)
+            .await
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?
+    })
+}
+
+/// Hashes `password` with a random salt, in a scheme `verify_hash` can tell apart
+fn hash_with(password: &str, scheme: &str) -> Result<String, String> {
+    match scheme {
+        "argon2" => {
+            use argon2::password_hash::{rand_core::OsRng, PasswordHasher, SaltString};
+
+            argon2::Argon2::default()
+                .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+                .map(|x| x.to_string())
+                .map_err(|e| e.to_string())
+        }
+        "bcrypt" => bcrypt::hash(password, bcrypt::DEFAULT_COST).map_err(|e| e.to_string()),
+        _ => Err(format!("scheme should be argon2 or bcrypt, not {scheme:?}")),
+    }
+}
+
+fn verify_hash(password: &str, hash: &str) -> Result<bool, String> {
+    if hash.starts_with("$argon2") {
+        use argon2::password_hash::{PasswordHash, PasswordVerifier};
+
+        let parsed = PasswordHash::new(hash).map_err(|e| format!("hash should be valid: {e}"))?;
+        return Ok(argon2::Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok());
+    }
+    bcrypt::verify(password, hash).map_err(|e| format!("hash should be valid: {e}"))
+}
+
+/// Hashes `password` with a random salt, with argon2id by default or bcrypt
+#[pyfunction]
+#[pyo3(signature = (password, scheme = "argon2"))]
+fn hash_password<'a>(py: Python<'a>, password: String, scheme: &str) -> PyResult<&'a PyAny> {
+    if !matches!(scheme, "argon2" | "bcrypt") {
+        return Err(PyValueError::new_err(format!(
+            "scheme should be argon2 or bcrypt, not {scheme:?}"
+        )));
+    }
+    let scheme = scheme.to_owned();
+    hash_off_loop(py, move || {
+        hash_with(&password, &scheme).map_err(PyValueError::new_err)
+    })
+}
+
+/// Whether `password` is the one `hash` was made from by `hash_password`, of
+/// either scheme
+#[pyfunction]
+fn verify_password(py: Python<'_>, password: String, hash: String) -> PyResult<&PyAny> {
+    hash_off_loop(py, move || {
+        verify_hash(&password, &hash).map_err(PyValueError::new_err)
+    })
+}
+
+/// Lets the server make the ids of `new_id`, as their format is part of its config
+pub mod ids {
+    use std::sync::OnceLock;
+
+    pub static GENERATOR: OnceLock<fn() -> String> = OnceLock::new();
+}
+
+/// Lets the server deliver events from `emit`, as the subscribers are part of its config
+pub mod events {
+    use std::sync::OnceLock;
+
+    /// Queues an event with a JSON payload, returning how many subscribers it is for
+    pub static EMITTER: OnceLock<fn(&str, String) -> std::io::Result<usize>> = OnceLock::new();
+}
+
+/// Queues `event` for delivery to every webhook subscriber that wants it, with
+/// `payload` encoded as JSON
+#[pyfunction]
+fn emit(py: Python, event: &str, payload: &PyAny) -> PyResult<usize> {
+    let Some(emitter) = events::EMITTER.get() else {
+        return Err(pyo3::exceptions::PyRuntimeError::new_err(
+            "webhooks should be configured to emit events",
+        ));
+    };
+    let payload: String = py
+        .import("json")?
+        .call_method1("dumps", (payload,))?
+        .extract()?;
+    Ok(emitter(event, payload)?)
+}
+
+/// Lets the server queue jobs for `enqueue`, as the queue is part of its config
+pub mod jobs {
+    use std::{sync::OnceLock, time::Duration};
+
+    /// Queues a call to `path/to/script.py:function` with a JSON list of arguments
+    /// after a delay, returning the id of the job
+    pub type Enqueuer = fn(&str, String, Duration) -> Result<i64, String>;
+
+    pub static ENQUEUER: OnceLock<Enqueuer> = OnceLock::new();
+}
+
+/// Queues a call to `func_name`, given as `path/to/script.py:function`, with `args`
+/// encoded as JSON, returning the id of the job. The call happens after `delay`
+/// seconds if given, and is retried with a backoff if it raises
+#[pyfunction]
+#[pyo3(signature = (func_name, args = None, delay = None))]
+fn enqueue(py: Python, func_name: &str, args: Option<&PyAny>, delay: Option<f64>) -> PyResult<i64> {
+    let Some(enqueuer) = jobs::ENQUEUER.get() else {
+        return Err(pyo3::exceptions::PyRuntimeError::new_err(
+            "jobs should be configured to enqueue jobs",
+        ));
+    };
+    let delay = match delay {
+        Some(x) if !x.is_finite() || x < 0.0 => {
+            return Err(PyValueError::new_err("delay should not be negative"))
+        }
+        Some(x) => std::time::Duration::from_secs_f64(x),
+        None => std::time::Duration::ZERO,
+    };
+    let args = match args {
+        Some(args) => pyo3::types::PyList::new(py, args.iter()?.collect::<PyResult<Vec<_>>>()?),
+        None => pyo3::types::PyList::empty(py),
+    };
+    let args: String = py
+        .import("json")?
+        .call_method1("dumps", (args,))?
+        .extract()?;
+    enqueuer(func_name, args, delay).map_err(pyo3::exceptions::PyRuntimeError::new_err)
+}
+
+/// Lets `TestClient` send requests through the router of `hypermangle test` without
+/// binding a port
+pub mod testing {
+    use std::{future::Future, path::PathBuf, pin::Pin, sync::OnceLock};
+
+    use axum::{body::Body, http::Request};
+
+    pub struct TestResult {
+        pub status: u16,
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+    }
+
+    pub type Sender = fn(Request<Body>) -> Pin<Box<dyn Future<Output = TestResult> + Send>>;
+
+    pub static SENDER: OnceLock<Sender> = OnceLock::new();
+    /// The folder `assert_snapshot` keeps snapshots in, and whether it overwrites them
+    pub static SNAPSHOTS: OnceLock<(PathBuf, bool)> = OnceLock::new();
+}
+
+/// Lets `hypermangle test` make `now` and `new_id` deterministic
+pub mod clock {
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+    /// 2000-01-01T00:00:00Z, where the clock starts in test mode
+    pub const TEST_EPOCH_MICROS: u64 = 946_684_800_000_000;
+
+    pub(crate) static TEST_MODE: AtomicBool = AtomicBool::new(false);
+    pub(crate) static NOW_MICROS: AtomicU64 = AtomicU64::new(TEST_EPOCH_MICROS);
+    pub(crate) static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+    pub fn enable_test_mode() {
+        TEST_MODE.store(true, Ordering::Relaxed);
+    }
+
+    /// Puts the clock back at the test epoch and restarts the ids, so that every test
+    /// sees the same values
+    pub fn reset() {
+        NOW_MICROS.store(TEST_EPOCH_MICROS, Ordering::Relaxed);
+        NEXT_ID.store(1, Ordering::Relaxed);
+    }
+}
+
+fn test_clock() -> PyResult<()> {
+    if !clock::TEST_MODE.load(std::sync::atomic::Ordering::Relaxed) {
+        return Err(pyo3::exceptions::PyRuntimeError::new_err(
+            "The clock can only be changed by `hypermangle test`",
+        ));
+    }
+    Ok(())
+}
+
+/// The current UNIX timestamp in seconds, which stands still in test mode unless
+/// moved with `set_time` or `advance_time`
+#[pyfunction]
+fn now() -> f64 {
+    use std::sync::atomic::Ordering;
+
+    if clock::TEST_MODE.load(Ordering::Relaxed) {
+        return clock::NOW_MICROS.load(Ordering::Relaxed) as f64 / 1e6;
+    }
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("System time should be after the UNIX epoch")
+        .as_secs_f64()
+}
+
+/// An id in the format of the `[ids]` of the server, which is a random UUID by
+/// default, or the next of a sequence of UUIDs in test mode
+#[pyfunction]
+fn new_id(py: Python) -> PyResult<String> {
+    use std::sync::atomic::Ordering;
+
+    if clock::TEST_MODE.load(Ordering::Relaxed) {
+        let id = clock::NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        return Ok(format!("00000000-0000-4000-8000-{id:012x}"));
+    }
+    if let Some(generator) = ids::GENERATOR.get() {
+        return Ok(generator());
+    }
+    py.import("uuid")?.call_method0("uuid4")?.str()?.extract()
+}
+
+#[pyfunction]
+fn set_time(timestamp: f64) -> PyResult<()> {
+    test_clock()?;
+    if !timestamp.is_finite() || timestamp < 0.0 {
+        return Err(PyValueError::new_err("timestamp should not be negative"));
+    }
+    clock::NOW_MICROS.store(
+        (timestamp * 1e6) as u64,
+        std::sync::atomic::Ordering::Relaxed,
+    );
+    Ok(())
+}
+
+#[pyfunction]
+fn advance_time(seconds: f64) -> PyResult<()> {
+    test_clock()?;
+    if !seconds.is_finite() || seconds < 0.0 {
+        return Err(PyValueError::new_err("seconds should not be negative"));
+    }
+    clock::NOW_MICROS.fetch_add((seconds * 1e6) as u64, std::sync::atomic::Ordering::Relaxed);
+    Ok(())
+}
+
+/// Headers that differ between every response, and are left out of snapshots
+const VOLATILE_HEADERS: [&str; 2] = ["date", "x-request-id"];
+
+/// Sends requests to the server that `hypermangle test` runs the tests against
+#[pyclass(frozen)]
+struct TestClient {
+    headers: Vec<(String, String)>,
+    /// Path prefixes and the seconds that requests to them may take, where the
+    /// longest matching prefix applies
+    latency_budgets: Vec<(String, f64)>,
+}
+
+#[pymethods]
+impl TestClient {
+    #[new]
+    #[pyo3(signature = (headers = None, latency_budgets = None))]
+    fn new(
+        headers: Option<std::collections::HashMap<String, String>>,
+        latency_budgets: Option<std::collections::HashMap<String, f64>>,
+    ) -> Self {
+        Self {
+            headers: headers.unwrap_or_default().into_iter().collect(),
+            latency_budgets: latency_budgets.unwrap_or_default().into_iter().collect(),
+        }
+    }
+
+    #[pyo3(signature = (path, headers = None))]
+    fn get<'a>(
+        &self,
+        py: Python<'a>,
+        path: &str,
+        headers: Option<std::collections::HashMap<String, String>>,
+    ) -> PyResult<&'a PyAny> {
+        self.request(py, "GET", path, None, headers)
+    }
+
+    #[pyo3(signature = (path, body = None, headers = None))]
+    fn post<'a>(
+        &self,
+        py: Python<'a>,
+        path: &str,
+        body: Option<&PyAny>,
+        headers: Option<std::collections::HashMap<String, String>>,
+    ) -> PyResult<&'a PyAny> {
+        self.request(py, "POST", path, body, headers)
+    }
+
+    /// Bodies other than `str` and `bytes` are sent as JSON
+    #[pyo3(signature = (method, path, body = None, headers = None))]
+    fn request<'a>(
+        &self,
+        py: Python<'a>,
+        method: &str,
+        path: &str,
+        body: Option<&PyAny>,
+        headers: Option<std::collections::HashMap<String, String>>,
+    ) -> PyResult<&'a PyAny> {
+        let Some(sender) = testing::SENDER.get() else {
+            return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                "TestClient should only be used by `hypermangle test`",
+            ));
+        };
+        let mut builder = axum::http::Request::builder().method(method).uri(path);
+        let headers = headers.into_iter().flatten();
+        for (name, value) in self.headers.iter().cloned().chain(headers) {
+            builder = builder.header(name, value);
+        }
+        let body = match body {
+            None => Vec::new(),
+            Some(body) => {
+                if let Ok(body) = body.extract::<String>() {
+                    body.into_bytes()
+                } else if let Some(body) = buffers::to_vec(body) {
+                    body
+                } else {
+                    if builder
+                        .headers_ref()
+                        .is_some_and(|x| !x.contains_key("content-type"))
+                    {
+                        builder = builder.header("content-type", "application/json");
+                    }
+                    py.import("json")?
+                        .call_method1("dumps", (body,))?
+                        .extract::<String>()?
+                        .into_bytes()
+                }
+            }
+        };
+        let request = builder
+            .body(axum::body::Body::from(body))
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let latency_budget = self
+            .latency_budgets
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, budget)| *budget);
+        let path = path.to_owned();
+
+        pyo3_asyncio::tokio::future_into_py(py, async move {
+            let start = std::time::Instant::now();
+            let result = sender(request).await;
+            let response = TestResponse {
+                path,
+                status: result.status,
+                headers: result.headers,
+                body: result.body,
+                elapsed: start.elapsed().as_secs_f64(),
+                latency_budget,
+            };
+            if let Some(budget) = latency_budget {
+                response.check_latency(budget)?;
+            }
+            Ok(response)
+        })
+    }
+}
+
+/// A response to a `TestClient`, whose assertions return the response so that they
+/// can be chained
+#[pyclass(frozen)]
+struct TestResponse {
+    path: String,
+    #[pyo3(get)]
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    /// In seconds
+    #[pyo3(get)]
+    elapsed: f64,
+    latency_budget: Option<f64>,
+}
+
+fn assertion_error(message: String) -> PyErr {
+    pyo3::exceptions::PyAssertionError::new_err(message)
+}
+
+impl TestResponse {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(x, _)| x.eq_ignore_ascii_case(name))
+            .map(|(_, x)| x.as_str())
+    }
+
+    fn check_latency(&self, budget: f64) -> PyResult<()> {
+        if self.elapsed > budget {
+            return Err(assertion_error(format!(
+                "{} took {:.1}ms, over its budget of {:.1}ms",
+                self.path,
+                self.elapsed * 1000.0,
+                budget * 1000.0
+            )));
+        }
+        Ok(())
+    }
+
+    /// The status, headers and body, as kept in snapshots
+    fn snapshot(&self) -> String {
+        let mut headers: Vec<_> = self
+            .headers
+            .iter()
+            .filter(|(name, _)| !VOLATILE_HEADERS.contains(&name.as_str()))
+            .map(|(name, value)| format!("{name}: {value}\n"))
+            .collect();
+        headers.sort();
+        format!(
+            "{}\n{}\n{}",
+            self.status,
+            headers.concat(),
+            String::from_utf8_lossy(&self.body)
+        )
+    }
+}
+
+#[pymethods]
+impl TestResponse {
+    #[getter]
+    fn headers(&self) -> std::collections::HashMap<String, String> {
+        self.headers.iter().cloned().collect()
+    }
+
+    #[getter]
+    fn body<'a>(&self, py: Python<'a>) -> &'a PyBytes {
+        PyBytes::new(py, &self.body)
+    }
+
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    fn json<'a>(&self, py: Python<'a>) -> PyResult<&'a PyAny> {
+        py.import("json")?.call_method1("loads", (self.text(),))
+    }
+
+    fn assert_status(slf: PyRef<'_, Self>, status: u16) -> PyResult<PyRef<'_, Self>> {
+        if slf.status != status {
+            return Err(assertion_error(format!(
+                "{} responded with {}, not {status}: {}",
+                slf.path,
+                slf.status,
+                slf.text()
+            )));
+        }
+        Ok(slf)
+    }
+
+    /// Checks that the header is present, and has `value` if given
+    #[pyo3(signature = (name, value = None))]
+    fn assert_header<'a>(
+        slf: PyRef<'a, Self>,
+        name: &str,
+        value: Option<&str>,
+    ) -> PyResult<PyRef<'a, Self>> {
+        match (slf.header(name), value) {
+            (None, _) => Err(assertion_error(format!(
+                "{} responded without {name}",
+                slf.path
+            ))),
+            (Some(actual), Some(value)) if actual != value => Err(assertion_error(format!(
+                "{} responded with {name}: {actual}, not {value}",
+                slf.path
+            ))),
+            _ => Ok(slf),
+        }
+    }
+
+    fn assert_json<'a>(slf: PyRef<'a, Self>, expected: &PyAny) -> PyResult<PyRef<'a, Self>> {
+        let py = slf.py();
+        let actual = slf.json(py)?;
+        if !actual.eq(expected)? {
+            return Err(assertion_error(format!(
+                "{} responded with {}, not {}",
+                slf.path,
+                actual.repr()?,
+                expected.repr()?
+            )));
+        }
+        Ok(slf)
+    }
+
+    /// Checks that the response took at most `budget` seconds, or the budget of its
+    /// path in the client if not given
+    #[pyo3(signature = (budget = None))]
+    fn assert_latency(slf: PyRef<'_, Self>, budget: Option<f64>) -> PyResult<PyRef<'_, Self>> {
+        let Some(budget) = budget.or(slf.latency_budget) else {
+            return Err(PyValueError::new_err(format!(
+                "{} has no latency budget",
+                slf.path
+            )));
+        };
+        slf.check_latency(budget)?;
+        Ok(slf)
+    }
+
+    /// Compares the response to the snapshot called `name`, which is written if it
+    /// does not exist yet, or if `hypermangle test` is run with `--update-snapshots`
+    fn assert_snapshot<'a>(slf: PyRef<'a, Self>, name: &str) -> PyResult<PyRef<'a, Self>> {
+        let Some((dir, update)) = testing::SNAPSHOTS.get() else {
+            return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                "Snapshots should only be taken by `hypermangle test`",
+            ));
+        };
+        let path = dir.join(format!("{name}.snap"));
+        let actual = slf.snapshot();
+        match std::fs::read_to_string(&path) {
+            Ok(expected) if !update => {
+                if expected != actual {
+                    return Err(assertion_error(format!(
+                        "{} does not match snapshot {name}\n--- expected\n{expected}\n+++ actual\n{actual}",
+                        slf.path
+                    )));
+                }
+            }
+            _ => {
+                std::fs::create_dir_all(dir)?;
+                std::fs::write(&path, actual)?;
+            }
+        }
+        Ok(slf)
+    }
+}
+
+/// Topics that scripts publish messages to, such as to fan chat messages out to every
+/// websocket in a room. In cluster mode, messages go through Redis to every instance
+pub mod hub {
+    use std::{collections::HashMap, sync::OnceLock};
+
+    use parking_lot::Mutex;
+    use tokio::sync::broadcast;
+
+    /// Messages a subscriber has not received yet beyond which it skips ahead
+    const CAPACITY: usize = 256;
+
+    #[derive(Clone)]
+    pub enum HubMessage {
+        Text(String),
+        Binary(Vec<u8>),
+    }
+
+    impl HubMessage {
+        /// A tag byte followed by the message, for relaying between instances
+        pub fn encode(&self) -> Vec<u8> {
+            match self {
+                Self::Text(x) => [b"t", x.as_bytes()].concat(),
+                Self::Binary(x) => [b"b", x.as_slice()].concat(),
+            }
+        }
+
+        pub fn decode(data: &[u8]) -> Option<Self> {
+            match data.split_first()? {
+                (b't', x) => String::from_utf8(x.to_vec()).ok().map(Self::Text),
+                (b'b', x) => Some(Self::Binary(x.to_vec())),
+                _ => None,
+            }
+        }
+    }
+
+    /// Sends a message to every instance, which each deliver it, including this one
+    pub static RELAY: OnceLock<fn(&str, HubMessage)> = OnceLock::new();
+
+    static TOPICS: Mutex<Option<HashMap<String, broadcast::Sender<HubMessage>>>> = Mutex::new(None);
+
+    pub(crate) fn subscribe(topic: &str) -> broadcast::Receiver<HubMessage> {
+        TOPICS
+            .lock()
+            .get_or_insert_with(Default::default)
+            .entry(topic.to_owned())
+            .or_insert_with(|| broadcast::channel(CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Hands `message` to the subscribers of `topic` on this instance, returning how
+    /// many there were
+    pub fn deliver(topic: &str, message: HubMessage) -> usize {
+        let mut topics = TOPICS.lock();
+        let Some(topics) = topics.as_mut() else {
+            return 0;
+        };
+        let Some(sender) = topics.get(topic) else {
+            return 0;
+        };
+        if sender.receiver_count() == 0 {
+            topics.remove(topic);
+            return 0;
+        }
+        sender.send(message).unwrap_or_default()
+    }
+}
+
+/// Messages published to a topic after subscribing to it
+#[pyclass(frozen)]
+struct Subscription {
+    receiver: Arc<Mutex<tokio::sync::broadcast::Receiver<hub::HubMessage>>>,
+}
+
+#[pymethods]
+impl Subscription {
+    fn recv<'a>(&self, py: Python<'a>) -> PyResult<&'a PyAny> {
+        use tokio::sync::broadcast::error::RecvError;
+
+        let receiver = self.receiver.clone();
+        pyo3_asyncio::tokio::future_into_py(py, async move {
+            let mut receiver = receiver.lock().await;
+            loop {
+                match receiver.recv().await {
+                    Ok(message) => {
+                        return Python::with_gil(|py| {
+                            Ok(match message {
+                                hub::HubMessage::Text(x) => x.into_py(py),
+                                hub::HubMessage::Binary(x) => PyBytes::new(py, &x).into_py(py),
+                            })
+                        })
+                    }
+                    // Subscribers that fall behind miss the oldest messages
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => unreachable!("Topics are never closed"),
+                }
+            }
+        })
+    }
+}
+
+#[pyfunction]
+fn subscribe(topic: &str) -> Subscription {
+    Subscription {
+        receiver: Arc::new(Mutex::new(hub::subscribe(topic))),
+    }
+}
+
+/// Sends `message` to every subscriber of `topic`, on every instance in cluster mode
+#[pyfunction]
+fn publish(topic: &str, message: &PyAny) -> PyResult<()> {
+    let message = if let Ok(message) = message.extract::<String>() {
+        hub::HubMessage::Text(message)
+    } else if let Some(message) = buffers::to_vec(message) {
+        hub::HubMessage::Binary(message)
+    } else {
+        return Err(PyValueError::new_err(
+            "Only strings or bytes can be published",
+        ));
+    };
+    match hub::RELAY.get() {
+        Some(relay) => relay(topic, message),
+        None => {
+            hub::deliver(topic, message);
+        }
+    }
+    Ok(())
+}
+
+/// The receiving end of the `ResponseStream`s that handlers return
+pub mod response_streams {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tokio::sync::{mpsc, Notify};
+
+    /// Flushed chunks a stream holds for a slow client before `flush` waits for it
+    pub(crate) const CAPACITY: usize = 16;
+
+    pub enum StreamFrame {
+        /// Everything written between two flushes
+        Data(Vec<u8>),
+        /// The handler closed the stream, so the body is complete
+        End,
+        /// The handler raised while writing, so the body is not complete
+        Abort(String),
+    }
+
+    #[derive(Default)]
+    pub(crate) struct Queue {
+        pub(crate) len: AtomicUsize,
+        pub(crate) drained: Notify,
+    }
+
+    pub struct StreamReceiver {
+        pub(crate) receiver: mpsc::UnboundedReceiver<StreamFrame>,
+        pub(crate) queue: Arc<Queue>,
+    }
+
+    impl StreamReceiver {
+        /// `None` if the stream was dropped without being closed
+        pub async fn recv(&mut self) -> Option<StreamFrame> {
+            let frame = self.receiver.recv().await?;
+            self.queue.len.fetch_sub(1, Ordering::Relaxed);
+            self.queue.drained.notify_waiters();
+            Some(frame)
+        }
+    }
+}
+
+struct ResponseStreamState {
+    buffer: Vec<u8>,
+    /// `None` once the stream is closed
+    sender: Option<tokio::sync::mpsc::UnboundedSender<response_streams::StreamFrame>>,
+    /// `None` once the stream was returned as the body of a response
+    receiver: Option<response_streams::StreamReceiver>,
+}
+
+/// A response body that the handler writes to, such as from a task it started,
+/// instead of yielding it. What was written reaches the client when it is flushed
+#[pyclass(frozen)]
+pub struct ResponseStream {
+    state: parking_lot::Mutex<ResponseStreamState>,
+    queue: Arc<response_streams::Queue>,
+}
+
+#[pymethods]
+impl ResponseStream {
+    #[new]
+    fn new() -> Self {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let queue = Arc::new(response_streams::Queue::default());
+        Self {
+            state: parking_lot::Mutex::new(ResponseStreamState {
+                buffer: vec![],
+                sender: Some(sender),
+                receiver: Some(response_streams::StreamReceiver {
+                    receiver,
+                    queue: queue.clone(),
+                }),
+            }),
+            queue,
+        }
+    }
+
+    fn write(&self, data: &PyAny) -> PyResult<()> {
+        let mut state = self.state.lock();
+        if state.sender.is_none() {
+            return Err(PyValueError::new_err("The response stream is closed"));
+        }
+        if let Ok(data) = data.extract::<&str>() {
+            state.buffer.extend_from_slice(data.as_bytes());
+        } else if let Ok(data) = data.downcast::<PyBytes>() {
+            state.buffer.extend_from_slice(data.as_bytes());
+        } else if let Some(data) = buffers::to_vec(data) {
+            state.buffer.extend_from_slice(&data);
+        } else {
+            return Err(PyValueError::new_err(
+                "Response streams can only be written Strings or Bytes",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Sends what was written so far, waiting if the client has fallen behind
+    fn flush<'a>(&self, py: Python<'a>) -> PyResult<&'a PyAny> {
+        self.send(py, false)
+    }
+
+    fn close<'a>(&self, py: Python<'a>) -> PyResult<&'a PyAny> {
+        self.send(py, true)
+    }
+
+    fn __aenter__<'a>(slf: &'a PyCell<Self>, py: Python<'a>) -> PyResult<&'a PyAny> {
+        let slf: Py<Self> = slf.into();
+        pyo3_asyncio::tokio::future_into_py(py, async move { Ok(slf) })
+    }
+
+    /// Closes the stream, or cuts the response short if the block raised, so that
+    /// the client does not take the body as complete
+    fn __aexit__<'a>(
+        &self,
+        py: Python<'a>,
+        _exc_type: &PyAny,
+        exc: &PyAny,
+        _traceback: &PyAny,
+    ) -> PyResult<&'a PyAny> {
+        if exc.is_none() {
+            return self.close(py);
+        }
+        if let Some(sender) = self.state.lock().sender.take() {
+            self.queue
+                .len
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let _ = sender.send(response_streams::StreamFrame::Abort(exc.to_string()));
+        }
+        pyo3_asyncio::tokio::future_into_py(py, async { Ok(false) })
+    }
+}
+
+impl ResponseStream {
+    /// The frames the handler flushes, which can only be taken once
+    pub fn take_receiver(&self) -> Option<response_streams::StreamReceiver> {
+        self.state.lock().receiver.take()
+    }
+
+    fn send<'a>(&self, py: Python<'a>, close: bool) -> PyResult<&'a PyAny> {
+        use response_streams::StreamFrame;
+        use std::sync::atomic::Ordering;
+
+        let mut state = self.state.lock();
+        let data = std::mem::take(&mut state.buffer);
+        let sender = if close {
+            state.sender.take()
+        } else {
+            state.sender.clone()
+        };
+        drop(state);
+        let Some(sender) = sender else {
+            if close {
+                return pyo3_asyncio::tokio::future_into_py(py, async { Ok(()) });
+            }
+            return Err(PyValueError::new_err("The response stream is closed"));
+        };
+
+        // Sent right away, so that frames keep their order even if flushes are not
+        // awaited one by one
+        let frames = (!data.is_empty())
+            .then_some(StreamFrame::Data(data))
+            .into_iter()
+            .chain(close.then_some(StreamFrame::End));
+        for frame in frames {
+            self.queue.len.fetch_add(1, Ordering::Relaxed);
+            if sender.send(frame).is_err() {
+                return Err(pyo3::exceptions::PyConnectionResetError::new_err(
+                    "The client stopped reading the response",
+                ));
+            }
+        }
+
+        let queue = self.queue.clone();
+        pyo3_asyncio::tokio::future_into_py(py, async move {
+            loop {
+                // Created before checking the length so that a frame taken in between
+                // is not missed
+                let drained = queue.drained.notified();
+                if queue.len.load(Ordering::Relaxed) <= response_streams::CAPACITY {
+                    return Ok(());
+                }
+                tokio::select! {
+                    _ = drained => {}
+                    _ = sender.closed() => {
+                        return Err(pyo3::exceptions::PyConnectionResetError::new_err(
+                            "The client stopped reading the response",
+                        ));
+                    }
+                }
+            }
+        })
+    }
+}
+
+struct NdJsonState {
+    body: Option<axum::body::Body>,
+    /// Received bytes from `start` on, of which those before `scanned` have no newline
+    pending: Vec<u8>,
+    start: usize,
+    scanned: usize,
+    line: usize,
+    max_line_bytes: usize,
+}
+
+impl NdJsonState {
+    /// The next line that is not blank out of those received, with its number
+    fn buffered_line(&mut self) -> Option<(usize, Vec<u8>)> {
+        while let Some(offset) = self.pending[self.scanned..]
+            .iter()
+            .position(|x| *x == b'\n')
+        {
+            let end = self.scanned + offset;
+            let line = self.pending[self.start..end].to_vec();
+            self.start = end + 1;
+            self.scanned = self.start;
+            self.line += 1;
+            if !line.iter().all(u8::is_ascii_whitespace) {
+                return Some((self.line, line));
+            }
+        }
+        self.scanned = self.pending.len();
+        None
+    }
+
+    /// The next line that is not blank, buffering no more than one line of the body
+    async fn next_line(&mut self) -> PyResult<Option<(usize, Vec<u8>)>> {
+        use axum::body::HttpBody;
+
+        loop {
+            if let Some(line) = self.buffered_line() {
+                return Ok(Some(line));
+            }
+            if self.pending.len() - self.start > self.max_line_bytes {
+                self.body = None;
+                return Err(PyValueError::new_err(format!(
+                    "Line {} should be no longer than {} bytes",
+                    self.line + 1,
+                    self.max_line_bytes
+                )));
+            }
+
+            let chunk = match &mut self.body {
+                Some(body) => body.data().await,
+                None => None,
+            };
+            match chunk {
+                Some(Ok(chunk)) => {
+                    self.pending.drain(..self.start);
+                    self.scanned -= self.start;
+                    self.start = 0;
+                    self.pending.extend_from_slice(&chunk);
+                }
+                Some(Err(e)) => {
+                    self.body = None;
+                    return Err(pyo3::exceptions::PyConnectionError::new_err(e.to_string()));
+                }
+                // The last line needs no newline
+                None => {
+                    self.body = None;
+                    let line = self.pending.split_off(self.start);
+                    self.scanned = self.start;
+                    if line.iter().all(u8::is_ascii_whitespace) {
+                        return Ok(None);
+                    }
+                    self.line += 1;
+                    return Ok(Some((self.line, line)));
+                }
+            }
+        }
+    }
+}
+
+fn parse_ndjson_line(py: Python, number: usize, line: &[u8]) -> PyResult<PyObject> {
+    py.import(pyo3::intern!(py, "json"))?
+        .call_method1(pyo3::intern!(py, "loads"), (PyBytes::new(py, line),))
+        .map(|x| x.into_py(py))
+        .map_err(|e| PyValueError::new_err(format!("Line {number} should be valid JSON: {e}")))
+}
+
+/// Passed to handlers instead of `application/x-ndjson` request bodies, parsing each
+/// line as it arrives, so that bodies of any length can be consumed
+#[pyclass(frozen)]
+pub struct NdJsonLines {
+    state: Arc<Mutex<NdJsonState>>,
+}
+
+impl NdJsonLines {
+    /// Lines longer than `max_line_bytes` are raised instead of being buffered
+    pub fn new(body: axum::body::Body, max_line_bytes: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(NdJsonState {
+                body: Some(body),
+                pending: Vec::new(),
+                start: 0,
+                scanned: 0,
+                line: 0,
+                max_line_bytes,
+            })),
+        }
+    }
+}
+
+#[pymethods]
+impl NdJsonLines {
+    fn __aiter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    // Always an awaitable, which raises `StopAsyncIteration` after the last line
+    fn __anext__(&self, py: Python) -> PyResult<Option<PyObject>> {
+        // Lines that were already received are parsed right away, as going through
+        // the runtime for each of them would take far longer than parsing them
+        if let Ok(mut state) = self.state.try_lock() {
+            if let Some((number, line)) = state.buffered_line() {
+                let future = pyo3_asyncio::get_running_loop(py)?
+                    .call_method0(pyo3::intern!(py, "create_future"))?;
+                match parse_ndjson_line(py, number, &line) {
+                    Ok(x) => future.call_method1(pyo3::intern!(py, "set_result"), (x,))?,
+                    Err(e) => future.call_method1(pyo3::intern!(py, "set_exception"), (e,))?,
+                };
+                return Ok(Some(future.into()));
+            }
+        }
+        let state = self.state.clone();
+        let next = pyo3_asyncio::tokio::future_into_py(py, async move {
+            let Some((number, line)) = state.lock().await.next_line().await? else {
+                return Err(pyo3::exceptions::PyStopAsyncIteration::new_err(()));
+            };
+            Python::with_gil(|py| parse_ndjson_line(py, number, &line))
+        })?;
+        Ok(Some(next.into()))
+    }
+}
+
+/// Seconds left before the handler being run is cancelled, for the timeouts of the
+/// calls it makes, so that they give up in time instead of adding up past it
+#[pyfunction]
+fn deadline(py: Python) -> PyResult<Option<f64>> {
+    request_vars::remaining(py)
+}
+
+#[pyclass(frozen)]
+struct RuntimeStats {
+    #[pyo3(get)]
+    in_flight: u64,
+    #[pyo3(get)]
+    queue_depth: u64,
+    /// In seconds
+    #[pyo3(get)]
+    event_loop_lag: f64,
+}
+
+#[pyfunction]
+fn runtime_stats() -> RuntimeStats {
+    use std::sync::atomic::Ordering;
+
+    RuntimeStats {
+        in_flight: runtime::IN_FLIGHT.load(Ordering::Relaxed),
+        queue_depth: runtime::QUEUE_DEPTH.load(Ordering::Relaxed),
+        event_loop_lag: runtime::EVENT_LOOP_LAG_MICROS.load(Ordering::Relaxed) as f64 / 1e6,
+    }
+}
+
+/// Makes `import hypermangle_py` in scripts use the module built into the server
+/// instead of an installed copy, which would not share any state with the server
+pub fn register_embedded_module(py: Python<'_>) -> PyResult<()> {
+    let module = PyModule::new(py, "hypermangle_py")?;
+    hypermangle_py(py, module)?;
+    py.import("sys")?
+        .getattr("modules")?
+        .set_item("hypermangle_py", module)
+}
+
 #[pymodule]
 fn hypermangle_py(py: Python<'_>, m: &PyModule) -> PyResult<()> {
     m.add("ClosedWebSocket", py.get_type::<ClosedWebSocket>())?;
     m.add("WebSocketError", py.get_type::<WebSocketError>())?;
     m.add("NotYetAccepted", py.get_type::<NotYetAccepted>())?;
     m.add("AlreadyAccepted", py.get_type::<AlreadyAccepted>())?;
+    m.add("CpuBudgetExceeded", py.get_type::<CpuBudgetExceeded>())?;
     m.add_class::<WebSocket>()?;
     m.add_class::<WebSocketMessage>()?;
+    m.add_class::<ApiKey>()?;
+    m.add_class::<RequestContext>()?;
+    m.add_class::<TlsInfo>()?;
+    m.add_class::<IncomingRequest>()?;
+    m.add_class::<UploadedFile>()?;
+    m.add_class::<Upload>()?;
+    m.add_class::<QueueMessage>()?;
+    m.add_class::<RuntimeStats>()?;
+    m.add_class::<TestClient>()?;
+    m.add_class::<Subscription>()?;
+    m.add_class::<ResponseStream>()?;
+    m.add_class::<NdJsonLines>()?;
+    m.add_class::<TestResponse>()?;
+    request_vars::add_to_module(py, m)?;
+    m.add_function(wrap_pyfunction!(runtime_stats, m)?)?;
+    m.add_function(wrap_pyfunction!(deadline, m)?)?;
+    m.add_function(wrap_pyfunction!(sign_url, m)?)?;
+    m.add_function(wrap_pyfunction!(hash_password, m)?)?;
+    m.add_function(wrap_pyfunction!(verify_password, m)?)?;
+    m.add_function(wrap_pyfunction!(emit, m)?)?;
+    m.add_function(wrap_pyfunction!(enqueue, m)?)?;
+    m.add_function(wrap_pyfunction!(publish, m)?)?;
+    m.add_function(wrap_pyfunction!(subscribe, m)?)?;
+    m.add_function(wrap_pyfunction!(now, m)?)?;
+    m.add_function(wrap_pyfunction!(new_id, m)?)?;
+    m.add_function(wrap_pyfunction!(set_time, m)?)?;
+    m.add_function(wrap_pyfunction!(advance_time, m)?)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_verify_against_their_hashes_in_either_scheme() {
+        for scheme in ["argon2", "bcrypt"] {
+            let hash = hash_with("hunter2", scheme).unwrap();
+            assert_eq!(verify_hash("hunter2", &hash), Ok(true), "{scheme}");
+            assert_eq!(verify_hash("hunter3", &hash), Ok(false), "{scheme}");
+        }
+    }
+
+    #[test]
+    fn hashes_are_salted() {
+        assert_ne!(
+            hash_with("hunter2", "argon2").unwrap(),
+            hash_with("hunter2", "argon2").unwrap()
+        );
+    }
+
+    #[test]
+    fn unknown_schemes_and_malformed_hashes_are_errors() {
+        assert!(hash_with("hunter2", "md5").is_err());
+        assert!(verify_hash("hunter2", "$argon2id$v=19$m=?,t=2$$").is_err());
+        assert!(verify_hash("hunter2", "garbage").is_err());
+    }
+}